@@ -0,0 +1,77 @@
+//! Appending a long document in small chunks, like text streamed from a
+//! network, compared to parsing everything again for every chunk.
+#![feature(test)]
+
+extern crate test;
+
+use test::Bencher;
+use wrenched::markdown::MarkdowWidget;
+
+const CHUNK_LEN: usize = 50;
+
+fn document() -> String {
+    include_str!("../test.md").repeat(4)
+}
+
+/// Splits `text` into chunks of about `CHUNK_LEN` bytes.
+fn chunks(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + CHUNK_LEN).min(text.len());
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+        chunks.push(&text[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+fn append_all(chunks: &[&str]) -> MarkdowWidget {
+    let mut widget = MarkdowWidget::from_source("", "");
+    for chunk in chunks {
+        widget.append_source(chunk);
+    }
+    widget
+}
+
+#[bench]
+fn append_chunks(b: &mut Bencher) {
+    let document = document();
+    let chunks = chunks(&document);
+    b.iter(|| append_all(&chunks));
+}
+
+/// Four times the text of `append_chunks`, the time should grow about as
+/// much and not with the square of the length.
+#[bench]
+fn append_chunks_of_a_long_document(b: &mut Bencher) {
+    let document = document().repeat(4);
+    let chunks = chunks(&document);
+    b.iter(|| append_all(&chunks));
+}
+
+/// A link reference definition at the start applies to the whole stream,
+/// the following chunks still only parse the tail.
+#[bench]
+fn append_chunks_after_a_reference_definition(b: &mut Bencher) {
+    let document = format!("[ref]: https://example.com\n\n{}", document());
+    let chunks = chunks(&document);
+    b.iter(|| append_all(&chunks));
+}
+
+#[bench]
+fn parse_everything_per_chunk(b: &mut Bencher) {
+    let document = document();
+    let chunks = chunks(&document);
+    b.iter(|| {
+        let mut source = String::new();
+        let mut widget = MarkdowWidget::from_source("", "");
+        for chunk in &chunks {
+            source.push_str(chunk);
+            widget = MarkdowWidget::from_source(source.as_str(), "");
+        }
+        widget
+    });
+}
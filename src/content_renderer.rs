@@ -0,0 +1,40 @@
+use kurbo::{Rect, Vec2};
+use vello::Scene;
+
+use crate::{markdown::MarkdownContent, theme::Theme};
+
+/// Hook into laying out and painting of markdown blocks, installed per
+/// widget with `MarkdowWidget::with_content_renderer`.
+///
+/// Every method has a default which keeps the built-in behavior, so a
+/// renderer only needs to implement what it wants to override. It is called
+/// for nested blocks (list items, quotes) as well.
+pub trait ContentRenderer: Send + Sync {
+    /// Called after the built-in layout of `content` was done for `width`.
+    /// Renderers which size themselves should compute their size here.
+    fn layout(&self, _content: &MarkdownContent, _width: f32, _theme: &Theme) {}
+
+    /// Returns the height of `content` laid out `width` wide, or `None` to
+    /// use the built-in height. Called right after `layout`.
+    fn height(&self, _content: &MarkdownContent, _width: f32) -> Option<f32> {
+        None
+    }
+
+    /// Paints `content` and returns `true`, or returns `false` to let the
+    /// built-in painting handle it.
+    fn paint(
+        &self,
+        _content: &MarkdownContent,
+        _scene: &mut Scene,
+        _translation: Vec2,
+        _source_rect: &Rect,
+        _theme: &Theme,
+    ) -> bool {
+        false
+    }
+}
+
+/// Renderer with the built-in behavior for all blocks.
+pub struct DefaultRenderer;
+
+impl ContentRenderer for DefaultRenderer {}
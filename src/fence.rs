@@ -0,0 +1,41 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::markdown::MarkdownContent;
+
+/// Turns the raw text of a fenced code block into the content shown instead.
+pub type FenceHandler = Arc<dyn Fn(&str) -> MarkdownContent + Send + Sync>;
+
+/// Handlers for fenced code blocks by the language of their info string, see
+/// `MarkdowWidget::with_fence_handler`.
+#[derive(Clone, Default)]
+pub struct FenceHandlers {
+    handlers: HashMap<String, FenceHandler>,
+}
+
+impl FenceHandlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` for fenced code blocks whose info string starts
+    /// with `language`. The handler gets the raw text of the fence and
+    /// returns the content which is shown instead of the code block.
+    /// Registering a language again replaces the previous handler.
+    pub fn insert<F>(&mut self, language: &str, handler: F)
+    where
+        F: Fn(&str) -> MarkdownContent + Send + Sync + 'static,
+    {
+        self.handlers
+            .insert(language.to_string(), Arc::new(handler));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Returns the handler for `language`, the first word of the info
+    /// string. `rust ignore` gives `rust`.
+    pub(crate) fn get(&self, language: &str) -> Option<&FenceHandler> {
+        self.handlers.get(language)
+    }
+}
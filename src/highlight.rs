@@ -0,0 +1,159 @@
+use std::ops::Range;
+
+use peniko::Color;
+
+use crate::theme::TokenClass;
+
+/// Style of a highlighted range of code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HighlightStyle {
+    /// Picks the color from the `CodeColorScheme` of the theme.
+    pub class: TokenClass,
+    /// Overrides the color of the class.
+    pub color: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+impl HighlightStyle {
+    pub fn new(class: TokenClass) -> Self {
+        HighlightStyle {
+            class,
+            color: None,
+            bold: false,
+            italic: false,
+        }
+    }
+}
+
+/// Highlights the code of fenced code blocks.
+///
+/// Implement it to reuse the highlighter of an editor or one based on
+/// tree-sitter. `SyntectHighlighter` is available with the `syntect` feature.
+pub trait SyntaxHighlighter: Send + Sync {
+    /// Returns the styled byte ranges of `text` written in `language`, the
+    /// first word of the info string of the block. Unknown languages return
+    /// no ranges and the code is shown without highlighting.
+    fn highlight(
+        &self,
+        language: &str,
+        text: &str,
+    ) -> Vec<(Range<usize>, HighlightStyle)>;
+}
+
+#[cfg(feature = "syntect")]
+pub use syntect_highlighter::SyntectHighlighter;
+
+#[cfg(feature = "syntect")]
+mod syntect_highlighter {
+    use std::ops::Range;
+
+    use syntect::{
+        parsing::{ParseState, ScopeRangeIterator, ScopeStack, SyntaxSet},
+        util::LinesWithEndings,
+    };
+    use tracing::warn;
+
+    use super::{HighlightStyle, SyntaxHighlighter};
+    use crate::theme::TokenClass;
+
+    /// Highlights with the syntax definitions bundled with syntect. Scopes
+    /// are mapped to token classes, so the colors come from the theme.
+    pub struct SyntectHighlighter {
+        syntax_set: SyntaxSet,
+    }
+
+    impl SyntectHighlighter {
+        pub fn new() -> Self {
+            Self::with_syntax_set(SyntaxSet::load_defaults_newlines())
+        }
+
+        /// Uses the syntax definitions of `syntax_set`, which must be built
+        /// for lines with their line endings.
+        pub fn with_syntax_set(syntax_set: SyntaxSet) -> Self {
+            SyntectHighlighter { syntax_set }
+        }
+    }
+
+    impl Default for SyntectHighlighter {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl SyntaxHighlighter for SyntectHighlighter {
+        fn highlight(
+            &self,
+            language: &str,
+            text: &str,
+        ) -> Vec<(Range<usize>, HighlightStyle)> {
+            let Some(syntax) = self.syntax_set.find_syntax_by_token(language) else {
+                return Vec::new();
+            };
+            let mut state = ParseState::new(syntax);
+            let mut stack = ScopeStack::new();
+            let mut spans = Vec::new();
+            let mut offset = 0;
+            for line in LinesWithEndings::from(text) {
+                let ops = match state.parse_line(line, &self.syntax_set) {
+                    Ok(ops) => ops,
+                    Err(e) => {
+                        warn!("Failed to highlight {language} code: {e}");
+                        return Vec::new();
+                    }
+                };
+                for (range, op) in ScopeRangeIterator::new(&ops, line) {
+                    if stack.apply(op).is_err() {
+                        return Vec::new();
+                    }
+                    if range.is_empty() {
+                        continue;
+                    }
+                    let class = token_class(&stack);
+                    if class != TokenClass::Plain {
+                        spans.push((
+                            offset + range.start..offset + range.end,
+                            HighlightStyle::new(class),
+                        ));
+                    }
+                }
+                offset += line.len();
+            }
+            spans
+        }
+    }
+
+    /// Class of the innermost scope which has one.
+    fn token_class(stack: &ScopeStack) -> TokenClass {
+        for scope in stack.as_slice().iter().rev() {
+            let name = scope.build_string();
+            let class = if name.starts_with("comment") {
+                TokenClass::Comment
+            } else if name.starts_with("string") {
+                TokenClass::String
+            } else if name.starts_with("constant.numeric") {
+                TokenClass::Number
+            } else if name.starts_with("constant") {
+                TokenClass::Constant
+            } else if name.starts_with("entity.name.function")
+                || name.starts_with("support.function")
+            {
+                TokenClass::Function
+            } else if name.starts_with("entity.name.type")
+                || name.starts_with("entity.name.class")
+                || name.starts_with("entity.name.struct")
+                || name.starts_with("entity.name.enum")
+                || name.starts_with("support.type")
+                || name.starts_with("support.class")
+            {
+                TokenClass::Type
+            } else if name.starts_with("keyword") || name.starts_with("storage") {
+                TokenClass::Keyword
+            } else {
+                continue;
+            };
+            return class;
+        }
+        TokenClass::Plain
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     cmp::Ordering,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range},
     slice::Iter,
 };
 
@@ -11,9 +11,21 @@ pub struct LayoutElement<Data> {
     // TODO: Change to Rect which has all offset(x,y), height, and width.
     pub offset: f32,
     pub height: f32,
+    /// Height given by the layout in place of `LayoutData::height`, see
+    /// `LayoutFlow::layout_all`.
+    pub custom_height: Option<f32>,
+    /// Hidden elements take no space in the flow and are not painted.
+    pub hidden: bool,
     pub data: Data,
 }
 
+impl<Data: LayoutData> LayoutElement<Data> {
+    /// Height the element takes when it is shown.
+    fn shown_height(&self) -> f32 {
+        self.custom_height.unwrap_or_else(|| self.data.height())
+    }
+}
+
 impl<Data> LayoutElement<Data> {
     pub fn get_source_rect(&self, parent_source_rect: &Rect) -> Rect {
         let x0 = 0.0;
@@ -34,6 +46,20 @@ pub struct LayoutFlow<Data> {
 
 pub trait LayoutData {
     fn height(&self) -> f32;
+
+    fn width(&self) -> f32;
+
+    /// Space requested above the element. It is suppressed for the first
+    /// element of a flow and collapses with the previous bottom margin.
+    fn top_margin(&self) -> f32 {
+        0.0
+    }
+
+    /// Space requested below the element. It collapses with the next top
+    /// margin.
+    fn bottom_margin(&self) -> f32 {
+        0.0
+    }
 }
 
 pub struct MutableData<'a, Data: LayoutData> {
@@ -66,14 +92,25 @@ where
     Data: LayoutData,
 {
     fn drop(&mut self) {
-        let new_height = self.layout_flow.flow[self.index].data.height();
-        let height_diff = new_height - self.layout_flow.flow[self.index].height;
+        let element = &self.layout_flow.flow[self.index];
+        if element.hidden {
+            return;
+        }
+        let height_diff = element.shown_height() - element.height;
         if height_diff.abs() > f32::EPSILON {
             self.layout_flow.recompute_from_index(self.index);
         }
     }
 }
 
+impl<Data: LayoutData> MutableData<'_, Data> {
+    /// Replaces `LayoutData::height` of the element, like the heights
+    /// returned to `LayoutFlow::layout_all`.
+    pub fn set_custom_height(&mut self, height: Option<f32>) {
+        self.layout_flow.flow[self.index].custom_height = height;
+    }
+}
+
 impl<Data: LayoutData> LayoutFlow<Data> {
     pub fn new() -> Self {
         Self {
@@ -89,60 +126,109 @@ impl<Data: LayoutData> LayoutFlow<Data> {
         }
     }
 
-    // TODO: Think about making it a `Result`
     pub fn get_visible_parts(
         &self,
         // TODO: Change it to Rect
         offset: f32,
         height: f32,
     ) -> &[LayoutElement<Data>] {
+        &self.flow[self.visible_range(offset, height)]
+    }
+
+    /// Indices of the elements overlapping `offset..offset + height`.
+    pub fn visible_range(&self, offset: f32, height: f32) -> Range<usize> {
         let bottom = offset + height;
-        if let Ok(index) = self.flow.binary_search_by(|v| {
-            // TODO: This comparison should probably use epsilon
-            if v.offset <= offset && v.offset + v.height >= offset {
-                Ordering::Equal
-            } else if v.offset < offset {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
-        }) {
-            let last_index = self.flow[index..]
-                .iter()
-                .position(|v| v.offset <= bottom && v.offset + v.height >= bottom)
-                .map(|index| index + self.flow[index..].len())
-                // TODO: Maybe this should return an error???
-                .unwrap_or(self.flow.len());
-            &self.flow[index..last_index]
-        } else {
-            &[]
-        }
+        // Elements are sorted by offset and don't overlap, but there can be
+        // gaps between them because of margins.
+        let first_index =
+            self.flow.partition_point(|v| v.offset + v.height < offset);
+        let last_index = self.flow.partition_point(|v| v.offset <= bottom);
+        first_index..last_index.max(first_index)
     }
 
     pub fn push(&mut self, element: Data) {
-        let offset = self.flow.last().map(|v| v.offset + v.height).unwrap_or(0.0);
-        let elem = LayoutElement {
-            offset,
-            height: element.height(),
+        self.flow.push(LayoutElement {
+            offset: 0.0,
+            height: 0.0,
+            custom_height: None,
+            hidden: false,
             data: element,
-        };
-        self.height += elem.height;
-        self.flow.push(elem);
+        });
+        self.recompute_from_index(self.flow.len() - 1);
     }
 
     pub fn insert(&mut self, index: usize, element: Data) {
-        let mut offset = self.flow[index].offset;
-        let elem = LayoutElement {
-            offset,
-            height: element.height(),
-            data: element,
-        };
-        offset += elem.height;
-        self.height += elem.height;
-        self.flow.insert(index, elem);
-        for e in self.flow[index + 1..].iter_mut() {
-            e.offset = offset;
-            offset += e.height;
+        self.flow.insert(
+            index,
+            LayoutElement {
+                offset: 0.0,
+                height: 0.0,
+                custom_height: None,
+                hidden: false,
+                data: element,
+            },
+        );
+        self.recompute_from_index(index);
+    }
+
+    /// Moves all elements of `other` to the end of this flow.
+    pub fn append(&mut self, mut other: LayoutFlow<Data>) {
+        let index = self.flow.len();
+        self.flow.append(&mut other.flow);
+        if index < self.flow.len() {
+            self.recompute_from_index(index);
+        }
+    }
+
+    /// Drops the elements from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        self.flow.truncate(len);
+        self.recompute_from_index(len.min(self.flow.len()));
+    }
+
+    /// Moves all elements of `other` to the start of this flow.
+    pub fn prepend(&mut self, mut other: LayoutFlow<Data>) {
+        other.flow.append(&mut self.flow);
+        self.flow = other.flow;
+        self.recopute_all();
+    }
+
+    pub fn get(&self, index: usize) -> Option<&LayoutElement<Data>> {
+        self.flow.get(index)
+    }
+
+    /// Returns the offset at which the element at `index` starts, this
+    /// includes the margin in front of it. For an `index` past the end it is
+    /// the height of the whole flow.
+    pub fn height_up_to(&self, index: usize) -> f32 {
+        self.flow
+            .get(index)
+            .map(|e| e.offset)
+            .unwrap_or(self.height)
+    }
+
+    /// Hides or shows the element at `index` and recomputes the offsets.
+    pub fn set_hidden(&mut self, index: usize, hidden: bool) {
+        if self.flow[index].hidden != hidden {
+            self.flow[index].hidden = hidden;
+            self.recompute_from_index(index);
+        }
+    }
+
+    /// Hides or shows the elements by `hidden`, one value per element from
+    /// the first. The offsets are recomputed once from the first change.
+    pub fn set_hidden_all(&mut self, hidden: impl IntoIterator<Item = bool>) {
+        let mut first_change = None;
+        for (index, (element, hidden)) in
+            self.flow.iter_mut().zip(hidden).enumerate()
+        {
+            if element.hidden != hidden {
+                element.hidden = hidden;
+                first_change.get_or_insert(index);
+            }
+        }
+        if let Some(index) = first_change {
+            self.recompute_from_index(index);
         }
     }
 
@@ -155,6 +241,14 @@ impl<Data: LayoutData> LayoutFlow<Data> {
 
     /// This return an element with correlated coordinates within the element
     pub fn get_element_at_offset(&self, offset: f32) -> Option<(&Data, f32)> {
+        self.get_index_at_offset(offset)
+            .map(|(index, corelated_offset)| {
+                (&self.flow[index].data, corelated_offset)
+            })
+    }
+
+    /// Same as `get_element_at_offset`, but returns the index of the element.
+    pub fn get_index_at_offset(&self, offset: f32) -> Option<(usize, f32)> {
         let res = self
             .flow
             .binary_search_by(|v| {
@@ -168,24 +262,41 @@ impl<Data: LayoutData> LayoutFlow<Data> {
                 }
             })
             .ok();
-        res.map(|index| {
-            let element = &self.flow[index];
-            let corelated_offset = offset - element.offset;
-            (&element.data, corelated_offset)
-        })
+        res.map(|index| (index, offset - self.flow[index].offset))
     }
 
     pub fn recopute_all(&mut self) {
         self.recompute_from_index(0);
     }
 
-    /// This return an element with correlated coordinates within the element
+    /// Recomputes heights and offsets of all elements starting at `index`.
+    /// Margins between elements are collapsed to the larger of the two and
+    /// the top margin of the first element is dropped.
     pub fn recompute_from_index(&mut self, index: usize) {
-        let mut offset = self.flow[index].offset;
+        let (mut offset, mut prev_bottom_margin) = match self.flow
+            [..index.min(self.flow.len())]
+            .iter()
+            .rev()
+            .find(|e| !e.hidden)
+        {
+            Some(prev) => {
+                (prev.offset + prev.height, Some(prev.data.bottom_margin()))
+            }
+            None => (0.0, None),
+        };
         for element in self.flow[index..].iter_mut() {
-            element.height = element.data.height();
+            if element.hidden {
+                element.height = 0.0;
+                element.offset = offset;
+                continue;
+            }
+            if let Some(bottom_margin) = prev_bottom_margin {
+                offset += bottom_margin.max(element.data.top_margin());
+            }
+            element.height = element.shown_height();
             element.offset = offset;
             offset += element.height;
+            prev_bottom_margin = Some(element.data.bottom_margin());
         }
         self.height = offset;
     }
@@ -200,6 +311,18 @@ impl<Data: LayoutData> LayoutFlow<Data> {
         self.recopute_all();
     }
 
+    /// Like `apply_to_all`, `f` returns the height of the element when it
+    /// differs from `LayoutData::height`.
+    pub fn layout_all<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Data) -> Option<f32>,
+    {
+        for e in self.flow.iter_mut() {
+            e.custom_height = f(&mut e.data);
+        }
+        self.recopute_all();
+    }
+
     pub fn iter(&self) -> Iter<'_, LayoutElement<Data>> {
         self.flow.iter()
     }
@@ -207,4 +330,218 @@ impl<Data: LayoutData> LayoutFlow<Data> {
     pub fn height(&self) -> f32 {
         self.height
     }
+
+    /// Splits the flow into pages of `page_height` and returns the offsets
+    /// where the pages start. Elements never straddle a page boundary, an
+    /// element taller than a page gets a page of its own.
+    pub fn page_starts(&self, page_height: f32) -> Vec<f32> {
+        let mut starts = vec![0.0];
+        for element in self.flow.iter().filter(|e| !e.hidden) {
+            let page_start = *starts.last().unwrap();
+            if element.offset > page_start
+                && element.offset + element.height > page_start + page_height
+            {
+                starts.push(element.offset);
+            }
+        }
+        starts
+    }
+
+    pub fn len(&self) -> usize {
+        self.flow.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flow.is_empty()
+    }
+
+    /// Width of the widest element.
+    pub fn width(&self) -> f32 {
+        self.flow.iter().map(|e| e.data.width()).fold(0.0, f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LayoutData, LayoutFlow};
+
+    struct Block {
+        height: f32,
+        margin: f32,
+    }
+
+    impl LayoutData for Block {
+        fn height(&self) -> f32 {
+            self.height
+        }
+
+        fn width(&self) -> f32 {
+            self.height * 2.0
+        }
+
+        fn top_margin(&self) -> f32 {
+            self.margin
+        }
+
+        fn bottom_margin(&self) -> f32 {
+            self.margin
+        }
+    }
+
+    fn block(height: f32, margin: f32) -> Block {
+        Block { height, margin }
+    }
+
+    #[test]
+    fn first_element_has_no_top_margin() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        assert_eq!(flow.iter().next().unwrap().offset, 0.0);
+        assert_eq!(flow.height(), 20.0);
+    }
+
+    #[test]
+    fn adjacent_margins_collapse() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(30.0, 5.0));
+        flow.push(block(20.0, 10.0));
+        flow.push(block(20.0, 0.0));
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 40.0, 70.0]);
+        assert_eq!(flow.height(), 90.0);
+    }
+
+    #[test]
+    fn leading_block_changes_height_by_its_size_and_gap() {
+        let mut without_heading = LayoutFlow::new();
+        without_heading.push(block(20.0, 10.0));
+        without_heading.push(block(20.0, 10.0));
+
+        let mut with_heading = LayoutFlow::new();
+        with_heading.push(block(40.0, 0.0));
+        with_heading.push(block(20.0, 10.0));
+        with_heading.push(block(20.0, 10.0));
+
+        assert_eq!(without_heading.height(), 50.0);
+        assert_eq!(with_heading.height(), 40.0 + 10.0 + 50.0);
+    }
+
+    #[test]
+    fn insert_at_start_recomputes_margins() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        flow.insert(0, block(20.0, 0.0));
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 30.0]);
+        assert_eq!(flow.height(), 50.0);
+    }
+
+    #[test]
+    fn append_and_prepend_collapse_margins_at_the_seam() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        let mut other = LayoutFlow::new();
+        other.push(block(20.0, 5.0));
+        flow.append(other);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 30.0]);
+
+        let mut other = LayoutFlow::new();
+        other.push(block(10.0, 0.0));
+        flow.prepend(other);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 20.0, 50.0]);
+        assert_eq!(flow.height(), 70.0);
+    }
+
+    #[test]
+    fn height_up_to_includes_margins() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        flow.push(block(30.0, 10.0));
+        assert_eq!(flow.height_up_to(0), 0.0);
+        assert_eq!(flow.height_up_to(1), 30.0);
+        assert_eq!(flow.height_up_to(2), 60.0);
+        assert_eq!(flow.width(), 60.0);
+    }
+
+    #[test]
+    fn hidden_elements_take_no_space() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        flow.push(block(20.0, 10.0));
+        flow.push(block(20.0, 10.0));
+        flow.set_hidden(1, true);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 20.0, 30.0]);
+        assert_eq!(flow.height(), 50.0);
+
+        flow.set_hidden(0, true);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 0.0, 0.0]);
+        assert_eq!(flow.height(), 20.0);
+
+        flow.set_hidden(0, false);
+        flow.set_hidden(1, false);
+        assert_eq!(flow.height(), 80.0);
+    }
+
+    #[test]
+    fn hiding_many_elements_at_once() {
+        let mut flow = LayoutFlow::new();
+        for _ in 0..4 {
+            flow.push(block(20.0, 10.0));
+        }
+        flow.set_hidden_all([false, true, false, true]);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 20.0, 30.0, 50.0]);
+        assert_eq!(flow.height(), 50.0);
+
+        // Elements past the values are left alone.
+        flow.set_hidden_all([false, false]);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 30.0, 60.0, 80.0]);
+        assert_eq!(flow.height(), 80.0);
+    }
+
+    #[test]
+    fn page_starts_dont_split_elements() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(40.0, 0.0));
+        flow.push(block(40.0, 0.0));
+        flow.push(block(250.0, 0.0));
+        flow.push(block(40.0, 0.0));
+        flow.push(block(40.0, 0.0));
+        assert_eq!(flow.page_starts(100.0), [0.0, 80.0, 330.0]);
+        assert_eq!(LayoutFlow::<Block>::new().page_starts(100.0), [0.0]);
+    }
+
+    #[test]
+    fn visible_parts_skip_margin_gaps() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        flow.push(block(20.0, 10.0));
+        flow.push(block(20.0, 10.0));
+        assert_eq!(flow.get_visible_parts(0.0, 5.0).len(), 1);
+        assert_eq!(flow.get_visible_parts(22.0, 5.0).len(), 0);
+        assert_eq!(flow.get_visible_parts(25.0, 50.0).len(), 2);
+        assert_eq!(flow.visible_range(25.0, 50.0), 1..3);
+        assert_eq!(flow.visible_range(22.0, 5.0), 1..1);
+    }
+
+    #[test]
+    fn custom_heights_replace_the_data_height() {
+        let mut flow = LayoutFlow::new();
+        flow.push(block(20.0, 10.0));
+        flow.push(block(20.0, 10.0));
+        flow.layout_all(|block| (block.height == 20.0).then_some(50.0));
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 60.0]);
+        assert_eq!(flow.height(), 110.0);
+
+        flow.get_mutable(0).set_custom_height(None);
+        let offsets: Vec<f32> = flow.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, [0.0, 30.0]);
+        assert_eq!(flow.height(), 80.0);
+    }
 }
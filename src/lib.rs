@@ -2,6 +2,11 @@ pub mod buffer;
 pub mod code_text_layout;
 pub mod code_widget;
 pub mod command;
+pub mod content_renderer;
+pub mod fence;
+pub mod highlight;
 pub mod layout_flow;
 pub mod markdown;
+pub mod outline;
+pub mod pdf;
 pub mod theme;
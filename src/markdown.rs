@@ -1,27 +1,68 @@
-use std::path::{Path, PathBuf};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, SendError, Sender},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    time::{Duration, Instant},
+};
 
-use accesskit::Role;
-use kurbo::{Affine, Cap, Join, Line, Rect, Stroke, Vec2};
-use masonry::{EventCtx, PointerEvent, Widget};
+use accesskit::{NodeId, Role};
+use kurbo::{
+    Affine, BezPath, Cap, Join, Line, Point, Rect, RoundedRect, Shape, Size, Stroke,
+    Vec2,
+};
+use masonry::{
+    widget::WidgetMut, AccessEvent, Action, EventCtx, PointerButton, PointerEvent,
+    TextEvent, Widget, WidgetId,
+};
 use parley::{
-    Alignment, Cluster, Decoration, FontContext, FontStyle, GlyphRun, Layout,
-    LayoutContext, PositionedLayoutItem, RangedBuilder, RunMetrics, StyleProperty,
+    layout::{Affinity, Cursor, Selection},
+    Alignment, Cluster, Decoration, FontContext, FontSettings, FontStyle, GlyphRun,
+    Layout, LayoutContext, PositionedLayoutItem, RangedBuilder, RunMetrics,
+    StyleProperty,
 };
 use peniko::{BlendMode, Color, Fill, Image, ImageFormat};
 use pulldown_cmark::{
-    BrokenLinkCallback, Event, HeadingLevel, Options, Parser, Tag, TagEnd,
+    BrokenLink, CodeBlockKind, CowStr, Event, HeadingLevel, LinkType, Options,
+    Parser, Tag, TagEnd,
 };
 use smallvec::SmallVec;
 use tracing::{debug, error, info, warn};
-use vello::Scene;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use vello::{
+    skrifa::{
+        instance::{LocationRef, NormalizedCoord, Size as FontSize},
+        outline::{DrawSettings, OutlinePen},
+        FontRef, GlyphId, MetadataProvider,
+    },
+    Scene,
+};
+use winit::{
+    event::ElementState,
+    keyboard::{Key, NamedKey},
+};
 use xilem::{
     core::{Message, MessageResult, View, ViewMarker},
     FontWeight, Pod, ViewCtx,
 };
 
 use crate::{
+    content_renderer::{ContentRenderer, DefaultRenderer},
+    fence::FenceHandlers,
+    highlight::{HighlightStyle, SyntaxHighlighter},
     layout_flow::{LayoutData, LayoutFlow},
-    theme::{get_theme, Theme},
+    pdf::{is_win_ansi, write_pdf, PageOptions, PdfFont, PdfPage},
+    theme::{
+        get_theme, CodeColorScheme, ImageAlignment, LineStyle, Padding, Theme,
+        ThemeMode,
+    },
 };
 
 #[derive(Clone)]
@@ -34,14 +75,200 @@ pub enum ListMarker {
         start_number: u32,
         layouted: Vec<Layout<MarkdownBrush>>,
     },
+    /// Ordered list with `a.`, `b.`, ..., `z.`, `aa.` markers.
+    Alpha {
+        uppercase: bool,
+        start: u32,
+        layouted: Vec<Layout<MarkdownBrush>>,
+    },
+    /// Ordered list with `i.`, `ii.`, `iii.`, ... markers.
+    Roman {
+        uppercase: bool,
+        start: u32,
+        layouted: Vec<Layout<MarkdownBrush>>,
+    },
+}
+
+impl ListMarker {
+    /// Creates the marker of an ordered list starting at `start` from a CSS
+    /// `list-style-type` name like `lower-alpha` or `upper-roman`. Unknown
+    /// styles get numbers.
+    pub fn from_style(style: &str, start: u32) -> Self {
+        match style {
+            "lower-alpha" | "lower-latin" | "upper-alpha" | "upper-latin" => {
+                let mut marker = ListMarker::Numbers {
+                    start_number: start,
+                    layouted: Vec::new(),
+                };
+                marker.set_alpha(Some(style.starts_with("upper")));
+                marker
+            }
+            "lower-roman" | "upper-roman" => ListMarker::Roman {
+                uppercase: style == "upper-roman",
+                start,
+                layouted: Vec::new(),
+            },
+            _ => {
+                if style != "decimal" {
+                    warn!("Unknown list style {style:?}, using numbers");
+                }
+                ListMarker::Numbers {
+                    start_number: start,
+                    layouted: Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// Number of the first item of ordered lists.
+    fn start_number(&self) -> Option<u32> {
+        match self {
+            ListMarker::Symbol { .. } => None,
+            ListMarker::Numbers { start_number, .. } => Some(*start_number),
+            ListMarker::Alpha { start, .. } | ListMarker::Roman { start, .. } => {
+                Some(*start)
+            }
+        }
+    }
+
+    /// Switches ordered list markers to `style`, see `from_style`. Without a
+    /// style Roman numerals go back to numbers.
+    fn set_style(&mut self, style: Option<&str>) {
+        let Some(start) = self.start_number() else {
+            return;
+        };
+        match style {
+            Some(style) => *self = ListMarker::from_style(style, start),
+            None => {
+                if let ListMarker::Roman { .. } = self {
+                    *self = ListMarker::from_style("decimal", start);
+                }
+            }
+        }
+    }
+
+    /// Returns the marker text of the item at `index` for ordered lists.
+    fn ordered_label(&self, index: usize) -> Option<String> {
+        match self {
+            ListMarker::Symbol { .. } => None,
+            ListMarker::Numbers { start_number, .. } => {
+                Some(format!("{}.", *start_number as usize + index))
+            }
+            ListMarker::Alpha {
+                uppercase, start, ..
+            } => Some(format!(
+                "{}.",
+                alpha_label(*start as usize + index, *uppercase)
+            )),
+            ListMarker::Roman {
+                uppercase, start, ..
+            } => Some(format!(
+                "{}.",
+                to_roman(start.saturating_add(index as u32), *uppercase)
+            )),
+        }
+    }
+
+    /// Switches ordered list markers between numbers and letters.
+    fn set_alpha(&mut self, alpha: Option<bool>) {
+        let marker = match (&*self, alpha) {
+            (
+                ListMarker::Numbers {
+                    start_number: start,
+                    ..
+                }
+                | ListMarker::Alpha { start, .. },
+                Some(uppercase),
+            ) => ListMarker::Alpha {
+                uppercase,
+                start: *start,
+                layouted: Vec::new(),
+            },
+            (ListMarker::Alpha { start, .. }, None) => ListMarker::Numbers {
+                start_number: *start,
+                layouted: Vec::new(),
+            },
+            _ => return,
+        };
+        *self = marker;
+    }
+}
+
+/// Formats a 1-based `number` as letters: 1 is `a`, 26 is `z`, 27 is `aa`.
+/// There are no letters for 0, it gives `?` like `to_roman`.
+fn alpha_label(number: usize, uppercase: bool) -> String {
+    if number == 0 {
+        return "?".to_string();
+    }
+    let base = if uppercase { b'A' } else { b'a' };
+    let mut number = number;
+    let mut label = Vec::new();
+    while number > 0 {
+        number -= 1;
+        label.push(base + (number % 26) as u8);
+        number /= 26;
+    }
+    label.reverse();
+    String::from_utf8(label).unwrap()
+}
+
+/// Formats `n` as a Roman numeral, values outside of 1–3999 can't be
+/// written and give `?`.
+fn to_roman(n: u32, uppercase: bool) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    if !(1..=3999).contains(&n) {
+        return "?".to_string();
+    }
+    let mut n = n;
+    let mut numeral = String::new();
+    for (value, letters) in NUMERALS {
+        while n >= value {
+            numeral.push_str(letters);
+            n -= value;
+        }
+    }
+    if uppercase {
+        numeral.make_ascii_uppercase();
+    }
+    numeral
+}
+
+/// Generated content painted after the glyphs of a run, it is not part of
+/// the text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlyphMark {
+    #[default]
+    None,
+    /// Arrow after the last character of a link to another site.
+    ExternalLink,
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct MarkdownBrush(Color);
+pub struct MarkdownBrush(Color, LineStyle, GlyphMark);
+
+impl MarkdownBrush {
+    fn new(color: Color) -> Self {
+        MarkdownBrush(color, LineStyle::Solid, GlyphMark::None)
+    }
+}
 
 impl Default for MarkdownBrush {
     fn default() -> Self {
-        MarkdownBrush(Color::from_rgb8(0x00, 0x00, 0x00))
+        MarkdownBrush::new(Color::from_rgb8(0x00, 0x00, 0x00))
     }
 }
 
@@ -50,10 +277,36 @@ pub struct List {
     list: Vec<LayoutFlow<MarkdownContent>>,
     marker: ListMarker,
     indentation: f32,
+    /// `Theme::paragraph_spacing` of the last layout, the margin around the
+    /// list.
+    spacing: f32,
 }
 
 #[derive(Clone)]
-pub struct IndentationDecoration {}
+pub struct IndentationDecoration {
+    /// Nesting level of the block quote, 0 for the outermost one.
+    pub level: usize,
+    /// Space above and below the content, taken from the theme on layout.
+    pub padding_top: f32,
+    pub padding_bottom: f32,
+    /// Width available in the last layout, the background spans it.
+    pub width: f32,
+    /// Width of the decoration left of the content, taken from the theme on
+    /// layout.
+    pub indentation: f32,
+}
+
+impl IndentationDecoration {
+    fn new(level: usize) -> Self {
+        Self {
+            level,
+            padding_top: 0.0,
+            padding_bottom: 0.0,
+            width: 0.0,
+            indentation: 0.0,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub enum MarkdownContent {
@@ -65,95 +318,356 @@ pub enum MarkdownContent {
         level: HeadingLevel,
         text: String,
         markers: Vec<TextMarker>,
+        /// Id given in the source with `{#id}`.
+        id: Option<String>,
+        /// Id links can refer to, the explicit `id` or one generated from the
+        /// text. `None` when nothing usable could be generated.
+        anchor_id: Option<String>,
         text_layout: Layout<MarkdownBrush>,
+        /// `Theme::paragraph_spacing` of the last layout, the margin around
+        /// the block.
+        spacing: f32,
     },
     List {
         list: List,
     },
     Paragraph {
-        top_margin: f32,
         text: String,
         markers: Vec<TextMarker>,
+        /// Attribution line closing a block quote, like `— Author, Source`.
+        attribution: bool,
+        /// Text the layout was built from when it differs from `text`, after
+        /// binding the last word or showing soft hyphens. Only characters are
+        /// replaced, offsets are mapped with `map_char_offset`.
+        shown_text: Option<String>,
         text_layout: Layout<MarkdownBrush>,
+        /// `Theme::paragraph_spacing` of the last layout, the margin around
+        /// the block.
+        spacing: f32,
     },
     Image {
         uri: String,
-        title: String,
+        /// The title of `![alt](uri "title")`, shown as a caption below the
+        /// image.
+        title: Option<String>,
+        /// Describes the image for accessibility.
+        alt_text: String,
+        caption_layout: Layout<MarkdownBrush>,
+        /// Size read from the file header, used to reserve space until the
+        /// image is decoded.
+        estimated_size: Option<(u32, u32)>,
         image: Option<Image>,
+        /// Width available in the last layout, wider images are scaled down.
+        layout_width: f32,
+        /// `Theme::image_max_height` of the last layout.
+        max_height: Option<f32>,
+        /// `Theme::image_alignment` of the last layout.
+        alignment: ImageAlignment,
+        /// `Theme::scale` of the last layout, images grow with the text.
+        scale: f32,
+        /// Shows the image at its natural size even when it is too wide.
+        natural_size: bool,
+        /// `Theme::paragraph_spacing` of the last layout, the margin around
+        /// the block.
+        spacing: f32,
     },
     CodeBlock {
         text: String,
+        /// Wraps long lines even when `Theme::code_block_word_wrap` is off,
+        /// set by a `<!-- wrap -->` comment before the block.
+        wrap: bool,
+        /// First word of the info string of a fenced block.
+        language: Option<String>,
+        /// Byte ranges styled by the syntax highlighter.
+        highlights: Vec<(Range<usize>, HighlightStyle)>,
         text_layout: Layout<MarkdownBrush>,
+        /// Height of the code with `Theme::code_block_padding` above and
+        /// below it.
+        rendered_height: f32,
+        /// Width of the code with `Theme::code_block_padding` left and right
+        /// of it.
+        rendered_width: f32,
+        /// Width available in the last layout, the background spans it.
+        layout_width: f32,
+        /// `Theme::paragraph_spacing` of the last layout, the margin around
+        /// the block.
+        spacing: f32,
     },
     HorizontalLine {
         height: f32,
+        /// Width of the line, the width of the content it was laid out in.
+        width: f32,
+        style: LineStyle,
+        /// `Theme::paragraph_spacing` of the last layout, the margin around
+        /// the block.
+        spacing: f32,
+    },
+    /// Collapsible `<details>` block, only the summary is shown when closed.
+    Details {
+        summary: String,
+        summary_layout: Layout<MarkdownBrush>,
+        open: bool,
+        flow: LayoutFlow<MarkdownContent>,
+        /// `details_indentation` of the last layout, the room for the
+        /// disclosure triangle.
+        indentation: f32,
     },
 }
 
 impl MarkdownContent {
+    /// Bounding box of the painted content when the top-left corner of the
+    /// block is at `translation`. Blocks with an unknown width, like
+    /// horizontal lines, have an empty one.
+    pub fn bounding_rect(&self, translation: Vec2) -> Rect {
+        let size = match self {
+            MarkdownContent::Paragraph { text_layout, .. }
+            | MarkdownContent::Header { text_layout, .. } => Size::new(
+                aligned_width(text_layout) as f64,
+                text_layout.height() as f64,
+            ),
+            MarkdownContent::Image { caption_layout, .. } => {
+                let image = self.image_display_size().unwrap_or(Size::ZERO);
+                Size::new(
+                    (self.image_offset() + image.width)
+                        .max(aligned_width(caption_layout) as f64),
+                    image.height + caption_layout.height() as f64,
+                )
+            }
+            _ => Size::new(self.background_width() as f64, self.height() as f64),
+        };
+        Rect::from_origin_size(translation.to_point(), size)
+    }
+
+    /// Whether painting is clipped to `bounding_rect`. Only blocks whose
+    /// content can overflow their box are, like unwrapped code and images.
+    /// Text keeps its overhang, descenders and wavy underlines.
+    fn clips_to_bounds(&self) -> bool {
+        matches!(
+            self,
+            MarkdownContent::CodeBlock { .. } | MarkdownContent::Image { .. }
+        )
+    }
+
+    /// Lays the block out `width` wide. Returns its height when the
+    /// renderer of `env` replaces the built-in one.
     fn layout(
         &mut self,
         font_ctx: &mut FontContext,
         layout_ctx: &mut LayoutContext<MarkdownBrush>,
         width: f32,
         theme: &Theme,
+        env: LayoutEnv<'_>,
+        inherited: InheritedStyle,
+    ) -> Option<f32> {
+        self.default_layout(font_ctx, layout_ctx, width, theme, env, inherited);
+        env.renderer.layout(self, width, theme);
+        env.renderer.height(self, width)
+    }
+
+    fn default_layout(
+        &mut self,
+        font_ctx: &mut FontContext,
+        layout_ctx: &mut LayoutContext<MarkdownBrush>,
+        width: f32,
+        theme: &Theme,
+        env: LayoutEnv<'_>,
+        inherited: InheritedStyle,
     ) {
         match self {
             MarkdownContent::Paragraph {
                 text,
                 markers,
-                top_margin: _,
+                attribution,
+                shown_text,
                 text_layout,
+                spacing,
             } => {
-                let mut builder =
-                    text_to_builder(text, markers, font_ctx, layout_ctx);
+                *spacing = theme.paragraph_spacing;
+                let mut defaults = inherited.properties();
+                if *attribution {
+                    defaults.push(StyleProperty::FontSize(
+                        theme.text_size as f32 * theme.attribution_text_scale,
+                    ));
+                    defaults.push(StyleProperty::Brush(MarkdownBrush::new(
+                        theme.attribution_text_color,
+                    )));
+                }
+                let mut builder = text_to_builder_with(
+                    text, markers, &defaults, theme, font_ctx, layout_ctx,
+                );
+                if !*attribution {
+                    push_first_line_style(&mut builder, text, theme);
+                }
                 let mut layout = builder.build(&text);
                 layout.break_all_lines(Some(width));
+                // The text the layout was built from, when it differs.
+                let mut built = None;
+                if !*attribution
+                    && theme.orphan_widow_control
+                    && has_short_last_line(
+                        &layout,
+                        width * theme.orphan_widow_threshold,
+                    )
+                {
+                    if let Some((text, markers)) = bind_last_word(text, markers) {
+                        let mut builder = text_to_builder_with(
+                            &text, &markers, &defaults, theme, font_ctx, layout_ctx,
+                        );
+                        push_first_line_style(&mut builder, &text, theme);
+                        layout = builder.build(&text);
+                        layout.break_all_lines(Some(width));
+                        built = Some((text, markers));
+                    }
+                }
+                let (built_text, built_markers) = match &built {
+                    Some((text, markers)) => (text.as_str(), markers.as_slice()),
+                    None => (text.as_str(), markers.as_slice()),
+                };
+                let hyphenated =
+                    hyphenate_soft_breaks(&layout, built_text, built_markers);
+                if let Some((text, markers)) = &hyphenated {
+                    let mut builder = text_to_builder_with(
+                        text, markers, &defaults, theme, font_ctx, layout_ctx,
+                    );
+                    if !*attribution {
+                        push_first_line_style(&mut builder, text, theme);
+                    }
+                    layout = builder.build(text);
+                    layout.break_all_lines(Some(width));
+                }
+                *shown_text = hyphenated.or(built).map(|(text, _)| text);
+                if *attribution && theme.attribution_right_aligned {
+                    layout.align(Some(width), Alignment::End);
+                }
                 *text_layout = layout;
             }
             MarkdownContent::Image {
                 uri,
-                title: _,
+                title,
+                alt_text: _,
+                caption_layout,
+                estimated_size,
                 image,
+                layout_width,
+                max_height,
+                alignment,
+                scale,
+                natural_size: _,
+                spacing,
             } => {
+                *spacing = theme.paragraph_spacing;
+                *layout_width = width;
+                *scale = theme.scale;
+                *max_height = theme.image_max_height;
+                *alignment = theme.image_alignment;
+                *caption_layout = match title {
+                    Some(title) => {
+                        let mut builder =
+                            text_to_builder(title, &[], theme, font_ctx, layout_ctx);
+                        builder.push_default(StyleProperty::FontSize(
+                            theme.text_size as f32 * theme.image_caption_text_scale,
+                        ));
+                        builder.push_default(StyleProperty::Brush(
+                            MarkdownBrush::new(theme.image_caption_text_color),
+                        ));
+                        let mut layout = builder.build(title);
+                        layout.break_all_lines(Some(width));
+                        // The caption follows the image.
+                        let caption_alignment = match theme.image_alignment {
+                            ImageAlignment::Left => Alignment::Start,
+                            ImageAlignment::Center => Alignment::Middle,
+                            ImageAlignment::Right => Alignment::End,
+                        };
+                        layout.align(Some(width), caption_alignment);
+                        layout
+                    }
+                    None => Layout::new(),
+                };
                 // TODO: This is a bit fishy place to load images
                 if image.is_none() {
-                    // TODO: Do something about unwraps
-                    // Maybe show broken link image or something
-                    let image_data = image::open(uri).unwrap().to_rgba8();
-                    let (width, height) = image_data.dimensions();
-                    *image = Some(Image::new(
-                        image_data.to_vec().into(),
-                        ImageFormat::Rgba8,
-                        width,
-                        height,
-                    ));
+                    let Some(path) = resolve_image_uri(uri, env.base_dir) else {
+                        if env
+                            .images
+                            .is_none_or(|images| images.fail(Path::new(uri)))
+                        {
+                            warn!(
+                                "Image URI {uri:?} does not point to a local file"
+                            );
+                        }
+                        return;
+                    };
+                    if estimated_size.is_none() {
+                        *estimated_size = read_image_header_size(&path);
+                    }
+                    // TODO: Maybe show broken link image or something
+                    let decoded = match env.images {
+                        Some(images) => images.get(&path),
+                        None => decode_image(&path),
+                    };
+                    if let Some(decoded) = decoded {
+                        *estimated_size = Some((decoded.width, decoded.height));
+                        *image = Some(decoded);
+                    }
                 }
             }
             MarkdownContent::CodeBlock {
-                text: _,
-                text_layout: _,
-            } => {}
-            MarkdownContent::Indented {
-                flow,
-                decoration: _,
+                text,
+                wrap,
+                highlights,
+                text_layout,
+                rendered_height,
+                rendered_width,
+                layout_width,
+                spacing,
+                ..
             } => {
-                flow.apply_to_all(|data| {
+                *spacing = theme.paragraph_spacing;
+                *layout_width = width;
+                let padding = theme.code_block_padding;
+                // Code is not wrapped unless asked for.
+                let wrap = *wrap || theme.code_block_word_wrap;
+                let layout = layout_code(
+                    text,
+                    highlights,
+                    wrap.then_some((width - 2.0 * padding).max(0.0)),
+                    theme,
+                    font_ctx,
+                    layout_ctx,
+                );
+                *rendered_height = layout.height() + 2.0 * padding;
+                *rendered_width = layout.width() + 2.0 * padding;
+                *text_layout = layout;
+            }
+            MarkdownContent::Indented { flow, decoration } => {
+                decoration.padding_top = theme.blockquote_padding_top;
+                decoration.padding_bottom = theme.blockquote_padding_bottom;
+                decoration.width = width;
+                decoration.indentation = quote_indentation(theme);
+                let inherited = inherited.quoted(theme);
+                flow.layout_all(|data| {
                     data.layout(
                         font_ctx,
                         layout_ctx,
-                        width - theme.markdown_indentation_decoration_width,
+                        width - quote_indentation(theme),
                         theme,
-                    );
+                        env,
+                        inherited,
+                    )
                 });
-
-                // TODO: Draw indentation decoration
             }
             MarkdownContent::List { list } => {
+                let labels: Vec<String> = (0..list.list.len())
+                    .filter_map(|k| list.marker.ordered_label(k))
+                    .collect();
                 let indentation: f32 = match &mut list.marker {
                     ListMarker::Symbol { symbol, layout } => {
-                        let mut builder =
-                            text_to_builder(symbol, &[], font_ctx, layout_ctx);
+                        let mut builder = text_to_builder(
+                            symbol,
+                            &[],
+                            theme,
+                            font_ctx,
+                            layout_ctx,
+                        );
                         let mut marker_layout = builder.build(&symbol);
                         // TODO: Maybe it should get some width to prevent some stupid behaviour in some
                         // corner cases
@@ -163,76 +677,114 @@ impl MarkdownContent {
                             + theme.markdown_bullet_list_indentation
                             + theme.markdown_list_after_indentation
                     }
-                    ListMarker::Numbers {
-                        start_number,
-                        layouted,
-                    } => {
-                        let mut max_width: f32 = 0.0;
+                    ListMarker::Numbers { layouted, .. }
+                    | ListMarker::Alpha { layouted, .. }
+                    | ListMarker::Roman { layouted, .. } => {
                         layouted.clear();
-                        for k in 0..list.list.len() {
+                        for str in labels {
                             // Not ideal way to layout the numbered list, but works for now.
-                            let mut str = (k as u32 + *start_number).to_string();
-                            str.push('.');
-                            let mut builder =
-                                text_to_builder(&str, &[], font_ctx, layout_ctx);
+                            let mut builder = text_to_builder(
+                                &str,
+                                &[],
+                                theme,
+                                font_ctx,
+                                layout_ctx,
+                            );
                             let mut marker_layout = builder.build(&str);
                             // TODO: Maybe it should get some width to prevent some stupid behaviour in some
                             // corner cases
                             marker_layout.break_all_lines(None);
-                            marker_layout.align(None, Alignment::End);
-                            let width = marker_layout.full_width()
-                                + theme.markdown_numbered_list_indentation
-                                + theme.markdown_list_after_indentation;
-                            if width > max_width {
-                                max_width = width;
-                            }
                             layouted.push(marker_layout);
                         }
-                        max_width
+                        marker_column_width(
+                            layouted.iter().map(|layout| layout.full_width()),
+                            theme,
+                        )
                     }
                 };
                 list.indentation = indentation;
+                list.spacing = theme.paragraph_spacing;
 
                 for element in list.list.iter_mut() {
-                    element.apply_to_all(|data| {
-                        data.layout(
+                    let mut index = 0;
+                    element.layout_all(|data| {
+                        let height = data.layout(
                             font_ctx,
                             layout_ctx,
-                            width - indentation,
+                            width - indentation - continuation_indent(theme, index),
                             theme,
+                            env,
+                            inherited,
                         );
+                        index += 1;
+                        height
                     });
                 }
             }
-            MarkdownContent::HorizontalLine { height: _ } => {}
+            MarkdownContent::HorizontalLine {
+                height,
+                width: line_width,
+                style,
+                spacing,
+            } => {
+                *spacing = theme.paragraph_spacing;
+                // Room for the wave of wavy rules and the stroke, the line
+                // is painted in the middle.
+                *height =
+                    (2.0 * wavy_amplitude(theme.text_size as f64) + 1.0) as f32;
+                *line_width = width;
+                *style = theme.horizontal_rule_style;
+            }
+            MarkdownContent::Details {
+                summary,
+                summary_layout,
+                open: _,
+                flow,
+                indentation,
+            } => {
+                *indentation = details_indentation(theme);
+                let indentation = *indentation;
+                let mut builder =
+                    text_to_builder(summary, &[], theme, font_ctx, layout_ctx);
+                builder.push_default(StyleProperty::FontWeight(FontWeight::BOLD));
+                let mut layout = builder.build(&summary);
+                layout.break_all_lines(Some(width - indentation));
+                *summary_layout = layout;
+                // Closed content is laid out as well, so opening it only
+                // needs to recompute the offsets.
+                flow.layout_all(|data| {
+                    data.layout(
+                        font_ctx,
+                        layout_ctx,
+                        width - indentation,
+                        theme,
+                        env,
+                        inherited,
+                    )
+                });
+            }
             MarkdownContent::Header {
                 level,
                 text,
                 text_layout,
                 markers,
+                spacing,
+                ..
             } => {
-                let mut builder =
-                    text_to_builder(text, markers, font_ctx, layout_ctx);
-                let font_size = match level {
-                    HeadingLevel::H1 => theme.text_size as f32 * 2.125,
-                    HeadingLevel::H2 => theme.text_size as f32 * 1.875,
-                    HeadingLevel::H3 => theme.text_size as f32 * 1.5,
-                    HeadingLevel::H4 => theme.text_size as f32 * 1.25,
-                    HeadingLevel::H5 => theme.text_size as f32 * 1.125,
-                    HeadingLevel::H6 => theme.text_size as f32,
-                };
-                let line_height = match level {
-                    // TODO: Experiment with line height to get better results???
-                    HeadingLevel::H1 => 2.0,
-                    HeadingLevel::H2 => 2.0,
-                    HeadingLevel::H3 => 2.0,
-                    HeadingLevel::H4 => 2.0,
-                    HeadingLevel::H5 => 2.0,
-                    HeadingLevel::H6 => 2.0,
-                };
-                builder.push_default(StyleProperty::FontSize(font_size));
-                builder.push_default(StyleProperty::LineHeight(line_height));
-                builder.push_default(StyleProperty::FontWeight(FontWeight::BOLD));
+                *spacing = theme.paragraph_spacing;
+                let font_size = theme.heading_text_size(*level as usize);
+                let line_height = theme.heading_style(*level as usize).line_height;
+                // The heading styles are the base the markers, like inline code,
+                // are applied on.
+                let mut defaults = inherited.properties();
+                defaults.extend([
+                    StyleProperty::FontSize(font_size),
+                    StyleProperty::LineHeight(line_height),
+                    StyleProperty::FontWeight(FontWeight::BOLD),
+                ]);
+                let mut builder = text_to_builder_with(
+                    text, markers, &defaults, theme, font_ctx, layout_ctx,
+                );
                 let mut layout = builder.build(&text);
                 layout.break_all_lines(Some(width));
                 *text_layout = layout;
@@ -240,6 +792,47 @@ impl MarkdownContent {
         }
     }
 
+    /// Width the background of the block is filled in, the width it was
+    /// laid out in for blocks with a background.
+    fn background_width(&self) -> f32 {
+        match self {
+            MarkdownContent::CodeBlock { layout_width, .. } => {
+                layout_width.max(self.width())
+            }
+            MarkdownContent::Indented { decoration, .. } => {
+                decoration.width.max(self.width())
+            }
+            _ => self.width(),
+        }
+    }
+
+    fn background_color(&self, theme: &Theme) -> Option<Color> {
+        match self {
+            MarkdownContent::CodeBlock { .. } => theme.code_color_scheme.background,
+            MarkdownContent::Indented { .. } => theme.quote_background_color,
+            _ => None,
+        }
+    }
+
+    /// Fills the background of the block, it is painted before the content
+    /// of the block.
+    fn paint_background(
+        &self,
+        scene: &mut vello::Scene,
+        translation: Vec2,
+        width: f64,
+        theme: &Theme,
+    ) {
+        let Some(color) = self.background_color(theme) else {
+            return;
+        };
+        let rect = Rect::from_origin_size(
+            translation.to_point(),
+            (width, self.height() as f64),
+        );
+        scene.fill(Fill::NonZero, Affine::IDENTITY, color, None, &rect);
+    }
+
     // TODO: Unify paint and draw call names.
     fn paint(
         &self,
@@ -247,51 +840,123 @@ impl MarkdownContent {
         mut translation: Vec2,
         source_rect: &Rect,
         theme: &Theme,
+        renderer: &dyn ContentRenderer,
     ) {
+        self.paint_background(
+            scene,
+            translation,
+            self.background_width() as f64,
+            theme,
+        );
         match self {
             MarkdownContent::Paragraph {
-                top_margin: _,
                 text: _,
                 markers: _,
+                attribution: _,
+                shown_text: _,
                 text_layout,
-            } => draw_text(scene, text_layout, translation, source_rect),
+                spacing: _,
+            } => draw_text(scene, text_layout, translation, source_rect, theme),
             MarkdownContent::Image {
-                uri: _,
-                title: _,
                 image,
+                caption_layout,
+                ..
             } => {
-                if let Some(image) = image {
-                    draw_image(scene, image, translation);
+                let size = self.image_display_size();
+                if let (Some(image), Some(size)) = (image, size) {
+                    let origin = translation + Vec2::new(self.image_offset(), 0.0);
+                    let frame = image_frame(
+                        Rect::from_origin_size(origin.to_point(), size),
+                        theme,
+                    );
+                    let clip = theme.image_corner_radius > 0.0;
+                    if clip {
+                        scene.push_layer(
+                            BlendMode::default(),
+                            1.,
+                            Affine::IDENTITY,
+                            &frame,
+                        );
+                    }
+                    draw_image(
+                        scene,
+                        image,
+                        origin,
+                        size.width / image.width as f64,
+                    );
+                    if clip {
+                        scene.pop_layer();
+                    }
+                    if let Some(color) = theme.image_border_color {
+                        scene.stroke(
+                            &Stroke::new(theme.image_border_width as f64),
+                            Affine::IDENTITY,
+                            color,
+                            None,
+                            &image_border(frame, theme),
+                        );
+                    }
                 }
+                let mut caption_translation = translation;
+                caption_translation.y += size.map_or(0.0, |size| size.height);
+                draw_text(
+                    scene,
+                    caption_layout,
+                    caption_translation,
+                    source_rect,
+                    theme,
+                );
             }
-            MarkdownContent::CodeBlock {
-                text: _,
-                text_layout: _,
-            } => todo!(),
-            MarkdownContent::Indented {
-                flow,
-                decoration: _,
-            } => {
-                let mut translation_elem = translation;
-                translation_elem.x +=
-                    theme.markdown_indentation_decoration_width as f64;
-                draw_flow(scene, flow, translation_elem, source_rect, theme, false);
+            MarkdownContent::CodeBlock { text_layout, .. } => {
+                let padding = theme.code_block_padding as f64;
+                draw_text(
+                    scene,
+                    text_layout,
+                    translation + Vec2::new(padding, padding),
+                    source_rect,
+                    theme,
+                )
+            }
+            MarkdownContent::Indented { flow, decoration } => {
+                draw_indentation_decoration(
+                    scene,
+                    decoration,
+                    self.height(),
+                    translation,
+                    theme,
+                );
+                let padding = Vec2::new(0.0, decoration.padding_top as f64);
+                let mut translation_elem = translation + padding;
+                translation_elem.x += quote_indentation(theme) as f64;
+                draw_flow(
+                    scene,
+                    flow,
+                    translation_elem,
+                    &(*source_rect - padding),
+                    theme,
+                    renderer,
+                );
             }
             MarkdownContent::List { list } => {
                 // TODO: Maybe it should get some width to prevent some stupid behaviour in some
                 // corner cases
                 // TODO: Maybe the LayoutFlow should have similar interface to list so it can be
                 // easily used to make the list bullet point and other stuff.
+                let mut item_y = 0.0;
                 for (index, flow) in list.list.iter().enumerate() {
+                    // The visible part of the item, relative to its top.
+                    let source_rect = &source_rect
+                        .with_origin((source_rect.x0, source_rect.y0 - item_y));
                     let mut translation_elem = translation;
                     translation_elem.x += list.indentation as f64;
-                    draw_flow(
+                    draw_flow_with_continuation(
                         scene,
                         flow,
                         translation_elem,
                         source_rect,
                         theme,
-                        false,
+                        renderer,
+                        theme.list_continuation_indent as f64,
                     );
                     match &list.marker {
                         ListMarker::Symbol { symbol: _, layout } => {
@@ -303,886 +968,12456 @@ impl MarkdownContent {
                                 layout,
                                 marker_translation,
                                 source_rect,
+                                theme,
                             );
                         }
-                        ListMarker::Numbers {
-                            start_number: _,
-                            layouted,
-                        } => {
+                        ListMarker::Numbers { layouted, .. }
+                        | ListMarker::Alpha { layouted, .. }
+                        | ListMarker::Roman { layouted, .. } => {
                             let mut marker_translation = translation;
-                            marker_translation.x += (list.indentation
-                                - layouted[index].full_width()
-                                - theme.markdown_list_after_indentation)
+                            marker_translation.x += number_marker_x(
+                                list.indentation,
+                                layouted[index].full_width(),
+                                theme,
+                            )
                                 as f64;
                             draw_text(
                                 scene,
                                 &layouted[index],
                                 marker_translation,
                                 source_rect,
+                                theme,
                             );
                         }
                     }
                     translation.y += flow.height() as f64;
+                    item_y += flow.height() as f64;
                 }
             }
-            MarkdownContent::HorizontalLine { height: _ } => todo!(),
-            MarkdownContent::Header {
-                level: _,
-                text: _,
-                text_layout,
-                markers: _,
+            MarkdownContent::HorizontalLine {
+                height,
+                width,
+                style,
+                spacing: _,
+            } => {
+                let y = translation.y + *height as f64 / 2.0;
+                let x1 = translation.x;
+                let x2 = x1 + *width as f64;
+                let shape = match style {
+                    LineStyle::Wavy => wavy_line(x1, x2, y, theme.text_size as f64),
+                    _ => Line::new((x1, y), (x2, y)).to_path(0.1),
+                };
+                let stroke = Stroke::new(1.0).with_dashes(0.0, style.dash_pattern());
+                scene.stroke(
+                    &stroke,
+                    Affine::IDENTITY,
+                    theme.text_color.multiply_alpha(0.3),
+                    None,
+                    &shape,
+                );
+            }
+            MarkdownContent::Details {
+                summary: _,
+                summary_layout,
+                open,
+                flow,
+                indentation: _,
             } => {
-                draw_text(scene, text_layout, translation, source_rect);
+                let indentation = details_indentation(theme) as f64;
+                draw_disclosure_triangle(
+                    scene,
+                    translation,
+                    indentation,
+                    *open,
+                    theme.text_color,
+                );
+                let mut summary_translation = translation;
+                summary_translation.x += indentation;
+                draw_text(
+                    scene,
+                    summary_layout,
+                    summary_translation,
+                    source_rect,
+                    theme,
+                );
+                if *open {
+                    let summary_height = summary_layout.height() as f64;
+                    let mut flow_translation = summary_translation;
+                    flow_translation.y += summary_height;
+                    let flow_source_rect = source_rect.with_origin((
+                        source_rect.x0,
+                        source_rect.y0 - summary_height,
+                    ));
+                    draw_flow(
+                        scene,
+                        flow,
+                        flow_translation,
+                        &flow_source_rect,
+                        theme,
+                        renderer,
+                    );
+                }
+            }
+            MarkdownContent::Header { text_layout, .. } => {
+                draw_text(scene, text_layout, translation, source_rect, theme);
             }
         }
     }
 }
 
-impl LayoutData for MarkdownContent {
-    fn height(&self) -> f32 {
+impl MarkdownContent {
+    /// Writes the block to a PDF page with its top-left corner at
+    /// `translation`, mirroring `paint`. `width` is only used by blocks
+    /// which span the whole width, like horizontal lines.
+    fn export_to_pdf(
+        &self,
+        page: &mut PdfPage,
+        mut translation: Vec2,
+        width: f64,
+        theme: &Theme,
+    ) {
+        if let Some(color) = self.background_color(theme) {
+            let rect = Rect::from_origin_size(
+                translation.to_point(),
+                (self.background_width() as f64, self.height() as f64),
+            );
+            page.fill_rect(rect, color);
+        }
         match self {
             MarkdownContent::Paragraph {
-                top_margin,
-                text: _,
-                markers: _,
-                text_layout,
-            } => text_layout.height() + top_margin,
-            MarkdownContent::Image {
-                uri: _,
-                title: _,
-                image,
-            } => image.as_ref().map(|i| i.height as f32).unwrap_or(0.0),
-            MarkdownContent::CodeBlock {
-                text: _,
+                text,
+                shown_text,
                 text_layout,
-            } => text_layout.height(),
-            MarkdownContent::Indented {
-                flow,
-                decoration: _,
-            } => flow.height(),
-            MarkdownContent::List { list } => {
-                list.list.iter().map(|l| l.height()).sum()
+                ..
+            } => {
+                let text = shown_text.as_deref().unwrap_or(text);
+                export_text_to_pdf(
+                    page,
+                    text_layout,
+                    text,
+                    translation,
+                    PdfFont::Sans,
+                );
             }
-            MarkdownContent::HorizontalLine { height } => *height,
             MarkdownContent::Header {
-                level: _,
-                text: _,
+                text, text_layout, ..
+            } => export_text_to_pdf(
+                page,
                 text_layout,
-                markers: _,
-            } => text_layout.height(),
-        }
-    }
-}
-
-#[derive(Clone)]
-pub struct TextMarker {
-    // TODO: Think about making it into range
-    start_pos: usize,
-    end_pos: usize,
-    kind: MarkerKind,
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-enum MarkerKind {
-    Bold,
-    Italic,
-    Strikethrough,
-    InlineCode,
-}
-
-fn process_image_events<'a, T: BrokenLinkCallback<'a>>(
-    events: &mut Parser<'a, T>,
-) -> String {
-    let mut text = String::new();
-    for event in events {
-        match event {
-            Event::Text(cow_str) => text = cow_str.to_string(),
-            Event::End(TagEnd::Image) => return text,
-            e => {
-                error!("Image tag parsing expects only Text event but {e:?} was received")
+                text,
+                translation,
+                PdfFont::Sans,
+            ),
+            MarkdownContent::CodeBlock {
+                text, text_layout, ..
+            } => {
+                let padding = theme.code_block_padding as f64;
+                export_text_to_pdf(
+                    page,
+                    text_layout,
+                    &shown_code_text(text),
+                    translation + Vec2::new(padding, padding),
+                    PdfFont::Mono,
+                );
+            }
+            MarkdownContent::Image {
+                image,
+                title,
+                caption_layout,
+                ..
+            } => {
+                let size = self.image_display_size();
+                if let (Some(image), Some(size)) = (image, size) {
+                    let origin = translation + Vec2::new(self.image_offset(), 0.0);
+                    page.image(
+                        Rect::from_origin_size(origin.to_point(), size),
+                        image,
+                    );
+                }
+                if let Some(title) = title {
+                    let mut caption_translation = translation;
+                    caption_translation.y += size.map_or(0.0, |size| size.height);
+                    export_text_to_pdf(
+                        page,
+                        caption_layout,
+                        title,
+                        caption_translation,
+                        PdfFont::Sans,
+                    );
+                }
+            }
+            MarkdownContent::Indented { flow, decoration } => {
+                if let Some((border, color)) =
+                    indentation_border(decoration, self.height(), theme)
+                {
+                    page.fill_rect(border + translation, color);
+                }
+                let indentation = quote_indentation(theme) as f64;
+                export_flow_to_pdf(
+                    page,
+                    flow,
+                    translation
+                        + Vec2::new(indentation, decoration.padding_top as f64),
+                    width - indentation,
+                    theme,
+                    0.0,
+                );
+            }
+            MarkdownContent::List { list } => {
+                for (index, flow) in list.list.iter().enumerate() {
+                    export_flow_to_pdf(
+                        page,
+                        flow,
+                        translation + Vec2::new(list.indentation as f64, 0.0),
+                        width - list.indentation as f64,
+                        theme,
+                        theme.list_continuation_indent as f64,
+                    );
+                    match &list.marker {
+                        ListMarker::Symbol { symbol, layout } => {
+                            let mut marker_translation = translation;
+                            marker_translation.x +=
+                                theme.markdown_bullet_list_indentation as f64;
+                            export_text_to_pdf(
+                                page,
+                                layout,
+                                symbol,
+                                marker_translation,
+                                PdfFont::Sans,
+                            );
+                        }
+                        ListMarker::Numbers { layouted, .. }
+                        | ListMarker::Alpha { layouted, .. }
+                        | ListMarker::Roman { layouted, .. } => {
+                            let label =
+                                list.marker.ordered_label(index).unwrap_or_default();
+                            let mut marker_translation = translation;
+                            marker_translation.x += number_marker_x(
+                                list.indentation,
+                                layouted[index].full_width(),
+                                theme,
+                            )
+                                as f64;
+                            export_text_to_pdf(
+                                page,
+                                &layouted[index],
+                                &label,
+                                marker_translation,
+                                PdfFont::Sans,
+                            );
+                        }
+                    }
+                    translation.y += flow.height() as f64;
+                }
+            }
+            MarkdownContent::HorizontalLine { height, style, .. } => {
+                let y = translation.y + *height as f64 / 2.0;
+                for (x1, x2) in dash_segments(style.dash_pattern(), width) {
+                    page.fill_rect(
+                        Rect::new(
+                            translation.x + x1,
+                            y - 0.5,
+                            translation.x + x2,
+                            y + 0.5,
+                        ),
+                        theme.text_color.multiply_alpha(0.3),
+                    );
+                }
+            }
+            MarkdownContent::Details {
+                summary,
+                summary_layout,
+                open,
+                flow,
+                indentation: _,
+            } => {
+                let indentation = details_indentation(theme) as f64;
+                let mut summary_translation = translation;
+                summary_translation.x += indentation;
+                export_text_to_pdf(
+                    page,
+                    summary_layout,
+                    summary,
+                    summary_translation,
+                    PdfFont::Sans,
+                );
+                if *open {
+                    let mut flow_translation = summary_translation;
+                    flow_translation.y += summary_layout.height() as f64;
+                    export_flow_to_pdf(
+                        page,
+                        flow,
+                        flow_translation,
+                        width - indentation,
+                        theme,
+                        0.0,
+                    );
+                }
             }
         }
     }
-    error!("Image tag parsing expects Image End tag and none was received");
-    String::new()
-}
-
-struct MarkeerState {
-    bold_start: usize,
-    italic_start: usize,
-    strikethrough_start: usize,
-    markers: Vec<TextMarker>,
-}
 
-impl MarkeerState {
-    fn new() -> Self {
-        Self {
-            bold_start: 0,
-            italic_start: 0,
-            strikethrough_start: 0,
-            markers: Vec::new(),
+    /// Toggles the `<details>` block whose summary is at `offset` relative to
+    /// this content. Returns `true` when a block was toggled.
+    fn toggle_details_at(&mut self, offset: f32) -> bool {
+        match self {
+            MarkdownContent::Details {
+                summary_layout,
+                open,
+                flow,
+                ..
+            } => {
+                let summary_height = summary_layout.height();
+                if offset <= summary_height {
+                    *open = !*open;
+                    true
+                } else if *open {
+                    toggle_details_in_flow(flow, offset - summary_height)
+                } else {
+                    false
+                }
+            }
+            MarkdownContent::Indented { flow, decoration } => {
+                toggle_details_in_flow(flow, offset - decoration.padding_top)
+            }
+            MarkdownContent::List { list } => {
+                let mut offset = offset;
+                for item in list.list.iter_mut() {
+                    if offset <= item.height() {
+                        return toggle_details_in_flow(item, offset);
+                    }
+                    offset -= item.height();
+                }
+                false
+            }
+            _ => false,
         }
     }
-}
 
-fn process_marker(
-    event: &Event,
-    marker_state: &mut MarkeerState,
-    text_end: usize,
-) -> bool {
-    match event {
-        Event::Start(Tag::Strong) => {
-            marker_state.bold_start = text_end;
-            true
+    /// Returns the size the image is shown at. Images wider than the layout
+    /// width or taller than `Theme::image_max_height` are scaled down to fit
+    /// unless `natural_size` is set.
+    fn image_display_size(&self) -> Option<Size> {
+        let MarkdownContent::Image {
+            estimated_size,
+            image,
+            layout_width,
+            scale,
+            max_height,
+            natural_size,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let (width, height) = image
+            .as_ref()
+            .map(|image| (image.width, image.height))
+            .or(*estimated_size)?;
+        let mut size = Size::new(width as f64, height as f64) * *scale as f64;
+        if *natural_size {
+            return Some(size);
         }
-        Event::Start(Tag::Emphasis) => {
-            marker_state.italic_start = text_end;
-            true
+        let max_width = *layout_width as f64;
+        if max_width > 0.0 && size.width > max_width {
+            size = size * (max_width / size.width);
         }
-        Event::Start(Tag::Strikethrough) => {
-            marker_state.strikethrough_start = text_end;
-            true
+        if let Some(max_height) = max_height.map(f64::from) {
+            if max_height > 0.0 && size.height > max_height {
+                size = size * (max_height / size.height);
+            }
         }
-        Event::End(TagEnd::Strong) => {
-            marker_state.markers.push(TextMarker {
-                start_pos: marker_state.bold_start,
-                end_pos: text_end,
-                kind: MarkerKind::Bold,
-            });
-            true
+        Some(size)
+    }
+
+    /// Horizontal offset of the image in the layout width, by its alignment.
+    fn image_offset(&self) -> f64 {
+        let MarkdownContent::Image {
+            layout_width,
+            alignment,
+            ..
+        } = self
+        else {
+            return 0.0;
+        };
+        self.image_display_size().map_or(0.0, |size| {
+            alignment.offset(size.width, *layout_width as f64)
+        })
+    }
+
+    /// Paragraph without any inline styling.
+    pub fn paragraph(text: String) -> Self {
+        MarkdownContent::Paragraph {
+            text,
+            markers: Vec::new(),
+            attribution: false,
+            shown_text: None,
+            text_layout: Layout::new(),
+            spacing: 0.0,
         }
-        Event::End(TagEnd::Emphasis) => {
-            marker_state.markers.push(TextMarker {
-                start_pos: marker_state.strikethrough_start,
-                end_pos: text_end,
-                kind: MarkerKind::Italic,
-            });
-            true
+    }
+
+    pub fn code_block(text: String) -> Self {
+        MarkdownContent::CodeBlock {
+            text,
+            wrap: false,
+            language: None,
+            highlights: Vec::new(),
+            text_layout: Layout::new(),
+            rendered_height: 0.0,
+            rendered_width: 0.0,
+            layout_width: 0.0,
+            spacing: 0.0,
         }
-        Event::End(TagEnd::Strikethrough) => {
-            marker_state.markers.push(TextMarker {
-                start_pos: marker_state.strikethrough_start,
-                end_pos: text_end,
-                kind: MarkerKind::Strikethrough,
-            });
-            true
+    }
+
+    fn accessibility_role(&self) -> Role {
+        match self {
+            MarkdownContent::Indented { .. } => Role::Blockquote,
+            MarkdownContent::Header { .. } => Role::Heading,
+            MarkdownContent::List { .. } => Role::List,
+            MarkdownContent::Paragraph { .. } => Role::Paragraph,
+            MarkdownContent::Image { .. } => Role::Image,
+            MarkdownContent::CodeBlock { .. } => Role::Code,
+            MarkdownContent::HorizontalLine { .. } => Role::Splitter,
+            MarkdownContent::Details { .. } => Role::Details,
         }
-        _ => false,
     }
-}
 
-fn process_header_events<'a, T: BrokenLinkCallback<'a>>(
-    events: &mut Parser<'a, T>,
-    header_level: &HeadingLevel,
-) -> MarkdownContent {
-    let mut text = String::new();
-    let mut marker_state = MarkeerState::new();
-    for event in events {
-        if process_marker(&event, &mut marker_state, text.len()) {
-            continue;
+    /// Maps a byte `range` of `selectable_text` to the text the layout was
+    /// built from, with expanded tabs, bound words and shown hyphens.
+    fn shown_range(&self, range: Range<usize>) -> Range<usize> {
+        match self {
+            MarkdownContent::Paragraph {
+                text,
+                shown_text: Some(shown_text),
+                ..
+            } => {
+                map_char_offset(text, shown_text, range.start)
+                    ..map_char_offset(text, shown_text, range.end)
+            }
+            MarkdownContent::CodeBlock { text, .. } => match expand_tabs(text) {
+                Some((_, offsets)) => {
+                    offsets[range.start.min(text.len())]
+                        ..offsets[range.end.min(text.len())]
+                }
+                None => range,
+            },
+            _ => range,
         }
-        match event {
-            Event::Text(cow_str) => text.push_str(&cow_str),
-            Event::End(TagEnd::Heading(_)) => {
-                return MarkdownContent::Header {
-                    level: *header_level,
-                    text,
-                    markers: marker_state.markers,
-                    text_layout: Layout::new(),
+    }
+
+    /// Text `ContentPos::byte_offset` counts in, `None` for items which are
+    /// selected whole.
+    fn selectable_text(&self) -> Option<&str> {
+        match self {
+            MarkdownContent::Paragraph { text, .. }
+            | MarkdownContent::Header { text, .. }
+            | MarkdownContent::CodeBlock { text, .. } => Some(text),
+            _ => None,
+        }
+    }
+
+    fn write_plain_text(&self, out: &mut String) {
+        match self {
+            // Soft hyphens are only hints for the line breaking, the
+            // non-breaking spaces are kept.
+            MarkdownContent::Paragraph { text, .. } => {
+                out.extend(text.chars().filter(|&c| c != '\u{ad}'))
+            }
+            MarkdownContent::Header { level, text, .. } => {
+                for _ in 0..*level as usize {
+                    out.push('#');
                 }
+                out.push(' ');
+                out.extend(text.chars().filter(|&c| c != '\u{ad}'));
             }
-            e => {
-                error!("Header tag parsing expects only some event but {e:?} was received")
+            MarkdownContent::Image { alt_text, .. } => {
+                out.push_str("[image: ");
+                out.push_str(alt_text);
+                out.push(']');
+            }
+            MarkdownContent::CodeBlock { text, .. } => {
+                push_prefixed_lines(out, text.trim_end_matches('\n'), "    ", "    ")
+            }
+            MarkdownContent::Indented { flow, .. } => {
+                push_prefixed_lines(out, &flow_to_plain_text(flow), "> ", "> ")
+            }
+            MarkdownContent::List { list } => {
+                for (index, item) in list.list.iter().enumerate() {
+                    if index > 0 {
+                        out.push('\n');
+                    }
+                    let marker = match list.marker.ordered_label(index) {
+                        Some(label) => format!("{label} "),
+                        None => "- ".to_string(),
+                    };
+                    let indent = " ".repeat(marker.len());
+                    push_prefixed_lines(
+                        out,
+                        &flow_to_plain_text(item),
+                        &marker,
+                        &indent,
+                    );
+                }
+            }
+            MarkdownContent::HorizontalLine { .. } => out.push_str("---"),
+            MarkdownContent::Details { summary, flow, .. } => {
+                out.push_str(summary);
+                if !flow.iter().as_slice().is_empty() {
+                    out.push_str("\n\n");
+                    out.push_str(&flow_to_plain_text(flow));
+                }
             }
         }
     }
-    panic!("Header tag parsing expects Heading end tag and none was received");
 }
 
-fn process_list_events<'a, T: BrokenLinkCallback<'a>>(
-    events: &mut Parser<'a, T>,
-) -> Vec<LayoutFlow<MarkdownContent>> {
-    let mut list_elements = Vec::new();
-
-    while let Some(event) = events.next() {
-        println!("Event: {event:?}");
-        if let Event::Start(Tag::Item) = event {
-            list_elements
-                .push(process_events(events, Some(Event::End(TagEnd::Item))));
-        } else if let Event::End(TagEnd::List(_)) = event {
-            break;
-        } else {
-            panic!("List tag parsing expects List end tag; received {event:?}");
+/// Pushes `text` into `out` with `first` prepended to the first line and
+/// `rest` prepended to all following non-empty lines.
+fn push_prefixed_lines(out: &mut String, text: &str, first: &str, rest: &str) {
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+        if index == 0 {
+            out.push_str(first);
+        } else if !line.is_empty() {
+            out.push_str(rest);
         }
+        out.push_str(line);
     }
-    list_elements
 }
 
-fn process_events<'a, T: BrokenLinkCallback<'a>>(
-    events: &mut Parser<'a, T>,
-    untill: Option<Event>,
-) -> LayoutFlow<MarkdownContent> {
-    let mut res = LayoutFlow::new();
+fn for_each_code_block<F>(flow: &mut LayoutFlow<MarkdownContent>, f: &mut F)
+where
+    F: FnMut(&mut MarkdownContent),
+{
+    for element in flow.flow.iter_mut() {
+        match &mut element.data {
+            content @ MarkdownContent::CodeBlock { .. } => f(content),
+            MarkdownContent::List { list } => {
+                for item in list.list.iter_mut() {
+                    for_each_code_block(item, f);
+                }
+            }
+            MarkdownContent::Indented { flow, .. }
+            | MarkdownContent::Details { flow, .. } => for_each_code_block(flow, f),
+            _ => {}
+        }
+    }
+}
 
-    let mut text = String::new();
-    let mut marker_state = MarkeerState::new();
+/// Columns between the tab stops of code.
+const CODE_TAB_WIDTH: usize = 4;
 
-    // TODO: Make sure the firsts element margin is 0.0.
-    while let Some(event) = events.next() {
-        println!("Event: {event:?}");
-        if let Some(event_) = &untill {
-            if &event == event_ {
-                break;
+/// Replaces the tabs of `text` with spaces up to the next tab stop. Returns
+/// `None` without tabs, otherwise the expanded text and for every byte of
+/// `text` and its end the offset in the expanded text.
+fn expand_tabs(text: &str) -> Option<(String, Vec<usize>)> {
+    if !text.contains('\t') {
+        return None;
+    }
+    let mut expanded = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    let mut column = 0;
+    for ch in text.chars() {
+        offsets.extend(std::iter::repeat_n(expanded.len(), ch.len_utf8()));
+        match ch {
+            '\t' => {
+                let spaces = CODE_TAB_WIDTH - column % CODE_TAB_WIDTH;
+                expanded.extend(std::iter::repeat_n(' ', spaces));
+                column += spaces;
+            }
+            '\n' => {
+                expanded.push(ch);
+                column = 0;
+            }
+            _ => {
+                expanded.push(ch);
+                column += 1;
             }
         }
-        if process_marker(&event, &mut marker_state, text.len()) {
+    }
+    offsets.push(expanded.len());
+    Some((expanded, offsets))
+}
+
+/// The text of code as it is laid out, with the tabs expanded.
+fn shown_code_text(text: &str) -> Cow<'_, str> {
+    match expand_tabs(text) {
+        Some((expanded, _)) => Cow::Owned(expanded),
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Maps the byte `offset` of the code `text` with expanded tabs, as it is
+/// laid out, back to `text`. Offsets inside an expanded tab give the tab.
+fn code_text_offset(text: &str, offset: usize) -> usize {
+    match expand_tabs(text) {
+        Some((_, offsets)) => offsets
+            .partition_point(|&shown| shown <= offset)
+            .saturating_sub(1),
+        None => offset,
+    }
+}
+
+/// Lays out `text` like the code blocks of markdown documents: monospace,
+/// with expanded tabs and highlighted by `highlighter` when it knows
+/// `language`. Lines are wrapped at `width` when it is given.
+pub fn build_code_layout(
+    text: &str,
+    language: Option<&str>,
+    highlighter: Option<&dyn SyntaxHighlighter>,
+    width: Option<f32>,
+    theme: &Theme,
+    font_ctx: &mut FontContext,
+    layout_ctx: &mut LayoutContext<MarkdownBrush>,
+) -> Layout<MarkdownBrush> {
+    let highlights = match (highlighter, language) {
+        (Some(highlighter), Some(language)) => highlighter.highlight(language, text),
+        _ => Vec::new(),
+    };
+    layout_code(text, &highlights, width, theme, font_ctx, layout_ctx)
+}
+
+fn layout_code(
+    text: &str,
+    highlights: &[(Range<usize>, HighlightStyle)],
+    width: Option<f32>,
+    theme: &Theme,
+    font_ctx: &mut FontContext,
+    layout_ctx: &mut LayoutContext<MarkdownBrush>,
+) -> Layout<MarkdownBrush> {
+    let expanded = expand_tabs(text);
+    let shown_text = expanded.as_ref().map_or(text, |(expanded, _)| expanded);
+    let mut builder = text_to_builder(shown_text, &[], theme, font_ctx, layout_ctx);
+    builder.push_default(StyleProperty::FontSize(theme.code_text_size()));
+    builder
+        .push_default(StyleProperty::FontStack(theme.monospace_font_stack.clone()));
+    builder.push_default(StyleProperty::Brush(MarkdownBrush::new(
+        theme.code_color_scheme.foreground,
+    )));
+    let offsets = expanded.as_ref().map(|(_, offsets)| offsets.as_slice());
+    push_highlights(&mut builder, text, offsets, highlights, theme);
+    let mut layout = builder.build(shown_text);
+    layout.break_all_lines(width);
+    layout
+}
+
+/// Styles the highlighted ranges of a code block, ranges which do not fit
+/// the text are skipped. `offsets` map them into the text with expanded
+/// tabs.
+fn push_highlights(
+    builder: &mut RangedBuilder<'_, MarkdownBrush>,
+    text: &str,
+    offsets: Option<&[usize]>,
+    highlights: &[(Range<usize>, HighlightStyle)],
+    theme: &Theme,
+) {
+    for (range, style) in highlights {
+        if range.start >= range.end
+            || range.end > text.len()
+            || !text.is_char_boundary(range.start)
+            || !text.is_char_boundary(range.end)
+        {
             continue;
         }
-        match event {
-            Event::Start(tag) => match &tag {
-                Tag::Image {
-                    link_type: _,
-                    dest_url,
-                    title,
-                    id: _,
-                } => {
-                    // TODO: Use the text...
-                    // TODO: Should the image be loaded here???
-                    // TODO: Maybe images should be done as markers instead and I
-                    // should just collect images into some `HashMap`.
-                    let _some_text = process_image_events(events);
-                    res.push(MarkdownContent::Image {
-                        uri: dest_url.to_string(),
-                        title: title.to_string(),
-                        image: None,
-                    })
-                }
-                Tag::CodeBlock(_kind) => { // TODO: Add code block
+        let range = match offsets {
+            Some(offsets) => offsets[range.start]..offsets[range.end],
+            None => range.clone(),
+        };
+        let color = style
+            .color
+            .unwrap_or_else(|| theme.code_color_scheme.token_color(style.class));
+        builder.push(
+            StyleProperty::Brush(MarkdownBrush::new(color)),
+            range.clone(),
+        );
+        if style.bold {
+            builder.push(StyleProperty::FontWeight(FontWeight::BOLD), range.clone());
+        }
+        if style.italic {
+            builder.push(StyleProperty::FontStyle(FontStyle::Italic), range.clone());
+        }
+    }
+}
+
+fn highlight_code_blocks(
+    flow: &mut LayoutFlow<MarkdownContent>,
+    highlighter: Option<&Arc<dyn SyntaxHighlighter>>,
+) {
+    for_each_code_block(flow, &mut |content| {
+        if let MarkdownContent::CodeBlock {
+            text,
+            language,
+            highlights,
+            ..
+        } = content
+        {
+            *highlights = match (highlighter, language) {
+                (Some(highlighter), Some(language)) => {
+                    highlighter.highlight(language, text)
                 }
-                Tag::Table(_alignments) => {
-                    warn!("Markdown tables not supported")
+                _ => Vec::new(),
+            };
+        }
+    });
+}
+
+/// Replaces the code blocks with a handler for their language by the content
+/// the handler returns for their text.
+fn apply_fence_handlers(
+    flow: &mut LayoutFlow<MarkdownContent>,
+    handlers: &FenceHandlers,
+) {
+    if handlers.is_empty() {
+        return;
+    }
+    for_each_code_block(flow, &mut |content| {
+        let MarkdownContent::CodeBlock {
+            text,
+            language: Some(language),
+            ..
+        } = content
+        else {
+            return;
+        };
+        if let Some(handler) = handlers.get(language) {
+            *content = handler(text);
+        }
+    });
+}
+
+fn for_each_image_uri<F>(flow: &mut LayoutFlow<MarkdownContent>, f: &mut F)
+where
+    F: FnMut(&mut String),
+{
+    for element in flow.flow.iter_mut() {
+        match &mut element.data {
+            MarkdownContent::Image { uri, .. } => f(uri),
+            MarkdownContent::List { list } => {
+                for item in list.list.iter_mut() {
+                    for_each_image_uri(item, f);
                 }
-                Tag::Paragraph => {}
-                Tag::Heading {
-                    level,
-                    id: _,
-                    classes: _,
-                    attrs: _,
-                } => res.push(process_header_events(events, level)),
-                Tag::BlockQuote(block_quote_kind) => {
-                    let flow = process_events(
-                        events,
-                        Some(Event::End(TagEnd::BlockQuote(*block_quote_kind))),
-                    );
-                    res.push(MarkdownContent::Indented {
-                        decoration: IndentationDecoration {},
-                        flow,
-                    });
-                }
-                Tag::HtmlBlock => todo!(),
-                Tag::List(list_marker) => {
-                    let list = process_list_events(events);
-                    // TODO: Think about the markers. There should be a better way to set them up
-                    let marker = if let Some(list_marker) = list_marker {
-                        ListMarker::Numbers {
-                            start_number: *list_marker as u32,
-                            layouted: Vec::new(),
-                        }
-                    } else {
-                        ListMarker::Symbol {
-                            symbol: "•".to_string(),
-                            layout: Box::new(Layout::new()),
-                        }
-                    };
-                    res.push(MarkdownContent::List {
-                        list: List {
-                            marker,
-                            list,
-                            indentation: 0.0,
-                        },
-                    });
-                }
-                Tag::FootnoteDefinition(_cow_str) => todo!(),
-                Tag::DefinitionList => {
-                    warn!("DefinitionList in markdown is not supported!")
-                }
-                Tag::DefinitionListTitle => {
-                    warn!("DefinitionList in markdown is not supported!")
-                }
-                Tag::DefinitionListDefinition => {
-                    warn!("DefinitionList in markdown is not supported!")
-                }
-                Tag::TableHead => todo!(),
-                Tag::TableRow => todo!(),
-                Tag::TableCell => todo!(),
-                Tag::Link {
-                    link_type: _,
-                    dest_url: _,
-                    title: _,
-                    id: _,
-                } => todo!(),
-                Tag::MetadataBlock(_metadata_block_kind) => {
-                    warn!("MetadataBlock in markdown are not supported")
-                }
-                _ => {}
-            },
-            Event::End(end_tag) => {
-                match end_tag {
-                    TagEnd::Paragraph => {
-                        // TODO: Work on the top_margin
-                        if !text.trim().is_empty() {
-                            res.push(MarkdownContent::Paragraph {
-                                top_margin: 10.0,
-                                text: text.clone(),
-                                markers: marker_state.markers.clone(),
-                                text_layout: Layout::new(),
-                            });
-                            text.clear();
-                            marker_state.markers.clear();
-                        }
-                    }
-                    TagEnd::CodeBlock => todo!(),
-                    TagEnd::HtmlBlock => todo!(),
-                    TagEnd::FootnoteDefinition => todo!(),
-                    TagEnd::Table => todo!(),
-                    TagEnd::TableHead => todo!(),
-                    TagEnd::TableRow => todo!(),
-                    TagEnd::TableCell => todo!(),
-                    TagEnd::Link => todo!(),
-                    e => {
-                        warn!("Markdown parsing unprocessed end tag: {e:?}");
-                    }
-                }
-            }
-            Event::Text(text_bit) => {
-                // TODO: Ignore text in some cases???
-                text.push_str(&text_bit);
-            }
-            Event::Code(text_bit) => {
-                // TODO: Maybe it should be a text_manager with both text and markers.
-                marker_state.markers.push(TextMarker {
-                    start_pos: text.len(),
-                    end_pos: text.len() + text_bit.len(),
-                    kind: MarkerKind::InlineCode,
-                });
-                text.push_str(&text_bit);
-            }
-            Event::Html(text_bit) => {
-                // TODO: This looks a bit fishy
-                marker_state.markers.push(TextMarker {
-                    start_pos: text.len(),
-                    end_pos: text.len() + text_bit.len(),
-                    kind: MarkerKind::InlineCode,
-                });
-                text.push_str(&text_bit);
-            }
-            Event::HardBreak => {
-                text.push('\n');
-            }
-            Event::SoftBreak => {
-                text.push(' ');
-            }
-            Event::Rule => {
-                // This adds random value. It will be recalculated anyway.
-                // TODO: Maybe it there should be additional step which adds
-                // these heights based on the theme???
-                res.push(MarkdownContent::HorizontalLine { height: 0.0 })
-            }
-            Event::FootnoteReference(_text) => {
-                warn!("FootnoteReference in markdown is not supported!")
-            }
-            Event::TaskListMarker(_marker) => {
-                warn!("TaskListMarker in markdown is not supported!")
-            }
-            Event::InlineHtml(_) => {
-                warn!("InlineHtml in markdown is not supported!")
-            }
-            Event::InlineMath(_) => {
-                warn!("InlineMath in markdown is not supported!")
-            }
-            Event::DisplayMath(_) => {
-                warn!("DisplayMath in markdown is not supported!")
             }
+            MarkdownContent::Indented { flow, .. }
+            | MarkdownContent::Details { flow, .. } => for_each_image_uri(flow, f),
+            _ => {}
         }
     }
+}
 
-    if !text.is_empty() {
-        res.push(MarkdownContent::Paragraph {
-            // TODO: Make nice offset
-            // TODO: This should be in theme as well
-            // TODO: It should be relative to the font size
-            top_margin: 12.0,
-            text,
-            markers: marker_state.markers,
-            text_layout: Layout::new(),
-        });
+fn for_each_list_marker<F>(flow: &mut LayoutFlow<MarkdownContent>, f: &mut F)
+where
+    F: FnMut(&mut ListMarker),
+{
+    for element in flow.flow.iter_mut() {
+        match &mut element.data {
+            MarkdownContent::List { list } => {
+                f(&mut list.marker);
+                for item in list.list.iter_mut() {
+                    for_each_list_marker(item, f);
+                }
+            }
+            MarkdownContent::Indented { flow, .. }
+            | MarkdownContent::Details { flow, .. } => for_each_list_marker(flow, f),
+            _ => {}
+        }
     }
-
-    res
 }
 
-fn parse_markdown(text: &str) -> LayoutFlow<MarkdownContent> {
-    let mut parser = Parser::new_ext(
-        text,
-        //Options::ENABLE_TABLES
-        //| Options::ENABLE_FOOTNOTES
-        //| Options::ENABLE_STRIKETHROUGH
-        Options::ENABLE_STRIKETHROUGH, //| Options::ENABLE_TASKLISTS
-                                       //| Options::ENABLE_HEADING_ATTRIBUTES,
-    );
+/// Turns heading text into an anchor id.
+pub type SlugGenerator = fn(&str) -> String;
 
-    process_events(&mut parser, None)
+/// Generates a GitHub style anchor id: lowercase, spaces replaced with `-`
+/// and other punctuation removed, so "Hello World!" becomes "hello-world".
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            slug.extend(c.to_lowercase());
+        } else if c.is_whitespace() {
+            slug.push('-');
+        }
+    }
+    slug
 }
 
-fn feed_marker_to_builder<'a>(
-    builder: &'a mut RangedBuilder<MarkdownBrush>,
-    text_marker: &TextMarker,
-    theme: &'a Theme,
+/// Sets `anchor_id` of all headings in document order. Headings without an
+/// explicit id get one from `generator`, repeated ids get a `-1`, `-2`, ...
+/// suffix.
+fn assign_anchor_ids(
+    flow: &mut LayoutFlow<MarkdownContent>,
+    generator: SlugGenerator,
+    used: &mut HashMap<String, usize>,
 ) {
-    let rang = text_marker.start_pos..text_marker.end_pos;
-    match text_marker.kind {
-        MarkerKind::Bold => {
-            builder.push(StyleProperty::FontWeight(FontWeight::BOLD), rang)
+    for element in flow.flow.iter_mut() {
+        match &mut element.data {
+            MarkdownContent::Header {
+                text,
+                id,
+                anchor_id,
+                ..
+            } => {
+                let slug = id.clone().unwrap_or_else(|| generator(text));
+                *anchor_id = if slug.is_empty() {
+                    None
+                } else {
+                    let count = used.entry(slug.clone()).or_insert(0);
+                    let anchor = match *count {
+                        0 => slug,
+                        n => format!("{slug}-{n}"),
+                    };
+                    *count += 1;
+                    Some(anchor)
+                };
+            }
+            MarkdownContent::List { list } => {
+                for item in list.list.iter_mut() {
+                    assign_anchor_ids(item, generator, used);
+                }
+            }
+            MarkdownContent::Indented { flow, .. }
+            | MarkdownContent::Details { flow, .. } => {
+                assign_anchor_ids(flow, generator, used)
+            }
+            _ => {}
         }
-        MarkerKind::Italic => {
-            builder.push(StyleProperty::FontStyle(FontStyle::Italic), rang)
+    }
+}
+
+/// Index of the top-level item holding the heading with `anchor` as its
+/// `anchor_id`.
+fn anchor_item_index(
+    flow: &LayoutFlow<MarkdownContent>,
+    anchor: &str,
+) -> Option<usize> {
+    flow.iter()
+        .position(|element| contains_anchor(&element.data, anchor))
+}
+
+fn contains_anchor(content: &MarkdownContent, anchor: &str) -> bool {
+    let in_flow = |flow: &LayoutFlow<MarkdownContent>| {
+        flow.iter()
+            .any(|element| contains_anchor(&element.data, anchor))
+    };
+    match content {
+        MarkdownContent::Header { anchor_id, .. } => {
+            anchor_id.as_deref() == Some(anchor)
         }
-        MarkerKind::Strikethrough => {
-            builder.push(StyleProperty::Strikethrough(true), rang)
+        MarkdownContent::List { list } => list.list.iter().any(in_flow),
+        MarkdownContent::Indented { flow, .. }
+        | MarkdownContent::Details { flow, .. } => in_flow(flow),
+        _ => false,
+    }
+}
+
+/// Appends the path of the innermost block at `y` of `flow` to `path` and
+/// returns how far `y` is into that block, see `ScrollAnchor`. Gaps between
+/// blocks belong to the block below them.
+fn block_path_at(
+    flow: &LayoutFlow<MarkdownContent>,
+    y: f32,
+    path: &mut Vec<usize>,
+) -> f32 {
+    if flow.is_empty() {
+        return 0.0;
+    }
+    let index = flow
+        .flow
+        .partition_point(|e| e.offset + e.height <= y)
+        .min(flow.len() - 1);
+    path.push(index);
+    let element = &flow.flow[index];
+    let y = y - element.offset;
+    if y <= 0.0 || element.height <= 0.0 {
+        return 0.0;
+    }
+    fn nested(
+        flow: &LayoutFlow<MarkdownContent>,
+        y: f32,
+    ) -> Option<(&LayoutFlow<MarkdownContent>, f32)> {
+        (y >= 0.0 && y < flow.height()).then_some((flow, y))
+    }
+    let inner = match &element.data {
+        MarkdownContent::Indented { flow, decoration } => {
+            nested(flow, y - decoration.padding_top).map(|inner| (None, inner))
         }
-        MarkerKind::InlineCode => {
-            builder.push(
-                StyleProperty::FontStack(theme.monospace_font_stack.clone()),
-                rang.clone(),
-            );
-            builder.push(
-                StyleProperty::Brush(MarkdownBrush(theme.monospace_text_color)),
-                rang,
-            );
+        MarkdownContent::List { list } => {
+            let mut item_y = 0.0;
+            list.list.iter().enumerate().find_map(|(index, item)| {
+                let inner =
+                    nested(item, y - item_y).map(|inner| (Some(index), inner));
+                item_y += item.height();
+                inner
+            })
         }
+        MarkdownContent::Details {
+            summary_layout,
+            open: true,
+            flow,
+            ..
+        } => nested(flow, y - summary_layout.height()).map(|inner| (None, inner)),
+        _ => None,
+    };
+    match inner {
+        Some((item, (flow, y))) => {
+            path.extend(item);
+            block_path_at(flow, y, path)
+        }
+        None => (y / element.height).min(1.0),
     }
 }
 
-fn text_to_builder<'a>(
-    text: &'a str,
-    markers: &[TextMarker],
-    font_ctx: &'a mut FontContext,
-    layout_ctx: &'a mut LayoutContext<MarkdownBrush>,
-) -> RangedBuilder<'a, MarkdownBrush> {
-    let theme = get_theme();
-
-    let mut builder: RangedBuilder<'_, MarkdownBrush> =
-        layout_ctx.ranged_builder(font_ctx, text, theme.scale);
-    builder.push_default(StyleProperty::Brush(MarkdownBrush(theme.text_color)));
-    builder.push_default(StyleProperty::FontSize(theme.text_size as f32));
-    builder.push_default(StyleProperty::FontStack(theme.font_stack.clone()));
-    builder.push_default(StyleProperty::FontWeight(FontWeight::NORMAL));
-    builder.push_default(StyleProperty::FontStyle(FontStyle::Normal));
-    builder.push_default(StyleProperty::LineHeight(1.0));
-    for marker in markers.iter() {
-        feed_marker_to_builder(&mut builder, marker, &theme);
+/// Offset and height of the block at `path` in `flow`. When the path does
+/// not fit the document any more, the last block of it which still exists
+/// is used and `false` is returned with it.
+fn block_extent(
+    flow: &LayoutFlow<MarkdownContent>,
+    path: &[usize],
+) -> Option<(f32, f32, bool)> {
+    let (&index, rest) = path.split_first()?;
+    let element = flow.get(index)?;
+    if rest.is_empty() {
+        return Some((element.offset, element.height, true));
     }
-    builder
+    let inner = match &element.data {
+        MarkdownContent::Indented { flow, decoration } => block_extent(flow, rest)
+            .map(|(offset, height, found)| {
+                (decoration.padding_top + offset, height, found)
+            }),
+        MarkdownContent::List { list } => {
+            rest.split_first().and_then(|(&item, rest)| {
+                let item_y: f32 = list
+                    .list
+                    .get(..item)?
+                    .iter()
+                    .map(|item| item.height())
+                    .sum();
+                block_extent(list.list.get(item)?, rest)
+                    .map(|(offset, height, found)| (item_y + offset, height, found))
+            })
+        }
+        MarkdownContent::Details {
+            summary_layout,
+            open: true,
+            flow,
+            ..
+        } => block_extent(flow, rest).map(|(offset, height, found)| {
+            (summary_layout.height() + offset, height, found)
+        }),
+        _ => None,
+    };
+    Some(match inner {
+        Some((offset, height, found)) => (element.offset + offset, height, found),
+        None => (element.offset, element.height, false),
+    })
 }
 
-pub struct MarkdowWidget {
-    markdown_layout: LayoutFlow<MarkdownContent>,
-    layout_ctx: LayoutContext<MarkdownBrush>,
-    max_advance: f64,
-    dirty: bool,
-    scroll: Vec2,
+/// Start of the text of a block, to recognize it in a changed document.
+fn block_fingerprint(content: &MarkdownContent) -> String {
+    const FINGERPRINT_CHARS: usize = 64;
+    let mut text = String::new();
+    content.write_plain_text(&mut text);
+    text.chars().take(FINGERPRINT_CHARS).collect()
 }
 
-impl MarkdowWidget {
-    pub fn new<P: AsRef<Path>>(markdown_file: P) -> Self {
-        // TODO: Ehm... unwraps...
-        let content: String =
-            String::from_utf8(std::fs::read(&markdown_file).unwrap()).unwrap();
-        let markdown_layout = parse_markdown(&content);
-        Self {
-            markdown_layout,
-            dirty: true,
-            layout_ctx: LayoutContext::new(),
-            max_advance: 0.0,
-            scroll: Vec2::new(0.0, 0.0),
+fn toggle_details_in_flow(
+    flow: &mut LayoutFlow<MarkdownContent>,
+    offset: f32,
+) -> bool {
+    let Some((index, offset)) = flow.get_index_at_offset(offset) else {
+        return false;
+    };
+    // Dropping the mutable data recomputes the offsets if the height changed.
+    let mut data = flow.get_mutable(index);
+    data.toggle_details_at(offset)
+}
+
+fn flow_to_plain_text(flow: &LayoutFlow<MarkdownContent>) -> String {
+    let mut out = String::new();
+    for (index, element) in flow.iter().enumerate() {
+        if index > 0 {
+            out.push_str("\n\n");
         }
+        element.data.write_plain_text(&mut out);
     }
+    out
 }
-fn draw_underline(
-    scene: &mut Scene,
-    underline: &Decoration<MarkdownBrush>,
-    glyph_run: &GlyphRun<'_, MarkdownBrush>,
-    run_metrics: &RunMetrics,
-    transform: &Affine,
-) {
-    let offset = underline.offset.unwrap_or(run_metrics.underline_offset);
-    let stroke_size = underline.size.unwrap_or(run_metrics.underline_size);
-    let y1 = glyph_run.baseline() - offset - (stroke_size / 2.0);
-    let x1 = glyph_run.offset();
-    let x2 = x1 + glyph_run.advance();
-    let underline_shape = Line::new((x1, y1), (x2, y1));
 
-    let stroke = Stroke {
-        width: stroke_size as f64,
-        join: Join::Bevel,
-        miter_limit: 4.0,
-        start_cap: Cap::Butt,
-        end_cap: Cap::Butt,
-        dash_pattern: Default::default(),
-        dash_offset: 0.0,
-    };
+/// Top-level heading of a document.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TocEntry {
+    pub level: HeadingLevel,
+    pub text: String,
+    /// Index of the heading among the top-level items, as taken by
+    /// `MarkdowWidget::scroll_into_view`.
+    pub item_index: usize,
+}
 
-    scene.stroke(
-        &stroke,
-        *transform,
-        underline.brush.0,
-        Some(Affine::IDENTITY),
-        &underline_shape,
-    );
+/// Extracts the top-level headings of `markdown`, the item indices match the
+/// ones of a `MarkdowWidget` showing the same source.
+pub fn table_of_contents(markdown: &str) -> Vec<TocEntry> {
+    flow_toc(&parse_markdown(markdown))
 }
 
-fn draw_strikethrough(
-    scene: &mut Scene,
-    strikethrough: &Decoration<MarkdownBrush>,
-    glyph_run: &GlyphRun<'_, MarkdownBrush>,
-    run_metrics: &RunMetrics,
-    transform: &Affine,
-) {
-    let offset = strikethrough
-        .offset
-        .unwrap_or(run_metrics.strikethrough_offset);
-    let size = strikethrough.size.unwrap_or(run_metrics.strikethrough_size);
-    // FIXME: This offset looks fishy... I think I should add it instead.
-    let y1 = glyph_run.baseline() - offset - (size / 2.0);
-    let x1 = glyph_run.offset();
-    let x2 = x1 + glyph_run.advance();
-    let strikethrough_shape = Line::new((x1, y1), (x2, y1));
+fn flow_toc(flow: &LayoutFlow<MarkdownContent>) -> Vec<TocEntry> {
+    flow.iter()
+        .enumerate()
+        .filter_map(|(item_index, element)| match &element.data {
+            MarkdownContent::Header { level, text, .. } => Some(TocEntry {
+                level: *level,
+                text: text.clone(),
+                item_index,
+            }),
+            _ => None,
+        })
+        .collect()
+}
 
-    let stroke = Stroke {
-        width: size as f64,
-        join: Join::Bevel,
-        miter_limit: 4.0,
-        start_cap: Cap::Butt,
-        end_cap: Cap::Butt,
-        dash_pattern: Default::default(),
-        dash_offset: 0.0,
+/// Kind of the block found by `MarkdowWidget::content_at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Indented,
+    Header,
+    List,
+    Paragraph,
+    Image,
+    CodeBlock,
+    HorizontalLine,
+    Details,
+}
+
+impl BlockKind {
+    pub const ALL: [BlockKind; 8] = [
+        BlockKind::Indented,
+        BlockKind::Header,
+        BlockKind::List,
+        BlockKind::Paragraph,
+        BlockKind::Image,
+        BlockKind::CodeBlock,
+        BlockKind::HorizontalLine,
+        BlockKind::Details,
+    ];
+}
+
+/// Whether `content` is or contains a code block.
+fn contains_code_block(content: &MarkdownContent) -> bool {
+    let any = |flow: &LayoutFlow<MarkdownContent>| {
+        flow.iter()
+            .any(|element| contains_code_block(&element.data))
     };
+    match content {
+        MarkdownContent::CodeBlock { .. } => true,
+        MarkdownContent::Indented { flow, .. }
+        | MarkdownContent::Details { flow, .. } => any(flow),
+        MarkdownContent::List { list } => list.list.iter().any(any),
+        _ => false,
+    }
+}
 
-    scene.stroke(
-        &stroke,
-        *transform,
-        strikethrough.brush.0,
-        Some(Affine::IDENTITY),
-        &strikethrough_shape,
+fn log_layout_time(item_index: usize, item: &MarkdownContent, start: Instant) {
+    let elapsed = start.elapsed();
+    debug!(
+        item_index,
+        kind = item.kind_name(),
+        elapsed_us = elapsed.as_micros() as u64,
+        "Laid out markdown item"
     );
 }
 
-fn draw_text(
-    scene: &mut Scene,
-    layout: &Layout<MarkdownBrush>,
-    translation: Vec2,
-    source_rect: &Rect,
-) {
-    let transform: Affine = Affine::translate(translation);
-    let mut top_line_index = if let Some((cluster, _)) =
-        Cluster::from_point(layout, 0.0, source_rect.y0 as f32)
-    {
-        cluster.path().line_index()
-    } else {
-        0
+/// Outline color of blocks of `kind` in the debug overlay.
+fn debug_overlay_color(kind: BlockKind) -> Color {
+    match kind {
+        BlockKind::Paragraph => Color::from_rgb8(0x00, 0xc0, 0x00),
+        BlockKind::Header => Color::from_rgb8(0x30, 0x60, 0xff),
+        BlockKind::Image => Color::from_rgb8(0xff, 0x8c, 0x00),
+        BlockKind::CodeBlock => Color::from_rgb8(0xa0, 0x40, 0xe0),
+        BlockKind::HorizontalLine => Color::from_rgb8(0x90, 0x90, 0x90),
+        BlockKind::List => Color::from_rgb8(0xe0, 0x20, 0x20),
+        BlockKind::Indented => Color::from_rgb8(0xe0, 0xe0, 0x00),
+        BlockKind::Details => Color::from_rgb8(0x00, 0xc0, 0xc0),
+    }
+}
+
+/// Content under a point, returned by `MarkdowWidget::content_at`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HitInfo {
+    /// Indices of the blocks from the top-level flow down to the innermost
+    /// block. A list adds the index of the item followed by the index of the
+    /// block within the item.
+    pub path: Vec<usize>,
+    pub kind: BlockKind,
+    /// Byte offset in the text of the block when the point is over text.
+    pub text_offset: Option<usize>,
+    pub link: Option<LinkTarget>,
+    pub image_uri: Option<String>,
+    /// The point relative to the top-left corner of the innermost block.
+    pub local_position: Point,
+}
+
+/// Converts a pointer position in widget coordinates to the coordinates of
+/// the top-level flow, which starts at `origin` when not scrolled.
+/// Everything hit testing the document goes through it.
+fn widget_to_document(position: Point, origin: Vec2, scroll: Vec2) -> Point {
+    position - origin + scroll
+}
+
+/// Hit tests `flow` at `position` in widget coordinates with the document
+/// scrolled by `scroll`.
+fn hit_test_document(
+    flow: &LayoutFlow<MarkdownContent>,
+    position: Point,
+    scroll: Vec2,
+    theme: &Theme,
+) -> Option<HitInfo> {
+    let position = widget_to_document(position, Vec2::ZERO, scroll);
+    hit_test_flow(flow, position, theme, &mut Vec::new())
+}
+
+/// Returns the block at `path` as given by `HitInfo::path`.
+fn content_at_path<'a>(
+    flow: &'a LayoutFlow<MarkdownContent>,
+    path: &[usize],
+) -> Option<&'a MarkdownContent> {
+    let (&index, rest) = path.split_first()?;
+    let data = &flow.get(index)?.data;
+    if rest.is_empty() {
+        return Some(data);
+    }
+    match data {
+        MarkdownContent::Indented { flow, .. }
+        | MarkdownContent::Details { flow, .. } => content_at_path(flow, rest),
+        MarkdownContent::List { list } => {
+            let (&item, rest) = rest.split_first()?;
+            content_at_path(list.list.get(item)?, rest)
+        }
+        _ => None,
+    }
+}
+
+/// Toggles `natural_size` of the image at `path`.
+fn toggle_image_size_at_path(
+    flow: &mut LayoutFlow<MarkdownContent>,
+    path: &[usize],
+) -> bool {
+    let Some((&index, rest)) = path.split_first() else {
+        return false;
     };
-    while let Some(line) = layout.get(top_line_index) {
-        let line_metrics = line.metrics();
-        if line_metrics.min_coord > source_rect.y1 as f32 {
-            break;
+    if index >= flow.len() {
+        return false;
+    }
+    // Dropping the mutable data recomputes the offsets if the height changed.
+    let mut data = flow.get_mutable(index);
+    match &mut *data {
+        MarkdownContent::Image { natural_size, .. } if rest.is_empty() => {
+            *natural_size = !*natural_size;
+            true
         }
-        for item in line.items() {
-            let PositionedLayoutItem::GlyphRun(glyph_run) = item else {
-                continue;
+        MarkdownContent::Indented { flow, .. }
+        | MarkdownContent::Details { flow, .. } => {
+            toggle_image_size_at_path(flow, rest)
+        }
+        MarkdownContent::List { list } => {
+            let Some((&item, rest)) = rest.split_first() else {
+                return false;
             };
-            let style = glyph_run.style();
-            let text_color = &style.brush;
+            match list.list.get_mut(item) {
+                Some(item) => toggle_image_size_at_path(item, rest),
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
 
-            let run = glyph_run.run();
-            // TODO: This needs to be some kind of a flow layout.
-            let font = run.font();
-            let font_size = run.font_size();
-            let synthesis = run.synthesis();
-            let glyph_xform = synthesis
-                .skew()
-                .map(|angle| Affine::skew(angle.to_radians().tan() as f64, 0.0));
-            let coords = run.normalized_coords();
-            scene
-                .draw_glyphs(font)
-                .brush(text_color.0)
-                .hint(true)
-                .transform(transform)
-                .glyph_transform(glyph_xform)
-                .font_size(font_size)
-                .normalized_coords(&coords)
-                .draw(
-                    Fill::NonZero,
-                    glyph_run.positioned_glyphs().map(|glyph| vello::Glyph {
-                        id: glyph.id as _,
-                        x: glyph.x,
-                        y: glyph.y,
-                    }),
-                );
+fn hit_test_flow(
+    flow: &LayoutFlow<MarkdownContent>,
+    position: Point,
+    theme: &Theme,
+    path: &mut Vec<usize>,
+) -> Option<HitInfo> {
+    if position.x < 0.0 || position.y < 0.0 {
+        return None;
+    }
+    let (index, offset) = flow.get_index_at_offset(position.y as f32)?;
+    let element = flow.get(index)?;
+    if element.hidden {
+        return None;
+    }
+    path.push(index);
+    let local = Point::new(position.x, offset as f64);
+    Some(element.data.hit_test(local, theme, path))
+}
+
+/// Width of `layout` including the offsets of aligned lines.
+fn aligned_width(layout: &Layout<MarkdownBrush>) -> f32 {
+    layout
+        .lines()
+        .map(|line| line.metrics().offset + line.metrics().advance)
+        .fold(layout.full_width(), f32::max)
+}
+
+/// Returns the link containing the byte `offset` of the text.
+fn link_at(markers: &[TextMarker], offset: usize) -> Option<LinkTarget> {
+    markers.iter().find_map(|marker| match &marker.kind {
+        MarkerKind::Link(link) if marker.range.contains(&offset) => {
+            Some(link.clone())
+        }
+        _ => None,
+    })
+}
+
+/// Returns the byte offset of the text at `position` when it is over the
+/// text of `layout`.
+fn text_offset_at(layout: &Layout<MarkdownBrush>, position: Point) -> Option<usize> {
+    if position.x > layout.width() as f64 || position.y > layout.height() as f64 {
+        return None;
+    }
+    let (cluster, _) =
+        Cluster::from_point(layout, position.x as f32, position.y as f32)?;
+    Some(cluster.text_range().start)
+}
+
+/// Maps the byte `offset` of `from` to the offset of the same character in
+/// `to`, which has the same number of characters with some of them replaced.
+fn map_char_offset(from: &str, to: &str, offset: usize) -> usize {
+    let chars = from[..from.floor_char_boundary(offset)].chars().count();
+    to.char_indices()
+        .nth(chars)
+        .map_or(to.len(), |(offset, _)| offset)
+}
+
+impl MarkdownContent {
+    /// Name of the variant, for logs.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            MarkdownContent::Indented { .. } => "Indented",
+            MarkdownContent::Header { .. } => "Header",
+            MarkdownContent::List { .. } => "List",
+            MarkdownContent::Paragraph { .. } => "Paragraph",
+            MarkdownContent::Image { .. } => "Image",
+            MarkdownContent::CodeBlock { .. } => "CodeBlock",
+            MarkdownContent::HorizontalLine { .. } => "HorizontalLine",
+            MarkdownContent::Details { .. } => "Details",
+        }
+    }
+
+    fn block_kind(&self) -> BlockKind {
+        match self {
+            MarkdownContent::Indented { .. } => BlockKind::Indented,
+            MarkdownContent::Header { .. } => BlockKind::Header,
+            MarkdownContent::List { .. } => BlockKind::List,
+            MarkdownContent::Paragraph { .. } => BlockKind::Paragraph,
+            MarkdownContent::Image { .. } => BlockKind::Image,
+            MarkdownContent::CodeBlock { .. } => BlockKind::CodeBlock,
+            MarkdownContent::HorizontalLine { .. } => BlockKind::HorizontalLine,
+            MarkdownContent::Details { .. } => BlockKind::Details,
+        }
+    }
+
+    /// Hit tests this block at `position` relative to its top-left corner.
+    /// Nested blocks are preferred, the block itself is returned for points
+    /// over its decorations.
+    fn hit_test(
+        &self,
+        position: Point,
+        theme: &Theme,
+        path: &mut Vec<usize>,
+    ) -> HitInfo {
+        let path_len = path.len();
+        let nested = match self {
+            MarkdownContent::Indented { flow, decoration } => {
+                let indentation = quote_indentation(theme) as f64;
+                hit_test_flow(
+                    flow,
+                    position - Vec2::new(indentation, decoration.padding_top as f64),
+                    theme,
+                    path,
+                )
+            }
+            MarkdownContent::List { list } => {
+                let mut item_y = 0.0;
+                let mut nested = None;
+                for (index, item) in list.list.iter().enumerate() {
+                    let item_height = item.height() as f64;
+                    if position.y < item_y + item_height {
+                        path.push(index);
+                        let block = item
+                            .get_index_at_offset((position.y - item_y) as f32)
+                            .map_or(0, |(block, _)| block);
+                        let item_position = Point::new(
+                            position.x
+                                - list.indentation as f64
+                                - continuation_indent(theme, block) as f64,
+                            position.y - item_y,
+                        );
+                        nested = hit_test_flow(item, item_position, theme, path);
+                        break;
+                    }
+                    item_y += item_height;
+                }
+                nested
+            }
+            MarkdownContent::Details {
+                summary_layout,
+                open,
+                flow,
+                ..
+            } => {
+                let summary_height = summary_layout.height() as f64;
+                let indentation = details_indentation(theme) as f64;
+                if *open && position.y >= summary_height {
+                    let flow_position = Point::new(
+                        position.x - indentation,
+                        position.y - summary_height,
+                    );
+                    hit_test_flow(flow, flow_position, theme, path)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(nested) = nested {
+            return nested;
+        }
+        path.truncate(path_len);
+
+        let text_offset = match self {
+            MarkdownContent::Paragraph {
+                text,
+                shown_text,
+                text_layout,
+                ..
+            } => text_offset_at(text_layout, position).map(
+                |offset| match shown_text {
+                    Some(shown_text) => map_char_offset(shown_text, text, offset),
+                    None => offset,
+                },
+            ),
+            MarkdownContent::Header { text_layout, .. } => {
+                text_offset_at(text_layout, position)
+            }
+            MarkdownContent::CodeBlock {
+                text, text_layout, ..
+            } => {
+                let padding = theme.code_block_padding as f64;
+                text_offset_at(text_layout, position - Vec2::new(padding, padding))
+                    .map(|offset| code_text_offset(text, offset))
+            }
+            MarkdownContent::Details { summary_layout, .. } => {
+                let indentation = details_indentation(theme) as f64;
+                text_offset_at(
+                    summary_layout,
+                    position - Vec2::new(indentation, 0.0),
+                )
+            }
+            _ => None,
+        };
+        let link = match self {
+            MarkdownContent::Paragraph { markers, .. }
+            | MarkdownContent::Header { markers, .. } => {
+                text_offset.and_then(|offset| link_at(markers, offset))
+            }
+            _ => None,
+        };
+        let image_uri = match self {
+            // Only the scaled image counts, not the rest of the line.
+            MarkdownContent::Image { uri, .. }
+                if self.image_display_size().is_some_and(|size| {
+                    let x = position.x - self.image_offset();
+                    (0.0..=size.width).contains(&x) && position.y <= size.height
+                }) =>
+            {
+                Some(uri.clone())
+            }
+            _ => None,
+        };
+        HitInfo {
+            path: path.clone(),
+            kind: self.block_kind(),
+            text_offset,
+            link,
+            image_uri,
+            local_position: position,
+        }
+    }
+}
+
+/// Options for `MarkdowWidget::stats_with`.
+#[derive(Clone, Debug)]
+pub struct StatsOptions {
+    pub words_per_minute: u32,
+    /// Reading time added for every image.
+    pub time_per_image: Duration,
+    /// Count words and characters inside code blocks too.
+    pub count_code: bool,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            words_per_minute: 230,
+            time_per_image: Duration::from_secs(12),
+            count_code: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocumentStats {
+    /// Words found by the Unicode word segmentation, CJK ideographs are
+    /// counted one by one.
+    pub words: usize,
+    /// Count of grapheme clusters.
+    pub characters: usize,
+    /// Heading counts, `headings[0]` are the H1 headings.
+    pub headings: [usize; 6],
+    pub images: usize,
+    pub code_blocks: usize,
+    pub reading_time: Duration,
+}
+
+impl DocumentStats {
+    fn add_text(&mut self, text: &str) {
+        self.words += text.unicode_words().count();
+        self.characters += text.graphemes(true).count();
+    }
+}
+
+fn flow_stats(
+    flow: &LayoutFlow<MarkdownContent>,
+    options: &StatsOptions,
+) -> DocumentStats {
+    let mut stats = DocumentStats::default();
+    collect_flow_stats(flow, options, &mut stats);
+    let words_per_second = options.words_per_minute.max(1) as f64 / 60.0;
+    stats.reading_time =
+        Duration::from_secs_f64(stats.words as f64 / words_per_second)
+            + options.time_per_image * stats.images as u32;
+    stats
+}
+
+fn collect_flow_stats(
+    flow: &LayoutFlow<MarkdownContent>,
+    options: &StatsOptions,
+    stats: &mut DocumentStats,
+) {
+    for element in flow.iter() {
+        match &element.data {
+            MarkdownContent::Paragraph { text, .. } => stats.add_text(text),
+            MarkdownContent::Header { level, text, .. } => {
+                stats.headings[*level as usize - 1] += 1;
+                stats.add_text(text);
+            }
+            MarkdownContent::Image { .. } => stats.images += 1,
+            MarkdownContent::CodeBlock { text, .. } => {
+                stats.code_blocks += 1;
+                if options.count_code {
+                    stats.add_text(text);
+                }
+            }
+            MarkdownContent::Indented { flow, .. } => {
+                collect_flow_stats(flow, options, stats)
+            }
+            MarkdownContent::List { list } => {
+                for item in list.list.iter() {
+                    collect_flow_stats(item, options, stats);
+                }
+            }
+            MarkdownContent::Details { summary, flow, .. } => {
+                stats.add_text(summary);
+                collect_flow_stats(flow, options, stats);
+            }
+            MarkdownContent::HorizontalLine { .. } => {}
+        }
+    }
+}
+
+/// Folds `text` for `find_text`: compatibility normalized and lowercased,
+/// soft hyphens are dropped as they are not shown.
+fn fold_for_search(text: &str) -> String {
+    text.nfkc()
+        .filter(|&c| c != '\u{ad}')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Counts the occurrences of the already folded `query` in the text of
+/// `flow`. Matches do not span blocks.
+fn flow_match_count(flow: &LayoutFlow<MarkdownContent>, query: &str) -> usize {
+    let count = |text: &str| fold_for_search(text).matches(query).count();
+    flow.iter()
+        .map(|element| match &element.data {
+            MarkdownContent::Paragraph { text, .. }
+            | MarkdownContent::Header { text, .. }
+            | MarkdownContent::CodeBlock { text, .. } => count(text),
+            MarkdownContent::Indented { flow, .. } => flow_match_count(flow, query),
+            MarkdownContent::List { list } => list
+                .list
+                .iter()
+                .map(|item| flow_match_count(item, query))
+                .sum(),
+            MarkdownContent::Details { summary, flow, .. } => {
+                count(summary) + flow_match_count(flow, query)
+            }
+            MarkdownContent::Image { .. }
+            | MarkdownContent::HorizontalLine { .. } => 0,
+        })
+        .sum()
+}
+
+impl LayoutData for MarkdownContent {
+    fn height(&self) -> f32 {
+        match self {
+            MarkdownContent::Paragraph {
+                text: _,
+                markers: _,
+                attribution: _,
+                shown_text: _,
+                text_layout,
+                spacing: _,
+            } => text_layout.height(),
+            MarkdownContent::Image { caption_layout, .. } => {
+                self.image_display_size()
+                    .map_or(0.0, |size| size.height as f32)
+                    + caption_layout.height()
+            }
+            MarkdownContent::CodeBlock {
+                rendered_height, ..
+            } => *rendered_height,
+            MarkdownContent::Indented { flow, decoration } => {
+                decoration.padding_top + flow.height() + decoration.padding_bottom
+            }
+            MarkdownContent::List { list } => {
+                list.list.iter().map(|l| l.height()).sum()
+            }
+            MarkdownContent::HorizontalLine { height, .. } => *height,
+            MarkdownContent::Details {
+                summary: _,
+                summary_layout,
+                open,
+                flow,
+                indentation: _,
+            } => {
+                let mut height = summary_layout.height();
+                if *open {
+                    height += flow.height();
+                }
+                height
+            }
+            MarkdownContent::Header { text_layout, .. } => text_layout.height(),
+        }
+    }
+
+    fn width(&self) -> f32 {
+        match self {
+            MarkdownContent::Paragraph { text_layout, .. }
+            | MarkdownContent::Header { text_layout, .. } => text_layout.width(),
+            MarkdownContent::CodeBlock { rendered_width, .. } => *rendered_width,
+            MarkdownContent::Image { caption_layout, .. } => {
+                let image_right = self
+                    .image_display_size()
+                    .map_or(0.0, |size| self.image_offset() + size.width);
+                (image_right as f32).max(aligned_width(caption_layout))
+            }
+            MarkdownContent::Indented { flow, decoration } => {
+                decoration.indentation + flow.width()
+            }
+            MarkdownContent::List { list } => {
+                list.indentation
+                    + list.list.iter().map(|l| l.width()).fold(0.0, f32::max)
+            }
+            // TODO: The line spans the whole widget, but the width is not
+            // known here.
+            MarkdownContent::HorizontalLine { .. } => 0.0,
+            MarkdownContent::Details {
+                summary_layout,
+                open,
+                flow,
+                indentation,
+                ..
+            } => {
+                let content_width = if *open { flow.width() } else { 0.0 };
+                indentation + summary_layout.width().max(content_width)
+            }
+        }
+    }
+
+    fn top_margin(&self) -> f32 {
+        match self {
+            MarkdownContent::Paragraph { spacing, .. }
+            | MarkdownContent::Header { spacing, .. }
+            | MarkdownContent::CodeBlock { spacing, .. }
+            | MarkdownContent::Image { spacing, .. }
+            | MarkdownContent::HorizontalLine { spacing, .. } => *spacing,
+            MarkdownContent::List { list } => list.spacing,
+            MarkdownContent::Indented { .. } | MarkdownContent::Details { .. } => {
+                0.0
+            }
+        }
+    }
+
+    fn bottom_margin(&self) -> f32 {
+        // Blocks are spaced evenly, the flow collapses the two margins.
+        self.top_margin()
+    }
+}
+
+/// Inline style of a byte range of the text of a paragraph or heading.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextMarker {
+    range: Range<usize>,
+    kind: MarkerKind,
+}
+
+impl TextMarker {
+    fn new(range: Range<usize>, kind: MarkerKind) -> Self {
+        TextMarker { range, kind }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// Empty and inverted markers style nothing.
+    pub fn is_empty(&self) -> bool {
+        self.range.start >= self.range.end
+    }
+
+    /// The marker with both ends moved by `f`, for edits of the text.
+    fn map_positions(&self, f: impl Fn(usize) -> usize) -> TextMarker {
+        TextMarker::new(f(self.range.start)..f(self.range.end), self.kind.clone())
+    }
+
+    /// Splits the marker at the byte `offset` into the part before it and
+    /// the part after it, which is moved to start at zero. Parts which would
+    /// be empty are `None`.
+    fn split_at(&self, offset: usize) -> (Option<TextMarker>, Option<TextMarker>) {
+        let before = TextMarker::new(
+            self.range.start.min(offset)..self.range.end.min(offset),
+            self.kind.clone(),
+        );
+        let after = TextMarker::new(
+            self.range.start.max(offset) - offset
+                ..self.range.end.max(offset) - offset,
+            self.kind.clone(),
+        );
+        (
+            (!before.is_empty()).then_some(before),
+            (!after.is_empty()).then_some(after),
+        )
+    }
+}
+
+/// Drops the empty and inverted markers and orders the rest by their start.
+/// Of markers with the same start the longer one comes first, so nested
+/// markers are applied after the markers around them and win.
+fn normalize_markers(markers: &mut Vec<TextMarker>) {
+    markers.retain(|marker| !marker.is_empty());
+    markers.sort_by_key(|marker| (marker.range.start, Reverse(marker.range.end)));
+}
+
+/// Joins markers of the same kind which overlap or touch, the result is
+/// normalized.
+fn merge_adjacent_markers(markers: &mut Vec<TextMarker>) {
+    normalize_markers(markers);
+    let mut merged: Vec<TextMarker> = Vec::with_capacity(markers.len());
+    for marker in markers.drain(..) {
+        // Merged markers of a kind don't touch, only the last one can reach
+        // the start of the next marker.
+        let joined = merged
+            .iter_mut()
+            .rev()
+            .find(|other| other.kind == marker.kind)
+            .filter(|other| other.range.end >= marker.range.start);
+        match joined {
+            Some(other) => other.range.end = other.range.end.max(marker.range.end),
+            None => merged.push(marker),
+        }
+    }
+    *markers = merged;
+    normalize_markers(markers);
+}
+
+/// Splits `markers` at the byte `offset` of the text, markers across it are
+/// clipped. The markers after it are moved to start at zero.
+fn split_markers_at(
+    markers: &[TextMarker],
+    offset: usize,
+) -> (Vec<TextMarker>, Vec<TextMarker>) {
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    for marker in markers {
+        let (head, tail) = marker.split_at(offset);
+        before.extend(head);
+        after.extend(tail);
+    }
+    (before, after)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum MarkerKind {
+    Bold,
+    Italic,
+    Strikethrough,
+    InlineCode,
+    Link(LinkTarget),
+    /// Wavy underline in the given color, for annotations.
+    WavyUnderline(Color),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LinkKind {
+    /// A regular markdown link or autolink.
+    Url,
+    /// A `[[Page Name]]` wikilink, the application resolves the target
+    /// against its own pages.
+    Wiki,
+}
+
+/// Where a link leads, by the scheme of its URL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LinkScheme {
+    /// `http` and `https` URLs, other sites.
+    External,
+    /// `mailto:` addresses.
+    Mail,
+    /// Paths relative to the document and wikilinks.
+    Relative,
+    /// `#anchor` links to a heading of the same document.
+    Anchor,
+    /// Any other scheme, like `ftp:` or `file:`.
+    Other,
+}
+
+impl LinkScheme {
+    pub fn of(url: &str) -> Self {
+        if url.starts_with('#') {
+            return LinkScheme::Anchor;
+        }
+        match uri_scheme(url) {
+            None => LinkScheme::Relative,
+            Some(scheme)
+                if scheme.eq_ignore_ascii_case("http")
+                    || scheme.eq_ignore_ascii_case("https") =>
+            {
+                LinkScheme::External
+            }
+            Some(scheme) if scheme.eq_ignore_ascii_case("mailto") => {
+                LinkScheme::Mail
+            }
+            Some(_) => LinkScheme::Other,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LinkTarget {
+    /// The destination of a `LinkKind::Url` link or the page name of a
+    /// `LinkKind::Wiki` link.
+    pub url: String,
+    pub kind: LinkKind,
+    /// Classifies `url`, wikilinks are `LinkScheme::Relative`.
+    pub scheme: LinkScheme,
+}
+
+impl LinkTarget {
+    fn new(url: &str, kind: LinkKind) -> Self {
+        let scheme = match kind {
+            LinkKind::Url => LinkScheme::of(url),
+            LinkKind::Wiki => LinkScheme::Relative,
+        };
+        Self {
+            url: url.to_string(),
+            kind,
+            scheme,
+        }
+    }
+
+    /// The URL with `%XX` escapes decoded, for showing it to the user. The
+    /// escaped `url` is the one to open.
+    pub fn display_url(&self) -> String {
+        percent_decode(&self.url)
+    }
+}
+
+/// Schemes which can run code and are never activated, whatever
+/// `LinkPolicy::allowed_schemes` says.
+const BLOCKED_LINK_SCHEMES: [&str; 3] = ["javascript", "vbscript", "data"];
+
+/// Decides which link destinations are passed on when a link is clicked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkPolicy {
+    /// Schemes of the links which are activated, compared case
+    /// insensitively. Relative links and anchors are always activated.
+    pub allowed_schemes: Vec<String>,
+    /// Relative links are resolved against this URL of the document, they
+    /// are passed on as written without it.
+    pub base_url: Option<String>,
+}
+
+impl Default for LinkPolicy {
+    fn default() -> Self {
+        LinkPolicy {
+            allowed_schemes: ["http", "https", "mailto"].map(String::from).to_vec(),
+            base_url: None,
+        }
+    }
+}
+
+impl LinkPolicy {
+    /// Returns the URL to activate for the link destination `url`, or
+    /// `None` when it must not be activated.
+    pub fn sanitize(&self, url: &str) -> Option<String> {
+        let url = strip_url_whitespace(url)?;
+        if url.starts_with('#') {
+            return Some(url);
+        }
+        match uri_scheme(&url) {
+            Some(scheme) => {
+                let scheme = scheme.to_ascii_lowercase();
+                let allowed = !BLOCKED_LINK_SCHEMES.contains(&scheme.as_str())
+                    && self
+                        .allowed_schemes
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(&scheme));
+                allowed.then_some(url)
+            }
+            None => Some(match &self.base_url {
+                Some(base) => resolve_url(base, &url),
+                None => url,
+            }),
+        }
+    }
+
+    /// Returns the wikilink `page` to activate, or `None` when it must not
+    /// be activated. The application resolves page names itself, so only
+    /// scripts are refused.
+    pub fn sanitize_page(&self, page: &str) -> Option<String> {
+        let url = strip_url_whitespace(page)?;
+        let blocked = uri_scheme(&url).is_some_and(|scheme| {
+            BLOCKED_LINK_SCHEMES
+                .iter()
+                .any(|blocked| blocked.eq_ignore_ascii_case(scheme))
+        });
+        (!blocked).then(|| page.to_string())
+    }
+}
+
+/// Drops the whitespace browsers ignore in URLs, so `java\tscript:` is
+/// still seen as script. `None` for URLs with other control characters.
+fn strip_url_whitespace(url: &str) -> Option<String> {
+    let url: String = url
+        .trim_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    (!url.chars().any(char::is_control)).then_some(url)
+}
+
+/// Resolves the `reference` without a scheme against the `base` URL.
+fn resolve_url(base: &str, reference: &str) -> String {
+    let base = &base[..base.find(['?', '#']).unwrap_or(base.len())];
+    let scheme_end = uri_scheme(base).map_or(0, |scheme| scheme.len() + 1);
+    if reference.starts_with("//") {
+        return format!("{}{reference}", &base[..scheme_end]);
+    }
+    if reference.starts_with('?') {
+        return format!("{base}{reference}");
+    }
+    let authority_end = match base[scheme_end..].strip_prefix("//") {
+        Some(rest) => scheme_end + 2 + rest.find('/').unwrap_or(rest.len()),
+        None => scheme_end,
+    };
+    let (origin, base_path) = base.split_at(authority_end);
+    let (path, suffix) =
+        reference.split_at(reference.find(['?', '#']).unwrap_or(reference.len()));
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else if base_path.is_empty() && authority_end > scheme_end {
+        format!("/{path}")
+    } else {
+        let dir_end = base_path.rfind('/').map_or(0, |index| index + 1);
+        format!("{}{path}", &base_path[..dir_end])
+    };
+    format!("{origin}{}{suffix}", remove_dot_segments(&path))
+}
+
+/// Removes the `.` and `..` segments of a URL path.
+fn remove_dot_segments(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').collect();
+    let mut segments: Vec<&str> = Vec::new();
+    for (index, segment) in parts.iter().enumerate() {
+        let last = index + 1 == parts.len();
+        match *segment {
+            "." => {}
+            ".." => match segments.last() {
+                // Above the root of an absolute path.
+                Some(&"") if segments.len() == 1 => {}
+                None | Some(&"..") => segments.push(".."),
+                Some(_) => {
+                    segments.pop();
+                }
+            },
+            segment => {
+                segments.push(segment);
+                continue;
+            }
+        }
+        // `a/b/..` is the directory `a/`.
+        if last && segments.last() != Some(&"..") {
+            segments.push("");
+        }
+    }
+    segments.join("/")
+}
+
+/// Link type of the links produced by `expand_wikilinks`. The parser only
+/// gives the `*Unknown` types to links of the broken link callback, and
+/// `with_known_link_type` replaces them, so no source text can produce it.
+const WIKILINK_TYPE: LinkType = LinkType::ShortcutUnknown;
+
+/// Options changing how the markdown source is parsed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Turns `[[Page Name]]` and `[[Page Name|label]]` into
+    /// `LinkKind::Wiki` links.
+    pub wikilinks: bool,
+    /// Keeps the line breaks of the source, like chat messages and notes
+    /// do. It takes precedence over joining East Asian lines without a
+    /// space.
+    pub soft_breaks_as_hard: bool,
+}
+
+/// Turns `[[target]]` and `[[target|label]]` in the text of `events` into
+/// links of the `WIKILINK_TYPE`. Code, HTML, links and images keep their
+/// text, as do wikilinks with escaped brackets which don't appear verbatim
+/// in `source`.
+fn expand_wikilinks<'a>(
+    source: &str,
+    events: impl Iterator<Item = (Event<'a>, Range<usize>)>,
+) -> Vec<Event<'a>> {
+    let mut res = Vec::new();
+    // Consecutive text events, wikilinks can span them.
+    let mut texts = Vec::new();
+    let mut verbatim_depth = 0usize;
+    for (event, range) in events {
+        match event {
+            Event::Text(text) if verbatim_depth == 0 => {
+                texts.push((text, range));
+                continue;
+            }
+            Event::Start(
+                Tag::CodeBlock(_)
+                | Tag::HtmlBlock
+                | Tag::Link { .. }
+                | Tag::Image { .. },
+            ) => verbatim_depth += 1,
+            Event::End(
+                TagEnd::CodeBlock | TagEnd::HtmlBlock | TagEnd::Link | TagEnd::Image,
+            ) => verbatim_depth = verbatim_depth.saturating_sub(1),
+            _ => {}
+        }
+        push_wikilink_text(source, &mut texts, &mut res);
+        res.push(event);
+    }
+    push_wikilink_text(source, &mut texts, &mut res);
+    res
+}
+
+/// Pushes the text events of `texts` to `res` with their wikilinks as links.
+fn push_wikilink_text<'a>(
+    source: &str,
+    texts: &mut Vec<(CowStr<'a>, Range<usize>)>,
+    res: &mut Vec<Event<'a>>,
+) {
+    if !texts.iter().any(|(text, _)| text.contains('[')) {
+        res.extend(texts.drain(..).map(|(text, _)| Event::Text(text)));
+        return;
+    }
+    let joined: String = texts.iter().map(|(text, _)| &**text).collect();
+    // Start of every text in `joined` and in the source.
+    let mut starts = Vec::with_capacity(texts.len());
+    let mut len = 0;
+    for (text, range) in texts.drain(..) {
+        starts.push((len, range.start));
+        len += text.len();
+    }
+    let is_verbatim = |start: usize, wikilink: &str| {
+        let index =
+            starts.partition_point(|(joined_start, _)| *joined_start <= start);
+        let (joined_start, source_start) = starts[index - 1];
+        let source_start = source_start + start - joined_start;
+        let backslashes = source[..source_start].len()
+            - source[..source_start].trim_end_matches('\\').len();
+        backslashes % 2 == 0 && source[source_start..].starts_with(wikilink)
+    };
+    let mut text_start = 0;
+    let mut pos = 0;
+    while let Some(found) = joined[pos..].find("[[") {
+        let start = pos + found;
+        let wikilink = parse_wikilink(&joined[start..])
+            .filter(|(_, _, len)| is_verbatim(start, &joined[start..start + len]));
+        let Some((target, label, len)) = wikilink else {
+            pos = start + 1;
+            continue;
+        };
+        if text_start < start {
+            res.push(Event::Text(joined[text_start..start].to_string().into()));
+        }
+        res.push(Event::Start(Tag::Link {
+            link_type: WIKILINK_TYPE,
+            dest_url: target.to_string().into(),
+            title: CowStr::Borrowed(""),
+            id: CowStr::Borrowed(""),
+        }));
+        res.push(Event::Text(label.to_string().into()));
+        res.push(Event::End(TagEnd::Link));
+        pos = start + len;
+        text_start = pos;
+    }
+    if text_start < joined.len() {
+        res.push(Event::Text(joined[text_start..].to_string().into()));
+    }
+}
+
+/// Parses `[[target]]` or `[[target|label]]` at the start of `text` into the
+/// target, the label and the length of the wikilink.
+fn parse_wikilink(text: &str) -> Option<(&str, &str, usize)> {
+    let inner = text.strip_prefix("[[")?;
+    let end = inner.find("]]")?;
+    let inner = &inner[..end];
+    if inner.contains(['[', ']', '\n']) {
+        return None;
+    }
+    let (target, label) = inner.split_once('|').unwrap_or((inner, inner));
+    let target = target.trim();
+    if target.is_empty() {
+        return None;
+    }
+    let label = match label.trim() {
+        "" => target,
+        label => label,
+    };
+    Some((target, label, end + 4))
+}
+
+/// Collects the alt text of an image. Formatting inside the alt text is
+/// flattened to its plain text.
+fn process_image_events<'a>(events: &mut impl Iterator<Item = Event<'a>>) -> String {
+    let mut text = String::new();
+    // Images can be nested inside the alt text, only the outer end tag
+    // finishes it.
+    let mut depth = 0;
+    for event in events {
+        match event {
+            Event::Text(cow_str) | Event::Code(cow_str) => text.push_str(&cow_str),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::Start(Tag::Image { .. }) => depth += 1,
+            Event::End(TagEnd::Image) if depth > 0 => depth -= 1,
+            Event::End(TagEnd::Image) => return text,
+            _ => {}
+        }
+    }
+    error!("Image tag parsing expects Image End tag and none was received");
+    String::new()
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are kept as they are.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = hex {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Returns the URI scheme if there is one. Single letter schemes are treated
+/// as Windows drive letters (`C:`) and not as a scheme.
+fn uri_scheme(uri: &str) -> Option<&str> {
+    let (scheme, _) = uri.split_once(':')?;
+    let mut chars = scheme.chars();
+    let first = chars.next()?;
+    if scheme.len() < 2
+        || !first.is_ascii_alphabetic()
+        || !chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return None;
+    }
+    Some(scheme)
+}
+
+/// Converts an image URI into a path string with separators of the requested
+/// platform. Returns `None` for URIs with a non-`file` scheme (`http:`, ...).
+fn image_uri_to_path(uri: &str, windows: bool) -> Option<String> {
+    let uri = uri.trim();
+    let path = match uri_scheme(uri) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("file") => {
+            let rest = &uri[scheme.len() + 1..];
+            match rest.strip_prefix("//") {
+                Some(rest) => {
+                    let (host, path) = match rest.find('/') {
+                        Some(index) => rest.split_at(index),
+                        None => (rest, ""),
+                    };
+                    if host.is_empty() || host.eq_ignore_ascii_case("localhost") {
+                        path.to_string()
+                    } else {
+                        // UNC path: file://server/share/img.png
+                        format!("//{host}{path}")
+                    }
+                }
+                None => rest.to_string(),
+            }
+        }
+        Some(_) => return None,
+        None => uri.to_string(),
+    };
+    let mut path = percent_decode(&path);
+    if windows {
+        // `file:///C:/docs` gives `/C:/docs`, drop the leading slash.
+        let bytes = path.as_bytes();
+        if bytes.len() >= 3
+            && (bytes[0] == b'/' || bytes[0] == b'\\')
+            && bytes[1].is_ascii_alphabetic()
+            && bytes[2] == b':'
+        {
+            path.remove(0);
+        }
+        Some(path.replace('/', "\\"))
+    } else {
+        Some(path.replace('\\', "/"))
+    }
+}
+
+/// Reads the image size from the first bytes of a PNG or GIF file.
+fn image_size_from_header(header: &[u8]) -> Option<(u32, u32)> {
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if header.starts_with(PNG_SIGNATURE) && header.get(12..16)? == b"IHDR" {
+        let width = u32::from_be_bytes(header.get(16..20)?.try_into().ok()?);
+        let height = u32::from_be_bytes(header.get(20..24)?.try_into().ok()?);
+        return Some((width, height));
+    }
+    if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        let width = u16::from_le_bytes(header.get(6..8)?.try_into().ok()?);
+        let height = u16::from_le_bytes(header.get(8..10)?.try_into().ok()?);
+        return Some((width as u32, height as u32));
+    }
+    None
+}
+
+/// Reads the image size without decoding the image. Returns `None` for
+/// unsupported formats.
+fn read_image_header_size(path: &Path) -> Option<(u32, u32)> {
+    let mut header = [0; 24];
+    let mut file = std::fs::File::open(path).ok()?;
+    let read = std::io::Read::read(&mut file, &mut header).ok()?;
+    image_size_from_header(&header[..read])
+}
+
+/// Decodes the image at `path`, errors are logged.
+fn decode_image(path: &Path) -> Option<Image> {
+    match image::open(path) {
+        Ok(image_data) => {
+            let image_data = image_data.to_rgba8();
+            let (width, height) = image_data.dimensions();
+            Some(Image::new(
+                image_data.to_vec().into(),
+                ImageFormat::Rgba8,
+                width,
+                height,
+            ))
+        }
+        Err(e) => {
+            error!("Failed to load image {path:?}: {e}");
+            None
+        }
+    }
+}
+
+enum ImageLoad {
+    Loading,
+    Loaded(Image),
+    /// Decoding failed, it is not tried again.
+    Failed,
+}
+
+#[derive(Default)]
+struct ImageCacheState {
+    images: HashMap<PathBuf, ImageLoad>,
+    loading: usize,
+    /// Set when an image finished decoding, until the widget picks it up.
+    finished: bool,
+    /// Queue of the decoding thread, started with the first image.
+    worker: Option<Sender<PathBuf>>,
+}
+
+/// Images of a widget by path. They are decoded one after the other on a
+/// thread of their own, the widget polls for the results on animation frames
+/// and lays out again.
+#[derive(Clone, Default)]
+struct ImageCache(Arc<Mutex<ImageCacheState>>);
+
+/// An image being decoded. Dropping it stores the result and counts the image
+/// as done, also when decoding panicked.
+struct PendingImage {
+    state: Arc<Mutex<ImageCacheState>>,
+    path: PathBuf,
+    load: ImageLoad,
+}
+
+impl PendingImage {
+    fn new(state: Arc<Mutex<ImageCacheState>>, path: PathBuf) -> Self {
+        Self {
+            state,
+            path,
+            load: ImageLoad::Failed,
+        }
+    }
+}
+
+impl Drop for PendingImage {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let load = std::mem::replace(&mut self.load, ImageLoad::Failed);
+        state.images.insert(std::mem::take(&mut self.path), load);
+        state.loading -= 1;
+        state.finished = true;
+    }
+}
+
+/// Starts the thread decoding the images sent to the returned queue. It ends
+/// with the cache or when decoding panics.
+fn spawn_image_worker(state: Weak<Mutex<ImageCacheState>>) -> Sender<PathBuf> {
+    let (sender, receiver) = mpsc::channel::<PathBuf>();
+    std::thread::spawn(move || {
+        for path in receiver {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let mut pending = PendingImage::new(state, path);
+            if let Some(image) = decode_image(&pending.path) {
+                pending.load = ImageLoad::Loaded(image);
+            }
+        }
+    });
+    sender
+}
+
+impl ImageCache {
+    /// Returns the image at `path` when it is decoded, otherwise starts
+    /// decoding it unless that failed before.
+    fn get(&self, path: &Path) -> Option<Image> {
+        let mut state = self.0.lock().unwrap();
+        match state.images.get(path) {
+            Some(ImageLoad::Loaded(image)) => return Some(image.clone()),
+            Some(ImageLoad::Loading | ImageLoad::Failed) => return None,
+            None => {}
+        }
+        state.images.insert(path.to_path_buf(), ImageLoad::Loading);
+        state.loading += 1;
+        let mut path = path.to_path_buf();
+        if let Some(worker) = &state.worker {
+            match worker.send(path) {
+                Ok(()) => return None,
+                // The thread is gone after a panic while decoding.
+                Err(SendError(unsent)) => path = unsent,
+            }
+        }
+        let worker = state
+            .worker
+            .insert(spawn_image_worker(Arc::downgrade(&self.0)));
+        // The new thread is waiting for it, sending can't fail.
+        let _ = worker.send(path);
+        None
+    }
+
+    /// Records that `path` can't be loaded. Returns `false` when it was
+    /// recorded before.
+    fn fail(&self, path: &Path) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state
+            .images
+            .insert(path.to_path_buf(), ImageLoad::Failed)
+            .is_none()
+    }
+
+    fn is_loading(&self) -> bool {
+        self.0.lock().unwrap().loading > 0
+    }
+
+    /// Returns whether images finished decoding since the last call.
+    fn take_finished(&self) -> bool {
+        std::mem::take(&mut self.0.lock().unwrap().finished)
+    }
+}
+
+/// Resolves an image URI from the document into a path on the local file
+/// system. Relative paths are joined with `base_dir`.
+fn resolve_image_uri(uri: &str, base_dir: &Path) -> Option<PathBuf> {
+    let path = PathBuf::from(image_uri_to_path(uri, cfg!(windows))?);
+    if path.is_absolute() {
+        Some(path)
+    } else {
+        Some(base_dir.join(path))
+    }
+}
+
+struct MarkeerState {
+    bold_start: usize,
+    italic_start: usize,
+    strikethrough_start: usize,
+    /// Start of the link being parsed and where it leads.
+    link: Option<(usize, LinkTarget)>,
+    markers: Vec<TextMarker>,
+    /// Byte positions of the spaces inserted for soft breaks.
+    soft_breaks: Vec<usize>,
+}
+
+impl MarkeerState {
+    fn new() -> Self {
+        Self {
+            bold_start: 0,
+            italic_start: 0,
+            strikethrough_start: 0,
+            link: None,
+            markers: Vec::new(),
+            soft_breaks: Vec::new(),
+        }
+    }
+
+    /// Removes the soft break spaces which should not be visible and shifts
+    /// the markers accordingly. Must be called once the text is complete.
+    fn finish_text(&mut self, text: &mut String) {
+        // Going from the back keeps the earlier positions valid.
+        for &pos in self.soft_breaks.iter().rev() {
+            let before = text[..pos].chars().next_back();
+            let after = text[pos + 1..].chars().next();
+            let (Some(before), Some(after)) = (before, after) else {
+                continue;
+            };
+            if !is_east_asian_wide(before) || !is_east_asian_wide(after) {
+                continue;
+            }
+            text.remove(pos);
+            for marker in self.markers.iter_mut() {
+                *marker =
+                    marker.map_positions(|at| if at > pos { at - 1 } else { at });
+            }
+        }
+        self.soft_breaks.clear();
+        merge_adjacent_markers(&mut self.markers);
+    }
+}
+
+/// Characters of scripts which don't use spaces between words, so a soft
+/// break between two of them must not be rendered as a space. Hangul is left
+/// out on purpose since Korean uses spaces.
+fn is_east_asian_wide(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x2E80..=0x303E // CJK radicals, Kangxi, CJK symbols and punctuation
+            | 0x3041..=0x33FF // Hiragana, Katakana, Bopomofo, CJK compatibility
+            | 0x3400..=0x4DBF // CJK unified ideographs extension A
+            | 0x4E00..=0x9FFF // CJK unified ideographs
+            | 0xA000..=0xA4CF // Yi
+            | 0xF900..=0xFAFF // CJK compatibility ideographs
+            | 0xFE30..=0xFE4F // CJK compatibility forms
+            | 0xFF00..=0xFF60 // Fullwidth forms
+            | 0xFFE0..=0xFFE6
+            | 0x20000..=0x2FFFD
+            | 0x30000..=0x3FFFD
+    )
+}
+
+fn process_marker(
+    event: &Event,
+    marker_state: &mut MarkeerState,
+    text_end: usize,
+) -> bool {
+    match event {
+        Event::Start(Tag::Strong) => {
+            marker_state.bold_start = text_end;
+            true
+        }
+        Event::Start(Tag::Emphasis) => {
+            marker_state.italic_start = text_end;
+            true
+        }
+        Event::Start(Tag::Strikethrough) => {
+            marker_state.strikethrough_start = text_end;
+            true
+        }
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            ..
+        }) => {
+            let kind = if *link_type == WIKILINK_TYPE {
+                LinkKind::Wiki
+            } else {
+                LinkKind::Url
+            };
+            marker_state.link = Some((text_end, LinkTarget::new(dest_url, kind)));
+            true
+        }
+        Event::End(TagEnd::Link) => {
+            if let Some((start_pos, target)) = marker_state.link.take() {
+                marker_state.markers.push(TextMarker::new(
+                    start_pos..text_end,
+                    MarkerKind::Link(target),
+                ));
+            }
+            true
+        }
+        Event::End(TagEnd::Strong) => {
+            marker_state.markers.push(TextMarker::new(
+                marker_state.bold_start..text_end,
+                MarkerKind::Bold,
+            ));
+            true
+        }
+        Event::End(TagEnd::Emphasis) => {
+            marker_state.markers.push(TextMarker::new(
+                marker_state.italic_start..text_end,
+                MarkerKind::Italic,
+            ));
+            true
+        }
+        Event::End(TagEnd::Strikethrough) => {
+            marker_state.markers.push(TextMarker::new(
+                marker_state.strikethrough_start..text_end,
+                MarkerKind::Strikethrough,
+            ));
+            true
+        }
+        _ => false,
+    }
+}
+
+fn process_header_events<'a>(
+    events: &mut impl Iterator<Item = Event<'a>>,
+    header_level: &HeadingLevel,
+    id: Option<String>,
+) -> MarkdownContent {
+    let mut text = String::new();
+    let mut marker_state = MarkeerState::new();
+    for event in events {
+        if process_marker(&event, &mut marker_state, text.len()) {
+            continue;
+        }
+        match event {
+            Event::Text(cow_str) => text.push_str(&cow_str),
+            Event::End(TagEnd::Heading(_)) => {
+                merge_adjacent_markers(&mut marker_state.markers);
+                // The anchor is assigned once the whole document is known,
+                // see `assign_anchor_ids`.
+                return MarkdownContent::Header {
+                    level: *header_level,
+                    text,
+                    markers: marker_state.markers,
+                    id,
+                    anchor_id: None,
+                    text_layout: Layout::new(),
+                    spacing: 0.0,
+                };
+            }
+            e => {
+                error!("Header tag parsing expects only some event but {e:?} was received")
+            }
+        }
+    }
+    panic!("Header tag parsing expects Heading end tag and none was received");
+}
+
+fn process_code_block_events<'a>(
+    events: &mut impl Iterator<Item = Event<'a>>,
+) -> String {
+    let mut text = String::new();
+    for event in events {
+        match event {
+            Event::Text(cow_str) => text.push_str(&cow_str),
+            Event::End(TagEnd::CodeBlock) => return text,
+            e => {
+                error!("Code block parsing expects only Text event but {e:?} was received")
+            }
+        }
+    }
+    error!("Code block parsing expects CodeBlock End tag and none was received");
+    text
+}
+
+fn process_html_block_events<'a>(
+    events: &mut impl Iterator<Item = Event<'a>>,
+) -> String {
+    let mut html = String::new();
+    for event in events {
+        match event {
+            Event::Html(cow_str) | Event::Text(cow_str) => html.push_str(&cow_str),
+            Event::End(TagEnd::HtmlBlock) => return html,
+            e => {
+                error!("Html block parsing expects only Html event but {e:?} was received")
+            }
+        }
+    }
+    error!("Html block parsing expects HtmlBlock End tag and none was received");
+    html
+}
+
+/// Start of a `<details>` block found in a HTML block.
+struct DetailsStart {
+    summary: String,
+    open: bool,
+    /// Markdown between `</summary>` and `</details>` when the whole block is
+    /// in a single HTML block. `None` when the content follows as markdown.
+    inline_body: Option<String>,
+}
+
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns `true` for the `<!-- wrap -->` comment which makes the following
+/// code block wrap.
+fn is_wrap_comment(html: &str) -> bool {
+    html.trim()
+        .strip_prefix("<!--")
+        .and_then(|comment| comment.strip_suffix("-->"))
+        .is_some_and(|comment| comment.trim().eq_ignore_ascii_case("wrap"))
+}
+
+fn is_details_end(html: &str) -> bool {
+    html.trim().eq_ignore_ascii_case("</details>")
+}
+
+fn parse_details_start(html: &str) -> Option<DetailsStart> {
+    let html = html.trim();
+    let lowercase = html.to_ascii_lowercase();
+    if !lowercase.starts_with("<details") {
+        return None;
+    }
+    let tag_end = lowercase.find('>')?;
+    let attributes = &lowercase["<details".len()..tag_end];
+    if !attributes.is_empty() && !attributes.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let open = attributes.split_whitespace().any(|a| a == "open");
+    let mut rest_start = tag_end + 1;
+    let mut summary = String::from("Details");
+    if let Some(start) = lowercase[rest_start..].find("<summary") {
+        let start = rest_start + start;
+        let content_start = start + lowercase[start..].find('>')? + 1;
+        let end = content_start + lowercase[content_start..].find("</summary>")?;
+        summary = strip_html_tags(&html[content_start..end]);
+        rest_start = end + "</summary>".len();
+    }
+    let inline_body = lowercase[rest_start..]
+        .rfind("</details>")
+        .map(|end| html[rest_start..rest_start + end].to_string());
+    Some(DetailsStart {
+        summary,
+        open,
+        inline_body,
+    })
+}
+
+fn process_list_events<'a>(
+    events: &mut impl Iterator<Item = Event<'a>>,
+    quote_level: usize,
+    options: ParseOptions,
+) -> Vec<LayoutFlow<MarkdownContent>> {
+    let mut list_elements = Vec::new();
+
+    while let Some(event) = events.next() {
+        println!("Event: {event:?}");
+        if let Event::Start(Tag::Item) = event {
+            list_elements.push(process_events(
+                events,
+                Some(Event::End(TagEnd::Item)),
+                quote_level,
+                options,
+            ));
+        } else if let Event::End(TagEnd::List(_)) = event {
+            break;
+        } else {
+            panic!("List tag parsing expects List end tag; received {event:?}");
+        }
+    }
+    list_elements
+}
+
+/// `quote_level` is the nesting level given to block quotes found in the
+/// collected content.
+fn process_events<'a>(
+    events: &mut impl Iterator<Item = Event<'a>>,
+    untill: Option<Event>,
+    quote_level: usize,
+    options: ParseOptions,
+) -> LayoutFlow<MarkdownContent> {
+    process_events_inner(events, untill, false, quote_level, options).0
+}
+
+/// Collects content until the `untill` event. With `inside_details` the
+/// collection also stops at a `</details>` HTML block. The returned flag is
+/// `true` when the `untill` event (or the end of events) was reached.
+fn process_events_inner<'a>(
+    events: &mut impl Iterator<Item = Event<'a>>,
+    untill: Option<Event>,
+    inside_details: bool,
+    quote_level: usize,
+    options: ParseOptions,
+) -> (LayoutFlow<MarkdownContent>, bool) {
+    let mut res = LayoutFlow::new();
+    let mut reached_untill = true;
+
+    let mut text = String::new();
+    let mut marker_state = MarkeerState::new();
+    let mut wrap_next_code_block = false;
+    let in_quote = matches!(untill, Some(Event::End(TagEnd::BlockQuote(_))));
+    // Index of the last paragraph and the start of its attribution line.
+    let mut attribution = None;
+
+    while let Some(event) = events.next() {
+        println!("Event: {event:?}");
+        if let Some(event_) = &untill {
+            if &event == event_ {
+                break;
+            }
+        }
+        // The `<!-- wrap -->` comment applies only to the block right after it.
+        let wrap_code_block = std::mem::take(&mut wrap_next_code_block);
+        if process_marker(&event, &mut marker_state, text.len()) {
+            continue;
+        }
+        match event {
+            Event::Start(tag) => match &tag {
+                Tag::Image {
+                    link_type: _,
+                    dest_url,
+                    title,
+                    id: _,
+                } => {
+                    // TODO: Should the image be loaded here???
+                    // TODO: Maybe images should be done as markers instead and I
+                    // should just collect images into some `HashMap`.
+                    let alt_text = process_image_events(events);
+                    res.push(MarkdownContent::Image {
+                        uri: dest_url.to_string(),
+                        title: (!title.is_empty()).then(|| title.to_string()),
+                        alt_text,
+                        caption_layout: Layout::new(),
+                        estimated_size: None,
+                        image: None,
+                        layout_width: 0.0,
+                        max_height: None,
+                        alignment: ImageAlignment::Left,
+                        scale: 1.0,
+                        natural_size: false,
+                        spacing: 0.0,
+                    })
+                }
+                Tag::CodeBlock(kind) => {
+                    let code = process_code_block_events(events);
+                    let mut code_block = MarkdownContent::code_block(code);
+                    if let MarkdownContent::CodeBlock { wrap, language, .. } =
+                        &mut code_block
+                    {
+                        *wrap = wrap_code_block;
+                        *language = match &kind {
+                            CodeBlockKind::Fenced(info) => {
+                                info.split_whitespace().next().map(str::to_string)
+                            }
+                            CodeBlockKind::Indented => None,
+                        };
+                    }
+                    res.push(code_block);
+                }
+                Tag::Table(_alignments) => {
+                    warn!("Markdown tables not supported")
+                }
+                Tag::Paragraph => {}
+                Tag::Heading {
+                    level,
+                    id,
+                    classes: _,
+                    attrs: _,
+                } => res.push(process_header_events(
+                    events,
+                    level,
+                    id.as_ref().map(|id| id.to_string()),
+                )),
+                Tag::BlockQuote(block_quote_kind) => {
+                    let flow = process_events(
+                        events,
+                        Some(Event::End(TagEnd::BlockQuote(*block_quote_kind))),
+                        quote_level + 1,
+                        options,
+                    );
+                    res.push(MarkdownContent::Indented {
+                        decoration: IndentationDecoration::new(quote_level),
+                        flow,
+                    });
+                }
+                Tag::HtmlBlock => {
+                    let html = process_html_block_events(events);
+                    if let Some(details) = parse_details_start(&html) {
+                        let (flow, unclosed) = match details.inline_body {
+                            Some(body) => {
+                                (parse_markdown_with(&body, options), false)
+                            }
+                            None => process_events_inner(
+                                events,
+                                untill.clone(),
+                                true,
+                                quote_level,
+                                options,
+                            ),
+                        };
+                        res.push(MarkdownContent::Details {
+                            summary: details.summary,
+                            summary_layout: Layout::new(),
+                            open: details.open,
+                            flow,
+                            indentation: 0.0,
+                        });
+                        // The `untill` event was consumed by the unclosed
+                        // details block, so this flow ends as well.
+                        if unclosed {
+                            break;
+                        }
+                    } else if is_wrap_comment(&html) {
+                        wrap_next_code_block = true;
+                    } else if is_details_end(&html) {
+                        if inside_details {
+                            reached_untill = false;
+                            break;
+                        }
+                        warn!("Unmatched </details> in markdown");
+                    } else {
+                        // TODO: Other HTML is not supported, show it as is.
+                        res.push(MarkdownContent::code_block(html));
+                    }
+                }
+                Tag::List(list_marker) => {
+                    let list = process_list_events(events, quote_level, options);
+                    // TODO: Think about the markers. There should be a better way to set them up
+                    let marker = if let Some(list_marker) = list_marker {
+                        ListMarker::Numbers {
+                            start_number: *list_marker as u32,
+                            layouted: Vec::new(),
+                        }
+                    } else {
+                        ListMarker::Symbol {
+                            symbol: "•".to_string(),
+                            layout: Box::new(Layout::new()),
+                        }
+                    };
+                    res.push(MarkdownContent::List {
+                        list: List {
+                            marker,
+                            list,
+                            indentation: 0.0,
+                            spacing: 0.0,
+                        },
+                    });
+                }
+                Tag::FootnoteDefinition(_cow_str) => todo!(),
+                Tag::DefinitionList => {
+                    warn!("DefinitionList in markdown is not supported!")
+                }
+                Tag::DefinitionListTitle => {
+                    warn!("DefinitionList in markdown is not supported!")
+                }
+                Tag::DefinitionListDefinition => {
+                    warn!("DefinitionList in markdown is not supported!")
+                }
+                Tag::TableHead => todo!(),
+                Tag::TableRow => todo!(),
+                Tag::TableCell => todo!(),
+                Tag::MetadataBlock(_metadata_block_kind) => {
+                    warn!("MetadataBlock in markdown are not supported")
+                }
+                _ => {}
+            },
+            Event::End(end_tag) => {
+                match end_tag {
+                    TagEnd::Paragraph => {
+                        if !text.trim().is_empty() {
+                            // Counted from the end, `finish_text` might remove
+                            // spaces before it.
+                            let attribution_len = in_quote
+                                .then(|| {
+                                    attribution_line_start(&text, &marker_state)
+                                })
+                                .flatten()
+                                .map(|line_start| text.len() - line_start);
+                            marker_state.finish_text(&mut text);
+                            attribution = attribution_len
+                                .map(|len| (res.len(), text.len() - len));
+                            res.push(MarkdownContent::Paragraph {
+                                text: text.clone(),
+                                markers: marker_state.markers.clone(),
+                                attribution: false,
+                                shown_text: None,
+                                text_layout: Layout::new(),
+                                spacing: 0.0,
+                            });
+                            text.clear();
+                            marker_state.markers.clear();
+                        }
+                        marker_state.soft_breaks.clear();
+                    }
+                    TagEnd::FootnoteDefinition => todo!(),
+                    TagEnd::Table => todo!(),
+                    TagEnd::TableHead => todo!(),
+                    TagEnd::TableRow => todo!(),
+                    TagEnd::TableCell => todo!(),
+                    e => {
+                        warn!("Markdown parsing unprocessed end tag: {e:?}");
+                    }
+                }
+            }
+            Event::Text(text_bit) => {
+                // TODO: Ignore text in some cases???
+                text.push_str(&text_bit);
+            }
+            Event::Code(text_bit) => {
+                // TODO: Maybe it should be a text_manager with both text and markers.
+                marker_state.markers.push(TextMarker::new(
+                    text.len()..text.len() + text_bit.len(),
+                    MarkerKind::InlineCode,
+                ));
+                text.push_str(&text_bit);
+            }
+            Event::Html(text_bit) => {
+                // TODO: This looks a bit fishy
+                marker_state.markers.push(TextMarker::new(
+                    text.len()..text.len() + text_bit.len(),
+                    MarkerKind::InlineCode,
+                ));
+                text.push_str(&text_bit);
+            }
+            Event::HardBreak => {
+                text.push('\n');
+            }
+            // A newline has the same length as the space, the markers are
+            // not affected by the option.
+            Event::SoftBreak if options.soft_breaks_as_hard => {
+                text.push('\n');
+            }
+            Event::SoftBreak => {
+                // The space might be removed by `MarkeerState::finish_text`
+                // once the following text is known.
+                marker_state.soft_breaks.push(text.len());
+                text.push(' ');
+            }
+            Event::Rule => {
+                // This adds random value. It will be recalculated anyway.
+                // TODO: Maybe it there should be additional step which adds
+                // these heights based on the theme???
+                res.push(MarkdownContent::HorizontalLine {
+                    height: 0.0,
+                    width: 0.0,
+                    style: LineStyle::Solid,
+                    spacing: 0.0,
+                })
+            }
+            Event::FootnoteReference(_text) => {
+                warn!("FootnoteReference in markdown is not supported!")
+            }
+            Event::TaskListMarker(_marker) => {
+                warn!("TaskListMarker in markdown is not supported!")
+            }
+            Event::InlineHtml(_) => {
+                warn!("InlineHtml in markdown is not supported!")
+            }
+            Event::InlineMath(_) => {
+                warn!("InlineMath in markdown is not supported!")
+            }
+            Event::DisplayMath(_) => {
+                warn!("DisplayMath in markdown is not supported!")
+            }
+        }
+    }
+
+    if !text.is_empty() {
+        marker_state.finish_text(&mut text);
+        res.push(MarkdownContent::Paragraph {
+            text,
+            markers: marker_state.markers,
+            attribution: false,
+            shown_text: None,
+            text_layout: Layout::new(),
+            spacing: 0.0,
+        });
+    }
+
+    // Only the last paragraph of a quote can end with an attribution.
+    if let Some((index, line_start)) = attribution {
+        if index + 1 == res.len() {
+            let line = split_attribution(&mut res.get_mutable(index), line_start);
+            if let Some(line) = line {
+                res.push(line);
+            }
+        }
+    }
+
+    (res, reached_untill)
+}
+
+/// Returns the start of the last line of a paragraph when it is an
+/// attribution like `— Author` or `-- Author`.
+fn attribution_line_start(text: &str, marker_state: &MarkeerState) -> Option<usize> {
+    let soft_break = marker_state.soft_breaks.last().map(|pos| pos + 1);
+    let hard_break = text.rfind('\n').map(|pos| pos + 1);
+    let line_start = soft_break.max(hard_break).unwrap_or(0);
+    let line = text[line_start..].trim_start();
+    let author = line
+        .strip_prefix('\u{2014}')
+        .or_else(|| line.strip_prefix("--"))?;
+    (!author.trim().is_empty()).then_some(line_start)
+}
+
+/// Marks the text of `paragraph` from `line_start` as an attribution. When
+/// there is text before it, the attribution is split off into a new
+/// paragraph which is returned.
+fn split_attribution(
+    paragraph: &mut MarkdownContent,
+    line_start: usize,
+) -> Option<MarkdownContent> {
+    let MarkdownContent::Paragraph {
+        text,
+        markers,
+        attribution,
+        ..
+    } = paragraph
+    else {
+        return None;
+    };
+    if line_start == 0 {
+        *attribution = true;
+        return None;
+    }
+    let line = text.split_off(line_start);
+    // Drops the space or the line break before the attribution.
+    text.truncate(text.trim_end().len());
+    let (text_markers, line_markers) = split_markers_at(markers, line_start);
+    // The trimmed space is not styled any more.
+    *markers = split_markers_at(&text_markers, text.len()).0;
+    Some(MarkdownContent::Paragraph {
+        text: line,
+        markers: line_markers,
+        attribution: true,
+        shown_text: None,
+        text_layout: Layout::new(),
+        spacing: 0.0,
+    })
+}
+
+/// Strips a leading UTF-8 BOM and turns `\r\n` and lone `\r` line endings
+/// into `\n`. Everything else is kept as it is.
+fn normalize_source(text: &str) -> Cow<'_, str> {
+    normalize_line_endings(text.strip_prefix('\u{feff}').unwrap_or(text))
+}
+
+/// Turns `\r\n` and lone `\r` line endings into `\n`.
+fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Parses, lays out and paints `content` into `scene` without a widget.
+/// Relative image paths are resolved against the working directory.
+pub fn render_markdown_to_scene(
+    content: &str,
+    scene: &mut Scene,
+    width: f32,
+    theme: &Theme,
+    font_ctx: &mut FontContext,
+    layout_ctx: &mut LayoutContext<MarkdownBrush>,
+) {
+    let mut flow = parse_markdown(content);
+    flow.apply_to_all(|data| {
+        data.layout(
+            font_ctx,
+            layout_ctx,
+            width,
+            theme,
+            LayoutEnv::new(Path::new("")),
+            InheritedStyle::default(),
+        );
+    });
+    let source_rect = Rect::new(0.0, 0.0, width as f64, flow.height() as f64);
+    draw_flow(
+        scene,
+        &flow,
+        Vec2::ZERO,
+        &source_rect,
+        theme,
+        &DefaultRenderer,
+    );
+}
+
+/// Extensions of the markdown parser, `{#id}` after a heading gives it an
+/// id.
+const PARSER_OPTIONS: Options =
+    Options::ENABLE_STRIKETHROUGH.union(Options::ENABLE_HEADING_ATTRIBUTES);
+
+fn parse_markdown(text: &str) -> LayoutFlow<MarkdownContent> {
+    parse_markdown_with(text, ParseOptions::default())
+}
+
+/// The source as a single wrapping code block, for the view-source mode.
+fn source_flow(text: &str) -> LayoutFlow<MarkdownContent> {
+    let mut flow = LayoutFlow::new();
+    if !text.is_empty() {
+        flow.push(MarkdownContent::CodeBlock {
+            text: text.trim_end_matches('\n').to_string(),
+            wrap: true,
+            language: None,
+            highlights: Vec::new(),
+            text_layout: Layout::new(),
+            rendered_height: 0.0,
+            rendered_width: 0.0,
+            layout_width: 0.0,
+            spacing: 0.0,
+        });
+    }
+    flow
+}
+
+fn parse_markdown_with(
+    text: &str,
+    options: ParseOptions,
+) -> LayoutFlow<MarkdownContent> {
+    parse_markdown_using(text, options, &ReferenceDefinitions::new())
+}
+
+/// Like `parse_markdown_with`, references which `text` doesn't define are
+/// looked up in `definitions`.
+fn parse_markdown_using(
+    text: &str,
+    options: ParseOptions,
+    definitions: &ReferenceDefinitions,
+) -> LayoutFlow<MarkdownContent> {
+    let text = normalize_source(text);
+    let resolve = |link: BrokenLink| {
+        let (url, title) = definitions.get(&reference_label(&link.reference))?;
+        Some((url.clone().into(), title.clone().into()))
+    };
+    let parser =
+        Parser::new_with_broken_link_callback(&text, PARSER_OPTIONS, Some(resolve));
+
+    let mut flow = if options.wikilinks {
+        let events = parser
+            .into_offset_iter()
+            .map(|(event, range)| (with_known_link_type(event), range));
+        let events = expand_wikilinks(&text, events);
+        process_events(&mut events.into_iter(), None, 0, options)
+    } else {
+        process_events(&mut parser.map(with_known_link_type), None, 0, options)
+    };
+    assign_anchor_ids(&mut flow, slugify, &mut HashMap::new());
+    flow
+}
+
+/// Link reference definitions by their `reference_label`, with their
+/// destination and title.
+type ReferenceDefinitions = HashMap<String, (String, String)>;
+
+/// Labels match case-insensitively and with any whitespace.
+fn reference_label(label: &str) -> String {
+    label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Adds the link reference definitions of `text` to `definitions`, the
+/// first definition of a label wins.
+fn collect_reference_definitions(
+    text: &str,
+    definitions: &mut ReferenceDefinitions,
+) {
+    // The definitions are collected when the parser is created.
+    let parser = Parser::new_ext(text, PARSER_OPTIONS);
+    for (label, definition) in parser.reference_definitions().iter() {
+        definitions
+            .entry(reference_label(label))
+            .or_insert_with(|| {
+                let title = definition.title.as_deref().unwrap_or_default();
+                (definition.dest.to_string(), title.to_string())
+            });
+    }
+}
+
+/// Gives the links resolved by the broken link callback their regular
+/// type, the `*Unknown` types are left to the `WIKILINK_TYPE`.
+fn with_known_link_type(event: Event<'_>) -> Event<'_> {
+    let known = |link_type| match link_type {
+        LinkType::ReferenceUnknown => LinkType::Reference,
+        LinkType::CollapsedUnknown => LinkType::Collapsed,
+        LinkType::ShortcutUnknown => LinkType::Shortcut,
+        link_type => link_type,
+    };
+    match event {
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type: known(link_type),
+            dest_url,
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type: known(link_type),
+            dest_url,
+            title,
+            id,
+        }),
+        event => event,
+    }
+}
+
+/// Whether `text` may define a link reference, which applies to the links
+/// before it as well.
+fn may_define_references(text: &str) -> bool {
+    text.lines().any(|line| {
+        line.find('[')
+            .is_some_and(|open| line[open..].contains("]:"))
+    })
+}
+
+/// Byte ranges of the top-level blocks of `text`.
+fn top_level_block_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut depth = 0usize;
+    let parser = Parser::new_ext(text, PARSER_OPTIONS);
+    for (event, range) in parser.into_offset_iter() {
+        match event {
+            Event::Start(_) => {
+                if depth == 0 {
+                    ranges.push(range);
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth = depth.saturating_sub(1),
+            _ if depth == 0 => ranges.push(range),
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// Escapes `text` so it parses back as the same plain text, line breaks
+/// become hard breaks.
+fn escape_markdown(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for (line_index, line) in text.split('\n').enumerate() {
+        if line_index > 0 {
+            res.push_str("\\\n");
+        }
+        // Digits at the start of a line followed by `.` or `)` make a list.
+        let digits =
+            line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        for (index, c) in line.char_indices() {
+            // Leading whitespace would be dropped or make an indented code
+            // block, a character reference keeps it.
+            if index == 0 && matches!(c, ' ' | '\t') {
+                res.push_str(if c == ' ' { "&#32;" } else { "&#9;" });
+                continue;
+            }
+            let escaped =
+                matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '&' | '~')
+                    || (index == 0 && matches!(c, '#' | '-' | '+' | '=' | '>'))
+                    || (index == digits && digits > 0 && matches!(c, '.' | ')'));
+            if escaped {
+                res.push('\\');
+            }
+            res.push(c);
+        }
+    }
+    res
+}
+
+/// Returns the start of the line of the last top-level block of `text`
+/// which can still change when more text is appended. The blocks before it
+/// parse the same on their own, so only the rest has to be parsed again.
+/// Returns 0 when all of `text` has to be parsed again, like for a list or
+/// an unclosed code fence which make up the whole text.
+fn stable_prefix_len(text: &str) -> usize {
+    let mut stable_len = 0;
+    let mut depth = 0usize;
+    // End of the last closed top-level block and whether the text can be
+    // split after it.
+    let mut last_block: Option<(usize, bool)> = None;
+    // `<details>` blocks take the following blocks until `</details>`.
+    let mut open_details = 0usize;
+    let mut block_start = |start: usize, last_block: Option<(usize, bool)>| {
+        let Some((end, splittable)) = last_block else {
+            return;
+        };
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        if splittable
+            && line_start >= end
+            && text[line_start..start].trim().is_empty()
+        {
+            stable_len = line_start;
+        }
+    };
+    let parser = Parser::new_ext(text, PARSER_OPTIONS);
+    for (event, range) in parser.into_offset_iter() {
+        match event {
+            Event::Start(_) => {
+                if depth == 0 && open_details == 0 {
+                    block_start(range.start, last_block);
+                }
+                depth += 1;
+            }
+            Event::End(tag) => {
+                depth -= 1;
+                if depth > 0 {
+                    continue;
+                }
+                let mut splittable = true;
+                if tag == TagEnd::HtmlBlock {
+                    let html = &text[range.clone()];
+                    if parse_details_start(html)
+                        .is_some_and(|details| details.inline_body.is_none())
+                    {
+                        open_details += 1;
+                    } else if is_details_end(html) {
+                        open_details = open_details.saturating_sub(1);
+                    }
+                    // The comment applies to the following block.
+                    splittable = !is_wrap_comment(html);
+                }
+                last_block = Some((range.end, splittable));
+            }
+            // Blocks without content, like rules.
+            _ if depth == 0 => {
+                if open_details == 0 {
+                    block_start(range.start, last_block);
+                }
+                last_block = Some((range.end, true));
+            }
+            _ => {}
+        }
+    }
+    stable_len
+}
+
+/// Parses `text` and numbers the heading anchors after the ones in `anchors`.
+fn parse_markdown_after(
+    text: &str,
+    options: ParseOptions,
+    slug_generator: SlugGenerator,
+    anchors: &mut HashMap<String, usize>,
+    definitions: &ReferenceDefinitions,
+) -> LayoutFlow<MarkdownContent> {
+    let mut flow = parse_markdown_using(text, options, definitions);
+    assign_anchor_ids(&mut flow, slug_generator, anchors);
+    flow
+}
+
+fn feed_marker_to_builder<'a>(
+    builder: &'a mut RangedBuilder<MarkdownBrush>,
+    text_marker: &TextMarker,
+    theme: &'a Theme,
+) {
+    let rang = text_marker.range();
+    match &text_marker.kind {
+        MarkerKind::Bold => {
+            builder.push(StyleProperty::FontWeight(FontWeight::BOLD), rang)
+        }
+        MarkerKind::Italic => {
+            builder.push(StyleProperty::FontStyle(FontStyle::Italic), rang)
+        }
+        MarkerKind::Strikethrough => {
+            builder.push(StyleProperty::Strikethrough(true), rang)
+        }
+        MarkerKind::InlineCode => {
+            builder.push(
+                StyleProperty::FontStack(theme.monospace_font_stack.clone()),
+                rang.clone(),
+            );
+            builder.push(
+                StyleProperty::Brush(MarkdownBrush::new(theme.monospace_text_color)),
+                rang,
+            );
+        }
+        MarkerKind::Link(target) => {
+            if target.scheme != LinkScheme::Mail || theme.underline_mail_links {
+                builder.push(StyleProperty::Underline(true), rang.clone());
+            }
+            builder.push(
+                StyleProperty::Brush(MarkdownBrush::new(theme.link_color)),
+                rang,
+            );
+        }
+        MarkerKind::WavyUnderline(color) => {
+            builder.push(StyleProperty::Underline(true), rang.clone());
+            builder.push(
+                StyleProperty::UnderlineBrush(Some(MarkdownBrush(
+                    *color,
+                    LineStyle::Wavy,
+                    GlyphMark::None,
+                ))),
+                rang,
+            );
+        }
+    }
+}
+
+/// Space reserved after external links for the arrow.
+fn external_link_indicator_width(theme: &Theme) -> f32 {
+    theme.text_size as f32 * 0.75
+}
+
+/// Reserves space for the arrow after the last character of an external
+/// link and marks its brush, so the arrow wraps with the link text. Applied
+/// after the other markers so they can't replace the mark.
+fn push_external_link_indicator(
+    builder: &mut RangedBuilder<MarkdownBrush>,
+    text: &str,
+    text_marker: &TextMarker,
+    theme: &Theme,
+) {
+    let MarkerKind::Link(target) = &text_marker.kind else {
+        return;
+    };
+    if target.scheme != LinkScheme::External || !theme.external_link_indicator {
+        return;
+    }
+    let Some(range) = text.get(text_marker.range()).and_then(|link| {
+        let (start, _) = link.char_indices().next_back()?;
+        Some(text_marker.range.start + start..text_marker.range.end)
+    }) else {
+        return;
+    };
+    builder.push(
+        StyleProperty::Brush(MarkdownBrush(
+            theme.link_color,
+            LineStyle::Solid,
+            GlyphMark::ExternalLink,
+        )),
+        range.clone(),
+    );
+    builder.push(
+        StyleProperty::LetterSpacing(external_link_indicator_width(theme)),
+        range,
+    );
+}
+
+/// Returns `true` when the layout has more lines and the last one is
+/// narrower than `min_width`.
+fn has_short_last_line(layout: &Layout<MarkdownBrush>, min_width: f32) -> bool {
+    layout.len() > 1
+        && layout
+            .lines()
+            .last()
+            .is_some_and(|line| line.metrics().advance < min_width)
+}
+
+/// Byte positions of the soft hyphens of `text` at which `layout` breaks
+/// its lines.
+fn soft_hyphen_breaks(layout: &Layout<MarkdownBrush>, text: &str) -> Vec<usize> {
+    layout
+        .lines()
+        .filter_map(|line| {
+            let end = line.text_range().end;
+            let before = text.get(..end)?;
+            before
+                .ends_with('\u{ad}')
+                .then(|| end - '\u{ad}'.len_utf8())
+        })
+        .collect()
+}
+
+/// Replaces the soft hyphens at which `layout` breaks by visible hyphens.
+/// The other soft hyphens stay invisible. A hyphen is one byte shorter than
+/// a soft hyphen, the markers are shifted to match.
+fn hyphenate_soft_breaks(
+    layout: &Layout<MarkdownBrush>,
+    text: &str,
+    markers: &[TextMarker],
+) -> Option<(String, Vec<TextMarker>)> {
+    let breaks = soft_hyphen_breaks(layout, text);
+    if breaks.is_empty() {
+        return None;
+    }
+    let shy_len = '\u{ad}'.len_utf8();
+    let mut hyphenated = String::with_capacity(text.len());
+    let mut start = 0;
+    for &pos in &breaks {
+        hyphenated.push_str(&text[start..pos]);
+        hyphenated.push('-');
+        start = pos + shy_len;
+    }
+    hyphenated.push_str(&text[start..]);
+    let shift = |pos: usize| {
+        let removed = breaks.iter().filter(|&&at| at < pos).count();
+        pos - removed * (shy_len - 1)
+    };
+    let markers = markers
+        .iter()
+        .map(|marker| marker.map_positions(shift))
+        .collect();
+    Some((hyphenated, markers))
+}
+
+/// Joins the last word of `text` to the previous one with a non-breaking
+/// space, so the last line of the paragraph is not a lone word. The
+/// non-breaking space is one byte longer, the markers are shifted to match.
+fn bind_last_word(
+    text: &str,
+    markers: &[TextMarker],
+) -> Option<(String, Vec<TextMarker>)> {
+    let space = text.trim_end().rfind(' ')?;
+    if text[..space].trim().is_empty() {
+        return None;
+    }
+    let mut bound = String::with_capacity(text.len() + 1);
+    bound.push_str(&text[..space]);
+    bound.push('\u{a0}');
+    bound.push_str(&text[space + 1..]);
+    let shift = |pos: usize| if pos > space { pos + 1 } else { pos };
+    let markers = markers
+        .iter()
+        .map(|marker| marker.map_positions(shift))
+        .collect();
+    Some((bound, markers))
+}
+
+fn text_to_builder<'a>(
+    text: &'a str,
+    markers: &[TextMarker],
+    theme: &Theme,
+    font_ctx: &'a mut FontContext,
+    layout_ctx: &'a mut LayoutContext<MarkdownBrush>,
+) -> RangedBuilder<'a, MarkdownBrush> {
+    text_to_builder_with(text, markers, &[], theme, font_ctx, layout_ctx)
+}
+
+/// What the blocks of a document are laid out with besides the theme, the
+/// same for the blocks at every nesting level.
+#[derive(Clone, Copy)]
+struct LayoutEnv<'a> {
+    /// Directory relative image paths are resolved against.
+    base_dir: &'a Path,
+    renderer: &'a dyn ContentRenderer,
+    /// Decodes the images in the background, without it they are decoded
+    /// right away.
+    images: Option<&'a ImageCache>,
+}
+
+impl<'a> LayoutEnv<'a> {
+    /// Lays out with the built-in renderer, decoding images right away.
+    fn new(base_dir: &'a Path) -> Self {
+        Self {
+            base_dir,
+            renderer: &DefaultRenderer,
+            images: None,
+        }
+    }
+}
+
+/// Default text styles a container passes to the blocks inside it, the
+/// markers of the blocks are applied on top.
+#[derive(Clone, Copy, Debug, Default)]
+struct InheritedStyle {
+    italic: bool,
+    color: Option<Color>,
+}
+
+impl InheritedStyle {
+    /// Styles for the content of a block quote.
+    fn quoted(self, theme: &Theme) -> Self {
+        Self {
+            italic: self.italic || theme.blockquote_italic,
+            color: theme.blockquote_text_color.or(self.color),
+        }
+    }
+
+    fn properties(&self) -> Vec<StyleProperty<'static, MarkdownBrush>> {
+        let mut properties = Vec::new();
+        if self.italic {
+            properties.push(StyleProperty::FontStyle(FontStyle::Italic));
+        }
+        if let Some(color) = self.color {
+            properties.push(StyleProperty::Brush(MarkdownBrush::new(color)));
+        }
+        properties
+    }
+}
+
+/// Like `text_to_builder`, `defaults` replace the default styles of the
+/// theme before the markers are applied.
+fn text_to_builder_with<'a>(
+    text: &'a str,
+    markers: &[TextMarker],
+    defaults: &[StyleProperty<'a, MarkdownBrush>],
+    theme: &Theme,
+    font_ctx: &'a mut FontContext,
+    layout_ctx: &'a mut LayoutContext<MarkdownBrush>,
+) -> RangedBuilder<'a, MarkdownBrush> {
+    let mut builder: RangedBuilder<'_, MarkdownBrush> =
+        layout_ctx.ranged_builder(font_ctx, text, theme.scale);
+    builder.push_default(StyleProperty::Brush(MarkdownBrush::new(theme.text_color)));
+    builder.push_default(StyleProperty::FontSize(theme.text_size as f32));
+    builder.push_default(StyleProperty::FontStack(theme.font_stack.clone()));
+    builder.push_default(StyleProperty::FontWeight(FontWeight::NORMAL));
+    builder.push_default(StyleProperty::FontStyle(FontStyle::Normal));
+    builder.push_default(StyleProperty::LineHeight(1.0));
+    for property in defaults {
+        builder.push_default(property.clone());
+    }
+    for marker in markers.iter() {
+        feed_marker_to_builder(&mut builder, marker, theme);
+    }
+    for marker in markers.iter() {
+        push_external_link_indicator(&mut builder, text, marker, theme);
+    }
+    builder
+}
+
+/// Byte range of the first `char_count` characters of `text`.
+fn first_chars_range(text: &str, char_count: usize) -> Range<usize> {
+    let end = text
+        .char_indices()
+        .nth(char_count)
+        .map_or(text.len(), |(index, _)| index);
+    0..end
+}
+
+/// The styles of `Theme::paragraph_first_line_style` and the byte range of
+/// `text` they apply to.
+fn first_line_properties(
+    text: &str,
+    theme: &Theme,
+) -> Option<(Range<usize>, Vec<StyleProperty<'static, MarkdownBrush>>)> {
+    let style = theme.paragraph_first_line_style.as_ref()?;
+    let range = first_chars_range(text, style.char_count);
+    if range.is_empty() {
+        return None;
+    }
+    let mut properties = vec![StyleProperty::FontWeight(style.font_weight)];
+    if let Some(settings) = style.font_variant.feature_settings() {
+        properties.push(StyleProperty::FontFeatures(FontSettings::Source(
+            Cow::Borrowed(settings),
+        )));
+    }
+    Some((range, properties))
+}
+
+/// Styles the start of a paragraph with `Theme::paragraph_first_line_style`.
+fn push_first_line_style(
+    builder: &mut RangedBuilder<'_, MarkdownBrush>,
+    text: &str,
+    theme: &Theme,
+) {
+    let Some((range, properties)) = first_line_properties(text, theme) else {
+        return;
+    };
+    for property in properties {
+        builder.push(property, range.clone());
+    }
+}
+
+const COPY_BUTTON_PADDING: f32 = 4.0;
+const COPY_FEEDBACK_DURATION: Duration = Duration::from_millis(1500);
+const CONTEXT_MENU_PADDING: f32 = 6.0;
+/// Distance the pointer can move between press and release of a click.
+const CLICK_SLOP: f64 = 4.0;
+
+/// Entry of the context menu opened by a right click.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContextMenuEntry {
+    Copy,
+    CopyAsMarkdown,
+    CopyLinkAddress(String),
+    CopyImagePath(PathBuf),
+    SelectAll,
+}
+
+impl ContextMenuEntry {
+    const LABELS: [&'static str; 5] = [
+        "Copy",
+        "Copy as Markdown",
+        "Copy Link Address",
+        "Copy Image Path",
+        "Select All",
+    ];
+
+    fn label_index(&self) -> usize {
+        match self {
+            ContextMenuEntry::Copy => 0,
+            ContextMenuEntry::CopyAsMarkdown => 1,
+            ContextMenuEntry::CopyLinkAddress(_) => 2,
+            ContextMenuEntry::CopyImagePath(_) => 3,
+            ContextMenuEntry::SelectAll => 4,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        Self::LABELS[self.label_index()]
+    }
+}
+
+struct ContextMenu {
+    /// Top-left corner in widget coordinates.
+    origin: Point,
+    entries: Vec<ContextMenuEntry>,
+}
+
+/// Receives state changes of `MarkdowWidget` without going through actions,
+/// for integrations which do not use Xilem.
+pub trait MarkdownObserver {
+    /// The vertical scroll offset changed to `y`.
+    fn on_scroll_changed(&mut self, _y: f64) {}
+
+    fn on_layout_completed(&mut self) {}
+
+    /// A link to `url` was clicked and allowed by the `LinkPolicy`.
+    fn on_link_clicked(&mut self, _url: &str) {}
+
+    /// Like `on_link_clicked`, with the kind and scheme of the link.
+    fn on_link_target_clicked(&mut self, _link: &LinkTarget) {}
+
+    /// The pointer entered a link to `url` or left the links with `None`.
+    fn on_hovered_link_changed(&mut self, _url: Option<&str>) {}
+}
+
+/// Places the text into the clipboard, failures are logged.
+fn set_clipboard_text(text: String) -> bool {
+    let result =
+        arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text));
+    if let Err(e) = &result {
+        error!("Failed to copy to the clipboard: {e}");
+    }
+    result.is_ok()
+}
+
+/// Position in the text of a top-level item of a document.
+///
+/// `byte_offset` counts in the text of paragraphs, headings and code blocks
+/// without their markup. Other items have no text and are selected whole.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ContentPos {
+    pub item_index: usize,
+    pub byte_offset: usize,
+}
+
+impl ContentPos {
+    pub fn new(item_index: usize, byte_offset: usize) -> Self {
+        ContentPos {
+            item_index,
+            byte_offset,
+        }
+    }
+}
+
+/// Part of the source which is parsed again by `MarkdowWidget::append_source`.
+/// The default makes the whole source the tail.
+#[derive(Clone, Debug, Default)]
+struct StreamTail {
+    /// Byte offset of the tail in the source.
+    start: usize,
+    /// Number of top-level items parsed from the source before the tail.
+    items: usize,
+    /// Heading anchors used before the tail, for numbering duplicates.
+    anchors: HashMap<String, usize>,
+    /// Link references defined before the tail, the tail is parsed without
+    /// their definitions.
+    definitions: ReferenceDefinitions,
+}
+
+/// Markdown source of `MarkdowWidget`. Content given at once is shared with
+/// the caller, appended content grows a buffer of its own.
+#[derive(Clone, Debug)]
+enum SourceText {
+    Shared(Arc<str>),
+    Streamed(String),
+}
+
+impl SourceText {
+    fn push_str(&mut self, text: &str) {
+        match self {
+            SourceText::Shared(shared) => {
+                let mut streamed = String::with_capacity(shared.len() + text.len());
+                streamed.push_str(shared);
+                streamed.push_str(text);
+                *self = SourceText::Streamed(streamed);
+            }
+            SourceText::Streamed(streamed) => streamed.push_str(text),
+        }
+    }
+
+    /// The source as shared content, streamed content is copied.
+    fn to_shared(&self) -> Arc<str> {
+        match self {
+            SourceText::Shared(shared) => shared.clone(),
+            SourceText::Streamed(streamed) => Arc::from(streamed.as_str()),
+        }
+    }
+}
+
+impl Deref for SourceText {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            SourceText::Shared(shared) => shared,
+            SourceText::Streamed(streamed) => streamed,
+        }
+    }
+}
+
+impl From<Arc<str>> for SourceText {
+    fn from(shared: Arc<str>) -> Self {
+        SourceText::Shared(shared)
+    }
+}
+
+impl From<String> for SourceText {
+    fn from(streamed: String) -> Self {
+        SourceText::Streamed(streamed)
+    }
+}
+
+pub struct MarkdowWidget {
+    markdown_layout: LayoutFlow<MarkdownContent>,
+    /// Directory relative image paths are resolved against.
+    base_dir: PathBuf,
+    layout_ctx: LayoutContext<MarkdownBrush>,
+    max_advance: f64,
+    viewport_height: f64,
+    dirty: bool,
+    /// Top-level items to lay out again when the rest of the document is
+    /// still valid.
+    dirty_items: BTreeSet<usize>,
+    scroll: Vec2,
+    selection_color: Color,
+    /// Scale factor of the window, it multiplies `Theme::scale` in layout.
+    scale_factor: f64,
+    /// Markdown source, used by "Copy as Markdown" and for parsing again
+    /// when the `ParseOptions` change. It shares the content given to
+    /// `from_source` unless line endings had to be normalized.
+    source: SourceText,
+    /// Hash of the content the source was made from, appended chunks are
+    /// added to it.
+    source_hasher: DefaultHasher,
+    /// The content ended with a `\r`, a `\n` at the start of the next
+    /// appended chunk belongs to it.
+    source_ends_with_cr: bool,
+    /// Byte ranges of the top-level blocks of the source with the hash of
+    /// the source they were found in, see `item_source_range`.
+    source_blocks: Option<(u64, Vec<Range<usize>>)>,
+    stream_tail: StreamTail,
+    /// Number of times the source was parsed, for checking that unchanged
+    /// content is not parsed again.
+    parse_count: usize,
+    /// Number of `paint` calls, for catching needless repaints.
+    paint_call_count: u64,
+    parse_options: ParseOptions,
+    /// Shows the raw source as a single code block instead of rendering it.
+    view_source_mode: bool,
+    slug_generator: SlugGenerator,
+    all_selected: bool,
+    /// Selected text from the first to the second position, which are in
+    /// document order.
+    selection: Option<(ContentPos, ContentPos)>,
+    context_menu: Option<ContextMenu>,
+    /// Where the primary button was pressed, links and images are clicked
+    /// when it is released at the same place.
+    pressed_at: Option<Point>,
+    /// Layouts of `ContextMenuEntry::LABELS`.
+    context_menu_labels: Vec<Layout<MarkdownBrush>>,
+    /// Indices of the top-level headings whose sections are collapsed.
+    collapsed_sections: BTreeSet<usize>,
+    /// Top-level code block under the pointer, it shows the copy button.
+    hovered_code_block: Option<usize>,
+    /// Set when code was copied, the button shows "Copied!" for a while.
+    copy_feedback_timer: Option<Instant>,
+    copy_label_layout: Layout<MarkdownBrush>,
+    copied_label_layout: Layout<MarkdownBrush>,
+    /// Shows the document as discrete pages of the viewport height.
+    paginated: bool,
+    page_starts: Vec<f32>,
+    current_page: usize,
+    /// When set, ordered lists use letters, `Some(true)` for uppercase.
+    alpha_list_marker: Option<bool>,
+    /// CSS `list-style-type` of ordered lists, overrides `alpha_list_marker`.
+    list_style: Option<String>,
+    /// Report the content height instead of taking all available height.
+    shrink_to_fit: bool,
+    /// Bounds of the width the content is laid out in, whatever width the
+    /// parent offers.
+    min_render_width: f32,
+    max_render_width: f32,
+    show_progress_bar: bool,
+    /// Last progress sent with `MarkdownAction::ProgressChanged`.
+    reported_progress: Option<f64>,
+    /// Accessibility node ids of the top-level items by the hash of their
+    /// kind and text and the number of items with the same hash before
+    /// them. Items keep their node when the document is parsed again or
+    /// items before them change.
+    item_node_ids: HashMap<(u64, usize), NodeId>,
+    /// Node ids of the top-level items by index, see `update_item_node_ids`.
+    item_nodes: Vec<NodeId>,
+    /// Landmark listing the headings, so they can be browsed without
+    /// reading the whole document.
+    navigation_node_id: NodeId,
+    /// Node ids of the heading links in the navigation landmark.
+    toc_link_ids: Vec<NodeId>,
+    /// Item index of the heading each navigation link scrolls to.
+    toc_link_targets: HashMap<NodeId, usize>,
+    /// Item to scroll to once the layout is known.
+    pending_scroll_item: Option<usize>,
+    /// Heading anchor to scroll to once the layout is known.
+    pending_scroll_anchor: Option<String>,
+    /// Scroll percentage to restore after the next layout.
+    pending_scroll_percentage: Option<f64>,
+    /// Scroll position of a restored `MarkdownViewState`, applied after the
+    /// next layout.
+    pending_scroll_state: Option<ScrollAnchor>,
+    /// Last heading sent with `MarkdownAction::VisibleHeadingChanged`.
+    reported_heading: Option<Option<usize>>,
+    observers: Vec<Box<dyn MarkdownObserver>>,
+    /// Clicking an image toggles it between fit-to-width and natural size.
+    enlarge_images_on_click: bool,
+    /// Filters and resolves the URLs of clicked links.
+    link_policy: LinkPolicy,
+    /// Relative links to markdown files open them in this widget.
+    link_navigation: bool,
+    scroll_propagation: ScrollPropagation,
+    /// File the document was read from.
+    file_path: Option<PathBuf>,
+    back_history: Vec<HistoryEntry>,
+    forward_history: Vec<HistoryEntry>,
+    /// Clicking `#anchor` links scrolls to the heading instead of
+    /// submitting `MarkdownAction::LinkClicked`.
+    internal_anchor_links: bool,
+    /// Decides about clicked links before the widget does.
+    link_click_handler: Option<LinkClickHandler>,
+    /// Shows a link glyph left of the hovered heading.
+    heading_permalinks: bool,
+    /// Clicking the link glyph also copies `#anchor` to the clipboard.
+    copy_permalinks: bool,
+    /// Top-level heading under the pointer.
+    hovered_heading: Option<usize>,
+    /// URL of the link under the pointer.
+    hovered_link_url: Option<String>,
+    /// Link URL last sent with `MarkdownAction::HoveredLinkChanged`.
+    reported_hovered_link: Option<String>,
+    /// Length last sent with `MarkdownAction::SelectionChanged`.
+    reported_selection_chars: usize,
+    /// Counts the changes of the selection and of the text it covers, the
+    /// length is only measured again when it moved on.
+    selection_generation: u64,
+    /// `selection_generation` of the last `take_selection_change`.
+    reported_selection_generation: u64,
+    /// Fonts used instead of the ones of Masonry, see `with_font_ctx`.
+    font_ctx: Option<FontContext>,
+    /// Space left of the content, reserved for the link glyphs.
+    left_gutter: f64,
+    /// Scroll offset last passed to `MarkdownObserver::on_scroll_changed`.
+    observed_scroll_y: f64,
+    /// Replace fenced code blocks by their language, see
+    /// `with_fence_handler`.
+    fence_handlers: FenceHandlers,
+    /// Lays out and paints the blocks, the built-in behavior by default.
+    content_renderer: Arc<dyn ContentRenderer>,
+    images: ImageCache,
+    /// Message about a dropped file which could not be opened and when it
+    /// was shown.
+    drop_message: Option<(String, Instant)>,
+    drop_message_layout: Layout<MarkdownBrush>,
+    /// Padding of the theme the document was last laid out with.
+    padding: Padding,
+    /// Outlines the top-level blocks with their kind, for development.
+    debug_overlay: bool,
+    /// Logs how long the layout of every top-level item takes.
+    layout_profiling: bool,
+    /// Overrides the code color scheme of the theme.
+    code_color_scheme: Option<CodeColorScheme>,
+    /// Built-in theme used instead of the global one, see `widget_theme`.
+    theme_mode: Option<ThemeMode>,
+    /// Highlights the code of fenced code blocks, see
+    /// `with_syntax_highlighter`.
+    syntax_highlighter: Option<Arc<dyn SyntaxHighlighter>>,
+    debug_labels: Vec<(BlockKind, Layout<MarkdownBrush>)>,
+}
+
+/// A parsed document outside of a widget, for exporting it.
+pub struct MarkdownDocument {
+    flow: LayoutFlow<MarkdownContent>,
+    /// Directory relative image paths are resolved against.
+    base_dir: PathBuf,
+}
+
+impl MarkdownDocument {
+    pub fn new(source: &str, base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            flow: parse_markdown(source),
+            base_dir: base_dir.into(),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(markdown_file: P) -> std::io::Result<Self> {
+        let source = std::fs::read_to_string(&markdown_file)?;
+        Ok(Self::new(&source, parent_dir(markdown_file.as_ref())))
+    }
+
+    /// Shows the fenced code blocks whose info string starts with `language`
+    /// as the content `handler` returns, like
+    /// `MarkdowWidget::with_fence_handler`.
+    pub fn with_fence_handler<F>(mut self, language: &str, handler: F) -> Self
+    where
+        F: Fn(&str) -> MarkdownContent + Send + Sync + 'static,
+    {
+        let mut handlers = FenceHandlers::new();
+        handlers.insert(language, handler);
+        apply_fence_handlers(&mut self.flow, &handlers);
+        self
+    }
+
+    /// Lays the document out at the content width of the pages and renders
+    /// it into a PDF. Like with pagination, blocks don't straddle pages,
+    /// except for blocks taller than a page which continue on the next ones.
+    pub fn to_pdf(&mut self, options: PageOptions) -> Vec<u8> {
+        let theme = get_theme();
+        let content = options.content_size();
+        let mut font_ctx = FontContext::new();
+        let mut layout_ctx = LayoutContext::new();
+        let env = LayoutEnv::new(&self.base_dir);
+        self.flow.apply_to_all(|data| {
+            data.layout(
+                &mut font_ctx,
+                &mut layout_ctx,
+                content.width as f32,
+                &theme,
+                env,
+                InheritedStyle::default(),
+            );
+        });
+
+        let starts = split_tall_pages(
+            &self.flow.page_starts(content.height as f32),
+            self.flow.height(),
+            content.height as f32,
+        );
+        let pages: Vec<PdfPage> = starts
+            .iter()
+            .enumerate()
+            .map(|(index, &start)| {
+                let end = starts.get(index + 1).copied().unwrap_or(f32::INFINITY);
+                let mut page = PdfPage::new(options.size.height);
+                page.clip(Rect::from_origin_size(
+                    (options.margin, options.margin),
+                    content,
+                ));
+                // A block continued from the previous page is shifted up and
+                // clipped at the top margin.
+                let elements = self.flow.iter().filter(|element| {
+                    !element.hidden
+                        && element.offset < end
+                        && (element.offset >= start
+                            || element.offset + element.height > start)
+                });
+                for element in elements {
+                    element.data.export_to_pdf(
+                        &mut page,
+                        Vec2::new(
+                            options.margin,
+                            options.margin + (element.offset - start) as f64,
+                        ),
+                        content.width,
+                        &theme,
+                    );
+                }
+                page
+            })
+            .collect();
+        write_pdf(&pages, options.size)
+    }
+
+    /// Writes the document to `path` as a PDF, see `to_pdf`. Links are kept
+    /// as text but can't be clicked.
+    pub fn export_pdf<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: PageOptions,
+    ) -> std::io::Result<()> {
+        std::fs::write(path, self.to_pdf(options))
+    }
+}
+
+/// Adds page starts to the pages of `starts` taller than `page_height`, they
+/// hold a single block which is continued on the added pages.
+fn split_tall_pages(starts: &[f32], flow_height: f32, page_height: f32) -> Vec<f32> {
+    if page_height <= 0.0 {
+        return starts.to_vec();
+    }
+    let mut split = Vec::with_capacity(starts.len());
+    for (index, &start) in starts.iter().enumerate() {
+        let end = starts.get(index + 1).copied().unwrap_or(flow_height);
+        let mut page_start = start;
+        split.push(page_start);
+        while end - page_start > page_height {
+            page_start += page_height;
+            split.push(page_start);
+        }
+    }
+    split
+}
+
+/// Actions submitted by `MarkdowWidget` as `masonry::Action::Other`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MarkdownAction {
+    /// The page was changed by the user in the paginated mode.
+    PageChanged(usize),
+    /// Reading progress from 0.0 to 1.0 of the scrollable range.
+    ProgressChanged(f64),
+    /// Item index of the top-level heading whose section is at the top of
+    /// the viewport, see `MarkdowWidget::visible_heading`.
+    VisibleHeadingChanged(Option<usize>),
+    /// The link glyph of a heading was clicked.
+    HeadingLinkClicked { anchor: String },
+    /// A link in the text was clicked. Wikilinks carry the page name as
+    /// `url` and `LinkKind::Wiki`.
+    LinkClicked { url: String, kind: LinkKind },
+    /// An image was clicked, `path` is the resolved local file if any.
+    ImageClicked {
+        uri: String,
+        path: Option<PathBuf>,
+        alt_text: String,
+    },
+    /// A file dropped onto the widget was opened and replaced the document.
+    FileOpened { path: PathBuf },
+    /// The selection changed to `char_count` characters, 0 when it was
+    /// cleared. The text is available from `MarkdowWidget::selected_text`.
+    SelectionChanged { char_count: usize },
+    /// The pointer entered a link, with its URL, or left it with `None`.
+    /// Moving within a link or between links to the same URL sends nothing.
+    HoveredLinkChanged(Option<String>),
+    /// The document was replaced by following a link or by going back or
+    /// forward in the history, `path` is the file shown if any.
+    Navigated { path: Option<PathBuf> },
+}
+
+/// State of a `MarkdowWidget` which is not part of the document, for
+/// applications which restore it when they are started again. See
+/// `MarkdowWidget::capture_state`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MarkdownViewState {
+    /// Block at the top of the viewport, `None` for an empty document.
+    pub scroll: Option<ScrollAnchor>,
+    /// Scale factor of the widget, see `MarkdowWidget::with_scale_factor`.
+    pub zoom: f64,
+    /// Anchors of the headings whose sections are collapsed.
+    pub collapsed_sections: Vec<String>,
+}
+
+/// Scroll position relative to a block, it survives changes of the width
+/// and of the theme which pixel offsets don't.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScrollAnchor {
+    /// Indices of the blocks from the top-level flow down to the block at
+    /// the top of the viewport, like `HitInfo::path`.
+    pub path: Vec<usize>,
+    /// How far the top of the viewport is into the block, 0.0 at its top
+    /// and 1.0 at its bottom.
+    pub fraction: f32,
+    /// Start of the text of the top-level block, it is looked for when
+    /// content was inserted or removed before it.
+    pub fingerprint: String,
+}
+
+/// A document left by following a link, see
+/// `MarkdowWidget::with_link_navigation`.
+struct HistoryEntry {
+    source: Arc<str>,
+    base_dir: PathBuf,
+    path: Option<PathBuf>,
+    scroll_percentage: f64,
+}
+
+/// What `MarkdownView::on_link_click` decided to do with a clicked link.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkDecision {
+    /// The application took care of the link.
+    Handled,
+    /// Open the link with the default application of the platform, needs the
+    /// `open` feature. Relative links are resolved against the directory of
+    /// the document.
+    OpenExternally,
+    /// Do nothing.
+    Ignore,
+    /// Treat the link like without a handler: anchors scroll to their
+    /// heading and the other links are submitted as
+    /// `MarkdownAction::LinkClicked`.
+    Default,
+}
+
+/// Decides synchronously what happens to a clicked link, gets the URL and
+/// the kind of the link.
+pub type LinkClickHandler =
+    Arc<dyn Fn(&str, LinkKind) -> LinkDecision + Send + Sync>;
+
+/// Opens `url` with the default application of the platform.
+fn open_externally(url: &str) {
+    #[cfg(feature = "open")]
+    if let Err(e) = open::that_detached(url) {
+        error!("Failed to open {url}: {e}");
+    }
+    #[cfg(not(feature = "open"))]
+    warn!("Not opening {url}, the `open` feature is disabled");
+}
+
+/// Whether mouse wheel events over `MarkdowWidget` also reach its parents,
+/// like a scroll container around it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScrollPropagation {
+    /// The parents get every event.
+    Always,
+    /// The parents get the events the widget can't scroll any further with,
+    /// at the top and the bottom of the document.
+    WhenAtBoundary,
+    /// The widget consumes every event.
+    #[default]
+    Never,
+}
+
+impl ScrollPropagation {
+    /// Whether the widget marks a wheel event as handled, `scrolled` tells
+    /// whether the event moved the document.
+    fn handles(self, scrolled: bool) -> bool {
+        match self {
+            ScrollPropagation::Always => false,
+            ScrollPropagation::WhenAtBoundary => scrolled,
+            ScrollPropagation::Never => true,
+        }
+    }
+}
+
+/// Smallest progress change which is reported with an action.
+const PROGRESS_THRESHOLD: f64 = 0.01;
+
+/// Returns how far the document is read, from 0.0 to 1.0 of the scrollable
+/// range. Documents which fit into the viewport are always fully read.
+fn reading_progress(
+    scroll_y: f64,
+    content_height: f64,
+    viewport_height: f64,
+) -> f64 {
+    let scrollable = content_height - viewport_height;
+    if scrollable <= 0.0 || !scrollable.is_finite() {
+        return 1.0;
+    }
+    (scroll_y / scrollable).clamp(0.0, 1.0)
+}
+
+/// Width the content is laid out in when the parent offers `available`.
+/// The minimum wins over the maximum.
+fn render_width(available: f64, min: f32, max: f32) -> f64 {
+    available.min(max as f64).max(min as f64)
+}
+
+/// Returns the scroll offset at `percentage` of the scrollable range.
+fn scroll_for_percentage(
+    percentage: f64,
+    content_height: f64,
+    viewport_height: f64,
+) -> f64 {
+    let scrollable = (content_height - viewport_height).max(0.0);
+    if !percentage.is_finite() || !scrollable.is_finite() {
+        return 0.0;
+    }
+    percentage.clamp(0.0, 1.0) * scrollable
+}
+
+/// Hash used to tell whether new content differs from the current one.
+/// Appended content can be added to it later.
+fn source_hasher(content: &str) -> DefaultHasher {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(content.as_bytes());
+    hasher
+}
+
+/// Normalizes `content` for parsing, sharing it when nothing changes.
+fn normalized_source(content: Arc<str>) -> Arc<str> {
+    match normalize_source(&content) {
+        Cow::Borrowed(text) if text.len() == content.len() => content.clone(),
+        text => Arc::from(text.as_ref()),
+    }
+}
+
+/// Files which can be dropped onto the widget to open them.
+const MARKDOWN_FILE_EXTENSIONS: [&str; 3] = ["md", "markdown", "txt"];
+const DROP_MESSAGE_DURATION: Duration = Duration::from_secs(3);
+const DROP_MESSAGE_PADDING: f32 = 6.0;
+
+fn is_markdown_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            MARKDOWN_FILE_EXTENSIONS
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+        })
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+/// Directory relative image paths of the file at `path` are resolved
+/// against.
+fn parent_dir(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+impl MarkdowWidget {
+    pub fn new<P: AsRef<Path>>(markdown_file: P) -> Self {
+        // TODO: Ehm... unwraps...
+        Self::try_new(markdown_file).unwrap()
+    }
+
+    /// Like `new`, but returns the error when the file can't be read or is
+    /// not UTF-8.
+    pub fn try_new<P: AsRef<Path>>(markdown_file: P) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(&markdown_file)?;
+        let mut widget =
+            Self::from_source(content, parent_dir(markdown_file.as_ref()));
+        widget.file_path = Some(markdown_file.as_ref().to_path_buf());
+        Ok(widget)
+    }
+
+    /// Replaces the document with the file at `path`. Nothing of the old
+    /// document is kept, on error the widget is left unchanged.
+    pub fn open_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        self.clear_document();
+        self.base_dir = parent_dir(path);
+        self.file_path = Some(path.to_path_buf());
+        self.replace_source(content.into());
+        Ok(())
+    }
+
+    /// Opens a file dropped onto the widget, unsupported files and errors
+    /// are shown as a message for a moment.
+    fn open_dropped_file(&mut self, ctx: &mut EventCtx, path: &Path) {
+        let message = if !is_markdown_file(path) {
+            format!(
+                "Can't open {}, only Markdown and text files",
+                file_name(path)
+            )
+        } else {
+            match self.open_file(path) {
+                Ok(()) => {
+                    ctx.submit_action(Action::Other(Box::new(
+                        MarkdownAction::FileOpened {
+                            path: path.to_path_buf(),
+                        },
+                    )));
+                    self.drop_message = None;
+                    ctx.request_anim_frame();
+                    ctx.request_layout();
+                    return;
+                }
+                Err(e) => format!("Can't open {}: {e}", file_name(path)),
+            }
+        };
+        warn!("{message}");
+        self.drop_message = Some((message, Instant::now()));
+        ctx.request_anim_frame();
+        ctx.request_layout();
+    }
+
+    fn draw_drop_message(&self, scene: &mut Scene, viewport: Size, theme: &Theme) {
+        if self.drop_message.is_none() {
+            return;
+        }
+        let layout = &self.drop_message_layout;
+        let width = (layout.width() + 2.0 * DROP_MESSAGE_PADDING) as f64;
+        let height = (layout.height() + DROP_MESSAGE_PADDING) as f64;
+        let x0 = ((viewport.width - width) / 2.0).max(0.0);
+        let y0 = (viewport.height - height - DROP_MESSAGE_PADDING as f64).max(0.0);
+        let rect = Rect::new(x0, y0, x0 + width, y0 + height);
+        scene.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            theme.text_color.multiply_alpha(0.2),
+            None,
+            &RoundedRect::from_rect(rect, DROP_MESSAGE_PADDING as f64),
+        );
+        draw_text(
+            scene,
+            layout,
+            Vec2::new(
+                x0 + DROP_MESSAGE_PADDING as f64,
+                y0 + DROP_MESSAGE_PADDING as f64 / 2.0,
+            ),
+            &Rect::new(0.0, 0.0, 0.0, f64::MAX),
+            theme,
+        );
+    }
+
+    /// Shows `content` instead of a file, relative image paths are resolved
+    /// against `base_dir`. Passing an `Arc<str>` avoids copying the content.
+    pub fn from_source(
+        content: impl Into<Arc<str>>,
+        base_dir: impl Into<PathBuf>,
+    ) -> Self {
+        let content = content.into();
+        let source_hasher = source_hasher(&content);
+        let source_ends_with_cr = content.ends_with('\r');
+        let source = normalized_source(content);
+        let markdown_layout = parse_markdown(&source);
+        Self {
+            markdown_layout,
+            base_dir: base_dir.into(),
+            dirty: true,
+            dirty_items: BTreeSet::new(),
+            layout_ctx: LayoutContext::new(),
+            max_advance: 0.0,
+            viewport_height: 0.0,
+            scroll: Vec2::new(0.0, 0.0),
+            selection_color: get_theme().selection_color,
+            scale_factor: 1.0,
+            source: source.into(),
+            source_hasher,
+            source_ends_with_cr,
+            source_blocks: None,
+            stream_tail: StreamTail::default(),
+            parse_count: 1,
+            paint_call_count: 0,
+            parse_options: ParseOptions::default(),
+            view_source_mode: false,
+            slug_generator: slugify,
+            all_selected: false,
+            selection: None,
+            context_menu: None,
+            pressed_at: None,
+            context_menu_labels: Vec::new(),
+            collapsed_sections: BTreeSet::new(),
+            hovered_code_block: None,
+            copy_feedback_timer: None,
+            copy_label_layout: Layout::new(),
+            copied_label_layout: Layout::new(),
+            paginated: false,
+            page_starts: vec![0.0],
+            current_page: 0,
+            alpha_list_marker: None,
+            list_style: None,
+            shrink_to_fit: false,
+            min_render_width: 0.0,
+            max_render_width: f32::INFINITY,
+            show_progress_bar: false,
+            reported_progress: None,
+            item_node_ids: HashMap::new(),
+            item_nodes: Vec::new(),
+            navigation_node_id: NodeId::from(WidgetId::next()),
+            toc_link_ids: Vec::new(),
+            toc_link_targets: HashMap::new(),
+            pending_scroll_item: None,
+            pending_scroll_anchor: None,
+            pending_scroll_percentage: None,
+            pending_scroll_state: None,
+            reported_heading: None,
+            observers: Vec::new(),
+            enlarge_images_on_click: false,
+            link_policy: LinkPolicy::default(),
+            link_navigation: false,
+            scroll_propagation: ScrollPropagation::default(),
+            file_path: None,
+            back_history: Vec::new(),
+            forward_history: Vec::new(),
+            internal_anchor_links: true,
+            link_click_handler: None,
+            observed_scroll_y: 0.0,
+            heading_permalinks: false,
+            copy_permalinks: false,
+            hovered_heading: None,
+            hovered_link_url: None,
+            reported_selection_chars: 0,
+            selection_generation: 0,
+            reported_selection_generation: 0,
+            reported_hovered_link: None,
+            font_ctx: None,
+            left_gutter: 0.0,
+            fence_handlers: FenceHandlers::new(),
+            content_renderer: Arc::new(DefaultRenderer),
+            images: ImageCache::default(),
+            drop_message: None,
+            drop_message_layout: Layout::new(),
+            padding: Padding::default(),
+            debug_overlay: false,
+            debug_labels: Vec::new(),
+            layout_profiling: false,
+            code_color_scheme: None,
+            theme_mode: None,
+            syntax_highlighter: None,
+        }
+    }
+
+    /// Replaces the document with `content`. It is parsed again only when it
+    /// differs from the current content.
+    pub fn set_source(this: &mut WidgetMut<'_, Self>, content: impl Into<Arc<str>>) {
+        if this.widget.replace_source(content.into()) {
+            // Polls for the images of the new document.
+            this.ctx.request_anim_frame();
+            this.ctx.request_layout();
+            Self::submit_hovered_link_change(this);
+        }
+    }
+
+    /// Appends `chunk` to the document, for text which arrives in pieces.
+    /// Only the blocks which the new text can change are parsed and laid out
+    /// again, usually just the last one.
+    pub fn append_markdown(this: &mut WidgetMut<'_, Self>, chunk: &str) {
+        if this.widget.append_source(chunk) {
+            this.ctx.request_anim_frame();
+            this.ctx.request_layout();
+            Self::submit_hovered_link_change(this);
+        }
+    }
+
+    /// Like `append_markdown`, for a widget which is not in a widget tree
+    /// yet. Returns `true` when something was appended.
+    pub fn append_source(&mut self, chunk: &str) -> bool {
+        if chunk.is_empty() {
+            return false;
+        }
+        self.source_hasher.write(chunk.as_bytes());
+        let mut chunk = chunk;
+        // The `\r` before was already turned into a line break.
+        if std::mem::replace(&mut self.source_ends_with_cr, chunk.ends_with('\r')) {
+            chunk = chunk.strip_prefix('\n').unwrap_or(chunk);
+        }
+        if self.source.is_empty() {
+            chunk = chunk.strip_prefix('\u{feff}').unwrap_or(chunk);
+        }
+        if chunk.is_empty() {
+            return false;
+        }
+        self.source.push_str(&normalize_line_endings(chunk));
+        if self.view_source_mode {
+            // A single block anyway.
+            self.parse_source();
+        } else if may_define_references(&self.source[self.stream_tail.start..]) {
+            self.parse_stream();
+        } else {
+            self.parse_tail();
+        }
+        true
+    }
+
+    /// Parses the whole source with the definitions of all its link
+    /// references, which apply to the links before them as well. The stable
+    /// blocks become the parsed part of the stream again.
+    fn parse_stream(&mut self) {
+        let mut definitions = ReferenceDefinitions::new();
+        collect_reference_definitions(&self.source, &mut definitions);
+        self.stream_tail = StreamTail {
+            definitions,
+            ..StreamTail::default()
+        };
+        self.parse_tail();
+    }
+
+    /// Parses the source from the start of the stream tail and replaces the
+    /// items parsed from it before.
+    fn parse_tail(&mut self) {
+        self.parse_count += 1;
+        let tail_start = self.stream_tail.start;
+        let tail = &self.source[tail_start..];
+        let stable_len = stable_prefix_len(tail);
+        let mut stable = parse_markdown_after(
+            &tail[..stable_len],
+            self.parse_options,
+            self.slug_generator,
+            &mut self.stream_tail.anchors,
+            &self.stream_tail.definitions,
+        );
+        let mut open = parse_markdown_after(
+            &tail[stable_len..],
+            self.parse_options,
+            self.slug_generator,
+            &mut self.stream_tail.anchors.clone(),
+            &self.stream_tail.definitions,
+        );
+        collect_reference_definitions(
+            &tail[..stable_len],
+            &mut self.stream_tail.definitions,
+        );
+        for flow in [&mut stable, &mut open] {
+            self.style_parsed_flow(flow);
+        }
+
+        let first_replaced = self.stream_tail.items;
+        self.stream_tail.start = tail_start + stable_len;
+        self.stream_tail.items += stable.len();
+        self.markdown_layout.truncate(first_replaced);
+        self.markdown_layout.append(stable);
+        self.markdown_layout.append(open);
+        self.dirty_items
+            .extend(first_replaced..self.markdown_layout.len());
+
+        // State of the replaced items is dropped.
+        if self.all_selected {
+            // The selected document grew.
+            self.selection_generation += 1;
+        } else if self
+            .selection
+            .is_some_and(|(_, end)| end.item_index >= first_replaced)
+        {
+            self.set_selection(None, false);
+        }
+        let len_before = self.collapsed_sections.len();
+        self.collapsed_sections
+            .retain(|&index| index < first_replaced);
+        if !self.collapsed_sections.is_empty()
+            || self.collapsed_sections.len() != len_before
+        {
+            self.update_hidden_items();
+        }
+        for hovered in [&mut self.hovered_code_block, &mut self.hovered_heading] {
+            if hovered.is_some_and(|index| index >= first_replaced) {
+                *hovered = None;
+            }
+        }
+    }
+
+    /// Applies the fence handlers, the list styles and the syntax
+    /// highlighting of the widget to newly parsed content.
+    fn style_parsed_flow(&self, flow: &mut LayoutFlow<MarkdownContent>) {
+        apply_fence_handlers(flow, &self.fence_handlers);
+        let style = self.list_style.as_deref();
+        for_each_list_marker(flow, &mut |marker| {
+            marker.set_style(style);
+            if style.is_none() {
+                marker.set_alpha(self.alpha_list_marker);
+            }
+        });
+        highlight_code_blocks(flow, self.syntax_highlighter.as_ref());
+    }
+
+    /// Returns `true` when the content changed and was parsed.
+    fn replace_source(&mut self, content: Arc<str>) -> bool {
+        if matches!(&self.source, SourceText::Shared(source) if Arc::ptr_eq(source, &content))
+        {
+            return false;
+        }
+        let hasher = source_hasher(&content);
+        if hasher.finish() == self.source_hasher.finish() {
+            return false;
+        }
+        self.source_hasher = hasher;
+        self.source_ends_with_cr = content.ends_with('\r');
+        self.source = normalized_source(content).into();
+        self.parse_source();
+        true
+    }
+
+    /// The document with its current state, like collapsed sections and
+    /// parse options, for exporting it.
+    pub fn document(&self) -> MarkdownDocument {
+        MarkdownDocument {
+            flow: self.markdown_layout.clone(),
+            base_dir: self.base_dir.clone(),
+        }
+    }
+
+    /// How many times the content was parsed.
+    pub fn parse_count(&self) -> usize {
+        self.parse_count
+    }
+
+    /// How many times the widget was painted.
+    pub fn paint_call_count(&self) -> u64 {
+        self.paint_call_count
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn MarkdownObserver>) {
+        self.observers.push(observer);
+    }
+
+    fn notify_scroll_changed(&mut self) {
+        if self.scroll.y != self.observed_scroll_y {
+            self.observed_scroll_y = self.scroll.y;
+            for observer in self.observers.iter_mut() {
+                observer.on_scroll_changed(self.scroll.y);
+            }
+        }
+    }
+
+    /// Scrolls to the top-level item at `item_index` after the first layout.
+    pub fn with_scroll_to_item(mut self, item_index: usize) -> Self {
+        self.pending_scroll_item = Some(item_index);
+        self
+    }
+
+    /// Shows a link glyph left of the hovered heading, clicking it submits
+    /// `MarkdownAction::HeadingLinkClicked` and with `copy_to_clipboard`
+    /// copies `#anchor` to the clipboard. A gutter is reserved for the glyph.
+    pub fn with_heading_permalinks(
+        mut self,
+        show: bool,
+        copy_to_clipboard: bool,
+    ) -> Self {
+        self.heading_permalinks = show;
+        self.copy_permalinks = copy_to_clipboard;
+        self.dirty = true;
+        self
+    }
+
+    pub fn set_heading_permalinks(
+        this: &mut WidgetMut<'_, Self>,
+        show: bool,
+        copy_to_clipboard: bool,
+    ) {
+        this.widget.copy_permalinks = copy_to_clipboard;
+        if this.widget.heading_permalinks != show {
+            this.widget.heading_permalinks = show;
+            this.widget.hovered_heading = None;
+            this.widget.dirty = true;
+            this.ctx.request_layout();
+        }
+    }
+
+    /// Returns the top-level heading whose row, including the gutter, is at
+    /// `position`.
+    fn heading_at(&self, position: Point) -> Option<usize> {
+        let offset = self.document_position(position).y as f32;
+        let (index, _) = self.markdown_layout.get_index_at_offset(offset)?;
+        let rect = self.item_rect(index)?;
+        match self.markdown_layout.get(index)?.data {
+            MarkdownContent::Header { .. } if position.x <= rect.x1 => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Rectangle of the link glyph of the hovered heading, centered on its
+    /// first line in the gutter.
+    fn permalink_rect(&self) -> Option<Rect> {
+        let index = self.hovered_heading?;
+        let rect = self.item_rect(index)?;
+        let MarkdownContent::Header { text_layout, .. } =
+            &self.markdown_layout.get(index)?.data
+        else {
+            return None;
+        };
+        let line_height = text_layout
+            .get(0)
+            .map_or(rect.height(), |line| line.metrics().line_height as f64);
+        let size = self.left_gutter * 0.6;
+        let x0 = rect.x0 - self.left_gutter + (self.left_gutter - size) / 2.0;
+        let y0 = rect.y0 + (line_height - size) / 2.0;
+        Some(Rect::new(x0, y0, x0 + size, y0 + size))
+    }
+
+    /// Submits `MarkdownAction::HeadingLinkClicked` for the hovered heading.
+    fn click_permalink(&mut self, ctx: &mut EventCtx) {
+        let Some(MarkdownContent::Header {
+            anchor_id: Some(anchor),
+            ..
+        }) = self
+            .hovered_heading
+            .and_then(|index| self.markdown_layout.get(index))
+            .map(|element| &element.data)
+        else {
+            return;
+        };
+        if self.copy_permalinks {
+            set_clipboard_text(format!("#{anchor}"));
+        }
+        ctx.submit_action(Action::Other(Box::new(
+            MarkdownAction::HeadingLinkClicked {
+                anchor: anchor.clone(),
+            },
+        )));
+    }
+
+    fn draw_permalink(&self, scene: &mut Scene, theme: &Theme) {
+        if let Some(rect) = self.permalink_rect() {
+            draw_link_glyph(scene, rect, theme.text_color.multiply_alpha(0.6));
+        }
+    }
+
+    /// Generates the heading anchor ids with `generator` instead of
+    /// `slugify`.
+    pub fn with_slug_generator(mut self, generator: SlugGenerator) -> Self {
+        self.slug_generator = generator;
+        assign_anchor_ids(&mut self.markdown_layout, generator, &mut HashMap::new());
+        self
+    }
+
+    pub fn set_slug_generator(
+        this: &mut WidgetMut<'_, Self>,
+        generator: SlugGenerator,
+    ) {
+        this.widget.slug_generator = generator;
+        assign_anchor_ids(
+            &mut this.widget.markdown_layout,
+            generator,
+            &mut HashMap::new(),
+        );
+    }
+
+    /// Parses `[[Page Name]]` wikilinks, clicking them submits
+    /// `MarkdownAction::LinkClicked` with `LinkKind::Wiki`.
+    pub fn with_wikilinks(mut self, wikilinks: bool) -> Self {
+        self.set_parse_options(ParseOptions {
+            wikilinks,
+            ..self.parse_options
+        });
+        self
+    }
+
+    pub fn set_wikilinks(this: &mut WidgetMut<'_, Self>, wikilinks: bool) {
+        let options = ParseOptions {
+            wikilinks,
+            ..this.widget.parse_options
+        };
+        this.widget.set_parse_options(options);
+        this.ctx.request_layout();
+        Self::submit_hovered_link_change(this);
+    }
+
+    /// Keeps every line break of the source instead of joining the lines of
+    /// a paragraph, see `ParseOptions::soft_breaks_as_hard`.
+    pub fn with_soft_breaks_as_hard(mut self, hard: bool) -> Self {
+        self.set_parse_options(ParseOptions {
+            soft_breaks_as_hard: hard,
+            ..self.parse_options
+        });
+        self
+    }
+
+    pub fn set_soft_breaks_as_hard(this: &mut WidgetMut<'_, Self>, hard: bool) {
+        let options = ParseOptions {
+            soft_breaks_as_hard: hard,
+            ..this.widget.parse_options
+        };
+        this.widget.set_parse_options(options);
+        this.ctx.request_layout();
+        Self::submit_hovered_link_change(this);
+    }
+
+    /// Shows the raw markdown in a monospace code block instead of the
+    /// rendered document, for edit / preview toggles.
+    pub fn with_view_source(mut self, view_source: bool) -> Self {
+        self.set_view_source_mode(view_source);
+        self
+    }
+
+    pub fn set_view_source(this: &mut WidgetMut<'_, Self>, view_source: bool) {
+        this.widget.set_view_source_mode(view_source);
+        this.ctx.request_layout();
+        Self::submit_hovered_link_change(this);
+    }
+
+    fn set_view_source_mode(&mut self, view_source: bool) {
+        if self.view_source_mode == view_source {
+            return;
+        }
+        self.view_source_mode = view_source;
+        self.scroll = Vec2::ZERO;
+        self.parse_source();
+    }
+
+    /// Parses the source again when `options` differ from the current ones.
+    /// The state tied to item indices, like collapsed sections, is reset.
+    fn set_parse_options(&mut self, options: ParseOptions) {
+        if self.parse_options == options {
+            return;
+        }
+        self.parse_options = options;
+        self.parse_source();
+    }
+
+    /// Parses the source again, the state tied to item indices is reset.
+    fn parse_source(&mut self) {
+        self.parse_count += 1;
+        self.stream_tail = StreamTail::default();
+        self.markdown_layout = if self.view_source_mode {
+            source_flow(&self.source)
+        } else {
+            parse_markdown_with(&self.source, self.parse_options)
+        };
+        apply_fence_handlers(&mut self.markdown_layout, &self.fence_handlers);
+        assign_anchor_ids(
+            &mut self.markdown_layout,
+            self.slug_generator,
+            &mut HashMap::new(),
+        );
+        self.apply_alpha_list_marker(self.alpha_list_marker);
+        self.apply_list_style(self.list_style.clone());
+        self.apply_syntax_highlighter();
+        self.set_selection(None, self.all_selected);
+        self.collapsed_sections.clear();
+        self.hovered_code_block = None;
+        self.hovered_heading = None;
+        self.set_hovered_link(None);
+        self.context_menu = None;
+        self.dirty = true;
+    }
+
+    /// Clicking an image toggles it between fit-to-width and its natural
+    /// size, `MarkdownAction::ImageClicked` is submitted either way.
+    pub fn with_enlarge_images_on_click(mut self, enlarge: bool) -> Self {
+        self.enlarge_images_on_click = enlarge;
+        self
+    }
+
+    pub fn set_enlarge_images_on_click(
+        this: &mut WidgetMut<'_, Self>,
+        enlarge: bool,
+    ) {
+        this.widget.enlarge_images_on_click = enlarge;
+    }
+
+    /// Opens relative links to markdown files in this widget instead of
+    /// submitting `MarkdownAction::LinkClicked`. The documents left are kept
+    /// in a history, mouse buttons 4 and 5 and Alt+Left and Alt+Right go
+    /// back and forward.
+    pub fn with_link_navigation(mut self, navigation: bool) -> Self {
+        self.link_navigation = navigation;
+        self
+    }
+
+    pub fn set_link_navigation(this: &mut WidgetMut<'_, Self>, navigation: bool) {
+        this.widget.link_navigation = navigation;
+    }
+
+    pub fn can_go_back(&self) -> bool {
+        !self.back_history.is_empty()
+    }
+
+    pub fn can_go_forward(&self) -> bool {
+        !self.forward_history.is_empty()
+    }
+
+    /// Shows the previous document of the link navigation history. Returns
+    /// `false` when there is none.
+    pub fn go_back(this: &mut WidgetMut<'_, Self>) -> bool {
+        let moved = this.widget.step_history(true);
+        if moved {
+            this.ctx.request_layout();
+            Self::submit_hovered_link_change(this);
+        }
+        moved
+    }
+
+    /// Shows the next document of the link navigation history. Returns
+    /// `false` when there is none.
+    pub fn go_forward(this: &mut WidgetMut<'_, Self>) -> bool {
+        let moved = this.widget.step_history(false);
+        if moved {
+            this.ctx.request_layout();
+            Self::submit_hovered_link_change(this);
+        }
+        moved
+    }
+
+    fn history_entry(&self) -> HistoryEntry {
+        HistoryEntry {
+            source: self.source.to_shared(),
+            base_dir: self.base_dir.clone(),
+            path: self.file_path.clone(),
+            scroll_percentage: self.scroll_percentage(),
+        }
+    }
+
+    /// Moves one document back or forward in the history.
+    fn step_history(&mut self, back: bool) -> bool {
+        let entry = if back {
+            self.back_history.pop()
+        } else {
+            self.forward_history.pop()
+        };
+        let Some(entry) = entry else {
+            return false;
+        };
+        let current = self.history_entry();
+        if back {
+            self.forward_history.push(current);
+        } else {
+            self.back_history.push(current);
+        }
+        self.clear_document();
+        self.base_dir = entry.base_dir;
+        self.file_path = entry.path;
+        self.replace_source(entry.source);
+        self.pending_scroll_percentage = Some(entry.scroll_percentage);
+        true
+    }
+
+    fn navigate_history(&mut self, ctx: &mut EventCtx, back: bool) {
+        if self.step_history(back) {
+            ctx.submit_action(Action::Other(Box::new(MarkdownAction::Navigated {
+                path: self.file_path.clone(),
+            })));
+            ctx.request_anim_frame();
+            ctx.request_layout();
+        }
+    }
+
+    /// Opens the markdown file of the relative link `url`, resolved against
+    /// `base_dir`, and scrolls to its anchor. Returns `None` when the link
+    /// doesn't point to a markdown file, on errors the document is kept.
+    fn follow_link(&mut self, url: &str) -> Option<std::io::Result<PathBuf>> {
+        let (url, anchor) = match url.split_once('#') {
+            Some((url, anchor)) => (url, Some(anchor)),
+            None => (url, None),
+        };
+        let file = percent_decode(url.split('?').next().unwrap_or_default());
+        if file.is_empty() || !is_markdown_file(Path::new(&file)) {
+            return None;
+        }
+        let path = self.base_dir.join(&file);
+        let previous = self.history_entry();
+        if let Err(e) = self.open_file(&path) {
+            return Some(Err(e));
+        }
+        self.back_history.push(previous);
+        self.forward_history.clear();
+        self.pending_scroll_anchor = anchor.map(percent_decode);
+        Some(Ok(path))
+    }
+
+    /// Decides which clicked links are activated, see `LinkPolicy`.
+    pub fn with_link_policy(mut self, policy: LinkPolicy) -> Self {
+        self.link_policy = policy;
+        self
+    }
+
+    pub fn set_link_policy(this: &mut WidgetMut<'_, Self>, policy: LinkPolicy) {
+        this.widget.link_policy = policy;
+    }
+
+    /// Scrolls to the heading of clicked `#anchor` links, which is the
+    /// default. When disabled they are submitted like the other links.
+    pub fn with_internal_anchor_links(mut self, internal: bool) -> Self {
+        self.internal_anchor_links = internal;
+        self
+    }
+
+    pub fn set_internal_anchor_links(
+        this: &mut WidgetMut<'_, Self>,
+        internal: bool,
+    ) {
+        this.widget.internal_anchor_links = internal;
+    }
+
+    /// Lets `handler` decide about every clicked link first, `#anchor` links
+    /// included. It gets the link after the `LinkPolicy` accepted it.
+    pub fn with_link_click_handler(mut self, handler: LinkClickHandler) -> Self {
+        self.link_click_handler = Some(handler);
+        self
+    }
+
+    pub fn set_link_click_handler(
+        this: &mut WidgetMut<'_, Self>,
+        handler: Option<LinkClickHandler>,
+    ) {
+        this.widget.link_click_handler = handler;
+    }
+
+    /// Resolves the relative `url` against the directory of the document, so
+    /// other applications can open it. URLs with a scheme are kept.
+    fn external_url(&self, url: &str) -> String {
+        if uri_scheme(url).is_some() {
+            return url.to_string();
+        }
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+        if path.is_empty() {
+            // An anchor of the document itself.
+            return match &self.file_path {
+                Some(file_path) => file_path.display().to_string(),
+                None => url.to_string(),
+            };
+        }
+        self.base_dir
+            .join(percent_decode(path))
+            .display()
+            .to_string()
+    }
+
+    /// Scrolls the top-level item holding the heading with the `anchor` id
+    /// to the top of the viewport. Unknown anchors are logged and ignored.
+    /// Returns `true` when the scroll changed.
+    fn scroll_to_anchor(&mut self, anchor: &str) -> bool {
+        let Some(item_index) = anchor_item_index(&self.markdown_layout, anchor)
+        else {
+            warn!("No heading with the anchor #{anchor}");
+            return false;
+        };
+        if self.dirty {
+            self.pending_scroll_anchor = Some(anchor.to_string());
+            return false;
+        }
+        self.scroll_item_to_top(item_index)
+    }
+
+    /// Remembers the URL of the hovered link, returns `true` when it
+    /// changed.
+    fn set_hovered_link(&mut self, link: Option<LinkTarget>) -> bool {
+        let url = link.map(|link| link.url);
+        if url == self.hovered_link_url {
+            return false;
+        }
+        self.hovered_link_url = url;
+        for observer in self.observers.iter_mut() {
+            observer.on_hovered_link_changed(self.hovered_link_url.as_deref());
+        }
+        true
+    }
+
+    /// Returns the hovered link URL when it differs from the one reported
+    /// last, also when a new document dropped it.
+    fn take_hovered_link_change(&mut self) -> Option<Option<String>> {
+        if self.hovered_link_url == self.reported_hovered_link {
+            return None;
+        }
+        self.reported_hovered_link = self.hovered_link_url.clone();
+        Some(self.hovered_link_url.clone())
+    }
+
+    fn report_hovered_link(&mut self, ctx: &mut EventCtx, link: Option<LinkTarget>) {
+        self.set_hovered_link(link);
+        self.report_hovered_link_change(ctx);
+    }
+
+    fn report_hovered_link_change(&mut self, ctx: &mut EventCtx) {
+        if let Some(url) = self.take_hovered_link_change() {
+            ctx.submit_action(Action::Other(Box::new(
+                MarkdownAction::HoveredLinkChanged(url),
+            )));
+        }
+    }
+
+    /// Like `report_hovered_link`, after a mutation which may have dropped
+    /// the hovered link with the old document.
+    fn submit_hovered_link_change(this: &mut WidgetMut<'_, Self>) {
+        if let Some(url) = this.widget.take_hovered_link_change() {
+            this.ctx.submit_action(Action::Other(Box::new(
+                MarkdownAction::HoveredLinkChanged(url),
+            )));
+        }
+    }
+
+    /// The URL activated for `link`, `None` when the `LinkPolicy` doesn't
+    /// allow it.
+    fn activated_url(&self, link: &LinkTarget) -> Option<String> {
+        let url = match link.kind {
+            LinkKind::Url => self.link_policy.sanitize(&link.url),
+            LinkKind::Wiki => self.link_policy.sanitize_page(&link.url),
+        };
+        if url.is_none() {
+            warn!(
+                "Not opening the link {:?}, its scheme is not allowed",
+                link.url
+            );
+        }
+        url
+    }
+
+    fn click_link(&mut self, ctx: &mut EventCtx, mut link: LinkTarget) {
+        if let Some(handler) = &self.link_click_handler {
+            let Some(url) = self.activated_url(&link) else {
+                return;
+            };
+            match handler(&url, link.kind) {
+                LinkDecision::Handled | LinkDecision::Ignore => return,
+                LinkDecision::OpenExternally => {
+                    open_externally(&self.external_url(&url));
+                    return;
+                }
+                LinkDecision::Default => {}
+            }
+        }
+        if self.internal_anchor_links && link.kind == LinkKind::Url {
+            if let Some(anchor) = link.url.strip_prefix('#') {
+                if self.scroll_to_anchor(&percent_decode(anchor)) {
+                    self.notify_scroll_changed();
+                    self.report_progress(ctx);
+                    self.report_visible_heading(ctx);
+                    ctx.request_paint_only();
+                }
+                return;
+            }
+        }
+        if self.link_navigation
+            && link.kind == LinkKind::Url
+            && link.scheme == LinkScheme::Relative
+        {
+            match self.follow_link(&link.url) {
+                Some(Ok(path)) => {
+                    ctx.submit_action(Action::Other(Box::new(
+                        MarkdownAction::Navigated { path: Some(path) },
+                    )));
+                    ctx.request_anim_frame();
+                    ctx.request_layout();
+                    return;
+                }
+                Some(Err(e)) => {
+                    let message = format!("Can't open {}: {e}", link.display_url());
+                    warn!("{message}");
+                    self.drop_message = Some((message, Instant::now()));
+                    ctx.request_anim_frame();
+                    ctx.request_layout();
+                    return;
+                }
+                None => {}
+            }
+        }
+        let Some(url) = self.activated_url(&link) else {
+            return;
+        };
+        link.url = url;
+        for observer in self.observers.iter_mut() {
+            observer.on_link_clicked(&link.url);
+            observer.on_link_target_clicked(&link);
+        }
+        ctx.submit_action(Action::Other(Box::new(MarkdownAction::LinkClicked {
+            url: link.url,
+            kind: link.kind,
+        })));
+    }
+
+    /// Adds the `Role::Navigation` landmark with a link to every visible
+    /// top-level heading.
+    fn navigation_accessibility(
+        &mut self,
+        ctx: &mut masonry::AccessCtx,
+        node: &mut accesskit::Node,
+    ) {
+        self.toc_link_targets.clear();
+        let mut navigation = accesskit::Node::new(Role::Navigation);
+        navigation.set_label("Table of contents");
+        let headings = flow_toc(&self.markdown_layout).into_iter().filter(|entry| {
+            self.markdown_layout
+                .get(entry.item_index)
+                .is_some_and(|element| !element.hidden)
+        });
+        for (index, entry) in headings.enumerate() {
+            if index == self.toc_link_ids.len() {
+                self.toc_link_ids.push(NodeId::from(WidgetId::next()));
+            }
+            let id = self.toc_link_ids[index];
+            let mut link = accesskit::Node::new(Role::Link);
+            link.set_label(entry.text);
+            link.set_in_page_link_target(self.item_nodes[entry.item_index]);
+            link.add_action(accesskit::Action::Click);
+            ctx.tree_update.nodes.push((id, link));
+            navigation.push_child(id);
+            self.toc_link_targets.insert(id, entry.item_index);
+        }
+        ctx.tree_update
+            .nodes
+            .push((self.navigation_node_id, navigation));
+        node.push_child(self.navigation_node_id);
+    }
+
+    /// Submits `MarkdownAction::ImageClicked` and with
+    /// `enlarge_images_on_click` toggles the image size. The point under the
+    /// pointer stays in place.
+    fn click_image(&mut self, ctx: &mut EventCtx, hit: &HitInfo) {
+        let Some(MarkdownContent::Image { uri, alt_text, .. }) =
+            content_at_path(&self.markdown_layout, &hit.path)
+        else {
+            return;
+        };
+        ctx.submit_action(Action::Other(Box::new(MarkdownAction::ImageClicked {
+            uri: uri.clone(),
+            path: resolve_image_uri(uri, &self.base_dir),
+            alt_text: alt_text.clone(),
+        })));
+        if !self.enlarge_images_on_click {
+            return;
+        }
+        let old_height = content_at_path(&self.markdown_layout, &hit.path)
+            .and_then(MarkdownContent::image_display_size)
+            .map_or(0.0, |size| size.height);
+        if !toggle_image_size_at_path(&mut self.markdown_layout, &hit.path) {
+            return;
+        }
+        let new_height = content_at_path(&self.markdown_layout, &hit.path)
+            .and_then(MarkdownContent::image_display_size)
+            .map_or(0.0, |size| size.height);
+        if old_height > 0.0 {
+            let anchor_y = hit.local_position.y;
+            let max_scroll = (self.scroll_height() - self.viewport_height).max(0.0);
+            self.scroll.y = (self.scroll.y
+                + anchor_y * (new_height / old_height - 1.0))
+                .clamp(0.0, max_scroll);
+        }
+        self.update_pages();
+        ctx.request_layout();
+    }
+
+    /// Paints a thin reading progress bar at the top edge.
+    pub fn with_progress_bar(mut self, show: bool) -> Self {
+        self.show_progress_bar = show;
+        self
+    }
+
+    pub fn set_progress_bar(this: &mut WidgetMut<'_, Self>, show: bool) {
+        this.widget.show_progress_bar = show;
+        this.ctx.request_paint_only();
+    }
+
+    /// Returns the reading progress from 0.0 to 1.0 of the scrollable range.
+    pub fn progress(&self) -> f64 {
+        reading_progress(self.scroll.y, self.scroll_height(), self.viewport_height)
+    }
+
+    /// Returns the scroll position from 0.0 at the top to 1.0 at the bottom
+    /// of the scrollable range. Unlike `progress`, a document which fits into
+    /// the viewport is at 0.0.
+    pub fn scroll_percentage(&self) -> f64 {
+        let scrollable = self.scroll_height() - self.viewport_height;
+        if scrollable <= 0.0 {
+            return 0.0;
+        }
+        reading_progress(self.scroll.y, self.scroll_height(), self.viewport_height)
+    }
+
+    /// Scrolls to `percentage` of the scrollable range, for example to
+    /// restore a bookmark. Before the first layout it is applied once the
+    /// size of the document is known.
+    pub fn set_scroll_percentage(this: &mut WidgetMut<'_, Self>, percentage: f64) {
+        if this.widget.dirty {
+            this.widget.pending_scroll_percentage = Some(percentage);
+            this.ctx.request_layout();
+            return;
+        }
+        this.widget.apply_scroll_percentage(percentage);
+        this.widget.notify_scroll_changed();
+        this.ctx.request_paint_only();
+    }
+
+    fn apply_scroll_percentage(&mut self, percentage: f64) {
+        let offset = scroll_for_percentage(
+            percentage,
+            self.scroll_height(),
+            self.viewport_height,
+        );
+        if self.paginated {
+            self.set_current_page(self.page_of_offset(offset as f32));
+        } else {
+            self.scroll.y = offset;
+        }
+    }
+
+    /// Captures the scroll position and the collapsed sections, to restore
+    /// them with `restore_state` later, maybe after the document changed.
+    pub fn capture_state(&self) -> MarkdownViewState {
+        MarkdownViewState {
+            scroll: self.scroll_anchor(),
+            zoom: self.scale_factor,
+            collapsed_sections: self
+                .collapsed_sections
+                .iter()
+                .filter_map(|&index| match &self.markdown_layout.get(index)?.data {
+                    MarkdownContent::Header { anchor_id, .. } => anchor_id.clone(),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Restores a state from `capture_state` as far as it fits the current
+    /// document, with its scale factor. Sections whose headings are gone stay expanded and the
+    /// scroll position falls back to the closest block which still exists.
+    pub fn restore_state(this: &mut WidgetMut<'_, Self>, state: &MarkdownViewState) {
+        this.widget.apply_view_state(state);
+        this.widget.notify_scroll_changed();
+        this.ctx.request_layout();
+    }
+
+    fn apply_view_state(&mut self, state: &MarkdownViewState) {
+        self.apply_scale_factor(state.zoom);
+        self.collapsed_sections.clear();
+        for anchor in &state.collapsed_sections {
+            let heading = self.markdown_layout.iter().position(|element| {
+                matches!(
+                    &element.data,
+                    MarkdownContent::Header { anchor_id, .. }
+                        if anchor_id.as_deref() == Some(anchor.as_str())
+                )
+            });
+            self.collapsed_sections.extend(heading);
+        }
+        self.update_hidden_items();
+        match &state.scroll {
+            // Block positions are not known before the layout.
+            Some(anchor) if self.dirty => {
+                self.pending_scroll_state = Some(anchor.clone())
+            }
+            Some(anchor) => self.apply_scroll_anchor(anchor),
+            None => self.scroll.y = 0.0,
+        }
+    }
+
+    /// Block at the top of the viewport.
+    fn scroll_anchor(&self) -> Option<ScrollAnchor> {
+        if self.markdown_layout.is_empty() {
+            return None;
+        }
+        let y = (self.scroll.y - self.content_origin().y).max(0.0) as f32;
+        let mut path = Vec::new();
+        let fraction = block_path_at(&self.markdown_layout, y, &mut path);
+        Some(ScrollAnchor {
+            fingerprint: block_fingerprint(&self.markdown_layout.get(path[0])?.data),
+            path,
+            fraction,
+        })
+    }
+
+    /// Scrolls `anchor` to the top of the viewport. When the top-level block
+    /// changed, the closest block with the same text is taken instead.
+    fn apply_scroll_anchor(&mut self, anchor: &ScrollAnchor) {
+        let Some(&index) = anchor.path.first() else {
+            return;
+        };
+        if self.markdown_layout.is_empty() {
+            return;
+        }
+        let fingerprint_at = |index: usize| {
+            self.markdown_layout.get(index).is_some_and(|element| {
+                block_fingerprint(&element.data) == anchor.fingerprint
+            })
+        };
+        let mut path = anchor.path.clone();
+        if !fingerprint_at(index) {
+            path[0] = (0..self.markdown_layout.len())
+                .filter(|&i| fingerprint_at(i))
+                .min_by_key(|&i| i.abs_diff(index))
+                .unwrap_or(index.min(self.markdown_layout.len() - 1));
+        }
+        let Some((offset, height, found)) =
+            block_extent(&self.markdown_layout, &path)
+        else {
+            return;
+        };
+        // A block which is gone is replaced by the start of its parent.
+        let fraction = if found {
+            anchor.fraction.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let y = offset + fraction * height;
+        if self.paginated {
+            self.set_current_page(self.page_of_offset(y));
+            return;
+        }
+        let max_scroll = (self.scroll_height() - self.viewport_height).max(0.0);
+        self.scroll.y = (y as f64 + self.content_origin().y).clamp(0.0, max_scroll);
+    }
+
+    /// Submits `MarkdownAction::ProgressChanged` when the progress changed
+    /// noticeably since the last report.
+    fn report_progress(&mut self, ctx: &mut EventCtx) {
+        let progress = self.progress();
+        let changed = self.reported_progress.map_or(true, |reported| {
+            (reported - progress).abs() >= PROGRESS_THRESHOLD
+                || (progress != reported && (progress == 0.0 || progress == 1.0))
+        });
+        if changed {
+            self.reported_progress = Some(progress);
+            ctx.submit_action(Action::Other(Box::new(
+                MarkdownAction::ProgressChanged(progress),
+            )));
+        }
+    }
+
+    /// Gives every top-level item its accessibility node id, items which
+    /// were there before keep theirs, see `item_node_ids`.
+    fn update_item_node_ids(&mut self) {
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        let mut ids = HashMap::with_capacity(self.markdown_layout.len());
+        self.item_nodes.clear();
+        for element in self.markdown_layout.iter() {
+            let mut hasher = DefaultHasher::new();
+            std::mem::discriminant(&element.data).hash(&mut hasher);
+            let mut text = String::new();
+            element.data.write_plain_text(&mut text);
+            hasher.write(text.as_bytes());
+            let hash = hasher.finish();
+            let count = counts.entry(hash).or_insert(0);
+            let key = (hash, *count);
+            *count += 1;
+            let id = self
+                .item_node_ids
+                .get(&key)
+                .copied()
+                .unwrap_or_else(|| NodeId::from(WidgetId::next()));
+            ids.insert(key, id);
+            self.item_nodes.push(id);
+        }
+        self.item_node_ids = ids;
+    }
+
+    /// Performs `action` of assistive technologies on the node `target`:
+    /// scrolling an item into view or activating a link of the navigation
+    /// landmark. Returns whether it scrolled, `None` when the action is not
+    /// for this document.
+    fn perform_access_action(
+        &mut self,
+        target: NodeId,
+        action: accesskit::Action,
+    ) -> Option<bool> {
+        let item_index = match action {
+            accesskit::Action::ScrollIntoView => {
+                // The items may have changed since the last accessibility
+                // pass, the ids follow them.
+                self.update_item_node_ids();
+                self.item_nodes.iter().position(|id| *id == target)?
+            }
+            accesskit::Action::Click => *self.toc_link_targets.get(&target)?,
+            _ => return None,
+        };
+        Some(self.scroll_item_into_view(item_index))
+    }
+
+    /// Part of a viewport `height` tall which shows the document.
+    fn visible_height(&self, height: f64) -> f64 {
+        if self.paginated {
+            // Blocks of the next page must not peek in at the bottom.
+            if let Some(next_start) = self.page_starts.get(self.current_page + 1) {
+                return height.min(
+                    self.padding.top as f64 + *next_start as f64 - self.scroll.y,
+                );
+            }
+        }
+        height
+    }
+
+    /// Returns the range of the top-level items in the viewport, at least
+    /// partially. Collapsed items in the range are not shown.
+    pub fn visible_item_indices(&self) -> Range<usize> {
+        self.markdown_layout.visible_range(
+            (self.scroll.y - self.padding.top as f64) as f32,
+            self.visible_height(self.viewport_height) as f32,
+        )
+    }
+
+    /// Returns the item index of the top-level heading whose section is at
+    /// the top of the viewport, `None` above the first heading.
+    pub fn visible_heading(&self) -> Option<usize> {
+        // Headings scrolled to exactly the top edge count as visible.
+        let top = (self.scroll.y - self.padding.top as f64) as f32 + 0.5;
+        self.markdown_layout
+            .iter()
+            .enumerate()
+            .take_while(|(_, element)| element.offset <= top)
+            .filter(|(_, element)| {
+                !element.hidden
+                    && matches!(element.data, MarkdownContent::Header { .. })
+            })
+            .map(|(index, _)| index)
+            .last()
+    }
+
+    fn report_visible_heading(&mut self, ctx: &mut EventCtx) {
+        let heading = self.visible_heading();
+        if self.reported_heading != Some(heading) {
+            self.reported_heading = Some(heading);
+            ctx.submit_action(Action::Other(Box::new(
+                MarkdownAction::VisibleHeadingChanged(heading),
+            )));
+        }
+    }
+
+    fn draw_progress_bar(&self, scene: &mut Scene, theme: &Theme) {
+        let width = self.max_advance * self.progress();
+        let bar = Rect::new(0.0, 0.0, width, theme.progress_bar_height as f64);
+        scene.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            theme.progress_bar_color,
+            None,
+            &bar,
+        );
+    }
+
+    pub fn with_shrink_to_fit(mut self, shrink_to_fit: bool) -> Self {
+        self.shrink_to_fit = shrink_to_fit;
+        self
+    }
+
+    pub fn set_shrink_to_fit(this: &mut WidgetMut<'_, Self>, shrink_to_fit: bool) {
+        this.widget.shrink_to_fit = shrink_to_fit;
+        this.ctx.request_layout();
+    }
+
+    /// Lays the text out with the fonts of `font_ctx` instead of the ones
+    /// shared by the Masonry widgets, for fonts registered by the
+    /// application, like branding fonts from a bundle.
+    pub fn with_font_ctx(mut self, font_ctx: FontContext) -> Self {
+        self.font_ctx = Some(font_ctx);
+        self
+    }
+
+    /// Like `with_font_ctx`, `None` goes back to the fonts of Masonry. The
+    /// whole document is laid out again.
+    pub fn set_font_ctx(
+        this: &mut WidgetMut<'_, Self>,
+        font_ctx: Option<FontContext>,
+    ) {
+        this.widget.font_ctx = font_ctx;
+        this.widget.dirty = true;
+        this.ctx.request_layout();
+    }
+
+    /// Lets mouse wheel events reach the parents, by default the widget
+    /// consumes them.
+    pub fn with_scroll_propagation(
+        mut self,
+        propagation: ScrollPropagation,
+    ) -> Self {
+        self.scroll_propagation = propagation;
+        self
+    }
+
+    pub fn set_scroll_propagation(
+        this: &mut WidgetMut<'_, Self>,
+        propagation: ScrollPropagation,
+    ) {
+        this.widget.scroll_propagation = propagation;
+    }
+
+    /// Caps the width the content is laid out in, for readable lines in
+    /// wide windows and parents offering an infinite width.
+    pub fn with_max_render_width(mut self, width: f32) -> Self {
+        self.max_render_width = width;
+        self
+    }
+
+    pub fn set_max_render_width(this: &mut WidgetMut<'_, Self>, width: f32) {
+        this.widget.max_render_width = width;
+        this.ctx.request_layout();
+    }
+
+    /// Lays the content out at least this wide, narrower parents clip it.
+    pub fn with_min_render_width(mut self, width: f32) -> Self {
+        self.min_render_width = width;
+        self
+    }
+
+    pub fn set_min_render_width(this: &mut WidgetMut<'_, Self>, width: f32) {
+        this.widget.min_render_width = width;
+        this.ctx.request_layout();
+    }
+
+    /// Renders ordered lists with `a.`, `b.`, ... markers instead of numbers.
+    pub fn with_alpha_list_marker(mut self, uppercase: bool) -> Self {
+        self.apply_alpha_list_marker(Some(uppercase));
+        self
+    }
+
+    pub fn set_alpha_list_marker(
+        this: &mut WidgetMut<'_, Self>,
+        alpha: Option<bool>,
+    ) {
+        this.widget.apply_alpha_list_marker(alpha);
+        this.ctx.request_layout();
+    }
+
+    fn apply_alpha_list_marker(&mut self, alpha: Option<bool>) {
+        self.alpha_list_marker = alpha;
+        for_each_list_marker(&mut self.markdown_layout, &mut |marker| {
+            marker.set_alpha(alpha)
+        });
+        self.dirty = true;
+    }
+
+    /// Lays out and paints the blocks with `renderer`, see `ContentRenderer`.
+    pub fn with_content_renderer(
+        mut self,
+        renderer: Arc<dyn ContentRenderer>,
+    ) -> Self {
+        self.content_renderer = renderer;
+        self.dirty = true;
+        self
+    }
+
+    pub fn set_content_renderer(
+        this: &mut WidgetMut<'_, Self>,
+        renderer: Arc<dyn ContentRenderer>,
+    ) {
+        this.widget.content_renderer = renderer;
+        this.widget.dirty = true;
+        this.ctx.request_layout();
+    }
+
+    /// Shows the fenced code blocks whose info string starts with `language`
+    /// as the content `handler` returns for their raw text, see
+    /// `FenceHandlers::insert`.
+    pub fn with_fence_handler<F>(mut self, language: &str, handler: F) -> Self
+    where
+        F: Fn(&str) -> MarkdownContent + Send + Sync + 'static,
+    {
+        self.fence_handlers.insert(language, handler);
+        self.parse_source();
+        self
+    }
+
+    /// Renders ordered lists with the markers of a CSS `list-style-type`,
+    /// like `upper-roman`, see `ListMarker::from_style`.
+    pub fn with_list_style(mut self, style: &str) -> Self {
+        self.apply_list_style(Some(style.to_string()));
+        self
+    }
+
+    pub fn set_list_style(this: &mut WidgetMut<'_, Self>, style: Option<String>) {
+        this.widget.apply_list_style(style);
+        this.ctx.request_layout();
+    }
+
+    fn apply_list_style(&mut self, style: Option<String>) {
+        for_each_list_marker(&mut self.markdown_layout, &mut |marker| {
+            marker.set_style(style.as_deref())
+        });
+        self.list_style = style;
+        if self.list_style.is_none() {
+            // Letters are kept when only the list style is reset.
+            self.apply_alpha_list_marker(self.alpha_list_marker);
+        }
+        self.dirty = true;
+    }
+
+    /// Highlights the fenced code blocks with `highlighter`, without one the
+    /// code is shown in a single color.
+    pub fn with_syntax_highlighter(
+        mut self,
+        highlighter: Arc<dyn SyntaxHighlighter>,
+    ) -> Self {
+        self.syntax_highlighter = Some(highlighter);
+        self.apply_syntax_highlighter();
+        self
+    }
+
+    /// Changes the highlighter, `None` turns the highlighting off.
+    pub fn set_syntax_highlighter(
+        this: &mut WidgetMut<'_, Self>,
+        highlighter: Option<Arc<dyn SyntaxHighlighter>>,
+    ) {
+        this.widget.syntax_highlighter = highlighter;
+        this.widget.apply_syntax_highlighter();
+        this.ctx.request_layout();
+    }
+
+    fn apply_syntax_highlighter(&mut self) {
+        highlight_code_blocks(
+            &mut self.markdown_layout,
+            self.syntax_highlighter.as_ref(),
+        );
+        self.dirty = true;
+    }
+
+    /// Logs the layout time of every top-level item with `tracing::debug!`,
+    /// to find the parts of a document which are slow to lay out.
+    pub fn with_layout_profiling(mut self, profiling: bool) -> Self {
+        self.layout_profiling = profiling;
+        self
+    }
+
+    pub fn set_layout_profiling(this: &mut WidgetMut<'_, Self>, profiling: bool) {
+        this.widget.layout_profiling = profiling;
+    }
+
+    /// Outlines every top-level block in a color by its kind and labels it,
+    /// for debugging the layout.
+    pub fn with_debug_overlay(mut self, debug_overlay: bool) -> Self {
+        self.debug_overlay = debug_overlay;
+        self
+    }
+
+    pub fn set_debug_overlay(this: &mut WidgetMut<'_, Self>, debug_overlay: bool) {
+        this.widget.debug_overlay = debug_overlay;
+        this.ctx.request_layout();
+    }
+
+    fn draw_debug_overlay(
+        &self,
+        scene: &mut Scene,
+        visible_height: f64,
+        theme: &Theme,
+    ) {
+        let visible_parts = self.markdown_layout.get_visible_parts(
+            (self.scroll.y - self.padding.top as f64) as f32,
+            visible_height as f32,
+        );
+        let stroke = Stroke::new(1.0);
+        for part in visible_parts.iter().filter(|part| !part.hidden) {
+            let kind = part.data.block_kind();
+            let origin = self.content_origin()
+                + Vec2::new(0.0, part.offset as f64 - self.scroll.y);
+            let mut rect = part.data.bounding_rect(origin);
+            if rect.width() <= 0.0 {
+                // Lines don't know their width, they span the whole layout.
+                rect.x1 = self.max_advance;
+            }
+            rect.y1 = rect.y0 + part.height as f64;
+            scene.stroke(
+                &stroke,
+                Affine::IDENTITY,
+                debug_overlay_color(kind),
+                None,
+                &rect.inset(-0.5),
+            );
+            if let Some((_, label)) =
+                self.debug_labels.iter().find(|(k, _)| *k == kind)
+            {
+                draw_text(
+                    scene,
+                    label,
+                    rect.origin().to_vec2(),
+                    &Rect::new(0.0, 0.0, 0.0, f64::MAX),
+                    theme,
+                );
+            }
+        }
+    }
+
+    pub fn with_paginated(mut self, paginated: bool) -> Self {
+        self.paginated = paginated;
+        self
+    }
+
+    pub fn set_paginated(this: &mut WidgetMut<'_, Self>, paginated: bool) {
+        this.widget.paginated = paginated;
+        this.widget.current_page = 0;
+        this.widget.scroll.y = 0.0;
+        this.ctx.request_layout();
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.page_starts.len()
+    }
+
+    pub fn current_page(&self) -> usize {
+        self.current_page
+    }
+
+    /// Shows the page `page`, out of range pages are clamped. Unlike page
+    /// changes done by the user, this does not submit an action.
+    pub fn go_to_page(this: &mut WidgetMut<'_, Self>, page: usize) {
+        if this.widget.set_current_page(page) {
+            this.ctx.request_paint_only();
+        }
+    }
+
+    /// Returns `true` when the page changed.
+    fn set_current_page(&mut self, page: usize) -> bool {
+        let page = page.min(self.page_count() - 1);
+        let changed = page != self.current_page;
+        self.current_page = page;
+        self.scroll.y = self.page_starts[page] as f64;
+        changed
+    }
+
+    /// Returns `true` when the page changed.
+    fn change_page(&mut self, ctx: &mut EventCtx, page: usize) -> bool {
+        if !self.set_current_page(page) {
+            return false;
+        }
+        ctx.submit_action(Action::Other(Box::new(MarkdownAction::PageChanged(
+            self.current_page,
+        ))));
+        ctx.request_paint_only();
+        true
+    }
+
+    /// Returns the page which contains the document `offset`.
+    fn page_of_offset(&self, offset: f32) -> usize {
+        self.page_starts
+            .partition_point(|start| *start <= offset)
+            .saturating_sub(1)
+    }
+
+    /// Height of the document shown on one page, the viewport without the
+    /// vertical padding.
+    fn page_height(&self) -> f64 {
+        (self.viewport_height - self.padding.vertical() as f64).max(1.0)
+    }
+
+    fn update_pages(&mut self) {
+        if !self.paginated {
+            self.page_starts = vec![0.0];
+            self.current_page = 0;
+            return;
+        }
+        self.page_starts =
+            self.markdown_layout.page_starts(self.page_height() as f32);
+        self.set_current_page(self.current_page);
+    }
+
+    pub fn with_selection_color(mut self, color: Color) -> Self {
+        self.selection_color = color;
+        self
+    }
+
+    pub fn set_selection_color(this: &mut WidgetMut<'_, Self>, color: Color) {
+        this.widget.selection_color = color;
+        this.ctx.request_paint();
+    }
+
+    /// Sets the scale factor of the window the widget is shown in. Masonry
+    /// does not pass it to widgets, the application has to forward it from
+    /// `WindowEvent::ScaleFactorChanged`.
+    pub fn with_scale_factor(mut self, scale_factor: f64) -> Self {
+        self.apply_scale_factor(scale_factor);
+        self
+    }
+
+    pub fn set_scale_factor(this: &mut WidgetMut<'_, Self>, scale_factor: f64) {
+        if this.widget.apply_scale_factor(scale_factor) {
+            this.ctx.request_layout();
+        }
+    }
+
+    /// Returns `true` when the scale factor changed and the document has to
+    /// be laid out again.
+    fn apply_scale_factor(&mut self, scale_factor: f64) -> bool {
+        if self.scale_factor == scale_factor {
+            return false;
+        }
+        self.scale_factor = scale_factor;
+        self.dirty = true;
+        true
+    }
+
+    /// Theme adjusted to the theme mode, the scale factor and the code color
+    /// scheme of the widget, `None` when `theme` is used as it is. The theme
+    /// mode replaces `theme` by the built-in one. The scale factor multiplies
+    /// `Theme::scale`. Hinting snaps the glyphs to the logical pixel grid,
+    /// which makes them blurry once the scene is scaled.
+    fn widget_theme(&self, theme: &Theme) -> Option<Theme> {
+        let mode_theme = self.theme_mode.map(Theme::for_mode);
+        let scaled = self.scale_factor != 1.0;
+        if !scaled && self.code_color_scheme.is_none() {
+            return mode_theme;
+        }
+        let theme = mode_theme.as_ref().unwrap_or(theme);
+        Some(Theme {
+            scale: theme.scale * self.scale_factor as f32,
+            text_hinting: theme.text_hinting && !scaled,
+            code_color_scheme: self
+                .code_color_scheme
+                .clone()
+                .unwrap_or_else(|| theme.code_color_scheme.clone()),
+            ..theme.clone()
+        })
+    }
+
+    /// Shows this widget with the built-in light or dark theme instead of
+    /// the global one, for example when the appearance of the system
+    /// changed. The global theme and the other widgets are left alone.
+    ///
+    /// Following the appearance of the system on its own is not
+    /// implemented: masonry doesn't pass winit's `WindowEvent::ThemeChanged`
+    /// on to widgets, so the app has to forward it with `set_theme_mode`.
+    /// `ThemeMode` converts from `winit::window::Theme`.
+    pub fn with_theme_mode(mut self, mode: ThemeMode) -> Self {
+        self.apply_theme_mode(mode);
+        self
+    }
+
+    pub fn set_theme_mode(this: &mut WidgetMut<'_, Self>, mode: ThemeMode) {
+        if this.widget.apply_theme_mode(mode) {
+            this.ctx.request_layout();
+        }
+    }
+
+    /// Returns `true` when the mode changed.
+    fn apply_theme_mode(&mut self, mode: ThemeMode) -> bool {
+        if self.theme_mode == Some(mode) {
+            return false;
+        }
+        self.theme_mode = Some(mode);
+        self.dirty = true;
+        true
+    }
+
+    pub fn theme_mode(&self) -> Option<ThemeMode> {
+        self.theme_mode
+    }
+
+    /// Uses `scheme` for code blocks instead of the scheme of the theme.
+    pub fn with_code_color_scheme(mut self, scheme: CodeColorScheme) -> Self {
+        self.code_color_scheme = Some(scheme);
+        self
+    }
+
+    /// Changes the code color scheme, `None` goes back to the one of the
+    /// theme. Only the items containing code blocks are laid out again.
+    pub fn set_code_color_scheme(
+        this: &mut WidgetMut<'_, Self>,
+        scheme: Option<CodeColorScheme>,
+    ) {
+        let widget = &mut *this.widget;
+        if widget.code_color_scheme == scheme {
+            return;
+        }
+        widget.code_color_scheme = scheme;
+        let code_items = widget
+            .markdown_layout
+            .iter()
+            .enumerate()
+            .filter(|(_, element)| contains_code_block(&element.data))
+            .map(|(index, _)| index);
+        widget.dirty_items.extend(code_items);
+        this.ctx.request_layout();
+    }
+
+    /// Where the top-level flow starts in the widget when not scrolled, after
+    /// the permalink gutter and the padding.
+    fn content_origin(&self) -> Vec2 {
+        Vec2::new(
+            self.left_gutter + self.padding.left as f64,
+            self.padding.top as f64,
+        )
+    }
+
+    /// Part of a widget of `size` the document is painted in. A page starts
+    /// below the top padding, the end of the previous page must not show in
+    /// it.
+    fn document_clip(&self, size: Size) -> Rect {
+        let top = if self.paginated {
+            self.padding.top as f64
+        } else {
+            0.0
+        };
+        Rect::new(0.0, top, size.width, self.visible_height(size.height))
+    }
+
+    /// Height of the scrollable content, the document with its vertical
+    /// padding.
+    fn scroll_height(&self) -> f64 {
+        self.markdown_layout.height() as f64 + self.padding.vertical() as f64
+    }
+
+    /// Returns the bounding rectangle of the top-level item at `index` in
+    /// widget coordinates, so the current scroll is already applied. Returns
+    /// `None` when there is no such item or the document was not laid out.
+    pub fn item_rect(&self, index: usize) -> Option<Rect> {
+        if self.dirty {
+            return None;
+        }
+        let element = self.markdown_layout.get(index)?;
+        if element.hidden {
+            return None;
+        }
+        let origin = self.content_origin();
+        let x0 = origin.x - self.scroll.x;
+        let y0 = origin.y + self.markdown_layout.height_up_to(index) as f64
+            - self.scroll.y;
+        Some(Rect::new(
+            x0,
+            y0,
+            x0 + element.data.width() as f64,
+            y0 + element.height as f64,
+        ))
+    }
+
+    /// Scrolls by the minimal amount so the item at `item_index` is fully
+    /// visible. Items taller than the viewport get aligned to its top.
+    pub fn scroll_into_view(this: &mut WidgetMut<'_, Self>, item_index: usize) {
+        if this.widget.dirty {
+            // Item positions are not known before the layout.
+            this.widget.pending_scroll_item = Some(item_index);
+            this.ctx.request_layout();
+            return;
+        }
+        if this.widget.scroll_item_into_view(item_index) {
+            this.widget.notify_scroll_changed();
+            this.ctx.request_paint_only();
+        }
+    }
+
+    /// Like `scroll_item_into_view`, but aligns the item with the top of the
+    /// viewport as far as the document can be scrolled, like browsers do for
+    /// anchors.
+    fn scroll_item_to_top(&mut self, item_index: usize) -> bool {
+        let changed = self.scroll_item_into_view(item_index);
+        if self.paginated {
+            return changed;
+        }
+        let Some(rect) = self.item_rect(item_index) else {
+            return changed;
+        };
+        let max_scroll = (self.scroll_height() - self.viewport_height).max(0.0);
+        let y = (self.scroll.y + rect.y0).clamp(0.0, max_scroll);
+        if y == self.scroll.y {
+            return changed;
+        }
+        self.scroll.y = y;
+        true
+    }
+
+    /// Returns `true` when the scroll changed.
+    fn scroll_item_into_view(&mut self, item_index: usize) -> bool {
+        let expanded = self.expand_sections_containing(item_index);
+        if self.paginated {
+            if expanded {
+                self.update_pages();
+            }
+            if item_index >= self.markdown_layout.len() {
+                return expanded;
+            }
+            let offset = self.markdown_layout.height_up_to(item_index);
+            return self.set_current_page(self.page_of_offset(offset)) || expanded;
+        }
+        let Some(rect) = self.item_rect(item_index) else {
+            return expanded;
+        };
+        let delta = if rect.y0 < 0.0 || rect.height() > self.viewport_height {
+            rect.y0
+        } else if rect.y1 > self.viewport_height {
+            rect.y1 - self.viewport_height
+        } else {
+            0.0
+        };
+        if delta == 0.0 {
+            return expanded;
+        }
+        let max_scroll = (self.scroll_height() - self.viewport_height).max(0.0);
+        self.scroll.y = (self.scroll.y + delta).clamp(0.0, max_scroll);
+        true
+    }
+
+    /// Collapses or expands the section of the top-level heading at
+    /// `heading_index`. The section ends at the next heading of the same or
+    /// higher level. Sections are tracked by the index of their heading, they
+    /// are forgotten when the heading is replaced.
+    pub fn set_section_collapsed(
+        this: &mut WidgetMut<'_, Self>,
+        heading_index: usize,
+        collapsed: bool,
+    ) {
+        if this.widget.collapse_section(heading_index, collapsed) {
+            this.ctx.request_paint_only();
+        }
+    }
+
+    pub fn is_section_collapsed(&self, heading_index: usize) -> bool {
+        self.collapsed_sections.contains(&heading_index)
+    }
+
+    /// Returns `true` when the state changed.
+    fn collapse_section(&mut self, heading_index: usize, collapsed: bool) -> bool {
+        let is_heading = matches!(
+            self.markdown_layout.get(heading_index).map(|e| &e.data),
+            Some(MarkdownContent::Header { .. })
+        );
+        if !is_heading {
+            return false;
+        }
+        let changed = if collapsed {
+            self.collapsed_sections.insert(heading_index)
+        } else {
+            self.collapsed_sections.remove(&heading_index)
+        };
+        if changed {
+            self.update_hidden_items();
+            // The document may now end above the viewport.
+            if self.paginated {
+                self.update_pages();
+            } else {
+                let max_scroll =
+                    (self.scroll_height() - self.viewport_height).max(0.0);
+                self.scroll.y = self.scroll.y.min(max_scroll);
+            }
+        }
+        changed
+    }
+
+    /// Expands all sections which hide the item at `index`. Returns `true`
+    /// when some section was expanded.
+    fn expand_sections_containing(&mut self, index: usize) -> bool {
+        let mut expanded = false;
+        while self
+            .markdown_layout
+            .get(index)
+            .is_some_and(|element| element.hidden)
+        {
+            let Some(heading_index) = self.section_heading_of(index) else {
+                break;
+            };
+            self.collapsed_sections.remove(&heading_index);
+            self.update_hidden_items();
+            expanded = true;
+        }
+        expanded
+    }
+
+    /// Returns the collapsed heading whose section contains `index`.
+    fn section_heading_of(&self, index: usize) -> Option<usize> {
+        let mut heading = None;
+        for (i, element) in self.markdown_layout.iter().enumerate().take(index) {
+            if let MarkdownContent::Header { level, .. } = &element.data {
+                if heading.is_some_and(|(_, l)| *level as u8 <= l) {
+                    heading = None;
+                }
+                if heading.is_none() && self.collapsed_sections.contains(&i) {
+                    heading = Some((i, *level as u8));
+                }
+            }
+        }
+        heading.map(|(i, _)| i)
+    }
+
+    fn update_hidden_items(&mut self) {
+        let mut collapsed_level: Option<u8> = None;
+        let mut hidden = Vec::with_capacity(self.markdown_layout.len());
+        for (index, element) in self.markdown_layout.iter().enumerate() {
+            let level = match &element.data {
+                MarkdownContent::Header { level, .. } => Some(*level as u8),
+                _ => None,
+            };
+            if let (Some(level), Some(collapsed)) = (level, collapsed_level) {
+                if level <= collapsed {
+                    collapsed_level = None;
+                }
+            }
+            hidden.push(collapsed_level.is_some());
+            if let Some(level) = level {
+                if collapsed_level.is_none()
+                    && self.collapsed_sections.contains(&index)
+                {
+                    collapsed_level = Some(level);
+                }
+            }
+        }
+        self.markdown_layout.set_hidden_all(hidden);
+    }
+
+    /// Rectangle of the collapse chevron of the heading at `index`, it is
+    /// placed right after the heading text.
+    fn section_chevron_rect(&self, index: usize, theme: &Theme) -> Option<Rect> {
+        let rect = self.item_rect(index)?;
+        let size = theme.text_size as f64;
+        let x0 = rect.x1 + size / 2.0;
+        let y0 = rect.center().y - size / 2.0;
+        Some(Rect::new(x0, y0, x0 + size, y0 + size))
+    }
+
+    fn section_chevron_at(&self, position: Point, theme: &Theme) -> Option<usize> {
+        self.markdown_layout
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| matches!(e.data, MarkdownContent::Header { .. }))
+            .find(|(index, _)| {
+                self.section_chevron_rect(*index, theme)
+                    .is_some_and(|rect| rect.contains(position))
+            })
+            .map(|(index, _)| index)
+    }
+
+    fn draw_section_chevrons(&self, scene: &mut Scene, theme: &Theme) {
+        let visible = Rect::new(0.0, 0.0, f64::MAX, self.viewport_height);
+        for (index, element) in self.markdown_layout.iter().enumerate() {
+            if !matches!(element.data, MarkdownContent::Header { .. }) {
+                continue;
+            }
+            let Some(rect) = self.section_chevron_rect(index, theme) else {
+                continue;
+            };
+            if rect.y1 < visible.y0 || rect.y0 > visible.y1 {
+                continue;
+            }
+            draw_disclosure_triangle(
+                scene,
+                rect.origin().to_vec2(),
+                rect.width(),
+                !self.is_section_collapsed(index),
+                theme.text_color,
+            );
+        }
+    }
+
+    fn copy_button_label(&self) -> &Layout<MarkdownBrush> {
+        if self.copy_feedback_timer.is_some() {
+            &self.copied_label_layout
+        } else {
+            &self.copy_label_layout
+        }
+    }
+
+    /// Rectangle of the copy button in the top-right corner of the hovered
+    /// code block.
+    fn copy_button_rect(&self) -> Option<Rect> {
+        let rect = self.item_rect(self.hovered_code_block?)?;
+        let label = self.copy_button_label();
+        let width = (label.width() + 2.0 * COPY_BUTTON_PADDING) as f64;
+        let height = (label.height() + COPY_BUTTON_PADDING) as f64;
+        let x1 = self.max_advance - COPY_BUTTON_PADDING as f64;
+        let y0 = rect.y0 + COPY_BUTTON_PADDING as f64;
+        Some(Rect::new(x1 - width, y0, x1, y0 + height))
+    }
+
+    /// Returns the index of the top-level code block at `position`.
+    fn code_block_at(&self, position: Point) -> Option<usize> {
+        let offset = self.document_position(position).y as f32;
+        let (index, _) = self.markdown_layout.get_index_at_offset(offset)?;
+        let element = self.markdown_layout.get(index)?;
+        match element.data {
+            MarkdownContent::CodeBlock { .. } if !element.hidden => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Copies the text of the hovered code block to the clipboard.
+    fn copy_hovered_code_block(&mut self) -> bool {
+        let Some(MarkdownContent::CodeBlock { text, .. }) = self
+            .hovered_code_block
+            .and_then(|index| self.markdown_layout.get(index))
+            .map(|element| &element.data)
+        else {
+            return false;
+        };
+        if !set_clipboard_text(text.clone()) {
+            return false;
+        }
+        self.copy_feedback_timer = Some(Instant::now());
+        true
+    }
+
+    /// Returns the context menu entries offered at `position`.
+    fn context_menu_entries(&self, position: Point) -> Vec<ContextMenuEntry> {
+        let mut entries = Vec::new();
+        if self.all_selected {
+            entries.push(ContextMenuEntry::Copy);
+            entries.push(ContextMenuEntry::CopyAsMarkdown);
+        } else if self.selection.is_some() {
+            entries.push(ContextMenuEntry::Copy);
+        }
+        if let Some(link) = self.content_at(position).and_then(|hit| hit.link) {
+            entries.push(ContextMenuEntry::CopyLinkAddress(link.url));
+        }
+        let offset = self.document_position(position).y as f32;
+        let item = self
+            .markdown_layout
+            .get_index_at_offset(offset)
+            .and_then(|(index, _)| self.markdown_layout.get(index))
+            .filter(|element| !element.hidden);
+        if let Some(MarkdownContent::Image { uri, .. }) = item.map(|e| &e.data) {
+            let path = resolve_image_uri(uri, &self.base_dir)
+                .unwrap_or_else(|| PathBuf::from(uri));
+            entries.push(ContextMenuEntry::CopyImagePath(path));
+        }
+        entries.push(ContextMenuEntry::SelectAll);
+        entries
+    }
+
+    fn open_context_menu(&mut self, position: Point) {
+        let entries = self.context_menu_entries(position);
+        let size = self.context_menu_entry_size();
+        let height = size.height * entries.len() as f64;
+        // Keep the menu inside the widget.
+        let origin = Point::new(
+            position.x.min(self.max_advance - size.width).max(0.0),
+            position.y.min(self.viewport_height - height).max(0.0),
+        );
+        self.context_menu = Some(ContextMenu { origin, entries });
+    }
+
+    fn context_menu_entry_size(&self) -> Size {
+        let (width, height) = self.context_menu_labels.iter().fold(
+            (0.0f32, 0.0f32),
+            |(width, height), label| {
+                (width.max(label.width()), height.max(label.height()))
+            },
+        );
+        Size::new(
+            (width + 2.0 * CONTEXT_MENU_PADDING) as f64,
+            (height + CONTEXT_MENU_PADDING) as f64,
+        )
+    }
+
+    fn context_menu_entry_rect(&self, menu: &ContextMenu, index: usize) -> Rect {
+        let size = self.context_menu_entry_size();
+        let origin = menu.origin + Vec2::new(0.0, size.height * index as f64);
+        Rect::from_origin_size(origin, size)
+    }
+
+    fn context_menu_entry_at(&self, position: Point) -> Option<ContextMenuEntry> {
+        let menu = self.context_menu.as_ref()?;
+        (0..menu.entries.len())
+            .find(|index| {
+                self.context_menu_entry_rect(menu, *index)
+                    .contains(position)
+            })
+            .map(|index| menu.entries[index].clone())
+    }
+
+    /// Returns the text which `entry` copies to the clipboard.
+    fn context_menu_entry_text(&self, entry: &ContextMenuEntry) -> Option<String> {
+        match entry {
+            ContextMenuEntry::Copy => self.selected_text(),
+            ContextMenuEntry::CopyAsMarkdown => Some(self.source.to_string()),
+            ContextMenuEntry::CopyLinkAddress(url) => Some(url.clone()),
+            ContextMenuEntry::CopyImagePath(path) => {
+                Some(path.to_string_lossy().into_owned())
+            }
+            ContextMenuEntry::SelectAll => None,
+        }
+    }
+
+    fn activate_context_menu_entry(&mut self, entry: ContextMenuEntry) {
+        if entry == ContextMenuEntry::SelectAll {
+            self.set_selection(None, true);
+        } else if let Some(text) = self.context_menu_entry_text(&entry) {
+            set_clipboard_text(text);
+        }
+    }
+
+    fn draw_context_menu(&self, scene: &mut Scene, theme: &Theme) {
+        let Some(menu) = &self.context_menu else {
+            return;
+        };
+        let Some(last) = menu.entries.len().checked_sub(1) else {
+            return;
+        };
+        let background = self
+            .context_menu_entry_rect(menu, 0)
+            .union(self.context_menu_entry_rect(menu, last));
+        scene.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            theme.context_menu_background_color,
+            None,
+            &RoundedRect::from_rect(background, CONTEXT_MENU_PADDING as f64),
+        );
+        for (index, entry) in menu.entries.iter().enumerate() {
+            let Some(label) = self.context_menu_labels.get(entry.label_index())
+            else {
+                continue;
+            };
+            let rect = self.context_menu_entry_rect(menu, index);
+            let translation = Vec2::new(
+                rect.x0 + CONTEXT_MENU_PADDING as f64,
+                rect.y0 + CONTEXT_MENU_PADDING as f64 / 2.0,
+            );
+            draw_text(
+                scene,
+                label,
+                translation,
+                &Rect::new(0.0, 0.0, 0.0, f64::MAX),
+                theme,
+            );
+        }
+    }
+
+    /// Returns the selected range, a select-all covers the whole document.
+    pub fn selected_range(&self) -> Option<(ContentPos, ContentPos)> {
+        if self.all_selected {
+            let last = self.markdown_layout.len().checked_sub(1)?;
+            let end = self
+                .markdown_layout
+                .get(last)
+                .and_then(|element| element.data.selectable_text())
+                .map_or(0, str::len);
+            return Some((ContentPos::new(0, 0), ContentPos::new(last, end)));
+        }
+        self.selection
+    }
+
+    /// Selects the text from `start` to `end`, in either order. Offsets past
+    /// the text of an item are clamped to its end, offsets inside a
+    /// character move to its start.
+    pub fn select_range(
+        this: &mut WidgetMut<'_, Self>,
+        start: ContentPos,
+        end: ContentPos,
+    ) {
+        this.widget.apply_selected_range(start, end);
+        this.ctx.request_paint_only();
+    }
+
+    fn apply_selected_range(&mut self, start: ContentPos, end: ContentPos) {
+        let (start, end) = if start <= end {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        let clamp = |pos: ContentPos| {
+            let text = self
+                .markdown_layout
+                .get(pos.item_index)
+                .and_then(|element| element.data.selectable_text())
+                .unwrap_or_default();
+            ContentPos::new(
+                pos.item_index,
+                text.floor_char_boundary(pos.byte_offset),
+            )
+        };
+        self.set_selection(Some((clamp(start), clamp(end))), false);
+    }
+
+    /// Selects the text between the positions, or the whole document with
+    /// `all_selected`.
+    fn set_selection(
+        &mut self,
+        selection: Option<(ContentPos, ContentPos)>,
+        all_selected: bool,
+    ) {
+        self.selection = selection;
+        self.all_selected = all_selected;
+        self.selection_generation += 1;
+    }
+
+    /// Number of selected characters, 0 without a selection.
+    fn selected_char_count(&self) -> usize {
+        self.selected_text().map_or(0, |text| text.chars().count())
+    }
+
+    /// Returns the new length of the selection when it differs from the
+    /// one reported last.
+    fn take_selection_change(&mut self) -> Option<usize> {
+        if self.selection_generation == self.reported_selection_generation {
+            return None;
+        }
+        self.reported_selection_generation = self.selection_generation;
+        let char_count = self.selected_char_count();
+        if char_count == self.reported_selection_chars {
+            return None;
+        }
+        self.reported_selection_chars = char_count;
+        Some(char_count)
+    }
+
+    fn report_selection(&mut self, ctx: &mut EventCtx) {
+        if let Some(char_count) = self.take_selection_change() {
+            ctx.submit_action(Action::Other(Box::new(
+                MarkdownAction::SelectionChanged { char_count },
+            )));
+        }
+    }
+
+    /// Returns the selected text, blocks are separated like in
+    /// `to_plain_text`.
+    pub fn selected_text(&self) -> Option<String> {
+        if self.all_selected {
+            return Some(self.to_plain_text());
+        }
+        let (start, end) = self.selection?;
+        let mut out = String::new();
+        let last = end
+            .item_index
+            .min(self.markdown_layout.len().saturating_sub(1));
+        for index in start.item_index..=last {
+            let data = &self.markdown_layout.get(index)?.data;
+            if index > start.item_index {
+                out.push_str("\n\n");
+            }
+            let Some(text) = data.selectable_text() else {
+                data.write_plain_text(&mut out);
+                continue;
+            };
+            let from = if index == start.item_index {
+                start.byte_offset
+            } else {
+                0
+            };
+            let to = if index == end.item_index {
+                end.byte_offset
+            } else {
+                text.len()
+            };
+            out.push_str(text.get(from..to).unwrap_or_default());
+        }
+        Some(out)
+    }
+
+    fn draw_copy_button(&self, scene: &mut Scene, theme: &Theme) {
+        let Some(rect) = self.copy_button_rect() else {
+            return;
+        };
+        let button = RoundedRect::from_rect(rect, COPY_BUTTON_PADDING as f64);
+        scene.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            theme.text_color.multiply_alpha(0.2),
+            None,
+            &button,
+        );
+        let translation = Vec2::new(
+            rect.x0 + COPY_BUTTON_PADDING as f64,
+            rect.y0 + COPY_BUTTON_PADDING as f64 / 2.0,
+        );
+        draw_text(
+            scene,
+            self.copy_button_label(),
+            translation,
+            &Rect::new(0.0, 0.0, 0.0, f64::MAX),
+            theme,
+        );
+    }
+
+    /// Returns level, text and bounding rectangle of all top-level headings
+    /// in document order. The rectangles are the same as from `item_rect`.
+    pub fn heading_rects(&self) -> Vec<(HeadingLevel, String, Rect)> {
+        self.markdown_layout
+            .iter()
+            .enumerate()
+            .filter_map(|(index, element)| match &element.data {
+                MarkdownContent::Header { level, text, .. } => self
+                    .item_rect(index)
+                    .map(|rect| (*level, text.clone(), rect)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Removes the whole document, its navigation history and decoded
+    /// images. The widget shows nothing until new content is appended.
+    pub fn clear(this: &mut WidgetMut<'_, Self>) {
+        this.widget.clear_all();
+        this.ctx.request_layout();
+        Self::submit_hovered_link_change(this);
+    }
+
+    fn clear_all(&mut self) {
+        self.clear_document();
+        self.file_path = None;
+        self.back_history.clear();
+        self.forward_history.clear();
+        self.images = ImageCache::default();
+    }
+
+    /// Removes the document and the state of its view, like the selection
+    /// and the scroll position. The history is kept for navigating.
+    fn clear_document(&mut self) {
+        self.markdown_layout = LayoutFlow::new();
+        self.source = Arc::<str>::from("").into();
+        self.source_hasher = source_hasher("");
+        self.source_ends_with_cr = false;
+        self.stream_tail = StreamTail::default();
+        self.scroll = Vec2::ZERO;
+        self.set_selection(None, false);
+        self.context_menu = None;
+        self.pressed_at = None;
+        self.collapsed_sections.clear();
+        self.hovered_code_block = None;
+        self.copy_feedback_timer = None;
+        self.hovered_heading = None;
+        self.set_hovered_link(None);
+        self.pending_scroll_item = None;
+        self.pending_scroll_anchor = None;
+        self.page_starts = vec![0.0];
+        self.pending_scroll_percentage = None;
+        self.pending_scroll_state = None;
+        self.current_page = 0;
+        self.dirty_items.clear();
+        self.dirty = true;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.markdown_layout.flow.is_empty()
+    }
+
+    /// Width the document was last laid out at, 0.0 before the first layout.
+    /// With heading permalinks the content starts after a gutter inside
+    /// this width, the theme padding is inside it as well.
+    pub fn layout_width(&self) -> f64 {
+        self.max_advance
+    }
+
+    /// Replaces the text of the top-level paragraph or heading at `index`,
+    /// the inline styles are dropped. Only that item is laid out again. The
+    /// source is updated as well, for "Copy as Markdown". Returns `false`
+    /// when there is no paragraph or heading at `index` or its source can't
+    /// be found, like when HTML comments in the source make no items.
+    pub fn replace_paragraph_text(
+        this: &mut WidgetMut<'_, Self>,
+        index: usize,
+        new_text: &str,
+    ) -> bool {
+        let widget = &mut *this.widget;
+        let Some(source_range) = widget.item_source_range(index) else {
+            return false;
+        };
+        {
+            let mut item = widget.markdown_layout.get_mutable(index);
+            let (MarkdownContent::Paragraph { text, markers, .. }
+            | MarkdownContent::Header { text, markers, .. }) = &mut *item
+            else {
+                return false;
+            };
+            *text = new_text.to_string();
+            markers.clear();
+        }
+        if matches!(
+            widget.markdown_layout.get(index).map(|e| &e.data),
+            Some(MarkdownContent::Header { .. })
+        ) {
+            assign_anchor_ids(
+                &mut widget.markdown_layout,
+                widget.slug_generator,
+                &mut HashMap::new(),
+            );
+        }
+        widget.replace_item_source(index, source_range, new_text);
+        widget.dirty_items.insert(index);
+        this.ctx.request_layout();
+        true
+    }
+
+    /// Underlines the byte `range` of the text of the top-level paragraph or
+    /// heading at `index` with a wavy line in `color`, like spell checkers
+    /// mark words. The range is clamped to the text, see
+    /// `get_paragraph_text`. Returns `false` when there is no paragraph or
+    /// heading at `index`.
+    pub fn add_wavy_underline(
+        this: &mut WidgetMut<'_, Self>,
+        index: usize,
+        range: Range<usize>,
+        color: Color,
+    ) -> bool {
+        let added =
+            this.widget
+                .add_marker(index, range, MarkerKind::WavyUnderline(color));
+        if added {
+            this.ctx.request_layout();
+        }
+        added
+    }
+
+    /// Removes the wavy underlines added with `add_wavy_underline`.
+    pub fn clear_wavy_underlines(this: &mut WidgetMut<'_, Self>) {
+        let widget = &mut *this.widget;
+        for index in 0..widget.markdown_layout.len() {
+            let mut item = widget.markdown_layout.get_mutable(index);
+            let (MarkdownContent::Paragraph { markers, .. }
+            | MarkdownContent::Header { markers, .. }) = &mut *item
+            else {
+                continue;
+            };
+            let len = markers.len();
+            markers.retain(|marker| {
+                !matches!(marker.kind, MarkerKind::WavyUnderline(_))
+            });
+            if markers.len() != len {
+                widget.dirty_items.insert(index);
+            }
+        }
+        this.ctx.request_layout();
+    }
+
+    /// Adds a marker of `kind` to the text of the top-level paragraph or
+    /// heading at `index`, see `normalize_markers` for the order it is
+    /// applied in.
+    fn add_marker(
+        &mut self,
+        index: usize,
+        range: Range<usize>,
+        kind: MarkerKind,
+    ) -> bool {
+        if index >= self.markdown_layout.len() {
+            return false;
+        }
+        let mut item = self.markdown_layout.get_mutable(index);
+        let (MarkdownContent::Paragraph { text, markers, .. }
+        | MarkdownContent::Header { text, markers, .. }) = &mut *item
+        else {
+            return false;
+        };
+        let end = text.floor_char_boundary(range.end);
+        let start = text.floor_char_boundary(range.start.min(end));
+        markers.push(TextMarker::new(start..end, kind));
+        normalize_markers(markers);
+        drop(item);
+        self.dirty_items.insert(index);
+        true
+    }
+
+    /// Returns the source of the top-level paragraph or heading at `index`.
+    /// Returns `None` when the blocks of the source don't map one to one to
+    /// the items, like with HTML comments.
+    fn item_source_range(&mut self, index: usize) -> Option<Range<usize>> {
+        let ranges = self.source_block_ranges();
+        if ranges.len() != self.markdown_layout.len() {
+            return None;
+        }
+        let range = ranges.get(index)?.clone();
+        let parsed =
+            parse_markdown_with(&self.source[range.clone()], self.parse_options);
+        let item = &self.markdown_layout.get(index)?.data;
+        let same = parsed.len() == 1
+            && match (&parsed.get(0)?.data, item) {
+                (
+                    MarkdownContent::Paragraph { text, .. },
+                    MarkdownContent::Paragraph {
+                        text: item_text, ..
+                    },
+                ) => text == item_text,
+                (
+                    MarkdownContent::Header { level, text, .. },
+                    MarkdownContent::Header {
+                        level: item_level,
+                        text: item_text,
+                        ..
+                    },
+                ) => level == item_level && text == item_text,
+                _ => false,
+            };
+        same.then_some(range)
+    }
+
+    /// Byte ranges of the top-level blocks of the source, the source is only
+    /// split again after it changed.
+    fn source_block_ranges(&mut self) -> &[Range<usize>] {
+        let hash = self.source_hasher.finish();
+        if self.source_blocks.as_ref().is_none_or(|(h, _)| *h != hash) {
+            let source =
+                self.source.strip_prefix('\u{feff}').unwrap_or(&self.source);
+            let bom_len = self.source.len() - source.len();
+            let ranges = top_level_block_ranges(source)
+                .into_iter()
+                .map(|range| range.start + bom_len..range.end + bom_len)
+                .collect();
+            self.source_blocks = Some((hash, ranges));
+        }
+        match &self.source_blocks {
+            Some((_, ranges)) => ranges,
+            None => &[],
+        }
+    }
+
+    /// Writes `new_text` into the source of the item at `index`, it is at
+    /// `range` of the source.
+    fn replace_item_source(
+        &mut self,
+        index: usize,
+        range: Range<usize>,
+        new_text: &str,
+    ) {
+        let block = &self.source[range.clone()];
+        let line_end = if block.ends_with('\n') { "\n" } else { "" };
+        let escaped = escape_markdown(new_text);
+        let replacement = match self.markdown_layout.get(index).map(|e| &e.data) {
+            Some(MarkdownContent::Header { level, .. }) => {
+                format!("{} {escaped}{line_end}", "#".repeat(*level as usize))
+            }
+            _ => format!("{escaped}{line_end}"),
+        };
+        let mut source =
+            String::with_capacity(self.source.len() + replacement.len());
+        source.push_str(&self.source[..range.start]);
+        source.push_str(&replacement);
+        source.push_str(&self.source[range.end..]);
+        if range.end <= self.stream_tail.start {
+            self.stream_tail.start =
+                self.stream_tail.start + replacement.len() - range.len();
+        }
+        let old_hash = self.source_hasher.finish();
+        self.source = source.into();
+        self.source_hasher = source_hasher(&self.source);
+        self.source_ends_with_cr = false;
+        // The replacement is a single block, the blocks after it only move.
+        if let Some((hash, ranges)) = &mut self.source_blocks {
+            if *hash == old_hash && index < ranges.len() {
+                ranges[index].end = range.start + replacement.len();
+                for block in &mut ranges[index + 1..] {
+                    block.start = block.start + replacement.len() - range.len();
+                    block.end = block.end + replacement.len() - range.len();
+                }
+                *hash = self.source_hasher.finish();
+            }
+        }
+    }
+
+    /// Returns the text of the top-level item at `index` when it is a
+    /// paragraph. Paragraphs nested in lists or quotes are not included.
+    pub fn get_paragraph_text(&self, index: usize) -> Option<&str> {
+        match &self.markdown_layout.get(index)?.data {
+            MarkdownContent::Paragraph { text, .. } => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Moves the content of `other` to the end of this document. Margins at
+    /// the seam are collapsed by the layout flow, so the first appended
+    /// block keeps its own top margin.
+    pub fn append(&mut self, mut other: MarkdowWidget) {
+        other.resolve_image_uris();
+        self.markdown_layout.append(other.markdown_layout);
+        self.source = format!("{}\n\n{}", &*self.source, &*other.source).into();
+        self.source_hasher = source_hasher(&self.source);
+        self.source_ends_with_cr = false;
+        self.dirty = true;
+    }
+
+    /// Moves the content of `other` to the start of this document.
+    pub fn prepend(&mut self, mut other: MarkdowWidget) {
+        other.resolve_image_uris();
+        // Item indices of this document move behind the new content.
+        let shift = other.markdown_layout.len();
+        self.collapsed_sections = self
+            .collapsed_sections
+            .iter()
+            .map(|index| index + shift)
+            .collect();
+        self.set_selection(None, self.all_selected);
+        self.hovered_code_block = None;
+        self.hovered_heading = None;
+        self.markdown_layout.prepend(other.markdown_layout);
+        self.source = format!("{}\n\n{}", &*other.source, &*self.source).into();
+        self.source_hasher = source_hasher(&self.source);
+        self.source_ends_with_cr = false;
+        self.dirty = true;
+    }
+
+    /// Replaces the relative image URIs with paths joined with `base_dir`,
+    /// so they keep pointing at the same files in another document.
+    fn resolve_image_uris(&mut self) {
+        let base_dir = &self.base_dir;
+        for_each_image_uri(&mut self.markdown_layout, &mut |uri| {
+            if let Some(path) = resolve_image_uri(uri, base_dir) {
+                *uri = path.to_string_lossy().into_owned();
+            }
+        });
+    }
+
+    /// Returns the content under `position` in widget coordinates, or `None`
+    /// when there is none or the document was not laid out yet.
+    pub fn content_at(&self, position: Point) -> Option<HitInfo> {
+        if self.dirty {
+            return None;
+        }
+        let global_theme = get_theme();
+        let widget_theme = self.widget_theme(&global_theme);
+        hit_test_flow(
+            &self.markdown_layout,
+            self.document_position(position),
+            widget_theme.as_ref().unwrap_or(&*global_theme),
+            &mut Vec::new(),
+        )
+    }
+
+    /// Converts `position` in widget coordinates to document coordinates.
+    fn document_position(&self, position: Point) -> Point {
+        widget_to_document(position, self.content_origin(), self.scroll)
+    }
+
+    /// Returns the top-level headings of the document.
+    pub fn table_of_contents(&self) -> Vec<TocEntry> {
+        flow_toc(&self.markdown_layout)
+    }
+
+    /// Returns word, heading, image and code block counts together with an
+    /// estimated reading time, code blocks are not counted as words.
+    pub fn stats(&self) -> DocumentStats {
+        self.stats_with(&StatsOptions::default())
+    }
+
+    pub fn stats_with(&self, options: &StatsOptions) -> DocumentStats {
+        flow_stats(&self.markdown_layout, options)
+    }
+
+    /// Counts the occurrences of `query` in the text of the document,
+    /// ignoring case and Unicode normalization differences. An empty query
+    /// matches nothing.
+    pub fn find_text(&self, query: &str) -> usize {
+        let query = fold_for_search(query);
+        if query.is_empty() {
+            return 0;
+        }
+        flow_match_count(&self.markdown_layout, &query)
+    }
+
+    /// Serializes the document into plain text with all inline styling
+    /// stripped. Block structure is kept with light markdown-like prefixes.
+    pub fn to_plain_text(&self) -> String {
+        flow_to_plain_text(&self.markdown_layout)
+    }
+
+    fn draw_selection(&self, scene: &mut Scene, theme: &Theme) {
+        if let Some((start, end)) = self.selection {
+            self.draw_range_selection(scene, start, end, theme);
+        }
+        if !self.all_selected {
+            return;
+        }
+        let origin = self.content_origin();
+        let selection = Rect::new(
+            0.0,
+            origin.y - self.scroll.y,
+            self.max_advance,
+            origin.y + self.markdown_layout.height() as f64 - self.scroll.y,
+        );
+        scene.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            self.selection_color,
+            None,
+            &selection,
+        );
+    }
+
+    fn draw_range_selection(
+        &self,
+        scene: &mut Scene,
+        start: ContentPos,
+        end: ContentPos,
+        theme: &Theme,
+    ) {
+        let last = end
+            .item_index
+            .min(self.markdown_layout.len().saturating_sub(1));
+        for index in start.item_index..=last {
+            let Some(rect) = self.item_rect(index) else {
+                continue;
+            };
+            let data = &self.markdown_layout.get(index).unwrap().data;
+            let (text_layout, text, padding) = match data {
+                MarkdownContent::Paragraph {
+                    text_layout, text, ..
+                }
+                | MarkdownContent::Header {
+                    text_layout, text, ..
+                } => (text_layout, text, 0.0),
+                MarkdownContent::CodeBlock {
+                    text_layout, text, ..
+                } => (text_layout, text, theme.code_block_padding as f64),
+                _ => {
+                    scene.fill(
+                        Fill::NonZero,
+                        Affine::IDENTITY,
+                        self.selection_color,
+                        None,
+                        &rect,
+                    );
+                    continue;
+                }
+            };
+            let from = if index == start.item_index {
+                start.byte_offset
+            } else {
+                0
+            };
+            let to = if index == end.item_index {
+                end.byte_offset
+            } else {
+                text.len()
+            };
+            let Range {
+                start: from,
+                end: to,
+            } = data.shown_range(from..to);
+            if from >= to {
+                continue;
+            }
+            let selection = Selection::new(
+                Cursor::from_byte_index(text_layout, from, Affinity::Downstream),
+                Cursor::from_byte_index(text_layout, to, Affinity::Upstream),
+            );
+            let transform = Affine::translate(
+                rect.origin().to_vec2() + Vec2::new(padding, padding),
+            );
+            for line_rect in selection.geometry(text_layout) {
+                scene.fill(
+                    Fill::NonZero,
+                    transform,
+                    self.selection_color,
+                    None,
+                    &line_rect,
+                );
+            }
+        }
+    }
+}
+
+/// Thinnest decoration line, lines get lost at small font sizes otherwise.
+/// The layout is in logical pixels, so this is at least one physical pixel.
+const MIN_DECORATION_THICKNESS: f32 = 1.0;
+
+fn decoration_thickness(size: f32) -> f32 {
+    size.max(MIN_DECORATION_THICKNESS)
+}
+
+/// Returns the y coordinate of the middle of a decoration line. Fonts give
+/// the offset of the top edge of the line from the baseline with y going up,
+/// while the layout has y going down.
+fn decoration_center(baseline: f32, offset: f32, size: f32) -> f32 {
+    baseline - offset + size / 2.0
+}
+
+/// Spans of the dashes of a line `width` long, the whole line when the
+/// `pattern` is empty.
+fn dash_segments(pattern: &[f64], width: f64) -> Vec<(f64, f64)> {
+    if pattern.iter().sum::<f64>() <= 0.0 {
+        return vec![(0.0, width)];
+    }
+    let mut segments = Vec::new();
+    let mut x = 0.0;
+    for (index, length) in pattern.iter().cycle().enumerate() {
+        if x >= width {
+            break;
+        }
+        if index % 2 == 0 {
+            segments.push((x, (x + length).min(width)));
+        }
+        x += length;
+    }
+    segments
+}
+
+/// Distance `wavy_line` moves away from its center line.
+fn wavy_amplitude(font_size: f64) -> f64 {
+    (font_size * 0.08).max(1.0)
+}
+
+/// Wavy line from `x1` to `x2` around `y`, the amplitude and the period
+/// grow with the font size.
+fn wavy_line(x1: f64, x2: f64, y: f64, font_size: f64) -> BezPath {
+    let amplitude = wavy_amplitude(font_size);
+    let half_period = (font_size * 0.15).max(2.0);
+    let mut path = BezPath::new();
+    path.move_to((x1, y));
+    let mut x = x1;
+    let mut direction = -1.0;
+    while x < x2 {
+        let next = (x + half_period).min(x2);
+        // The curve reaches half of the control point distance.
+        path.quad_to(
+            ((x + next) / 2.0, y + direction * amplitude * 2.0),
+            (next, y),
+        );
+        direction = -direction;
+        x = next;
+    }
+    path
+}
+
+fn draw_underline(
+    scene: &mut Scene,
+    underline: &Decoration<MarkdownBrush>,
+    glyph_run: &GlyphRun<'_, MarkdownBrush>,
+    run_metrics: &RunMetrics,
+    transform: &Affine,
+) {
+    let offset = underline.offset.unwrap_or(run_metrics.underline_offset);
+    let stroke_size =
+        decoration_thickness(underline.size.unwrap_or(run_metrics.underline_size));
+    let y1 = decoration_center(glyph_run.baseline(), offset, stroke_size) as f64;
+    let x1 = glyph_run.offset() as f64;
+    let x2 = x1 + glyph_run.advance() as f64;
+    let underline_shape = match underline.brush.1 {
+        LineStyle::Wavy => wavy_line(x1, x2, y1, glyph_run.run().font_size() as f64),
+        _ => Line::new((x1, y1), (x2, y1)).to_path(0.1),
+    };
+
+    let stroke = Stroke {
+        width: stroke_size as f64,
+        join: Join::Bevel,
+        miter_limit: 4.0,
+        start_cap: Cap::Butt,
+        end_cap: Cap::Butt,
+        dash_pattern: underline.brush.1.dash_pattern().iter().copied().collect(),
+        dash_offset: 0.0,
+    };
+
+    scene.stroke(
+        &stroke,
+        *transform,
+        underline.brush.0,
+        Some(Affine::IDENTITY),
+        &underline_shape,
+    );
+}
+
+fn draw_strikethrough(
+    scene: &mut Scene,
+    strikethrough: &Decoration<MarkdownBrush>,
+    glyph_run: &GlyphRun<'_, MarkdownBrush>,
+    run_metrics: &RunMetrics,
+    transform: &Affine,
+) {
+    let offset = strikethrough
+        .offset
+        .unwrap_or(run_metrics.strikethrough_offset);
+    let size = decoration_thickness(
+        strikethrough.size.unwrap_or(run_metrics.strikethrough_size),
+    );
+    // Custom sizes grow around the center of the line the font suggests.
+    let y1 = decoration_center(
+        glyph_run.baseline(),
+        offset,
+        decoration_thickness(run_metrics.strikethrough_size),
+    );
+    let x1 = glyph_run.offset();
+    let x2 = x1 + glyph_run.advance();
+    let strikethrough_shape = Line::new((x1, y1), (x2, y1));
+
+    let stroke = Stroke {
+        width: size as f64,
+        join: Join::Bevel,
+        miter_limit: 4.0,
+        start_cap: Cap::Butt,
+        end_cap: Cap::Butt,
+        dash_pattern: Default::default(),
+        dash_offset: 0.0,
+    };
+
+    scene.stroke(
+        &stroke,
+        *transform,
+        strikethrough.brush.0,
+        Some(Affine::IDENTITY),
+        &strikethrough_shape,
+    );
+}
+
+fn export_flow_to_pdf(
+    page: &mut PdfPage,
+    flow: &LayoutFlow<MarkdownContent>,
+    translation: Vec2,
+    width: f64,
+    theme: &Theme,
+    continuation_indent: f64,
+) {
+    for (index, element) in flow.iter().enumerate() {
+        if element.hidden {
+            continue;
+        }
+        let indent = if index > 0 { continuation_indent } else { 0.0 };
+        element.data.export_to_pdf(
+            page,
+            translation + Vec2::new(indent, element.offset as f64),
+            width - indent,
+            theme,
+        );
+    }
+}
+
+/// Writes the glyph runs of `layout`, which was built from `text`, to a PDF
+/// page with underlines and strikethroughs.
+fn export_text_to_pdf(
+    page: &mut PdfPage,
+    layout: &Layout<MarkdownBrush>,
+    text: &str,
+    translation: Vec2,
+    font: PdfFont,
+) {
+    for line in layout.lines() {
+        for item in line.items() {
+            let PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+                continue;
+            };
+            let run = glyph_run.run();
+            let style = glyph_run.style();
+            let x1 = translation.x + glyph_run.offset() as f64;
+            let x2 = x1 + glyph_run.advance() as f64;
+            if let Some(run_text) = text.get(run.text_range()) {
+                let run_text = run_text.trim_end_matches(['\n', '\r']);
+                if is_win_ansi(run_text) {
+                    page.text(
+                        Point::new(x1, translation.y + glyph_run.baseline() as f64),
+                        font,
+                        run.font_size(),
+                        glyph_run.advance(),
+                        style.brush.0,
+                        run_text,
+                    );
+                } else {
+                    page.fill_path(
+                        &glyph_run_outlines(&glyph_run, translation),
+                        style.brush.0,
+                    );
+                }
+            }
+            let metrics = run.metrics();
+            let decorations = [
+                (
+                    &style.underline,
+                    metrics.underline_offset,
+                    metrics.underline_size,
+                ),
+                (
+                    &style.strikethrough,
+                    metrics.strikethrough_offset,
+                    metrics.strikethrough_size,
+                ),
+            ];
+            for (decoration, offset, size) in decorations {
+                let Some(decoration) = decoration else {
+                    continue;
+                };
+                let size = decoration_thickness(decoration.size.unwrap_or(size));
+                let offset = decoration.offset.unwrap_or(offset);
+                let y = translation.y
+                    + decoration_center(glyph_run.baseline(), offset, size) as f64;
+                let half = size as f64 / 2.0;
+                page.fill_rect(
+                    Rect::new(x1, y - half, x2, y + half),
+                    decoration.brush.0,
+                );
+            }
+        }
+    }
+}
+
+/// Collects glyph outlines into a path in layout coordinates, the outlines
+/// grow upwards from `origin`.
+struct OutlinePath {
+    path: BezPath,
+    origin: Point,
+    /// Horizontal shift per unit of height, for synthesized italics.
+    skew: f64,
+}
+
+impl OutlinePath {
+    fn point(&self, x: f32, y: f32) -> Point {
+        Point::new(
+            self.origin.x + x as f64 + y as f64 * self.skew,
+            self.origin.y - y as f64,
+        )
+    }
+}
+
+impl OutlinePen for OutlinePath {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.path.move_to(self.point(x, y));
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.path.line_to(self.point(x, y));
+    }
+
+    fn quad_to(&mut self, cx0: f32, cy0: f32, x: f32, y: f32) {
+        self.path.quad_to(self.point(cx0, cy0), self.point(x, y));
+    }
+
+    fn curve_to(&mut self, cx0: f32, cy0: f32, cx1: f32, cy1: f32, x: f32, y: f32) {
+        self.path.curve_to(
+            self.point(cx0, cy0),
+            self.point(cx1, cy1),
+            self.point(x, y),
+        );
+    }
+
+    fn close(&mut self) {
+        self.path.close_path();
+    }
+}
+
+/// Outlines of the glyphs of `glyph_run` moved by `translation`, for text
+/// the PDF fonts don't have.
+fn glyph_run_outlines(
+    glyph_run: &GlyphRun<'_, MarkdownBrush>,
+    translation: Vec2,
+) -> BezPath {
+    let run = glyph_run.run();
+    let font = run.font();
+    let Ok(font_ref) = FontRef::from_index(font.data.as_ref(), font.index) else {
+        return BezPath::new();
+    };
+    let outlines = font_ref.outline_glyphs();
+    let coords: Vec<NormalizedCoord> = run
+        .normalized_coords()
+        .iter()
+        .map(|&coord| NormalizedCoord::from_bits(coord))
+        .collect();
+    let skew = run
+        .synthesis()
+        .skew()
+        .map_or(0.0, |angle| angle.to_radians().tan() as f64);
+    let mut pen = OutlinePath {
+        path: BezPath::new(),
+        origin: Point::ZERO,
+        skew,
+    };
+    for glyph in glyph_run.positioned_glyphs() {
+        let Some(outline) = outlines.get(GlyphId::new(glyph.id as _)) else {
+            continue;
+        };
+        pen.origin = Point::new(glyph.x as f64, glyph.y as f64) + translation;
+        let settings = DrawSettings::unhinted(
+            FontSize::new(run.font_size()),
+            LocationRef::new(&coords),
+        );
+        // A glyph which fails to draw is left out like a missing one.
+        let _ = outline.draw(settings, &mut pen);
+    }
+    pen.path
+}
+
+fn draw_text(
+    scene: &mut Scene,
+    layout: &Layout<MarkdownBrush>,
+    translation: Vec2,
+    source_rect: &Rect,
+    theme: &Theme,
+) {
+    let transform: Affine = Affine::translate(translation);
+    let mut top_line_index = if let Some((cluster, _)) =
+        Cluster::from_point(layout, 0.0, source_rect.y0 as f32)
+    {
+        cluster.path().line_index()
+    } else {
+        0
+    };
+    while let Some(line) = layout.get(top_line_index) {
+        let line_metrics = line.metrics();
+        if line_metrics.min_coord > source_rect.y1 as f32 {
+            break;
+        }
+        for item in line.items() {
+            let PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+                continue;
+            };
+            let style = glyph_run.style();
+            let text_color = &style.brush;
+
+            let run = glyph_run.run();
+            // TODO: This needs to be some kind of a flow layout.
+            let font = run.font();
+            let font_size = run.font_size();
+            let synthesis = run.synthesis();
+            let glyph_xform = synthesis
+                .skew()
+                .map(|angle| Affine::skew(angle.to_radians().tan() as f64, 0.0));
+            let coords = run.normalized_coords();
+            scene
+                .draw_glyphs(font)
+                .brush(text_color.0)
+                .hint(theme.text_hinting)
+                .transform(transform)
+                .glyph_transform(glyph_xform)
+                .font_size(font_size)
+                .normalized_coords(&coords)
+                .draw(
+                    Fill::NonZero,
+                    glyph_run.positioned_glyphs().map(|glyph| vello::Glyph {
+                        id: glyph.id as _,
+                        x: glyph.x,
+                        y: glyph.y,
+                    }),
+                );
+
+            let run_metrics = run.metrics();
+            if let Some(underline) = &style.underline {
+                draw_underline(
+                    scene,
+                    underline,
+                    &glyph_run,
+                    run_metrics,
+                    &transform,
+                );
+            }
+
+            if let Some(strikethrough) = &style.strikethrough {
+                draw_strikethrough(
+                    scene,
+                    strikethrough,
+                    &glyph_run,
+                    run_metrics,
+                    &transform,
+                );
+            }
+
+            if text_color.2 == GlyphMark::ExternalLink {
+                let width = external_link_indicator_width(theme) as f64;
+                let x = (glyph_run.offset() + glyph_run.advance()) as f64 - width;
+                let rect = Rect::new(
+                    x,
+                    (glyph_run.baseline() - run_metrics.ascent * 0.6) as f64,
+                    x + width,
+                    glyph_run.baseline() as f64,
+                );
+                draw_external_link_glyph(scene, transform, rect, text_color.0);
+            }
+        }
+        top_line_index += 1;
+    }
+}
+
+/// Rectangle and color of the border of a block quote relative to its
+/// top-left corner, the color cycles through `theme.indentation_colors` by
+/// the nesting level.
+fn indentation_border(
+    decoration: &IndentationDecoration,
+    height: f32,
+    theme: &Theme,
+) -> Option<(Rect, Color)> {
+    if theme.indentation_colors.is_empty() {
+        return None;
+    }
+    let color =
+        theme.indentation_colors[decoration.level % theme.indentation_colors.len()];
+    let width = (theme.markdown_indentation_decoration_width / 3.0) as f64;
+    Some((Rect::new(0.0, 0.0, width, height as f64), color))
+}
+
+/// Paints the border of a block quote.
+fn draw_indentation_decoration(
+    scene: &mut Scene,
+    decoration: &IndentationDecoration,
+    height: f32,
+    translation: Vec2,
+    theme: &Theme,
+) {
+    let Some((border, color)) = indentation_border(decoration, height, theme) else {
+        return;
+    };
+    scene.fill(
+        Fill::NonZero,
+        Affine::translate(translation),
+        color,
+        None,
+        &border,
+    );
+}
+
+/// Outline of an image shown in `rect`, the clip path of its rounded
+/// corners.
+fn image_frame(rect: Rect, theme: &Theme) -> RoundedRect {
+    let radius = (theme.image_corner_radius as f64)
+        .min(rect.width() / 2.0)
+        .min(rect.height() / 2.0)
+        .max(0.0);
+    RoundedRect::from_rect(rect, radius)
+}
+
+/// Path of the image border, moved inwards by half its width so the stroke
+/// stays within the image.
+fn image_border(frame: RoundedRect, theme: &Theme) -> RoundedRect {
+    let inset = theme.image_border_width as f64 / 2.0;
+    let radius = (frame.radii().top_left - inset).max(0.0);
+    RoundedRect::from_rect(frame.rect().inset(-inset), radius)
+}
+
+fn draw_image(scene: &mut Scene, image: &Image, translation: Vec2, scale: f64) {
+    let transform: Affine = Affine::translate(translation) * Affine::scale(scale);
+    scene.draw_image(image, transform);
+}
+
+/// Width reserved left of the content for the heading link glyphs.
+fn permalink_gutter(theme: &Theme) -> f64 {
+    theme.text_size as f64 * 1.25
+}
+
+/// Draws a chain link, two rounded links crossing each other diagonally.
+fn draw_link_glyph(scene: &mut Scene, rect: Rect, color: Color) {
+    let size = rect.width();
+    let link = RoundedRect::new(
+        -size * 0.35,
+        -size * 0.14,
+        size * 0.1,
+        size * 0.14,
+        size * 0.14,
+    );
+    let stroke = Stroke::new(size * 0.1);
+    let center = Affine::translate(rect.center().to_vec2())
+        * Affine::rotate(-std::f64::consts::FRAC_PI_4);
+    scene.stroke(&stroke, center, color, None, &link);
+    scene.stroke(
+        &stroke,
+        center * Affine::translate((size * 0.25, 0.0)),
+        color,
+        None,
+        &link,
+    );
+}
+
+/// Draws an arrow pointing up and right, centered in `rect`.
+fn draw_external_link_glyph(
+    scene: &mut Scene,
+    transform: Affine,
+    rect: Rect,
+    color: Color,
+) {
+    let size = rect.width().min(rect.height()) * 0.7;
+    let center = rect.center();
+    let tip = Point::new(center.x + size / 2.0, center.y - size / 2.0);
+    let mut arrow = BezPath::new();
+    arrow.move_to((center.x - size / 2.0, center.y + size / 2.0));
+    arrow.line_to(tip);
+    arrow.move_to((tip.x - size * 0.6, tip.y));
+    arrow.line_to(tip);
+    arrow.line_to((tip.x, tip.y + size * 0.6));
+    let stroke = Stroke::new((size * 0.15).max(1.0))
+        .with_caps(Cap::Round)
+        .with_join(Join::Round);
+    scene.stroke(&stroke, transform, color, None, &arrow);
+}
+
+/// Indentation of ordered list items, wide enough for the widest label of
+/// the list so the labels can share their right edge.
+fn marker_column_width(
+    label_widths: impl Iterator<Item = f32>,
+    theme: &Theme,
+) -> f32 {
+    theme.markdown_numbered_list_indentation
+        + label_widths.fold(0.0, f32::max)
+        + theme.markdown_list_after_indentation
+}
+
+/// Start of an ordered list label, right-aligned to the column of a list
+/// with the given `indentation`.
+fn number_marker_x(indentation: f32, label_width: f32, theme: &Theme) -> f32 {
+    indentation - theme.markdown_list_after_indentation - label_width
+}
+
+/// Extra indentation of the block at `index` of a list item. The first block
+/// follows the marker, the continuation blocks are aligned with it and then
+/// shifted by `theme.list_continuation_indent`.
+fn continuation_indent(theme: &Theme, index: usize) -> f32 {
+    if index == 0 {
+        0.0
+    } else {
+        theme.list_continuation_indent
+    }
+}
+
+/// Space left of the content of a block quote, both layout and paint of
+/// nested blocks use it.
+fn quote_indentation(theme: &Theme) -> f32 {
+    theme.markdown_indentation_decoration_width
+}
+
+/// Width reserved for the disclosure triangle of `<details>` blocks, the
+/// summary and the content are shifted right by it.
+fn details_indentation(theme: &Theme) -> f32 {
+    theme.text_size as f32
+}
+
+fn draw_disclosure_triangle(
+    scene: &mut Scene,
+    translation: Vec2,
+    size: f64,
+    open: bool,
+    color: Color,
+) {
+    let half = size / 4.0;
+    let center = Point::new(size / 2.0, size / 2.0);
+    let mut triangle = BezPath::new();
+    if open {
+        triangle.move_to((center.x - half, center.y - half / 2.0));
+        triangle.line_to((center.x + half, center.y - half / 2.0));
+        triangle.line_to((center.x, center.y + half));
+    } else {
+        triangle.move_to((center.x - half / 2.0, center.y - half));
+        triangle.line_to((center.x + half, center.y));
+        triangle.line_to((center.x - half / 2.0, center.y + half));
+    }
+    triangle.close_path();
+    scene.fill(
+        Fill::NonZero,
+        Affine::translate(translation),
+        color,
+        None,
+        &triangle,
+    );
+}
+
+fn draw_flow(
+    scene: &mut Scene,
+    flow: &LayoutFlow<MarkdownContent>,
+    source_translation: Vec2,
+    source_rect: &Rect,
+    theme: &Theme,
+    renderer: &dyn ContentRenderer,
+) {
+    draw_flow_with_continuation(
+        scene,
+        flow,
+        source_translation,
+        source_rect,
+        theme,
+        renderer,
+        0.0,
+    );
+}
+
+/// Fills a widget of `size` with the background color of the theme, if it
+/// has one.
+fn draw_background(scene: &mut Scene, size: Size, theme: &Theme) {
+    if let Some(color) = theme.background_color {
+        scene.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            color,
+            None,
+            &size.to_rect(),
+        );
+    }
+}
+
+/// Like `draw_flow`, but every block after the first one is shifted right by
+/// `continuation_indent`.
+fn draw_flow_with_continuation(
+    scene: &mut Scene,
+    flow: &LayoutFlow<MarkdownContent>,
+    source_translation: Vec2,
+    source_rect: &Rect,
+    theme: &Theme,
+    renderer: &dyn ContentRenderer,
+    continuation_indent: f64,
+) {
+    let visible_range = flow.visible_range(
+        source_rect.y0 as f32,
+        (source_rect.y1 - source_rect.y0) as f32,
+    );
+    let visible_parts = flow.get_visible_parts(
+        source_rect.y0 as f32,
+        (source_rect.y1 - source_rect.y0) as f32,
+    );
+
+    for (index, visible_part) in visible_range.zip(visible_parts) {
+        if visible_part.hidden {
+            continue;
+        }
+        // The first block follows the marker, even when it is empty and
+        // the next one starts at the same offset.
+        let indent = if index > 0 { continuation_indent } else { 0.0 };
+        let translation =
+            source_translation + Vec2::new(indent, visible_part.offset as f64);
+        let sub_source_rect = visible_part.get_source_rect(source_rect);
+        // Nothing is painted into an empty box. Blocks sized by the renderer
+        // paint outside of the built-in box.
+        let clip = visible_part.data.bounding_rect(translation);
+        let clipped = visible_part.data.clips_to_bounds()
+            && visible_part.custom_height.is_none()
+            && clip.area() > 0.0;
+        if clipped {
+            scene.push_layer(BlendMode::default(), 1., Affine::IDENTITY, &clip);
+        }
+        if !renderer.paint(
+            &visible_part.data,
+            scene,
+            translation,
+            &sub_source_rect,
+            theme,
+        ) {
+            visible_part.data.paint(
+                scene,
+                translation,
+                &sub_source_rect,
+                theme,
+                renderer,
+            );
+        }
+        if clipped {
+            scene.pop_layer();
+        }
+    }
+}
+
+impl Widget for MarkdowWidget {
+    fn on_pointer_event(&mut self, ctx: &mut EventCtx, event: &PointerEvent) {
+        println!("event: {event:?} >>> ctx: {}", ctx.size());
+        match event {
+            PointerEvent::MouseWheel(delta, _) if self.paginated => {
+                // TODO: Accumulate the deltas, touchpads send many small ones.
+                let changed = if delta.y < 0.0 {
+                    self.change_page(ctx, self.current_page + 1)
+                } else if delta.y > 0.0 {
+                    self.change_page(ctx, self.current_page.saturating_sub(1))
+                } else {
+                    false
+                };
+                if self.scroll_propagation.handles(changed) {
+                    ctx.set_handled();
+                }
+            }
+            PointerEvent::MouseWheel(delta, _) => {
+                const SCROLLING_SPEED: f64 = 3.0;
+                let delta = Vec2::new(
+                    delta.x * -SCROLLING_SPEED,
+                    delta.y * -SCROLLING_SPEED,
+                );
+                let previous_scroll = self.scroll;
+                self.scroll += delta;
+                let size = ctx.size();
+                let baseline = ctx.baseline_offset();
+                self.scroll.x = self.scroll.x.max(0.0);
+                self.scroll.y = self.scroll.y.max(0.0);
+                // TODO: Get corrent view port width so the horizontal scroll is
+                // possible.
+                self.scroll.x = self.scroll.x.min(0.0);
+                self.scroll.y = self
+                    .scroll
+                    .y
+                    .min(self.scroll_height() - size.height + baseline);
+                info!("scrolling new scroll: {} , self.markdown_layout.height() {}, ctx.size() {}", self.scroll, self.markdown_layout.height(), ctx.size());
+                if let Some(bla) = self.markdown_layout.flow.last() {
+                    info!("bla.offset: {}", bla.offset);
+                }
+                ctx.request_paint_only();
+                if self
+                    .scroll_propagation
+                    .handles(self.scroll != previous_scroll)
+                {
+                    ctx.set_handled();
+                }
+            }
+            PointerEvent::PointerMove(_) => {
+                let position = event.local_position(ctx);
+                let hovered = self.code_block_at(position);
+                if hovered != self.hovered_code_block {
+                    self.hovered_code_block = hovered;
+                    self.copy_feedback_timer = None;
+                    ctx.request_paint_only();
+                }
+                if self.heading_permalinks {
+                    let hovered = self.heading_at(position);
+                    if hovered != self.hovered_heading {
+                        self.hovered_heading = hovered;
+                        ctx.request_paint_only();
+                    }
+                }
+                let link = self.content_at(position).and_then(|hit| hit.link);
+                self.report_hovered_link(ctx, link);
+            }
+            PointerEvent::DropFile(path, _) => {
+                self.open_dropped_file(ctx, path);
+                ctx.set_handled();
+            }
+            PointerEvent::PointerLeave(_) => {
+                if self.hovered_code_block.take().is_some() {
+                    self.copy_feedback_timer = None;
+                    ctx.request_paint_only();
+                }
+                if self.hovered_heading.take().is_some() {
+                    ctx.request_paint_only();
+                }
+                self.report_hovered_link(ctx, None);
+            }
+            PointerEvent::PointerUp(_, _) => {
+                let position = event.local_position(ctx);
+                let on_button = self
+                    .copy_button_rect()
+                    .is_some_and(|rect| rect.contains(position));
+                if on_button && self.copy_hovered_code_block() {
+                    // Animation frames are used to hide the feedback later.
+                    ctx.request_anim_frame();
+                    ctx.request_paint_only();
+                    ctx.set_handled();
+                    return;
+                }
+                let Some(pressed_at) = self.pressed_at.take() else {
+                    return;
+                };
+                // Dragging away from a link doesn't click it.
+                if (position - pressed_at).hypot() > CLICK_SLOP {
+                    return;
+                }
+                if let Some(hit) = self.content_at(position) {
+                    if let Some(link) = hit.link.clone() {
+                        self.click_link(ctx, link);
+                        ctx.set_handled();
+                    } else if hit.image_uri.is_some() {
+                        self.click_image(ctx, &hit);
+                        ctx.set_handled();
+                    }
+                }
+            }
+            PointerEvent::PointerDown(_, _) if self.context_menu.is_some() => {
+                let position = event.local_position(ctx);
+                if let Some(entry) = self.context_menu_entry_at(position) {
+                    self.activate_context_menu_entry(entry);
+                }
+                // Clicking anywhere closes the menu.
+                self.context_menu = None;
+                ctx.request_paint_only();
+                ctx.set_handled();
+            }
+            PointerEvent::PointerDown(
+                button @ (PointerButton::X1 | PointerButton::X2),
+                _,
+            ) if self.link_navigation => {
+                self.navigate_history(ctx, *button == PointerButton::X1);
+                ctx.set_handled();
+            }
+            PointerEvent::PointerDown(PointerButton::Secondary, _) => {
+                ctx.request_focus();
+                self.open_context_menu(event.local_position(ctx));
+                ctx.request_paint_only();
+                ctx.set_handled();
+            }
+            PointerEvent::PointerDown(_, _) => {
+                ctx.request_focus();
+                if self.all_selected || self.selection.is_some() {
+                    self.set_selection(None, false);
+                    ctx.request_paint_only();
+                }
+                let position = event.local_position(ctx);
+                if self
+                    .copy_button_rect()
+                    .is_some_and(|rect| rect.contains(position))
+                {
+                    ctx.set_handled();
+                    return;
+                }
+                if self
+                    .permalink_rect()
+                    .is_some_and(|rect| rect.contains(position))
+                {
+                    self.click_permalink(ctx);
+                    ctx.set_handled();
+                    return;
+                }
+                let chevron = {
+                    let global_theme = get_theme();
+                    let widget_theme = self.widget_theme(&global_theme);
+                    let theme = widget_theme.as_ref().unwrap_or(&*global_theme);
+                    self.section_chevron_at(position, theme)
+                };
+                if let Some(heading_index) = chevron {
+                    let collapsed = !self.is_section_collapsed(heading_index);
+                    self.collapse_section(heading_index, collapsed);
+                    ctx.request_paint_only();
+                    ctx.set_handled();
+                    return;
+                }
+                let offset = self.document_position(position).y as f32;
+                if toggle_details_in_flow(&mut self.markdown_layout, offset) {
+                    self.update_pages();
+                    ctx.request_paint_only();
+                    ctx.set_handled();
+                    return;
+                }
+                self.pressed_at = Some(position);
+            }
+            _ => {}
+        }
+        self.notify_scroll_changed();
+        self.report_progress(ctx);
+        self.report_visible_heading(ctx);
+        self.report_selection(ctx);
+        self.report_hovered_link_change(ctx);
+    }
+
+    fn on_text_event(&mut self, ctx: &mut EventCtx, event: &TextEvent) {
+        let TextEvent::KeyboardKey(key_event, modifiers) = event else {
+            return;
+        };
+        if key_event.state != ElementState::Pressed {
+            return;
+        }
+        if key_event.logical_key == Key::Named(NamedKey::Escape)
+            && self.context_menu.take().is_some()
+        {
+            ctx.request_paint_only();
+            ctx.set_handled();
+            return;
+        }
+        if self.link_navigation && modifiers.alt_key() {
+            let back = match key_event.logical_key {
+                Key::Named(NamedKey::ArrowLeft) => Some(true),
+                Key::Named(NamedKey::ArrowRight) => Some(false),
+                _ => None,
+            };
+            if let Some(back) = back {
+                self.navigate_history(ctx, back);
+                ctx.set_handled();
+                return;
+            }
+        }
+        if !self.paginated {
+            return;
+        }
+        match key_event.logical_key {
+            Key::Named(NamedKey::PageDown) => {
+                self.change_page(ctx, self.current_page + 1);
+                ctx.set_handled();
+            }
+            Key::Named(NamedKey::PageUp) => {
+                self.change_page(ctx, self.current_page.saturating_sub(1));
+                ctx.set_handled();
+            }
+            _ => {}
+        }
+        self.notify_scroll_changed();
+        self.report_progress(ctx);
+        self.report_visible_heading(ctx);
+        self.report_selection(ctx);
+        self.report_hovered_link_change(ctx);
+    }
+
+    fn accepts_focus(&self) -> bool {
+        true
+    }
+
+    fn update(&mut self, ctx: &mut masonry::UpdateCtx, event: &masonry::Update) {
+        if let masonry::Update::WidgetAdded = event {
+            // Polls for the images of the first layout.
+            ctx.request_anim_frame();
+        }
+    }
+
+    fn on_anim_frame(&mut self, ctx: &mut masonry::UpdateCtx, _interval: u64) {
+        if self.images.take_finished() {
+            // Decoded images take the size of their file.
+            self.dirty = true;
+            ctx.request_layout();
+        }
+        // Images are requested by the layout, there may be new ones after it.
+        if self.dirty || self.images.is_loading() {
+            ctx.request_anim_frame();
+        }
+        if let Some(timer) = self.copy_feedback_timer {
+            if timer.elapsed() >= COPY_FEEDBACK_DURATION {
+                self.copy_feedback_timer = None;
+                ctx.request_paint_only();
+            } else {
+                ctx.request_anim_frame();
+            }
+        }
+        if let Some((_, shown)) = &self.drop_message {
+            if shown.elapsed() >= DROP_MESSAGE_DURATION {
+                self.drop_message = None;
+                ctx.request_paint_only();
+            } else {
+                ctx.request_anim_frame();
+            }
+        }
+    }
+
+    fn register_children(&mut self, _ctx: &mut masonry::RegisterCtx) {}
+
+    fn compose(&mut self, ctx: &mut masonry::ComposeCtx) {
+        info!("compose called: size: {}, baseline_offset: {}, window_origin: {}, layout_rect: {}", ctx.size(), ctx.baseline_offset(), ctx.window_origin(), ctx.layout_rect());
+    }
+
+    fn layout(
+        &mut self,
+        ctx: &mut masonry::LayoutCtx,
+        bc: &masonry::BoxConstraints,
+    ) -> kurbo::Size {
+        debug!("cool layout");
+        let size = Size::new(
+            render_width(
+                bc.max().width,
+                self.min_render_width,
+                self.max_render_width,
+            ),
+            bc.max().height,
+        );
+        let global_theme = get_theme();
+        let widget_theme = self.widget_theme(&global_theme);
+        let theme = widget_theme.as_ref().unwrap_or(&*global_theme);
+        // TODO: Think about putting the context into the theme??? Or somewhere else???
+        let font_ctx = match &mut self.font_ctx {
+            Some(font_ctx) => font_ctx,
+            None => ctx.text_contexts().0,
+        };
+        let left_gutter = if self.heading_permalinks {
+            permalink_gutter(theme)
+        } else {
+            0.0
+        };
+        let env = LayoutEnv {
+            base_dir: &self.base_dir,
+            renderer: &*self.content_renderer,
+            images: Some(&self.images),
+        };
+        // A different padding changes the wrap width.
+        let padding = theme.content_padding;
+        let padding_changed = padding != self.padding;
+        self.padding = padding;
+        let layout_width =
+            (size.width - left_gutter - padding.horizontal() as f64).max(0.0) as f32;
+        if self.dirty || padding_changed || self.max_advance != size.width {
+            let mut item_index = 0;
+            self.markdown_layout.layout_all(|data| {
+                let start = self.layout_profiling.then(Instant::now);
+                let height = data.layout(
+                    font_ctx,
+                    &mut self.layout_ctx,
+                    layout_width,
+                    theme,
+                    env,
+                    InheritedStyle::default(),
+                );
+                if let Some(start) = start {
+                    log_layout_time(item_index, data, start);
+                }
+                item_index += 1;
+                height
+            });
+            self.dirty_items.clear();
+            for (label, layout) in [
+                ("Copy", &mut self.copy_label_layout),
+                ("Copied!", &mut self.copied_label_layout),
+            ] {
+                let mut builder = text_to_builder(
+                    label,
+                    &[],
+                    theme,
+                    font_ctx,
+                    &mut self.layout_ctx,
+                );
+                *layout = builder.build(label);
+                layout.break_all_lines(None);
+            }
+            self.context_menu_labels = ContextMenuEntry::LABELS
+                .iter()
+                .map(|label| {
+                    let mut builder = text_to_builder(
+                        label,
+                        &[],
+                        theme,
+                        font_ctx,
+                        &mut self.layout_ctx,
+                    );
+                    builder.push_default(StyleProperty::Brush(MarkdownBrush::new(
+                        theme.context_menu_text_color,
+                    )));
+                    let mut layout = builder.build(label);
+                    layout.break_all_lines(None);
+                    layout
+                })
+                .collect();
+        } else {
+            for index in std::mem::take(&mut self.dirty_items) {
+                if index >= self.markdown_layout.len() {
+                    continue;
+                }
+                // Dropping the item recomputes the offsets of the following
+                // ones.
+                let start = self.layout_profiling.then(Instant::now);
+                let mut item = self.markdown_layout.get_mutable(index);
+                let height = item.layout(
+                    font_ctx,
+                    &mut self.layout_ctx,
+                    layout_width,
+                    theme,
+                    env,
+                    InheritedStyle::default(),
+                );
+                item.set_custom_height(height);
+                if let Some(start) = start {
+                    log_layout_time(index, &item, start);
+                }
+            }
+        }
+
+        let size = if self.shrink_to_fit {
+            Size::new(
+                size.width,
+                (self.markdown_layout.height() as f64 + padding.vertical() as f64)
+                    .min(size.height),
+            )
+        } else {
+            size
+        };
+
+        self.debug_labels.clear();
+        if self.debug_overlay {
+            for kind in BlockKind::ALL {
+                let label = format!("{kind:?}");
+                let mut builder = text_to_builder(
+                    &label,
+                    &[],
+                    theme,
+                    font_ctx,
+                    &mut self.layout_ctx,
+                );
+                builder.push_default(StyleProperty::FontSize(
+                    theme.text_size as f32 * 0.6,
+                ));
+                builder.push_default(StyleProperty::Brush(MarkdownBrush::new(
+                    debug_overlay_color(kind),
+                )));
+                let mut layout = builder.build(&label);
+                layout.break_all_lines(None);
+                self.debug_labels.push((kind, layout));
+            }
+        }
+        if let Some((message, _)) = &self.drop_message {
+            let mut builder =
+                text_to_builder(message, &[], theme, font_ctx, &mut self.layout_ctx);
+            self.drop_message_layout = builder.build(message);
+            self.drop_message_layout
+                .break_all_lines(Some((size.width * 0.8) as f32));
+        }
+        self.max_advance = size.width;
+        self.left_gutter = left_gutter;
+        self.viewport_height = size.height;
+        self.dirty = false;
+        self.update_pages();
+        if let Some(item_index) = self.pending_scroll_item.take() {
+            self.scroll_item_into_view(item_index);
+        }
+        if let Some(anchor) = self.pending_scroll_anchor.take() {
+            self.scroll_to_anchor(&anchor);
+        }
+        if let Some(percentage) = self.pending_scroll_percentage.take() {
+            self.apply_scroll_percentage(percentage);
+        }
+        if let Some(anchor) = self.pending_scroll_state.take() {
+            self.apply_scroll_anchor(&anchor);
+        }
+        self.notify_scroll_changed();
+        for observer in self.observers.iter_mut() {
+            observer.on_layout_completed();
+        }
+        info!("size: {}", size);
+        bc.constrain(size)
+    }
+
+    fn paint(&mut self, ctx: &mut masonry::PaintCtx, scene: &mut vello::Scene) {
+        self.paint_call_count += 1;
+        let visible_height = self.visible_height(ctx.size().height);
+        let global_theme = get_theme();
+        let widget_theme = self.widget_theme(&global_theme);
+        let theme = widget_theme.as_ref().unwrap_or(&*global_theme);
+        draw_background(scene, ctx.size(), theme);
+        scene.push_layer(
+            BlendMode::default(),
+            1.,
+            Affine::IDENTITY,
+            &Rect::new(0.0, 0.0, ctx.size().width, visible_height),
+        );
+        scene.push_layer(
+            BlendMode::default(),
+            1.,
+            Affine::IDENTITY,
+            &self.document_clip(ctx.size()),
+        );
+        // The part of the document shown, the top padding scrolls with it.
+        let origin = self.content_origin();
+        let top = self.scroll.y - origin.y;
+        let source_rect = Rect::new(0.0, top, 0.0, top + visible_height);
+        draw_flow(
+            scene,
+            &self.markdown_layout,
+            Vec2::new(origin.x, -top),
+            &source_rect,
+            theme,
+            &*self.content_renderer,
+        );
+        self.draw_selection(scene, theme);
+        scene.pop_layer();
+        self.draw_section_chevrons(scene, theme);
+        self.draw_permalink(scene, theme);
+        self.draw_copy_button(scene, theme);
+        if self.show_progress_bar {
+            self.draw_progress_bar(scene, theme);
+        }
+        self.draw_context_menu(scene, theme);
+        self.draw_drop_message(scene, ctx.size(), theme);
+        if self.debug_overlay {
+            self.draw_debug_overlay(scene, visible_height, theme);
+        }
+        scene.pop_layer();
+    }
+
+    fn accessibility_role(&self) -> accesskit::Role {
+        Role::Document
+    }
+
+    fn on_access_event(&mut self, ctx: &mut EventCtx, event: &AccessEvent) {
+        let Some(scrolled) =
+            self.perform_access_action(NodeId::from(event.target), event.action)
+        else {
+            return;
+        };
+        if scrolled {
+            ctx.request_paint_only();
+        }
+        ctx.set_handled();
+        self.notify_scroll_changed();
+        self.report_progress(ctx);
+        self.report_visible_heading(ctx);
+    }
+
+    fn accessibility(
+        &mut self,
+        ctx: &mut masonry::AccessCtx,
+        node: &mut accesskit::Node,
+    ) {
+        self.update_item_node_ids();
+        self.navigation_accessibility(ctx, node);
+        for (index, element) in self.markdown_layout.iter().enumerate() {
+            if element.hidden {
+                continue;
+            }
+            let id = self.item_nodes[index];
+            let mut child = accesskit::Node::new(element.data.accessibility_role());
+            if let MarkdownContent::Header { level, .. } = &element.data {
+                child.set_level(*level as usize);
+            }
+            if let MarkdownContent::Image {
+                alt_text, title, ..
+            } = &element.data
+            {
+                child.set_label(alt_text.clone());
+                if let Some(title) = title {
+                    child.set_description(title.clone());
+                }
+            } else {
+                let mut label = String::new();
+                element.data.write_plain_text(&mut label);
+                child.set_label(label);
+            }
+            let origin = self.content_origin();
+            let y0 = origin.y + element.offset as f64 - self.scroll.y;
+            child.set_bounds(accesskit::Rect {
+                x0: origin.x - self.scroll.x,
+                y0,
+                x1: origin.x + element.data.width() as f64 - self.scroll.x,
+                y1: y0 + element.height as f64,
+            });
+            ctx.tree_update.nodes.push((id, child));
+            node.push_child(id);
+        }
+    }
+
+    fn children_ids(&self) -> SmallVec<[masonry::WidgetId; 16]> {
+        SmallVec::new()
+    }
+}
+
+///// Highlight the text in a richtext builder like it was a markdown codeblock
+//pub fn highlight_as_code(
+//    attr_list: &mut AttrsList,
+//    default_attrs: Attrs,
+//    language: Option<LapceLanguage>,
+//    text: &str,
+//    start_offset: usize,
+//    config: &LapceConfig,
+//) {
+//    let syntax = language.map(Syntax::from_language);
+//
+//    let styles = syntax
+//        .map(|mut syntax| {
+//            syntax.parse(0, Rope::from(text), None);
+//            syntax.styles
+//        })
+//        .unwrap_or(None);
+//
+//    if let Some(styles) = styles {
+//        for (range, style) in styles.iter() {
+//            if let Some(color) = style
+//                .fg_color
+//                .as_ref()
+//                .and_then(|fg| config.style_color(fg))
+//            {
+//                attr_list.add_span(
+//                    start_offset + range.start..start_offset + range.end,
+//                    default_attrs.color(color),
+//                );
+//            }
+//        }
+//    }
+//}
+
+/// Where a `MarkdownView` gets its document from.
+enum MarkdownSource {
+    File(PathBuf),
+    Content(Arc<str>),
+}
+
+pub struct MarkdownView<F> {
+    source: MarkdownSource,
+    selection_color: Option<Color>,
+    paginated: bool,
+    alpha_list_marker: Option<bool>,
+    list_style: Option<String>,
+    shrink_to_fit: bool,
+    /// Bounds of the width the content is laid out in, whatever width the
+    /// parent offers.
+    min_render_width: f32,
+    max_render_width: f32,
+    progress_bar: bool,
+    content_renderer: Option<Arc<dyn ContentRenderer>>,
+    enlarge_images_on_click: bool,
+    link_policy: Option<LinkPolicy>,
+    link_navigation: bool,
+    scroll_propagation: ScrollPropagation,
+    slug_generator: Option<SlugGenerator>,
+    wikilinks: bool,
+    soft_breaks_as_hard: bool,
+    view_source: bool,
+    theme_mode: Option<ThemeMode>,
+    syntax_highlighter: Option<Arc<dyn SyntaxHighlighter>>,
+    scale_factor: f64,
+    /// Whether the permalinks are shown and copied to the clipboard.
+    heading_permalinks: (bool, bool),
+    scroll_request: Option<(usize, u64)>,
+    internal_anchor_links: bool,
+    on_link_click: Option<LinkClickHandler>,
+    on_action: Option<F>,
+}
+
+pub fn markdown_view<State, Action>(
+    path: PathBuf,
+) -> MarkdownView<fn(&mut State, MarkdownAction) -> Action> {
+    new_markdown_view(MarkdownSource::File(path))
+}
+
+/// Shows `content` instead of a file. Keep the content in an `Arc<str>` and
+/// pass clones of it, unchanged content is then recognized without comparing
+/// the text.
+pub fn markdown_content_view<State, Action>(
+    content: impl Into<Arc<str>>,
+) -> MarkdownView<fn(&mut State, MarkdownAction) -> Action> {
+    new_markdown_view(MarkdownSource::Content(content.into()))
+}
+
+fn new_markdown_view<State, Action>(
+    source: MarkdownSource,
+) -> MarkdownView<fn(&mut State, MarkdownAction) -> Action> {
+    MarkdownView {
+        source,
+        selection_color: None,
+        paginated: false,
+        alpha_list_marker: None,
+        list_style: None,
+        shrink_to_fit: false,
+        min_render_width: 0.0,
+        max_render_width: f32::INFINITY,
+        progress_bar: false,
+        content_renderer: None,
+        enlarge_images_on_click: false,
+        link_policy: None,
+        link_navigation: false,
+        scroll_propagation: ScrollPropagation::Never,
+        slug_generator: None,
+        wikilinks: false,
+        soft_breaks_as_hard: false,
+        view_source: false,
+        theme_mode: None,
+        syntax_highlighter: None,
+        scale_factor: 1.0,
+        heading_permalinks: (false, false),
+        scroll_request: None,
+        internal_anchor_links: true,
+        on_link_click: None,
+        on_action: None,
+    }
+}
+
+impl<F> MarkdownView<F> {
+    /// Calls `on_action` with the `MarkdownAction`s of the widget.
+    pub fn on_action<State, Action, G>(self, on_action: G) -> MarkdownView<G>
+    where
+        G: Fn(&mut State, MarkdownAction) -> Action + Send + Sync + 'static,
+    {
+        MarkdownView {
+            source: self.source,
+            selection_color: self.selection_color,
+            paginated: self.paginated,
+            alpha_list_marker: self.alpha_list_marker,
+            list_style: self.list_style,
+            shrink_to_fit: self.shrink_to_fit,
+            min_render_width: self.min_render_width,
+            max_render_width: self.max_render_width,
+            progress_bar: self.progress_bar,
+            content_renderer: self.content_renderer,
+            enlarge_images_on_click: self.enlarge_images_on_click,
+            link_policy: self.link_policy,
+            link_navigation: self.link_navigation,
+            scroll_propagation: self.scroll_propagation,
+            slug_generator: self.slug_generator,
+            wikilinks: self.wikilinks,
+            soft_breaks_as_hard: self.soft_breaks_as_hard,
+            view_source: self.view_source,
+            theme_mode: self.theme_mode,
+            syntax_highlighter: self.syntax_highlighter,
+            scale_factor: self.scale_factor,
+            heading_permalinks: self.heading_permalinks,
+            scroll_request: self.scroll_request,
+            internal_anchor_links: self.internal_anchor_links,
+            on_link_click: self.on_link_click,
+            on_action: Some(on_action),
+        }
+    }
+
+    /// Decides what happens to clicked links, `#anchor` links included,
+    /// before they scroll to their heading or reach `on_action` as
+    /// `MarkdownAction::LinkClicked`, see `LinkDecision`.
+    pub fn on_link_click(
+        mut self,
+        on_link_click: impl Fn(&str, LinkKind) -> LinkDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.on_link_click = Some(Arc::new(on_link_click));
+        self
+    }
+
+    /// Scrolls to the heading of clicked `#anchor` links, see
+    /// `MarkdowWidget::with_internal_anchor_links`.
+    pub fn internal_anchor_links(mut self, internal: bool) -> Self {
+        self.internal_anchor_links = internal;
+        self
+    }
+
+    /// Scrolls the top-level item at `item_index` into view every time
+    /// `request_id` changes.
+    pub fn scroll_to(mut self, item_index: usize, request_id: u64) -> Self {
+        self.scroll_request = Some((item_index, request_id));
+        self
+    }
+
+    /// Overrides the selection highlight color of the theme.
+    pub fn selection_color(mut self, color: Color) -> Self {
+        self.selection_color = Some(color);
+        self
+    }
+
+    /// Lays out and paints the blocks with `renderer`, see
+    /// `MarkdowWidget::with_content_renderer`.
+    pub fn content_renderer(mut self, renderer: Arc<dyn ContentRenderer>) -> Self {
+        self.content_renderer = Some(renderer);
+        self
+    }
+
+    /// Renders ordered lists with letters instead of numbers.
+    pub fn alpha_list_marker(mut self, uppercase: bool) -> Self {
+        self.alpha_list_marker = Some(uppercase);
+        self
+    }
+
+    /// Renders ordered lists with the markers of a CSS `list-style-type`,
+    /// like `upper-roman` or `lower-alpha`.
+    pub fn list_style(mut self, style: impl Into<String>) -> Self {
+        self.list_style = Some(style.into());
+        self
+    }
+
+    /// Paints a reading progress bar at the top edge.
+    pub fn progress_bar(mut self, show: bool) -> Self {
+        self.progress_bar = show;
+        self
+    }
+
+    /// Shows a link glyph next to hovered headings, clicking it submits
+    /// `MarkdownAction::HeadingLinkClicked`.
+    pub fn heading_permalinks(
+        mut self,
+        show: bool,
+        copy_to_clipboard: bool,
+    ) -> Self {
+        self.heading_permalinks = (show, copy_to_clipboard);
+        self
+    }
+
+    /// Replaces `slugify` for generating the heading anchor ids.
+    pub fn slug_generator(mut self, generator: SlugGenerator) -> Self {
+        self.slug_generator = Some(generator);
+        self
+    }
+
+    /// Scale factor of the window, see `MarkdowWidget::with_scale_factor`.
+    pub fn scale_factor(mut self, scale_factor: f64) -> Self {
+        self.scale_factor = scale_factor;
+        self
+    }
+
+    /// Parses `[[Page Name]]` wikilinks, see `MarkdownAction::LinkClicked`.
+    pub fn wikilinks(mut self, wikilinks: bool) -> Self {
+        self.wikilinks = wikilinks;
+        self
+    }
+
+    /// Keeps the line breaks of the source, for chat messages and notes.
+    pub fn soft_breaks_as_hard(mut self, hard: bool) -> Self {
+        self.soft_breaks_as_hard = hard;
+        self
+    }
+
+    /// Shows the raw markdown instead of the rendered document.
+    pub fn view_source(mut self, view_source: bool) -> Self {
+        self.view_source = view_source;
+        self
+    }
+
+    /// Uses the built-in light or dark theme, see
+    /// `MarkdowWidget::set_theme_mode`.
+    pub fn theme_mode(mut self, mode: ThemeMode) -> Self {
+        self.theme_mode = Some(mode);
+        self
+    }
+
+    /// Highlights code blocks with `highlighter`, see
+    /// `MarkdowWidget::with_syntax_highlighter`.
+    pub fn syntax_highlighter(
+        mut self,
+        highlighter: Arc<dyn SyntaxHighlighter>,
+    ) -> Self {
+        self.syntax_highlighter = Some(highlighter);
+        self
+    }
+
+    /// Toggles images between fit-to-width and natural size on click.
+    pub fn enlarge_images_on_click(mut self, enlarge: bool) -> Self {
+        self.enlarge_images_on_click = enlarge;
+        self
+    }
+
+    /// Opens relative links to markdown files in the widget, see
+    /// `MarkdowWidget::with_link_navigation`.
+    pub fn link_navigation(mut self, navigation: bool) -> Self {
+        self.link_navigation = navigation;
+        self
+    }
+
+    /// Decides which clicked links are activated, see `LinkPolicy`.
+    pub fn link_policy(mut self, policy: LinkPolicy) -> Self {
+        self.link_policy = Some(policy);
+        self
+    }
+
+    /// Caps the width the content is laid out in.
+    pub fn max_render_width(mut self, width: f32) -> Self {
+        self.max_render_width = width;
+        self
+    }
+
+    /// Lays the content out at least this wide.
+    pub fn min_render_width(mut self, width: f32) -> Self {
+        self.min_render_width = width;
+        self
+    }
+
+    /// Lets mouse wheel events reach the parents, see `ScrollPropagation`.
+    pub fn scroll_propagation(mut self, propagation: ScrollPropagation) -> Self {
+        self.scroll_propagation = propagation;
+        self
+    }
+
+    /// Makes the widget only as tall as its content.
+    pub fn shrink_to_fit(mut self, shrink_to_fit: bool) -> Self {
+        self.shrink_to_fit = shrink_to_fit;
+        self
+    }
+
+    /// Shows the document as pages instead of continuous scrolling.
+    pub fn paginated(mut self, paginated: bool) -> Self {
+        self.paginated = paginated;
+        self
+    }
+}
+
+impl<F> ViewMarker for MarkdownView<F> {}
+impl<F, State, Action> View<State, Action, ViewCtx> for MarkdownView<F>
+where
+    State: 'static,
+    Action: 'static,
+    F: Fn(&mut State, MarkdownAction) -> Action + Send + Sync + 'static,
+{
+    type Element = Pod<MarkdowWidget>;
+
+    type ViewState = ();
+
+    fn build(&self, ctx: &mut ViewCtx) -> (Self::Element, Self::ViewState) {
+        debug!("CodeView::build");
+        ctx.with_leaf_action_widget(|ctx| {
+            let widget = match &self.source {
+                MarkdownSource::File(path) => MarkdowWidget::new(path),
+                MarkdownSource::Content(content) => {
+                    MarkdowWidget::from_source(content.clone(), PathBuf::new())
+                }
+            };
+            let mut widget = widget
+                .with_wikilinks(self.wikilinks)
+                .with_soft_breaks_as_hard(self.soft_breaks_as_hard)
+                .with_view_source(self.view_source)
+                .with_scale_factor(self.scale_factor)
+                .with_paginated(self.paginated)
+                .with_shrink_to_fit(self.shrink_to_fit)
+                .with_min_render_width(self.min_render_width)
+                .with_max_render_width(self.max_render_width)
+                .with_progress_bar(self.progress_bar)
+                .with_enlarge_images_on_click(self.enlarge_images_on_click)
+                .with_internal_anchor_links(self.internal_anchor_links)
+                .with_link_navigation(self.link_navigation)
+                .with_scroll_propagation(self.scroll_propagation);
+            if let Some(uppercase) = self.alpha_list_marker {
+                widget = widget.with_alpha_list_marker(uppercase);
+            }
+            if let Some(renderer) = &self.content_renderer {
+                widget = widget.with_content_renderer(renderer.clone());
+            }
+            if let Some(style) = &self.list_style {
+                widget = widget.with_list_style(style);
+            }
+            if let Some(mode) = self.theme_mode {
+                widget = widget.with_theme_mode(mode);
+            }
+            if let Some(policy) = &self.link_policy {
+                widget = widget.with_link_policy(policy.clone());
+            }
+            if let Some(highlighter) = &self.syntax_highlighter {
+                widget = widget.with_syntax_highlighter(highlighter.clone());
+            }
+            if let Some(handler) = &self.on_link_click {
+                widget = widget.with_link_click_handler(handler.clone());
+            }
+            if let Some(color) = self.selection_color {
+                widget = widget.with_selection_color(color);
+            }
+            if let Some((item_index, _)) = self.scroll_request {
+                widget = widget.with_scroll_to_item(item_index);
+            }
+            if let Some(generator) = self.slug_generator {
+                widget = widget.with_slug_generator(generator);
+            }
+            let (show, copy_to_clipboard) = self.heading_permalinks;
+            widget = widget.with_heading_permalinks(show, copy_to_clipboard);
+            ctx.new_pod(widget)
+        })
+    }
+
+    fn rebuild(
+        &self,
+        prev: &Self,
+        _view_state: &mut Self::ViewState,
+        _ctx: &mut ViewCtx,
+        mut element: xilem::core::Mut<Self::Element>,
+    ) {
+        debug!("CodeView::rebuild");
+        if let (MarkdownSource::Content(prev), MarkdownSource::Content(content)) =
+            (&prev.source, &self.source)
+        {
+            if !Arc::ptr_eq(prev, content) {
+                // Streamed content only grows.
+                match content.strip_prefix(&**prev) {
+                    Some(added) if !prev.is_empty() => {
+                        MarkdowWidget::append_markdown(&mut element, added)
+                    }
+                    _ => MarkdowWidget::set_source(&mut element, content.clone()),
+                }
+            }
+        }
+        if prev.selection_color != self.selection_color {
+            MarkdowWidget::set_selection_color(
+                &mut element,
+                self.selection_color
+                    .unwrap_or_else(|| get_theme().selection_color),
+            );
+        }
+        if prev.scale_factor != self.scale_factor {
+            MarkdowWidget::set_scale_factor(&mut element, self.scale_factor);
+        }
+        if prev.wikilinks != self.wikilinks {
+            MarkdowWidget::set_wikilinks(&mut element, self.wikilinks);
+        }
+        let renderer_changed = match (&prev.content_renderer, &self.content_renderer)
+        {
+            (Some(prev), Some(new)) => !Arc::ptr_eq(prev, new),
+            (prev, new) => prev.is_some() != new.is_some(),
+        };
+        if renderer_changed {
+            MarkdowWidget::set_content_renderer(
+                &mut element,
+                self.content_renderer
+                    .clone()
+                    .unwrap_or_else(|| Arc::new(DefaultRenderer)),
+            );
+        }
+        if let Some(mode) = self
+            .theme_mode
+            .filter(|_| prev.theme_mode != self.theme_mode)
+        {
+            MarkdowWidget::set_theme_mode(&mut element, mode);
+        }
+        let highlighter_changed =
+            match (&prev.syntax_highlighter, &self.syntax_highlighter) {
+                (Some(prev), Some(new)) => !Arc::ptr_eq(prev, new),
+                (prev, new) => prev.is_some() != new.is_some(),
+            };
+        if highlighter_changed {
+            MarkdowWidget::set_syntax_highlighter(
+                &mut element,
+                self.syntax_highlighter.clone(),
+            );
+        }
+        let handler_changed = match (&prev.on_link_click, &self.on_link_click) {
+            (Some(prev), Some(new)) => !Arc::ptr_eq(prev, new),
+            (prev, new) => prev.is_some() != new.is_some(),
+        };
+        if handler_changed {
+            MarkdowWidget::set_link_click_handler(
+                &mut element,
+                self.on_link_click.clone(),
+            );
+        }
+        if prev.view_source != self.view_source {
+            MarkdowWidget::set_view_source(&mut element, self.view_source);
+        }
+        if prev.soft_breaks_as_hard != self.soft_breaks_as_hard {
+            MarkdowWidget::set_soft_breaks_as_hard(
+                &mut element,
+                self.soft_breaks_as_hard,
+            );
+        }
+        if prev.alpha_list_marker != self.alpha_list_marker {
+            MarkdowWidget::set_alpha_list_marker(
+                &mut element,
+                self.alpha_list_marker,
+            );
+        }
+        if prev.list_style != self.list_style {
+            MarkdowWidget::set_list_style(&mut element, self.list_style.clone());
+        }
+        if prev.progress_bar != self.progress_bar {
+            MarkdowWidget::set_progress_bar(&mut element, self.progress_bar);
+        }
+        let slug_generator_changed = match (prev.slug_generator, self.slug_generator)
+        {
+            (Some(prev), Some(new)) => !std::ptr::fn_addr_eq(prev, new),
+            (prev, new) => prev.is_some() != new.is_some(),
+        };
+        if slug_generator_changed {
+            MarkdowWidget::set_slug_generator(
+                &mut element,
+                self.slug_generator.unwrap_or(slugify),
+            );
+        }
+        if prev.heading_permalinks != self.heading_permalinks {
+            MarkdowWidget::set_heading_permalinks(
+                &mut element,
+                self.heading_permalinks.0,
+                self.heading_permalinks.1,
+            );
+        }
+        if prev.enlarge_images_on_click != self.enlarge_images_on_click {
+            MarkdowWidget::set_enlarge_images_on_click(
+                &mut element,
+                self.enlarge_images_on_click,
+            );
+        }
+        if prev.link_policy != self.link_policy {
+            MarkdowWidget::set_link_policy(
+                &mut element,
+                self.link_policy.clone().unwrap_or_default(),
+            );
+        }
+        if prev.link_navigation != self.link_navigation {
+            MarkdowWidget::set_link_navigation(&mut element, self.link_navigation);
+        }
+        if prev.internal_anchor_links != self.internal_anchor_links {
+            MarkdowWidget::set_internal_anchor_links(
+                &mut element,
+                self.internal_anchor_links,
+            );
+        }
+        if prev.scroll_propagation != self.scroll_propagation {
+            MarkdowWidget::set_scroll_propagation(
+                &mut element,
+                self.scroll_propagation,
+            );
+        }
+        if prev.shrink_to_fit != self.shrink_to_fit {
+            MarkdowWidget::set_shrink_to_fit(&mut element, self.shrink_to_fit);
+        }
+        if prev.min_render_width != self.min_render_width {
+            MarkdowWidget::set_min_render_width(&mut element, self.min_render_width);
+        }
+        if prev.max_render_width != self.max_render_width {
+            MarkdowWidget::set_max_render_width(&mut element, self.max_render_width);
+        }
+        if prev.paginated != self.paginated {
+            MarkdowWidget::set_paginated(&mut element, self.paginated);
+        }
+        if prev.scroll_request != self.scroll_request {
+            if let Some((item_index, _)) = self.scroll_request {
+                MarkdowWidget::scroll_into_view(&mut element, item_index);
+            }
+        }
+    }
+
+    fn teardown(
+        &self,
+        _view_state: &mut Self::ViewState,
+        ctx: &mut ViewCtx,
+        element: xilem::core::Mut<Self::Element>,
+    ) {
+        debug!("CodeView::teardown");
+        ctx.teardown_leaf(element);
+    }
+
+    fn message(
+        &self,
+        _view_state: &mut Self::ViewState,
+        _id_path: &[xilem::core::ViewId],
+        message: Box<dyn Message>,
+        app_state: &mut State,
+    ) -> xilem::core::MessageResult<Action, Box<dyn Message>> {
+        debug!("CodeView::message");
+        match message.downcast::<masonry::Action>() {
+            Ok(action) => {
+                if let masonry::Action::Other(other) = &*action {
+                    if let Some(action) = other.downcast_ref::<MarkdownAction>() {
+                        debug!("MarkdownView action: {action:?}");
+                        return match &self.on_action {
+                            Some(on_action) => MessageResult::Action(on_action(
+                                app_state,
+                                action.clone(),
+                            )),
+                            None => MessageResult::Nop,
+                        };
+                    }
+                }
+                tracing::error!(
+                    "Wrong action type in CodeView::message: {action:?}"
+                );
+                MessageResult::Stale(action)
+            }
+            Err(message) => {
+                tracing::error!(
+                    "Wrong message type in Button::message: {message:?}"
+                );
+                MessageResult::Stale(message)
+            }
+        }
+    }
+}
+
+/// Layout and assertions for parsed documents, indices are of top-level
+/// items.
+#[cfg(test)]
+mod test_helpers {
+    use std::path::Path;
+
+    use parley::{FontContext, LayoutContext};
+    use pulldown_cmark::HeadingLevel;
+
+    use super::{
+        parse_markdown, InheritedStyle, LayoutEnv, MarkdownContent, MarkerKind,
+    };
+    use crate::{layout_flow::LayoutFlow, theme::Theme};
+
+    /// Lays `flow` out `width` wide with `theme`, like the widget does.
+    pub fn lay_out(
+        flow: &mut LayoutFlow<MarkdownContent>,
+        width: f32,
+        theme: &Theme,
+    ) {
+        let mut font_ctx = FontContext::new();
+        let mut layout_ctx = LayoutContext::new();
+        flow.layout_all(|data| {
+            data.layout(
+                &mut font_ctx,
+                &mut layout_ctx,
+                width,
+                theme,
+                LayoutEnv::new(Path::new("")),
+                InheritedStyle::default(),
+            )
+        });
+    }
+
+    /// Parses `markdown` and lays it out `width` wide with `theme`.
+    pub fn laid_out(
+        markdown: &str,
+        width: f32,
+        theme: &Theme,
+    ) -> LayoutFlow<MarkdownContent> {
+        let mut flow = parse_markdown(markdown);
+        lay_out(&mut flow, width, theme);
+        flow
+    }
+
+    pub fn item(
+        flow: &LayoutFlow<MarkdownContent>,
+        index: usize,
+    ) -> &MarkdownContent {
+        match flow.get(index) {
+            Some(element) => &element.data,
+            None => panic!("No item {index}, the flow has {} items", flow.len()),
+        }
+    }
+
+    pub fn assert_paragraph_text(
+        flow: &LayoutFlow<MarkdownContent>,
+        index: usize,
+        expected: &str,
+    ) {
+        match item(flow, index) {
+            MarkdownContent::Paragraph { text, .. } => {
+                assert_eq!(text, expected, "paragraph {index}")
+            }
+            _ => panic!("Item {index} is not a paragraph"),
+        }
+    }
+
+    pub fn assert_header(
+        flow: &LayoutFlow<MarkdownContent>,
+        index: usize,
+        level: HeadingLevel,
+        expected: &str,
+    ) {
+        match item(flow, index) {
+            MarkdownContent::Header {
+                level: header_level,
+                text,
+                ..
+            } => {
+                assert_eq!(*header_level, level, "header {index}");
+                assert_eq!(text, expected, "header {index}");
+            }
+            _ => panic!("Item {index} is not a header"),
+        }
+    }
+
+    pub fn assert_list_len(
+        flow: &LayoutFlow<MarkdownContent>,
+        index: usize,
+        len: usize,
+    ) {
+        match item(flow, index) {
+            MarkdownContent::List { list } => {
+                assert_eq!(list.list.len(), len, "list {index}")
+            }
+            _ => panic!("Item {index} is not a list"),
+        }
+    }
+
+    /// Counts the markers of `kind` in the paragraph or header at `index`.
+    pub fn count_markers(
+        flow: &LayoutFlow<MarkdownContent>,
+        index: usize,
+        kind: MarkerKind,
+    ) -> usize {
+        match item(flow, index) {
+            MarkdownContent::Paragraph { markers, .. }
+            | MarkdownContent::Header { markers, .. } => {
+                markers.iter().filter(|marker| marker.kind == kind).count()
+            }
+            _ => panic!("Item {index} has no markers"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        cmp::Reverse,
+        collections::BTreeSet,
+        ops::Range,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    use accesskit::NodeId;
+    use kurbo::{Point, Rect, Shape, Size, Vec2};
+    use masonry::{testing::TestHarness, Action, PointerButton, WidgetId};
+    use parley::{
+        FontContext, FontSettings, FontWeight, Layout, LayoutContext,
+        PositionedLayoutItem, StyleProperty,
+    };
+    use peniko::Color;
+    use pulldown_cmark::HeadingLevel;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use unicode_segmentation::UnicodeSegmentation;
+    use vello::Scene;
+
+    use super::{
+        alpha_label, anchor_item_index, bind_last_word, build_code_layout,
+        code_text_offset, contains_code_block, dash_segments, debug_overlay_color,
+        decoration_center, decoration_thickness, details_indentation,
+        draw_background, expand_tabs, first_chars_range, first_line_properties,
+        flow_stats, flow_to_plain_text, for_each_image_uri, hit_test_document,
+        hit_test_flow, hyphenate_soft_breaks, image_border, image_frame,
+        image_size_from_header, image_uri_to_path, is_markdown_file,
+        map_char_offset, merge_adjacent_markers, normalize_markers, parse_markdown,
+        parse_markdown_with, quote_indentation, reading_progress, render_width,
+        resolve_image_uri, resolve_url, scroll_for_percentage, slugify,
+        soft_hyphen_breaks, split_markers_at, split_tall_pages, stable_prefix_len,
+        table_of_contents,
+        test_helpers::{
+            assert_header, assert_list_len, assert_paragraph_text, count_markers,
+            item, laid_out, lay_out,
+        },
+        text_to_builder, to_roman, top_level_block_ranges, wavy_amplitude,
+        wavy_line, widget_to_document, BlockKind, ContentPos, ContextMenuEntry,
+        GlyphMark, ImageCache, ImageLoad, InheritedStyle, LayoutEnv,
+        LinkClickHandler, LinkDecision, LinkKind, LinkPolicy, LinkScheme,
+        LinkTarget, List, ListMarker, MarkdowWidget, MarkdownAction, MarkdownBrush,
+        MarkdownContent, MarkdownDocument, MarkdownObserver, MarkdownViewState,
+        MarkerKind, ParseOptions, PendingImage, ScrollAnchor, ScrollPropagation,
+        SourceText, StatsOptions, TextMarker,
+    };
+    use crate::{
+        content_renderer::ContentRenderer,
+        highlight::{HighlightStyle, SyntaxHighlighter},
+        layout_flow::{LayoutData, LayoutFlow},
+        pdf::PageOptions,
+        theme::{
+            get_theme, FirstLineStyle, FontVariant, ImageAlignment, LineStyle,
+            Padding, Theme, ThemeMode, TokenClass,
+        },
+    };
+
+    #[test]
+    fn text_is_styled_by_the_given_theme() {
+        let global = get_theme().clone();
+        let theme = Theme {
+            text_size: global.text_size * 2,
+            text_color: Color::from_rgba8(0x24, 0x29, 0x2e, 0xff),
+            ..global.clone()
+        };
+        let small = laid_out("Some text", 300.0, &global);
+        let flow = laid_out("Some text", 300.0, &theme);
+        assert!(flow.height() > small.height() * 1.5);
+        let Some(MarkdownContent::Paragraph { text_layout, .. }) =
+            flow.get(0).map(|element| &element.data)
+        else {
+            panic!("expected a paragraph");
+        };
+        for line in text_layout.lines() {
+            for item in line.items() {
+                if let PositionedLayoutItem::GlyphRun(run) = item {
+                    assert_eq!(run.style().brush.0, theme.text_color);
+                    assert_eq!(run.run().font_size(), theme.text_size as f32);
+                }
+            }
+        }
+    }
+
+    /// Returns the text and markers of the first paragraph in `markdown`.
+    fn first_paragraph(markdown: &str) -> (String, Vec<(usize, usize, MarkerKind)>) {
+        let flow = parse_markdown(markdown);
+        for element in flow.iter() {
+            if let MarkdownContent::Paragraph { text, markers, .. } = &element.data {
+                let markers = markers
+                    .iter()
+                    .map(|m| (m.range.start, m.range.end, m.kind.clone()))
+                    .collect();
+                return (text.clone(), markers);
+            }
+        }
+        panic!("No paragraph in {markdown:?}");
+    }
+
+    #[test]
+    fn markdown_files_can_be_dropped() {
+        assert!(is_markdown_file(Path::new("/notes/README.md")));
+        assert!(is_markdown_file(Path::new("notes.MARKDOWN")));
+        assert!(is_markdown_file(Path::new("todo.txt")));
+        assert!(!is_markdown_file(Path::new("image.png")));
+        assert!(!is_markdown_file(Path::new("Makefile")));
+        assert!(!is_markdown_file(Path::new(".md")));
+    }
+
+    #[test]
+    fn relative_markdown_links_navigate_with_history() {
+        let dir = std::env::temp_dir()
+            .join(format!("wrenched-navigation-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("chapters")).unwrap();
+        std::fs::write(dir.join("index.md"), "[Next](chapters/two.md#second)")
+            .unwrap();
+        std::fs::write(
+            dir.join("chapters/two.md"),
+            "# First\n\ntext\n\n# Second\n\n[Back](../index.md)",
+        )
+        .unwrap();
+
+        let mut widget =
+            MarkdowWidget::new(dir.join("index.md")).with_link_navigation(true);
+        assert!(widget.follow_link("image.png").is_none());
+        assert!(!widget.can_go_back());
+
+        let path = widget
+            .follow_link("chapters/two.md#second")
+            .unwrap()
+            .unwrap();
+        assert_eq!(path, dir.join("chapters/two.md"));
+        assert_eq!(widget.base_dir, dir.join("chapters"));
+        assert_eq!(widget.pending_scroll_anchor.as_deref(), Some("second"));
+        assert!(widget.can_go_back());
+
+        // Broken targets keep the document and the history.
+        assert!(widget.follow_link("missing.md").unwrap().is_err());
+        assert_eq!(
+            widget.file_path.as_deref(),
+            Some(&*dir.join("chapters/two.md"))
+        );
+        assert_eq!(widget.back_history.len(), 1);
+
+        assert!(widget.step_history(true));
+        assert_eq!(widget.file_path.as_deref(), Some(&*dir.join("index.md")));
+        assert_eq!(widget.base_dir, dir);
+        assert!(widget.source.starts_with("[Next]"));
+        assert!(!widget.can_go_back());
+        assert!(widget.can_go_forward());
+        assert!(widget.step_history(false));
+        assert!(widget.source.starts_with("# First"));
+        assert!(!widget.step_history(false));
+
+        // Following a link drops the forward history.
+        widget.step_history(true);
+        widget.follow_link("chapters/two.md").unwrap().unwrap();
+        assert!(!widget.can_go_forward());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn top_level_anchors(flow: &LayoutFlow<MarkdownContent>) -> Vec<Option<String>> {
+        flow.iter()
+            .filter_map(|element| match &element.data {
+                MarkdownContent::Header { anchor_id, .. } => Some(anchor_id.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Appends `markdown` in chunks of `chunk_len` characters and checks the
+    /// result against a full parse after every chunk.
+    fn assert_streams_like_full_parse(
+        markdown: &str,
+        chunk_len: usize,
+    ) -> MarkdowWidget {
+        let mut widget = MarkdowWidget::from_source("", PathBuf::new());
+        let chars: Vec<char> = markdown.chars().collect();
+        let mut appended = String::new();
+        for chunk in chars.chunks(chunk_len) {
+            let chunk: String = chunk.iter().collect();
+            appended.push_str(&chunk);
+            assert!(widget.append_source(&chunk));
+            let full = parse_markdown(&appended);
+            assert_eq!(
+                flow_to_plain_text(&widget.markdown_layout),
+                flow_to_plain_text(&full),
+                "after appending {chunk:?} of {appended:?}"
+            );
+            assert_eq!(widget.markdown_layout.len(), full.len());
+            assert_eq!(
+                top_level_anchors(&widget.markdown_layout),
+                top_level_anchors(&full)
+            );
+        }
+        assert_eq!(&*widget.source, markdown);
+        widget
+    }
+
+    #[test]
+    fn appended_text_parses_like_the_whole_source() {
+        let markdown = "# Title\n\nFirst *paragraph* with\na soft break.\n\nSetext heading\n===\n\n- one\n- two\n\n  still two\n- three\n\n1. first\n2. second\n\n> quoted\nlazy line\n\n---\n\n<!-- wrap -->\n```\nwrapped code\n```\n\n<details>\n<summary>More</summary>\n\nHidden text\n\n</details>\n\n# Title\n\n    indented code\n\n    after a blank line\n\nThe end\n";
+        for chunk_len in [1, 3, 50, markdown.len()] {
+            assert_streams_like_full_parse(markdown, chunk_len);
+        }
+    }
+
+    #[test]
+    fn stable_prefix_ends_before_the_last_block() {
+        assert_eq!(stable_prefix_len(""), 0);
+        assert_eq!(stable_prefix_len("Only text"), 0);
+        assert_eq!(stable_prefix_len("# A\n\nText\n"), 5);
+        assert_eq!(stable_prefix_len("Text\n# A\n"), 5);
+        // Nothing can follow an unclosed fence or a list.
+        assert_eq!(stable_prefix_len("```\na\n\nb\n"), 0);
+        assert_eq!(stable_prefix_len("- a\n\n- b\n\n  c\n"), 0);
+        // The wrap comment belongs to the block after it, details blocks to
+        // their end.
+        assert_eq!(stable_prefix_len("<!-- wrap -->\n\n```\na\n```\n"), 0);
+        assert_eq!(stable_prefix_len("<details>\n\nA\n\nB\n"), 0);
+        assert_eq!(
+            stable_prefix_len("<details>\n\nA\n\n</details>\n\nB\n"),
+            "<details>\n\nA\n\n</details>\n\n".len()
+        );
+    }
+
+    #[test]
+    fn appending_parses_only_the_tail() {
+        let markdown = "# Title\n\nA paragraph.\n\nAnother one.\n\nLast words";
+        let mut widget = assert_streams_like_full_parse(markdown, 5);
+        assert_eq!(widget.stream_tail.start, markdown.find("Last").unwrap());
+        assert_eq!(widget.stream_tail.items, 3);
+
+        // The last block goes on, only it is replaced.
+        widget.dirty_items.clear();
+        widget.append_source(" and more");
+        assert_eq!(widget.dirty_items, BTreeSet::from([3]));
+        widget.append_source("\n\nNew block");
+        assert_eq!(widget.stream_tail.items, 4);
+        assert_eq!(widget.dirty_items, BTreeSet::from([3, 4]));
+        assert!(!widget.append_source(""));
+
+        // A cleared document starts a new stream.
+        widget.clear_all();
+        widget.append_source("# New");
+        assert_header(&widget.markdown_layout, 0, HeadingLevel::H1, "New");
+        assert_eq!(widget.markdown_layout.len(), 1);
+    }
+
+    #[test]
+    fn appending_falls_back_to_larger_parses() {
+        // An unclosed fence takes everything after it.
+        let markdown = "Intro\n\n```\ncode\n\n# not a heading\n\nmore code\n";
+        let widget = assert_streams_like_full_parse(markdown, 4);
+        assert_eq!(widget.stream_tail.start, markdown.find("```").unwrap());
+
+        // Underlines and list items change the block before them.
+        assert_streams_like_full_parse("Some text\n\nHeading\n---\n\nText\n", 2);
+        assert_streams_like_full_parse("- a\n- b\n\n- loose now\n\ntext\n", 2);
+
+        // A reference definition applies to the links before it.
+        let markdown = "[link][ref]\n\nText\n\n[ref]: https://example.com\n\nEnd\n";
+        let widget = assert_streams_like_full_parse(markdown, 3);
+        let Some(MarkdownContent::Paragraph { markers, .. }) =
+            widget.markdown_layout.get(0).map(|element| &element.data)
+        else {
+            panic!("expected a paragraph");
+        };
+        assert!(markers.iter().any(|marker| matches!(
+            &marker.kind,
+            MarkerKind::Link(target) if target.url == "https://example.com"
+        )));
+    }
+
+    #[test]
+    fn references_defined_before_the_tail_keep_the_stream() {
+        let markdown =
+            "Intro [link][ref]\n\n[ref]: https://example.com\n\nSecond\n\nThird";
+        let mut widget = assert_streams_like_full_parse(markdown, 7);
+        assert_eq!(widget.stream_tail.start, markdown.find("Third").unwrap());
+        assert!(widget.stream_tail.definitions.contains_key("ref"));
+
+        // Only the tail is parsed, with the definition from before it.
+        let parse_count = widget.parse_count();
+        widget.append_source(" [again][REF]");
+        assert_eq!(widget.parse_count(), parse_count + 1);
+        assert_eq!(widget.stream_tail.start, markdown.find("Third").unwrap());
+        let Some(MarkdownContent::Paragraph { markers, .. }) =
+            widget.markdown_layout.get(2).map(|element| &element.data)
+        else {
+            panic!("expected a paragraph");
+        };
+        assert!(markers.iter().any(|marker| matches!(
+            &marker.kind,
+            MarkerKind::Link(target) if target.url == "https://example.com"
+        )));
+    }
+
+    #[test]
+    fn line_endings_split_between_chunks_stay_one_break() {
+        let mut widget = MarkdowWidget::from_source("", PathBuf::new());
+        for chunk in ["a\r", "\nb\r", "\r\n", "c"] {
+            widget.append_source(chunk);
+        }
+        assert_eq!(&*widget.source, "a\nb\n\nc");
+        assert_eq!(widget.markdown_layout.len(), 2);
+        // The streamed content is the same as when given at once.
+        assert!(!widget.replace_source(Arc::from("a\r\nb\r\r\nc")));
+
+        let mut widget = MarkdowWidget::from_source("Text\r", PathBuf::new());
+        assert!(!widget.append_source("\n"));
+        widget.append_source("more");
+        assert_eq!(&*widget.source, "Text\nmore");
+    }
+
+    #[test]
+    fn same_content_is_not_parsed_again() {
+        let content: Arc<str> = Arc::from("# Title\n\nText\n");
+        let mut widget = MarkdowWidget::from_source(content.clone(), "");
+        assert_eq!(widget.parse_count(), 1);
+        assert!(
+            matches!(&widget.source, SourceText::Shared(source) if Arc::ptr_eq(source, &content))
+        );
+
+        assert!(!widget.replace_source(content.clone()));
+        assert!(!widget.replace_source(Arc::from("# Title\n\nText\n")));
+        assert_eq!(widget.parse_count(), 1);
+
+        assert!(widget.replace_source(Arc::from("# Other\n")));
+        assert_eq!(widget.parse_count(), 2);
+        assert_header(&widget.markdown_layout, 0, HeadingLevel::H1, "Other");
+
+        // Normalized line endings need a copy of the content.
+        let content: Arc<str> = Arc::from("Text\r\n");
+        assert!(widget.replace_source(content.clone()));
+        assert!(
+            !matches!(&widget.source, SourceText::Shared(source) if Arc::ptr_eq(source, &content))
+        );
+        assert_eq!(&*widget.source, "Text\n");
+        assert!(!widget.replace_source(content));
+        assert_eq!(widget.parse_count(), 3);
+    }
+
+    #[test]
+    fn view_source_shows_the_raw_markdown() {
+        let source = "# Title\n\n*Text*\n";
+        let mut widget =
+            MarkdowWidget::from_source(source, "").with_view_source(true);
+        assert_eq!(widget.markdown_layout.len(), 1);
+        let MarkdownContent::CodeBlock { text, wrap, .. } =
+            &widget.markdown_layout.get(0).unwrap().data
+        else {
+            panic!("Expected a code block");
+        };
+        assert_eq!(text, "# Title\n\n*Text*");
+        assert!(wrap);
+
+        widget.set_view_source_mode(false);
+        assert_header(&widget.markdown_layout, 0, HeadingLevel::H1, "Title");
+        assert_paragraph_text(&widget.markdown_layout, 1, "Text");
+    }
+
+    #[test]
+    fn pdf_export_keeps_blocks_on_pages() {
+        let source = "# Title\n\n".to_string() + &"Some (text) here.\n\n".repeat(40);
+        let mut document = MarkdownDocument::new(&source, "");
+        let options = PageOptions::new(Size::new(300.0, 200.0), 20.0);
+        let pdf = document.to_pdf(options);
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert!(pdf.starts_with("%PDF-1.4"));
+        let pages = document.flow.page_starts(160.0).len();
+        assert!(pages > 1);
+        assert!(pdf.contains(&format!("/Count {pages} >>")));
+        assert!(pdf.contains("MediaBox [0 0 300.00 200.00]"));
+    }
+
+    /// Random markers of a few kinds, some of them empty or inverted.
+    fn random_markers(rng: &mut StdRng, text_len: usize) -> Vec<TextMarker> {
+        let kinds = [MarkerKind::Bold, MarkerKind::Italic, MarkerKind::InlineCode];
+        (0..rng.gen_range(0..12))
+            .map(|_| {
+                let start = rng.gen_range(0..=text_len);
+                let end = rng.gen_range(0..=text_len);
+                let kind = kinds[rng.gen_range(0..kinds.len())].clone();
+                TextMarker::new(start..end, kind)
+            })
+            .collect()
+    }
+
+    /// Kinds styling every byte of the text, to compare marker sets.
+    fn styled_bytes(
+        markers: &[TextMarker],
+        text_len: usize,
+    ) -> Vec<Vec<MarkerKind>> {
+        (0..text_len)
+            .map(|at| {
+                let mut kinds: Vec<MarkerKind> = markers
+                    .iter()
+                    .filter(|marker| marker.range.contains(&at))
+                    .map(|marker| marker.kind.clone())
+                    .collect();
+                kinds.sort_by_key(|kind| format!("{kind:?}"));
+                kinds.dedup();
+                kinds
+            })
+            .collect()
+    }
+
+    fn assert_normalized(markers: &[TextMarker]) {
+        assert!(markers.iter().all(|marker| !marker.is_empty()));
+        assert!(markers.windows(2).all(|pair| {
+            (pair[0].range.start, Reverse(pair[0].range.end))
+                <= (pair[1].range.start, Reverse(pair[1].range.end))
+        }));
+    }
+
+    #[test]
+    fn marker_normalize_merge_and_split_keep_the_styles() {
+        let text_len = 20;
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..500 {
+            let markers = random_markers(&mut rng, text_len);
+            let styles = styled_bytes(&markers, text_len);
+
+            let mut normalized = markers.clone();
+            normalize_markers(&mut normalized);
+            assert_normalized(&normalized);
+            assert_eq!(styled_bytes(&normalized, text_len), styles);
+
+            let mut merged = markers.clone();
+            merge_adjacent_markers(&mut merged);
+            assert_normalized(&merged);
+            assert_eq!(styled_bytes(&merged, text_len), styles);
+            // No two markers of a kind touch any more.
+            for (index, marker) in merged.iter().enumerate() {
+                assert!(merged[index + 1..].iter().all(|other| {
+                    other.kind != marker.kind || other.range.start > marker.range.end
+                }));
+            }
+
+            let offset = rng.gen_range(0..=text_len);
+            let (before, after) = split_markers_at(&merged, offset);
+            assert!(before
+                .iter()
+                .all(|m| !m.is_empty() && m.range.end <= offset));
+            assert!(after
+                .iter()
+                .all(|m| !m.is_empty() && m.range.end <= text_len - offset));
+            let mut joined = styled_bytes(&before, offset);
+            joined.extend(styled_bytes(&after, text_len - offset));
+            assert_eq!(joined, styles);
+        }
+    }
+
+    #[test]
+    fn touching_markers_of_a_kind_are_merged() {
+        let mut markers = vec![
+            TextMarker::new(2..4, MarkerKind::Bold),
+            TextMarker::new(1..3, MarkerKind::Italic),
+            TextMarker::new(0..2, MarkerKind::Bold),
+            TextMarker::new(6..7, MarkerKind::Bold),
+            TextMarker::new(5..5, MarkerKind::Italic),
+        ];
+        merge_adjacent_markers(&mut markers);
+        assert_eq!(
+            markers,
+            [
+                TextMarker::new(0..4, MarkerKind::Bold),
+                TextMarker::new(1..3, MarkerKind::Italic),
+                TextMarker::new(6..7, MarkerKind::Bold),
+            ]
+        );
+    }
+
+    #[test]
+    fn pdf_export_continues_tall_blocks() {
+        assert_eq!(
+            split_tall_pages(&[0.0, 80.0, 330.0], 360.0, 100.0),
+            [0.0, 80.0, 180.0, 280.0, 330.0]
+        );
+        assert_eq!(split_tall_pages(&[0.0], 250.0, 100.0), [0.0, 100.0, 200.0]);
+        assert_eq!(split_tall_pages(&[0.0], 250.0, 0.0), [0.0]);
+
+        let lines: String = (0..60).map(|line| format!("line {line}\n")).collect();
+        let source = format!("Before\n\n```\n{lines}```\n");
+        let mut document = MarkdownDocument::new(&source, "");
+        let options = PageOptions::new(Size::new(300.0, 200.0), 20.0);
+        let pdf = document.to_pdf(options);
+        let pdf = String::from_utf8_lossy(&pdf);
+        let starts = document.flow.page_starts(160.0);
+        let pages = split_tall_pages(&starts, document.flow.height(), 160.0).len();
+        assert!(pages > starts.len());
+        assert!(pdf.contains(&format!("/Count {pages} >>")));
+        // Every page of the code block has its text.
+        assert_eq!(pdf.matches("(line 59) Tj").count(), pages - 1);
+    }
+
+    #[test]
+    fn inline_code_between_text_events() {
+        let (text, markers) = first_paragraph("before `code` after");
+        assert_eq!(text, "before code after");
+        assert_eq!(markers, [(7, 11, MarkerKind::InlineCode)]);
+
+        // Inline HTML is dropped and splits the text into several events.
+        let (text, markers) = first_paragraph("a <b>b</b> `c` d");
+        assert_eq!(text, "a b c d");
+        assert_eq!(markers, [(4, 5, MarkerKind::InlineCode)]);
+    }
+
+    #[test]
+    fn image_uri_to_unix_path() {
+        let cases = [
+            ("img.png", Some("img.png")),
+            ("./img/a.png", Some("./img/a.png")),
+            ("docs\\img.png", Some("docs/img.png")),
+            ("my%20image.png", Some("my image.png")),
+            ("100%.png", Some("100%.png")),
+            ("file:///home/user/img.png", Some("/home/user/img.png")),
+            ("FILE:///home/user/img.png", Some("/home/user/img.png")),
+            ("file://localhost/home/img.png", Some("/home/img.png")),
+            ("file:/home/my%20img.png", Some("/home/my img.png")),
+            ("https://example.com/img.png", None),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(
+                image_uri_to_path(uri, false).as_deref(),
+                expected,
+                "uri: {uri}"
+            );
+        }
+    }
+
+    #[test]
+    fn image_uri_to_windows_path() {
+        let cases = [
+            ("img.png", Some("img.png")),
+            ("docs/sub/img.png", Some("docs\\sub\\img.png")),
+            ("docs\\img.png", Some("docs\\img.png")),
+            ("file:///C:/docs/img.png", Some("C:\\docs\\img.png")),
+            ("file:///c:/my%20docs/img.png", Some("c:\\my docs\\img.png")),
+            ("C:\\docs\\my%20image.png", Some("C:\\docs\\my image.png")),
+            ("C:/docs/img.png", Some("C:\\docs\\img.png")),
+            (
+                "file://server/share/img.png",
+                Some("\\\\server\\share\\img.png"),
+            ),
+            ("http://example.com/img.png", None),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(
+                image_uri_to_path(uri, true).as_deref(),
+                expected,
+                "uri: {uri}"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn resolve_image_uri_against_base() {
+        let base = Path::new("/docs");
+        assert_eq!(
+            resolve_image_uri("img/a%20b.png", base),
+            Some(PathBuf::from("/docs/img/a b.png"))
+        );
+        assert_eq!(
+            resolve_image_uri("file:///tmp/a.png", base),
+            Some(PathBuf::from("/tmp/a.png"))
+        );
+        assert_eq!(resolve_image_uri("https://example.com/a.png", base), None);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn merged_documents_keep_their_image_paths() {
+        let mut widget = MarkdowWidget::from_source("![own](a.png)", "/docs");
+        widget.append(MarkdowWidget::from_source(
+            "![appended](b.png)\n\n![remote](https://example.com/c.png)",
+            "/other",
+        ));
+        widget.prepend(MarkdowWidget::from_source("> ![quoted](d.png)", "/third"));
+        let mut uris = Vec::new();
+        for_each_image_uri(&mut widget.markdown_layout, &mut |uri| {
+            uris.push(uri.clone())
+        });
+        assert_eq!(
+            uris,
+            [
+                "/third/d.png",
+                "a.png",
+                "/other/b.png",
+                "https://example.com/c.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_images_are_not_decoded_again() {
+        let images = ImageCache::default();
+        let path = Path::new("/does/not/exist.png");
+        assert!(images.get(path).is_none());
+        while images.is_loading() {
+            std::thread::yield_now();
+        }
+        assert!(images.take_finished());
+        assert!(images.get(path).is_none());
+        assert!(!images.is_loading());
+        assert!(!images.take_finished());
+
+        assert!(images.fail(Path::new("https://example.com/a.png")));
+        assert!(!images.fail(Path::new("https://example.com/a.png")));
+    }
+
+    #[test]
+    fn images_finish_when_decoding_panics() {
+        let images = ImageCache::default();
+        let path = PathBuf::from("/panics/while/decoding.png");
+        {
+            let mut state = images.0.lock().unwrap();
+            state.images.insert(path.clone(), ImageLoad::Loading);
+            state.loading += 1;
+        }
+        let state = images.0.clone();
+        let decoded = std::thread::spawn(move || {
+            let _pending = PendingImage::new(state, path);
+            panic!("decoder bug");
+        })
+        .join();
+        assert!(decoded.is_err());
+        assert!(!images.is_loading());
+        assert!(images.take_finished());
+        // It counts as failed and is not decoded again.
+        assert!(images
+            .get(Path::new("/panics/while/decoding.png"))
+            .is_none());
+        assert!(!images.is_loading());
+    }
+
+    #[test]
+    fn plain_text_strips_inline_markers() {
+        let flow = parse_markdown(
+            "# Title\n\nSome **bold** and `code`.\n\n- one\n- two\n\n3. three\n4. four\n",
+        );
+        assert_eq!(
+            flow_to_plain_text(&flow),
+            "# Title\n\nSome bold and code.\n\n- one\n- two\n\n3. three\n4. four"
+        );
+        assert_header(&flow, 0, HeadingLevel::H1, "Title");
+        assert_paragraph_text(&flow, 1, "Some bold and code.");
+        assert_eq!(count_markers(&flow, 1, MarkerKind::Bold), 1);
+        assert_eq!(count_markers(&flow, 1, MarkerKind::InlineCode), 1);
+        assert_list_len(&flow, 2, 2);
+        assert_list_len(&flow, 3, 2);
+    }
+
+    #[test]
+    fn soft_break_between_japanese_text_is_removed() {
+        let (text, markers) = first_paragraph("これは**太字\nです**。\n次の行");
+        assert_eq!(text, "これは太字です。次の行");
+        let start = "これは".len();
+        let end = "これは太字です".len();
+        assert_eq!(markers, [(start, end, MarkerKind::Bold)]);
+    }
+
+    #[test]
+    fn soft_break_next_to_latin_text_stays_a_space() {
+        let (text, markers) =
+            first_paragraph("日本語\n**English** text\nand\n日本語");
+        assert_eq!(text, "日本語 English text and 日本語");
+        let start = "日本語 ".len();
+        assert_eq!(
+            markers,
+            [(start, start + "English".len(), MarkerKind::Bold)]
+        );
+    }
+
+    #[test]
+    fn emphasis_starts_where_the_emphasis_starts() {
+        let (text, markers) = first_paragraph("Plain *em* and **bold**");
+        assert_eq!(text, "Plain em and bold");
+        assert_eq!(
+            markers,
+            [(6, 8, MarkerKind::Italic), (13, 17, MarkerKind::Bold)]
+        );
+    }
+
+    #[test]
+    fn crlf_and_bom_are_normalized() {
+        let (text, markers) =
+            first_paragraph("\u{feff}Some *em*\r\nand **bold**\r\ntext\r\n");
+        assert_eq!(text, "Some em and bold text");
+        assert_eq!(
+            markers,
+            [(5, 7, MarkerKind::Italic), (12, 16, MarkerKind::Bold)]
+        );
+
+        let flow = parse_markdown("\u{feff}# Heading\r\n\r\nText\r\n");
+        assert_header(&flow, 0, HeadingLevel::H1, "Heading");
+        assert_paragraph_text(&flow, 1, "Text");
+    }
+
+    /// Makes paragraphs half as tall as they are wide.
+    struct HalfWidthParagraphs;
+
+    impl ContentRenderer for HalfWidthParagraphs {
+        fn height(&self, content: &MarkdownContent, width: f32) -> Option<f32> {
+            matches!(content, MarkdownContent::Paragraph { .. })
+                .then_some(width / 2.0)
+        }
+    }
+
+    #[test]
+    fn content_renderer_sizes_blocks_by_their_width() {
+        let source = "- item\n\nText";
+        let theme = get_theme().clone();
+        let mut flow = parse_markdown(source);
+        let mut font_ctx = FontContext::new();
+        let mut layout_ctx = LayoutContext::new();
+        let env = LayoutEnv {
+            renderer: &HalfWidthParagraphs,
+            ..LayoutEnv::new(Path::new(""))
+        };
+        flow.layout_all(|data| {
+            data.layout(
+                &mut font_ctx,
+                &mut layout_ctx,
+                300.0,
+                &theme,
+                env,
+                InheritedStyle::default(),
+            )
+        });
+        assert_eq!(flow.get(1).unwrap().height, 150.0);
+        let Some(MarkdownContent::List { list }) = flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a list");
+        };
+        let nested = list.list[0].get(0).unwrap();
+        assert_eq!(nested.height, (300.0 - list.indentation) / 2.0);
+        assert_eq!(flow.get(0).unwrap().height, nested.height);
+
+        // The renderer belongs to the layout it was given to.
+        let flow = laid_out(source, 300.0, &theme);
+        assert_ne!(flow.get(1).unwrap().height, 150.0);
+    }
+
+    #[test]
+    fn registered_fence_handler_replaces_code_block() {
+        let source =
+            "```uppercase\nshout this\n```\n\n> ```uppercase rest\n> quoted\n> ```\n\n```rust\nfn main() {}\n```\n";
+        let uppercase =
+            |code: &str| MarkdownContent::paragraph(code.trim_end().to_uppercase());
+        let widget = MarkdowWidget::from_source(source, PathBuf::new())
+            .with_fence_handler("uppercase", uppercase);
+        let flow = &widget.markdown_layout;
+        assert_paragraph_text(flow, 0, "SHOUT THIS");
+        let MarkdownContent::Indented { flow: quote, .. } = item(flow, 1) else {
+            panic!("Expected a block quote");
+        };
+        assert_paragraph_text(quote, 0, "QUOTED");
+        assert!(matches!(
+            item(flow, 2),
+            MarkdownContent::CodeBlock { text, .. } if text == "fn main() {}\n"
+        ));
+
+        // Handlers belong to the widget or document they were given to.
+        let other = MarkdowWidget::from_source(source, PathBuf::new());
+        assert!(matches!(
+            item(&other.markdown_layout, 0),
+            MarkdownContent::CodeBlock { .. }
+        ));
+        let document = MarkdownDocument::new(source, PathBuf::new())
+            .with_fence_handler("uppercase", uppercase);
+        assert_paragraph_text(&document.flow, 0, "SHOUT THIS");
+    }
+
+    #[test]
+    fn details_blocks_are_parsed_into_nested_flows() {
+        let flow = parse_markdown(
+            "<details>\n<summary>Outer <b>title</b></summary>\n\n\
+             Text\n\n\
+             <details open>\n<summary>Inner</summary>\n\n\
+             ```\ncode\n```\n\n\
+             </details>\n\n\
+             </details>\n\n\
+             After\n",
+        );
+        let elements: Vec<_> = flow.iter().map(|e| &e.data).collect();
+        assert_eq!(elements.len(), 2);
+        let MarkdownContent::Details {
+            summary,
+            open,
+            flow: outer,
+            ..
+        } = elements[0]
+        else {
+            panic!("Expected details block");
+        };
+        assert_eq!(summary, "Outer title");
+        assert!(!open);
+        assert_paragraph_text(outer, 0, "Text");
+        let inner: Vec<_> = outer.iter().map(|e| &e.data).collect();
+        let MarkdownContent::Details {
+            summary,
+            open,
+            flow: inner,
+            ..
+        } = inner[1]
+        else {
+            panic!("Expected nested details block");
+        };
+        assert_eq!(summary, "Inner");
+        assert!(open);
+        assert!(matches!(
+            &inner.get(0).unwrap().data,
+            MarkdownContent::CodeBlock { text, .. } if text == "code\n"
+        ));
+        assert_paragraph_text(&flow, 1, "After");
+    }
+
+    #[test]
+    fn single_html_block_details() {
+        let flow = parse_markdown(
+            "<details><summary>Hi</summary>Hidden *text*</details>\n",
+        );
+        let MarkdownContent::Details { summary, flow, .. } =
+            &flow.get(0).unwrap().data
+        else {
+            panic!("Expected details block");
+        };
+        assert_eq!(summary, "Hi");
+        assert!(matches!(
+            &flow.get(0).unwrap().data,
+            MarkdownContent::Paragraph { text, .. } if text == "Hidden text"
+        ));
+    }
+
+    #[test]
+    fn image_size_is_read_from_header() {
+        let png = std::fs::read("small-image-test.png").unwrap();
+        assert_eq!(image_size_from_header(&png[..24]), Some((50, 50)));
+        let gif = b"GIF89a\x20\x01\x10\x00";
+        assert_eq!(image_size_from_header(gif), Some((288, 16)));
+        assert_eq!(image_size_from_header(&png[..20]), None);
+        assert_eq!(image_size_from_header(b"not an image"), None);
+    }
+
+    #[test]
+    fn alpha_list_labels() {
+        let labels: Vec<String> = [1, 2, 26, 27, 52, 53, 702, 703]
+            .map(|n| alpha_label(n, false))
+            .into();
+        assert_eq!(labels, ["a", "b", "z", "aa", "az", "ba", "zz", "aaa"]);
+        assert_eq!(alpha_label(28, true), "AB");
+
+        let mut marker = ListMarker::Numbers {
+            start_number: 3,
+            layouted: Vec::new(),
+        };
+        marker.set_alpha(Some(false));
+        assert_eq!(marker.ordered_label(0).as_deref(), Some("c."));
+        assert_eq!(marker.ordered_label(24).as_deref(), Some("aa."));
+        marker.set_alpha(None);
+        assert_eq!(marker.ordered_label(0).as_deref(), Some("3."));
+
+        // Starts without a single letter survive the round trip.
+        for start in [0, 30] {
+            let mut marker = ListMarker::Numbers {
+                start_number: start,
+                layouted: Vec::new(),
+            };
+            marker.set_alpha(Some(true));
+            assert_eq!(marker.start_number(), Some(start));
+            marker.set_alpha(None);
+            assert_eq!(marker.ordered_label(0), Some(format!("{start}.")));
+        }
+        let zero = ListMarker::from_style("lower-alpha", 0);
+        assert_eq!(zero.ordered_label(0).as_deref(), Some("?."));
+        assert_eq!(zero.ordered_label(1).as_deref(), Some("a."));
+        let late = ListMarker::from_style("upper-alpha", 30);
+        assert_eq!(late.ordered_label(0).as_deref(), Some("AD."));
+    }
+
+    #[test]
+    fn collapsed_sections_clamp_the_scroll_and_follow_prepended_content() {
+        let dir = std::env::temp_dir().join("wrenched-collapsed-sections");
+        std::fs::create_dir_all(&dir).unwrap();
+        let theme = get_theme().clone();
+        let open = |name: &str, source: &str| {
+            let file = dir.join(name);
+            std::fs::write(&file, source).unwrap();
+            let mut widget = MarkdowWidget::new(file);
+            lay_out(&mut widget.markdown_layout, 300.0, &theme);
+            widget.viewport_height = 100.0;
+            widget
+        };
+        let filler = "Some filler text.\n\n".repeat(10);
+        let mut widget =
+            open("doc.md", &format!("# Intro\n\n{filler}# Later\n\n{filler}"));
+        let later = 11;
+        widget.scroll.y =
+            widget.markdown_layout.height() as f64 - widget.viewport_height;
+        assert!(widget.collapse_section(later, true));
+        let max_scroll =
+            widget.markdown_layout.height() as f64 - widget.viewport_height;
+        assert!(max_scroll > 0.0);
+        assert_eq!(widget.scroll.y, max_scroll);
+
+        widget.prepend(open("preface.md", "# Preface\n\nFirst.\n\nSecond.\n"));
+        assert!(widget.is_section_collapsed(later + 3));
+        assert!(!widget.is_section_collapsed(later));
+        assert!(widget.markdown_layout.get(later + 4).unwrap().hidden);
+    }
+
+    #[test]
+    fn debug_overlay_colors_tell_blocks_apart() {
+        let colors: Vec<_> = BlockKind::ALL
+            .map(|kind| {
+                let rgba = debug_overlay_color(kind).to_rgba8();
+                (rgba.r, rgba.g, rgba.b)
+            })
+            .into();
+        for (index, color) in colors.iter().enumerate() {
+            assert!(!colors[..index].contains(color));
+        }
+    }
+
+    #[test]
+    fn code_blocks_are_found_in_nested_blocks() {
+        let flow = parse_markdown(
+            "Text\n\n- item\n\n  > ```\n  > code\n  > ```\n\n```\ncode\n```\n",
+        );
+        let found: Vec<bool> = flow
+            .iter()
+            .map(|element| contains_code_block(&element.data))
+            .collect();
+        assert_eq!(found, [false, true, true]);
+    }
+
+    /// Marks every `fn` of Rust code as a bold keyword.
+    struct MockHighlighter;
+
+    impl SyntaxHighlighter for MockHighlighter {
+        fn highlight(
+            &self,
+            language: &str,
+            text: &str,
+        ) -> Vec<(Range<usize>, HighlightStyle)> {
+            if language != "rust" {
+                return Vec::new();
+            }
+            let style = HighlightStyle {
+                bold: true,
+                ..HighlightStyle::new(TokenClass::Keyword)
+            };
+            let mut spans: Vec<_> = text
+                .match_indices("fn")
+                .map(|(start, _)| (start..start + 2, style))
+                .collect();
+            // Out of the text, must be skipped by the layout.
+            spans.push((text.len()..text.len() + 4, style));
+            spans
+        }
+    }
+
+    #[test]
+    fn code_block_padding_is_part_of_its_height() {
+        let mut theme = get_theme().clone();
+        theme.code_block_padding = 10.0;
+        let flow =
+            laid_out("```\nlet x = 1;\n```\n\nAfter the code.", 400.0, &theme);
+        let code = flow.get(0).unwrap();
+        let MarkdownContent::CodeBlock { text_layout, .. } = &code.data else {
+            panic!("Expected a code block");
+        };
+        assert_eq!(code.height, text_layout.height() + 20.0);
+        // The paragraph starts below the bottom padding of the code.
+        let paragraph = flow.get(1).unwrap();
+        assert!(paragraph.offset >= code.offset + text_layout.height() + 20.0);
+    }
+
+    #[test]
+    fn selected_range_is_ordered_and_clamped() {
+        let mut widget = MarkdowWidget::from_source(
+            "First paragraph\n\n- item\n\nLast words",
+            PathBuf::new(),
+        );
+        assert_eq!(widget.selected_range(), None);
+        assert_eq!(widget.selected_text(), None);
+
+        widget.apply_selected_range(ContentPos::new(2, 4), ContentPos::new(0, 6));
+        assert_eq!(
+            widget.selected_range(),
+            Some((ContentPos::new(0, 6), ContentPos::new(2, 4)))
+        );
+        assert_eq!(
+            widget.selected_text().as_deref(),
+            Some("paragraph\n\n- item\n\nLast")
+        );
+
+        widget.apply_selected_range(ContentPos::new(2, 5), ContentPos::new(2, 100));
+        assert_eq!(widget.selected_text().as_deref(), Some("words"));
+
+        widget.all_selected = true;
+        assert_eq!(
+            widget.selected_range(),
+            Some((ContentPos::new(0, 0), ContentPos::new(2, 10)))
+        );
+    }
+
+    #[test]
+    fn selected_range_snaps_to_characters() {
+        let mut widget =
+            MarkdowWidget::from_source("caf\u{e9} cr\u{e8}me", PathBuf::new());
+        // Both offsets are inside a two byte character.
+        widget.apply_selected_range(ContentPos::new(0, 4), ContentPos::new(0, 9));
+        assert_eq!(
+            widget.selected_range(),
+            Some((ContentPos::new(0, 3), ContentPos::new(0, 8)))
+        );
+        assert_eq!(widget.selected_text().as_deref(), Some("\u{e9} cr"));
+    }
+
+    #[test]
+    fn selection_is_mapped_to_the_shown_text() {
+        let theme = get_theme();
+        let source = "in&shy;com&shy;pre&shy;hen&shy;si&shy;bil&shy;i&shy;ties\n\n\
+                      ```\n\tx\n```\n";
+        let flow = laid_out(source, 60.0, &theme);
+        let paragraph = item(&flow, 0);
+        let MarkdownContent::Paragraph {
+            text,
+            shown_text: Some(shown_text),
+            ..
+        } = paragraph
+        else {
+            panic!("Expected a hyphenated paragraph");
+        };
+        let range = paragraph.shown_range(0..text.len());
+        assert_eq!(range, 0..shown_text.len());
+        let ties = text.find("ties").unwrap();
+        let range = paragraph.shown_range(ties..text.len());
+        assert_eq!(&shown_text[range], "ties");
+        // The tab is expanded to four spaces.
+        assert_eq!(item(&flow, 1).shown_range(1..2), 4..5);
+    }
+
+    #[test]
+    fn repeated_pointer_moves_do_not_repaint() {
+        let widget = MarkdowWidget::from_source(
+            "Some text\n\n```\ncode\n```\n",
+            PathBuf::new(),
+        );
+        let mut harness = TestHarness::create(widget);
+        let paint_call_count = |harness: &TestHarness| {
+            harness
+                .root_widget()
+                .downcast::<MarkdowWidget>()
+                .unwrap()
+                .paint_call_count()
+        };
+        let _ = harness.render();
+        assert_eq!(paint_call_count(&harness), 1);
+
+        // Nothing under the pointer changes, so there is nothing to repaint.
+        harness.mouse_move((5.0, 5.0));
+        harness.mouse_move((5.0, 5.0));
+        let _ = harness.render();
+        assert_eq!(paint_call_count(&harness), 1);
+    }
+
+    #[test]
+    fn render_width_is_bounded() {
+        assert_eq!(render_width(f64::INFINITY, 0.0, 700.0), 700.0);
+        assert_eq!(render_width(500.0, 0.0, 700.0), 500.0);
+        assert_eq!(render_width(500.0, 0.0, f32::INFINITY), 500.0);
+        assert_eq!(render_width(100.0, 200.0, 700.0), 200.0);
+        assert_eq!(render_width(500.0, 800.0, 700.0), 800.0);
+
+        let widget = MarkdowWidget::from_source("Some text", PathBuf::new())
+            .with_max_render_width(150.0);
+        let mut harness = TestHarness::create(widget);
+        let _ = harness.render();
+        let max_advance = harness
+            .root_widget()
+            .downcast::<MarkdowWidget>()
+            .unwrap()
+            .max_advance;
+        assert_eq!(max_advance, 150.0);
+    }
+
+    #[test]
+    fn visible_item_indices_follow_the_scroll() {
+        let mut widget = MarkdowWidget::from_source("", PathBuf::new());
+        widget.markdown_layout = flow_of((0..10).map(|_| line(100.0)).collect());
+        widget.viewport_height = 150.0;
+        widget.scroll.y = widget.padding.top as f64;
+        assert_eq!(widget.visible_item_indices(), 0..2);
+
+        let gap = get_theme().paragraph_spacing;
+        assert!(gap < 50.0);
+        let offset = widget.markdown_layout.get(3).unwrap().offset;
+        widget.scroll.y = widget.padding.top as f64 + offset as f64 + 1.0;
+        assert_eq!(widget.visible_item_indices(), 3..5);
+        widget.scroll.y = 1e6;
+        assert_eq!(widget.visible_item_indices(), 10..10);
+    }
+
+    #[test]
+    fn scroll_propagation_decides_who_handles_the_wheel() {
+        for scrolled in [false, true] {
+            assert!(!ScrollPropagation::Always.handles(scrolled));
+            assert!(ScrollPropagation::Never.handles(scrolled));
+        }
+        assert!(ScrollPropagation::WhenAtBoundary.handles(true));
+        assert!(!ScrollPropagation::WhenAtBoundary.handles(false));
+        assert_eq!(ScrollPropagation::default(), ScrollPropagation::Never);
+    }
+
+    #[test]
+    fn anchor_links_scroll_forward_and_back() {
+        let filler = "Some filler text.\n\n".repeat(30);
+        let source = format!(
+            "# Top\n\n[Down](#the-end)\n\n{filler}# The End\n\n[Up](#top)\n\n{filler}"
+        );
+        let mut widget = MarkdowWidget::from_source(source, PathBuf::new());
+        lay_out(&mut widget.markdown_layout, 300.0, &get_theme());
+        widget.viewport_height = 100.0;
+
+        // Before the layout the anchor waits for it.
+        assert!(!widget.scroll_to_anchor("the-end"));
+        assert_eq!(widget.pending_scroll_anchor.as_deref(), Some("the-end"));
+        widget.dirty = false;
+        widget.pending_scroll_anchor = None;
+
+        let end = anchor_item_index(&widget.markdown_layout, "the-end").unwrap();
+        assert_eq!(end, 32);
+        assert!(widget.scroll_to_anchor("the-end"));
+        assert_eq!(widget.item_rect(end).unwrap().y0, 0.0);
+        assert_eq!(widget.visible_heading(), Some(end));
+
+        assert!(widget.scroll_to_anchor("top"));
+        assert_eq!(widget.scroll.y, 0.0);
+        assert!(!widget.scroll_to_anchor("top"));
+
+        // Unknown anchors don't move the document.
+        assert!(!widget.scroll_to_anchor("nowhere"));
+        assert_eq!(widget.scroll.y, 0.0);
+    }
+
+    fn laid_out_widget(source: &str) -> MarkdowWidget {
+        let mut widget = MarkdowWidget::from_source(source, PathBuf::new());
+        lay_out(&mut widget.markdown_layout, 300.0, &get_theme());
+        widget.viewport_height = 100.0;
+        widget.dirty = false;
+        widget
+    }
+
+    fn view_state_source(before: &str, last_heading: &str) -> String {
+        let filler = "Some filler text.\n\n".repeat(10);
+        format!(
+            "{before}# Intro\n\n{filler}## Notes\n\n{filler}## Details\n\n- first item\n- second item with some more text\n\n  and a second paragraph\n\n{filler}# {last_heading}\n\n{filler}"
+        )
+    }
+
+    fn heading_index(widget: &MarkdowWidget, anchor: &str) -> usize {
+        anchor_item_index(&widget.markdown_layout, anchor).unwrap()
+    }
+
+    #[test]
+    fn view_state_round_trips() {
+        let source = view_state_source("", "Later");
+        let mut widget = laid_out_widget(&source);
+        widget.collapse_section(heading_index(&widget, "notes"), true);
+        widget.collapse_section(heading_index(&widget, "later"), true);
+        // Into the second paragraph of the second list item.
+        let list = heading_index(&widget, "details") + 1;
+        let element = widget.markdown_layout.get(list).unwrap();
+        let MarkdownContent::List { list: items } = &element.data else {
+            panic!("expected a list");
+        };
+        let item = &items.list[1];
+        let paragraph = item.get(1).unwrap();
+        let y = element.offset
+            + items.list[0].height()
+            + paragraph.offset
+            + paragraph.height / 4.0;
+        widget.scroll.y = y as f64 + widget.content_origin().y;
+
+        let state: MarkdownViewState = widget.capture_state();
+        let anchor: ScrollAnchor = state.scroll.clone().unwrap();
+        assert_eq!(anchor.path, vec![list, 1, 1]);
+        assert!((anchor.fraction - 0.25).abs() < 0.01);
+        assert!(anchor.fingerprint.contains("first item"));
+        assert_eq!(state.collapsed_sections, vec!["notes", "later"]);
+
+        let mut restored = laid_out_widget(&source);
+        restored.apply_view_state(&state);
+        assert!((restored.scroll.y - widget.scroll.y).abs() < 0.5);
+        assert!(restored.is_section_collapsed(heading_index(&restored, "notes")));
+        assert_eq!(restored.capture_state().scroll.unwrap().path, anchor.path);
+
+        // Before the layout the position waits for it.
+        let mut pending =
+            MarkdowWidget::from_source(source.as_str(), PathBuf::new());
+        pending.apply_view_state(&state);
+        assert_eq!(pending.pending_scroll_state, state.scroll);
+        assert_eq!(pending.scroll.y, 0.0);
+
+        // The scale factor is restored, the position waits for the layout
+        // with it.
+        assert_eq!(state.zoom, 1.0);
+        let zoomed = widget.with_scale_factor(2.0).capture_state();
+        assert_eq!(zoomed.zoom, 2.0);
+        let mut restored = laid_out_widget(&source);
+        restored.apply_view_state(&zoomed);
+        assert_eq!(restored.scale_factor, 2.0);
+        assert_eq!(restored.pending_scroll_state, zoomed.scroll);
+    }
+
+    #[test]
+    fn view_state_survives_document_changes() {
+        let mut widget = laid_out_widget(&view_state_source("", "Later"));
+        widget.collapse_section(heading_index(&widget, "notes"), true);
+        widget.collapse_section(heading_index(&widget, "later"), true);
+        let details = heading_index(&widget, "details");
+        widget.scroll.y = widget.item_rect(details).unwrap().y0 + 5.0;
+        let state = widget.capture_state();
+        assert_eq!(state.scroll.as_ref().unwrap().path, vec![details]);
+
+        // A paragraph was added in front and a heading renamed.
+        let mut changed = laid_out_widget(&view_state_source(
+            "A new paragraph.\n\n",
+            "Much later",
+        ));
+        changed.apply_view_state(&state);
+        let anchor = changed.capture_state().scroll.unwrap();
+        assert_eq!(anchor.path, vec![details + 1]);
+        assert!(
+            (anchor.fraction - state.scroll.as_ref().unwrap().fraction).abs() < 0.01
+        );
+        assert_eq!(changed.capture_state().collapsed_sections, vec!["notes"]);
+
+        // Without the block the one at its index is taken.
+        let other = format!("# Intro\n\n{}", "Other text.\n\n".repeat(40));
+        let mut other = laid_out_widget(&other);
+        other.apply_view_state(&state);
+        assert_eq!(other.capture_state().scroll.unwrap().path, vec![details]);
+        assert!(other.capture_state().collapsed_sections.is_empty());
+
+        let mut empty = laid_out_widget("");
+        empty.apply_view_state(&state);
+        assert_eq!(empty.capture_state().scroll, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn view_state_serializes() {
+        let state = MarkdownViewState {
+            scroll: Some(ScrollAnchor {
+                path: vec![3, 1, 0],
+                fraction: 0.5,
+                fingerprint: "Some text".to_string(),
+            }),
+            zoom: 1.25,
+            collapsed_sections: vec!["notes".to_string()],
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MarkdownViewState>(&json).unwrap(),
+            state
+        );
+    }
+
+    #[test]
+    fn image_frame_rounds_within_the_image() {
+        let theme = Theme {
+            image_corner_radius: 12.0,
+            image_border_width: 4.0,
+            ..get_theme().clone()
+        };
+        let rect = Rect::new(10.0, 20.0, 110.0, 40.0);
+        let frame = image_frame(rect, &theme);
+        assert_eq!(frame.rect(), rect);
+        // Small images can't be rounder than a pill.
+        assert_eq!(frame.radii().top_left, 10.0);
+
+        let border = image_border(frame, &theme);
+        assert_eq!(border.rect(), Rect::new(12.0, 22.0, 108.0, 38.0));
+        assert_eq!(border.radii().top_left, 8.0);
+
+        let square = Theme {
+            image_corner_radius: 0.0,
+            ..theme
+        };
+        let border = image_border(image_frame(rect, &square), &square);
+        assert_eq!(border.radii().top_left, 0.0);
+    }
+
+    #[test]
+    fn hovered_link_changes_are_reported_once() {
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<std::sync::Mutex<Vec<Option<String>>>>);
+        impl MarkdownObserver for Recorder {
+            fn on_hovered_link_changed(&mut self, url: Option<&str>) {
+                self.0.lock().unwrap().push(url.map(str::to_string));
+            }
+        }
+        let recorder = Recorder::default();
+        let mut widget = MarkdowWidget::from_source("", PathBuf::new());
+        widget.add_observer(Box::new(recorder.clone()));
+        let link = |url: &str| Some(LinkTarget::from_destination(url));
+
+        assert!(!widget.set_hovered_link(None));
+        assert!(widget.set_hovered_link(link("https://a.b")));
+        // Moving within the link or to another link to the same URL.
+        assert!(!widget.set_hovered_link(link("https://a.b")));
+        assert!(widget.set_hovered_link(link("#usage")));
+        assert!(widget.set_hovered_link(None));
+        assert!(!widget.set_hovered_link(None));
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                Some("https://a.b".to_string()),
+                Some("#usage".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn clearing_the_document_reports_the_dropped_hovered_link() {
+        let widget = MarkdowWidget::from_source("[[Page]]", PathBuf::new())
+            .with_wikilinks(true);
+        let mut harness = TestHarness::create(widget);
+        let _ = harness.render();
+        let hovered_links = |harness: &mut TestHarness| {
+            std::iter::from_fn(|| harness.pop_action())
+                .filter_map(|(action, _)| match action {
+                    Action::Other(action) => {
+                        match action.downcast_ref::<MarkdownAction>() {
+                            Some(MarkdownAction::HoveredLinkChanged(url)) => {
+                                Some(url.clone())
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        harness.mouse_move((5.0, 8.0));
+        assert_eq!(hovered_links(&mut harness), [Some("Page".to_string())]);
+        harness.edit_root_widget(|mut root| {
+            MarkdowWidget::clear(&mut root.downcast::<MarkdowWidget>());
+        });
+        assert_eq!(hovered_links(&mut harness), [None]);
+        // Nothing is hovered anymore, so nothing more is reported.
+        harness.edit_root_widget(|mut root| {
+            MarkdowWidget::set_source(&mut root.downcast::<MarkdowWidget>(), "Text");
+        });
+        assert!(hovered_links(&mut harness).is_empty());
+    }
+
+    #[test]
+    fn custom_font_context_is_kept_across_layouts() {
+        let widget = MarkdowWidget::from_source("Branded *text*", PathBuf::new())
+            .with_font_ctx(FontContext::new());
+        let mut harness = TestHarness::create(widget);
+        let _ = harness.render();
+        harness.edit_root_widget(|mut root| {
+            let mut widget = root.downcast::<MarkdowWidget>();
+            MarkdowWidget::set_max_render_width(&mut widget, 100.0);
+        });
+        let _ = harness.render();
+        let (font_ctx, max_advance) = {
+            let widget = harness.root_widget();
+            let widget = widget.downcast::<MarkdowWidget>().unwrap();
+            (widget.font_ctx.is_some(), widget.max_advance)
+        };
+        assert!(font_ctx);
+        assert_eq!(max_advance, 100.0);
+    }
+
+    #[test]
+    fn selection_changes_are_reported_by_length() {
+        let mut widget =
+            MarkdowWidget::from_source("Hello world\n\nBye", PathBuf::new());
+        assert_eq!(widget.take_selection_change(), None);
+
+        widget.apply_selected_range(ContentPos::new(0, 0), ContentPos::new(0, 5));
+        assert_eq!(widget.take_selection_change(), Some(5));
+        assert_eq!(widget.selected_text().as_deref(), Some("Hello"));
+        // The same length is no substantial change.
+        widget.apply_selected_range(ContentPos::new(0, 6), ContentPos::new(0, 11));
+        assert_eq!(widget.take_selection_change(), None);
+
+        widget.set_selection(None, true);
+        assert_eq!(widget.take_selection_change(), Some(16));
+        // Appended text is selected as well.
+        widget.append_source("!");
+        assert_eq!(widget.take_selection_change(), Some(17));
+        widget.set_selection(None, false);
+        assert_eq!(widget.take_selection_change(), Some(0));
+        assert_eq!(widget.take_selection_change(), None);
+        // Without a new generation the length isn't measured again.
+        widget.selection = Some((ContentPos::new(0, 0), ContentPos::new(0, 5)));
+        assert_eq!(widget.take_selection_change(), None);
+    }
+
+    #[test]
+    fn first_line_style_covers_whole_characters() {
+        assert_eq!(first_chars_range("Once upon", 4), 0..4);
+        assert_eq!(first_chars_range("Ça été", 4), 0..6);
+        assert_eq!(first_chars_range("Hi", 10), 0..2);
+        assert_eq!(first_chars_range("", 3), 0..0);
+
+        let style = FirstLineStyle {
+            char_count: 4,
+            font_weight: FontWeight::BOLD,
+            font_variant: FontVariant::SmallCaps,
+        };
+        let theme = Theme {
+            paragraph_first_line_style: Some(style),
+            ..get_theme().clone()
+        };
+        let (range, properties) = first_line_properties("Ça été", &theme).unwrap();
+        assert_eq!(range, 0..6);
+        assert_eq!("Ça été"[range].chars().count(), 4);
+        assert_eq!(
+            properties,
+            [
+                StyleProperty::FontWeight(FontWeight::BOLD),
+                StyleProperty::FontFeatures(FontSettings::Source(Cow::Borrowed(
+                    "\"smcp\" on"
+                ))),
+            ]
+        );
+        // Without a variant only the weight changes.
+        let theme = Theme {
+            paragraph_first_line_style: Some(FirstLineStyle {
+                font_variant: FontVariant::Normal,
+                ..style
+            }),
+            ..theme
+        };
+        let (range, properties) =
+            first_line_properties("Once upon", &theme).unwrap();
+        assert_eq!(range, 0..4);
+        assert_eq!(properties, [StyleProperty::FontWeight(FontWeight::BOLD)]);
+        assert_eq!(first_line_properties("", &theme), None);
+
+        let flow = laid_out("Once upon a time\n\n> — Someone", 200.0, &theme);
+        assert!(flow.height() > 0.0);
+    }
+
+    #[test]
+    fn tabs_expand_to_the_next_tab_stop() {
+        assert_eq!(expand_tabs("no tabs"), None);
+        let (expanded, offsets) = expand_tabs("\tx\nab\tc").unwrap();
+        assert_eq!(expanded, "    x\nab  c");
+        assert_eq!(offsets, [0, 4, 5, 6, 7, 8, 10, 11]);
+        // The spaces of a tab map back to it.
+        let text = "\tx\nab\tc";
+        let mapped: Vec<usize> = (0..=11)
+            .map(|offset| code_text_offset(text, offset))
+            .collect();
+        assert_eq!(mapped, [0, 0, 0, 0, 1, 2, 3, 4, 5, 5, 6, 7]);
+        assert_eq!(code_text_offset("no tabs", 3), 3);
+    }
+
+    #[test]
+    fn code_layout_without_markdown() {
+        let theme = get_theme();
+        let mut font_ctx = FontContext::new();
+        let mut layout_ctx = LayoutContext::new();
+        let text = "\tfn a() {}\n\tfn b() {}";
+        let layout = build_code_layout(
+            text,
+            Some("rust"),
+            Some(&MockHighlighter),
+            None,
+            &theme,
+            &mut font_ctx,
+            &mut layout_ctx,
+        );
+        let keyword = MarkdownBrush::new(theme.code_color_scheme.keyword);
+        for line in layout.lines() {
+            assert!(line.metrics().advance <= layout.width() + 0.001);
+            for item in line.items() {
+                if let PositionedLayoutItem::GlyphRun(glyph_run) = item {
+                    // The keywords moved behind the expanded tabs.
+                    let range = glyph_run.run().text_range();
+                    if range.start == 4 || range.start == 18 {
+                        assert!(glyph_run.style().brush == keyword);
+                    }
+                }
+            }
+        }
+
+        let wrapped = build_code_layout(
+            text,
+            Some("rust"),
+            Some(&MockHighlighter),
+            Some(40.0),
+            &theme,
+            &mut font_ctx,
+            &mut layout_ctx,
+        );
+        assert!(wrapped.len() >= layout.len());
+    }
+
+    #[test]
+    fn syntax_highlighter_styles_code_blocks() {
+        let source =
+            "```rust\nfn main() {}\n```\n\n```python\ndef fn(): pass\n```\n";
+        let widget = MarkdowWidget::from_source(source, PathBuf::new())
+            .with_syntax_highlighter(Arc::new(MockHighlighter));
+        let highlights: Vec<(Option<String>, usize)> = widget
+            .markdown_layout
+            .iter()
+            .filter_map(|element| match &element.data {
+                MarkdownContent::CodeBlock {
+                    language,
+                    highlights,
+                    ..
+                } => Some((language.clone(), highlights.len())),
+                _ => None,
+            })
+            .collect();
+        // The unknown language is rendered plain.
+        assert_eq!(
+            highlights,
+            [
+                (Some("rust".to_string()), 2),
+                (Some("python".to_string()), 0)
+            ]
+        );
+
+        let mut flow = widget.markdown_layout;
+        let theme = get_theme();
+        lay_out(&mut flow, 400.0, &theme);
+        let Some(MarkdownContent::CodeBlock { text_layout, .. }) =
+            flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a code block");
+        };
+        let keyword = MarkdownBrush::new(theme.code_color_scheme.keyword);
+        for line in text_layout.lines() {
+            for item in line.items() {
+                if let PositionedLayoutItem::GlyphRun(glyph_run) = item {
+                    let range = glyph_run.run().text_range();
+                    if range.start == 0 {
+                        assert!(glyph_run.style().brush == keyword);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roman_list_labels() {
+        let numerals: Vec<String> = [1, 4, 9, 14, 40, 90, 400, 1994, 3999]
+            .map(|n| to_roman(n, false))
+            .into();
+        assert_eq!(
+            numerals,
+            [
+                "i",
+                "iv",
+                "ix",
+                "xiv",
+                "xl",
+                "xc",
+                "cd",
+                "mcmxciv",
+                "mmmcmxcix"
+            ]
+        );
+        assert_eq!(to_roman(12, true), "XII");
+        assert_eq!(to_roman(0, true), "?");
+        assert_eq!(to_roman(4000, false), "?");
+
+        let mut marker = ListMarker::from_style("upper-roman", 3);
+        assert_eq!(marker.ordered_label(0).as_deref(), Some("III."));
+        assert_eq!(marker.ordered_label(1).as_deref(), Some("IV."));
+        marker.set_style(Some("lower-alpha"));
+        assert_eq!(marker.ordered_label(0).as_deref(), Some("c."));
+        marker.set_style(Some("lower-roman"));
+        marker.set_style(None);
+        assert_eq!(marker.ordered_label(0).as_deref(), Some("3."));
+        let marker = ListMarker::from_style("decimal", 7);
+        assert_eq!(marker.ordered_label(0).as_deref(), Some("7."));
+    }
+
+    #[test]
+    fn scroll_percentage_offsets() {
+        assert_eq!(scroll_for_percentage(0.3, 1100.0, 100.0), 300.0);
+        assert_eq!(scroll_for_percentage(1.5, 1100.0, 100.0), 1000.0);
+        assert_eq!(scroll_for_percentage(-1.0, 1100.0, 100.0), 0.0);
+        assert_eq!(scroll_for_percentage(f64::NAN, 1100.0, 100.0), 0.0);
+        assert_eq!(scroll_for_percentage(0.5, 50.0, 100.0), 0.0);
+        let offset = scroll_for_percentage(0.3, 1100.0, 100.0);
+        assert_eq!(reading_progress(offset, 1100.0, 100.0), 0.3);
+    }
+
+    #[test]
+    fn reading_progress_is_clamped() {
+        assert_eq!(reading_progress(0.0, 100.0, 200.0), 1.0);
+        assert_eq!(reading_progress(0.0, 200.0, 200.0), 1.0);
+        assert_eq!(reading_progress(0.0, 300.0, 100.0), 0.0);
+        assert_eq!(reading_progress(100.0, 300.0, 100.0), 0.5);
+        assert_eq!(reading_progress(500.0, 300.0, 100.0), 1.0);
+        assert_eq!(reading_progress(-20.0, 300.0, 100.0), 0.0);
+    }
+
+    #[test]
+    fn accessibility_actions_find_their_item_after_a_reparse() {
+        let dir = std::env::temp_dir().join("wrenched-accessibility-actions");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("doc.md");
+        let filler = "Some filler text.\n\n".repeat(20);
+        std::fs::write(&file, format!("# Top\n\n{filler}# End\n")).unwrap();
+        let theme = get_theme().clone();
+        let mut widget = MarkdowWidget::new(file);
+        lay_out(&mut widget.markdown_layout, 300.0, &theme);
+        widget.viewport_height = 100.0;
+        widget.dirty = false;
+        widget.update_item_node_ids();
+        let end = widget.markdown_layout.len() - 1;
+        let end_id = widget.item_nodes[end];
+        let top_id = widget.item_nodes[0];
+
+        // A paragraph is added before the heading, it keeps its node.
+        widget.markdown_layout =
+            parse_markdown(&format!("# Top\n\nNew\n\n{filler}# End\n"));
+        lay_out(&mut widget.markdown_layout, 300.0, &theme);
+        assert_eq!(
+            widget.perform_access_action(end_id, accesskit::Action::ScrollIntoView),
+            Some(true)
+        );
+        assert_eq!(widget.item_nodes[0], top_id);
+        assert_eq!(widget.item_nodes[end + 1], end_id);
+        assert!(![top_id, end_id].contains(&widget.item_nodes[1]));
+        let rect = widget.item_rect(end + 1).unwrap();
+        assert!(rect.y0 >= 0.0 && rect.y1 <= widget.viewport_height);
+
+        assert_eq!(
+            widget.perform_access_action(
+                NodeId::from(WidgetId::next()),
+                accesskit::Action::ScrollIntoView
+            ),
+            None
+        );
+        assert_eq!(
+            widget.perform_access_action(end_id, accesskit::Action::Focus),
+            None
+        );
+    }
+
+    #[test]
+    fn stats_count_nested_content() {
+        let flow = parse_markdown(
+            "# Title\n\nTwo words.\n\n> quoted text here\n\n- item one\n- item two\n\n## Sub\n\n![alt](img.png)\n\n```\nlet code = 1;\n```\n",
+        );
+        let stats = flow_stats(&flow, &StatsOptions::default());
+        assert_eq!(stats.words, 11);
+        assert_eq!(stats.headings, [1, 1, 0, 0, 0, 0]);
+        assert_eq!(stats.images, 1);
+        assert_eq!(stats.code_blocks, 1);
+
+        let with_code = StatsOptions {
+            count_code: true,
+            ..StatsOptions::default()
+        };
+        assert_eq!(flow_stats(&flow, &with_code).words, 14);
+    }
+
+    #[test]
+    fn find_text_counts_folded_matches() {
+        let widget = MarkdowWidget::from_source(
+            "# Café\n\nThe CAFE\u{301} and the cafe\u{301}.\n\n> - nested café\n\n```\ncafé()\n```\n",
+            PathBuf::new(),
+        );
+        assert_eq!(widget.find_text("café"), 5);
+        assert_eq!(widget.find_text("CAFÉ"), 5);
+        assert_eq!(widget.find_text("the"), 2);
+        assert_eq!(widget.find_text(""), 0);
+        // Matches do not overlap.
+        assert_eq!(
+            MarkdowWidget::from_source("aaaa", PathBuf::new()).find_text("aa"),
+            2
+        );
+    }
+
+    #[test]
+    fn stats_segment_cjk_words() {
+        let flow = parse_markdown("日本語の文章");
+        let stats = flow_stats(&flow, &StatsOptions::default());
+        assert_eq!(stats.characters, 6);
+        assert_eq!(stats.words, 6);
+
+        // Every ideograph and kana is a word of its own while the latin
+        // words around them stay whole.
+        let text = "Rust日本語の文章 text";
+        let words: Vec<_> = text.unicode_word_indices().collect();
+        assert_eq!(
+            words,
+            [
+                (0, "Rust"),
+                (4, "日"),
+                (7, "本"),
+                (10, "語"),
+                (13, "の"),
+                (16, "文"),
+                (19, "章"),
+                (23, "text"),
+            ]
+        );
+        let flow = parse_markdown(text);
+        assert_eq!(flow_stats(&flow, &StatsOptions::default()).words, 8);
+    }
+
+    #[test]
+    fn stats_reading_time() {
+        let flow = parse_markdown("one two three four five six\n\n![a](a.png)");
+        let options = StatsOptions {
+            words_per_minute: 60,
+            time_per_image: std::time::Duration::from_secs(10),
+            count_code: false,
+        };
+        let stats = flow_stats(&flow, &options);
+        assert_eq!(stats.reading_time, std::time::Duration::from_secs(16));
+    }
+
+    #[test]
+    fn nested_block_quotes_increase_level() {
+        let flow = parse_markdown("> outer\n>\n> > inner");
+        let MarkdownContent::Indented { decoration, flow } =
+            &flow.iter().next().unwrap().data
+        else {
+            panic!("Expected a block quote");
+        };
+        assert_eq!(decoration.level, 0);
+        let inner = flow
+            .iter()
+            .find_map(|e| match &e.data {
+                MarkdownContent::Indented { decoration, .. } => {
+                    Some(decoration.level)
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(inner, 1);
+    }
+
+    #[test]
+    fn toc_lists_top_level_headings() {
+        let toc = table_of_contents("# One\n\ntext\n\n> # Quoted\n\n## Two\n");
+        let entries: Vec<_> = toc
+            .iter()
+            .map(|e| (e.level as usize, e.text.as_str(), e.item_index))
+            .collect();
+        assert_eq!(entries, [(1, "One", 0), (2, "Two", 3)]);
+    }
+
+    #[test]
+    fn bind_last_word_shifts_markers() {
+        let (text, markers) = first_paragraph("some text with *last* word");
+        let markers: Vec<_> = markers
+            .into_iter()
+            .map(|(start, end, kind)| TextMarker::new(start..end, kind))
+            .collect();
+        let (bound, bound_markers) = bind_last_word(&text, &markers).unwrap();
+        assert_eq!(bound, "some text with last\u{a0}word");
+        assert_eq!(bound_markers[0].range(), markers[0].range());
+
+        let (bound, _) = bind_last_word("first *second*", &[]).unwrap();
+        assert_eq!(bound, "first\u{a0}*second*");
+        assert!(bind_last_word("single", &[]).is_none());
+    }
+
+    #[test]
+    fn context_menu_copy_takes_only_the_selection() {
+        let source = "First paragraph\n\n- item\n\nLast words";
+        let mut widget = MarkdowWidget::from_source(source, PathBuf::new());
+        assert_eq!(
+            widget.context_menu_entry_text(&ContextMenuEntry::Copy),
+            None
+        );
+
+        widget.apply_selected_range(ContentPos::new(0, 6), ContentPos::new(2, 4));
+        assert_eq!(
+            widget
+                .context_menu_entry_text(&ContextMenuEntry::Copy)
+                .as_deref(),
+            Some("paragraph\n\n- item\n\nLast")
+        );
+
+        widget.apply_selected_range(ContentPos::new(2, 5), ContentPos::new(2, 10));
+        assert_eq!(
+            widget
+                .context_menu_entry_text(&ContextMenuEntry::Copy)
+                .as_deref(),
+            Some("words")
+        );
+        assert_eq!(
+            widget
+                .context_menu_entry_text(&ContextMenuEntry::CopyAsMarkdown)
+                .as_deref(),
+            Some(source)
+        );
+        assert_eq!(
+            widget.context_menu_entry_text(&ContextMenuEntry::SelectAll),
+            None
+        );
+    }
+
+    fn alt_text_of(text: &str) -> String {
+        let flow = parse_markdown(text);
+        flow.iter()
+            .find_map(|element| match &element.data {
+                MarkdownContent::Image { alt_text, .. } => Some(alt_text.clone()),
+                _ => None,
+            })
+            .expect("image")
+    }
+
+    #[test]
+    fn image_title_is_separate_from_alt_text() {
+        let flow =
+            parse_markdown("![a diagram](x.png \"Figure 1\")\n\n![plain](y.png)");
+        let images: Vec<_> = flow
+            .iter()
+            .filter_map(|element| match &element.data {
+                MarkdownContent::Image {
+                    alt_text, title, ..
+                } => Some((alt_text.as_str(), title.as_deref())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(images, [("a diagram", Some("Figure 1")), ("plain", None)]);
+    }
+
+    #[test]
+    fn image_alt_text_keeps_all_fragments() {
+        assert_eq!(alt_text_of("![a diagram](x.png)"), "a diagram");
+        assert_eq!(alt_text_of("![one &amp; two](x.png)"), "one & two");
+        assert_eq!(alt_text_of("![first\nsecond](x.png)"), "first second");
+    }
+
+    #[test]
+    fn image_alt_text_flattens_formatting() {
+        assert_eq!(
+            alt_text_of("![*important* diagram](x.png)"),
+            "important diagram"
+        );
+        assert_eq!(
+            alt_text_of("![see `code` and [link](y)](x.png)"),
+            "see code and link"
+        );
+        assert_eq!(
+            alt_text_of("![outer ![inner](y.png) end](x.png)"),
+            "outer inner end"
+        );
+    }
+
+    fn flow_of(blocks: Vec<MarkdownContent>) -> LayoutFlow<MarkdownContent> {
+        let mut flow = LayoutFlow::new();
+        for block in blocks {
+            flow.push(block);
+        }
+        flow
+    }
+
+    fn list_of(items: Vec<LayoutFlow<MarkdownContent>>) -> MarkdownContent {
+        MarkdownContent::List {
+            list: List {
+                list: items,
+                marker: ListMarker::Symbol {
+                    symbol: "•".to_string(),
+                    layout: Box::new(Layout::new()),
+                },
+                indentation: 20.0,
+                spacing: get_theme().paragraph_spacing,
+            },
+        }
+    }
+
+    fn image(width: u32, height: u32) -> MarkdownContent {
+        MarkdownContent::Image {
+            uri: "img.png".to_string(),
+            title: None,
+            alt_text: String::new(),
+            caption_layout: Layout::new(),
+            estimated_size: Some((width, height)),
+            image: None,
+            layout_width: 0.0,
+            max_height: None,
+            alignment: ImageAlignment::Left,
+            scale: 1.0,
+            natural_size: false,
+            spacing: get_theme().paragraph_spacing,
+        }
+    }
+
+    fn line(height: f32) -> MarkdownContent {
+        MarkdownContent::HorizontalLine {
+            height,
+            width: 0.0,
+            style: LineStyle::Solid,
+            spacing: get_theme().paragraph_spacing,
+        }
+    }
+
+    /// With `gap` the paragraph spacing: a line 0..10, then a list
+    /// starting at 10 + gap whose first item holds a line 0..20 and whose
+    /// second item holds a line 0..5 and, after another gap, a nested list
+    /// with an image of 40 px. The image starts at x 40 and y 35 + 2 * gap.
+    fn nested_lists() -> LayoutFlow<MarkdownContent> {
+        flow_of(vec![
+            line(10.0),
+            list_of(vec![
+                flow_of(vec![line(20.0)]),
+                flow_of(vec![
+                    line(5.0),
+                    list_of(vec![flow_of(vec![image(30, 40)])]),
+                ]),
+            ]),
+        ])
+    }
+
+    #[test]
+    fn hit_test_nested_lists() {
+        let flow = nested_lists();
+        let gap = get_theme().paragraph_spacing as f64;
+        let hit = hit_test_document(
+            &flow,
+            Point::new(45.0, 50.0 + 2.0 * gap),
+            Vec2::ZERO,
+            &get_theme(),
+        )
+        .unwrap();
+        assert_eq!(hit.path, [1, 1, 1, 0, 0]);
+        assert_eq!(hit.kind, BlockKind::Image);
+        assert_eq!(hit.image_uri.as_deref(), Some("img.png"));
+        assert_eq!(hit.local_position, Point::new(5.0, 15.0));
+
+        let hit = hit_test_document(
+            &flow,
+            Point::new(25.0, 15.0 + gap),
+            Vec2::ZERO,
+            &get_theme(),
+        )
+        .unwrap();
+        assert_eq!(hit.path, [1, 0, 0]);
+        assert_eq!(hit.kind, BlockKind::HorizontalLine);
+    }
+
+    #[test]
+    fn widget_positions_map_to_nested_blocks() {
+        let position = widget_to_document(
+            Point::new(30.0, 5.0),
+            Vec2::new(10.0, 0.0),
+            Vec2::new(3.0, 40.0),
+        );
+        assert_eq!(position, Point::new(23.0, 45.0));
+        // Padding moves the document like the gutter does.
+        let position = widget_to_document(
+            Point::new(30.0, 5.0),
+            Vec2::new(16.0, 8.0),
+            Vec2::new(0.0, 40.0),
+        );
+        assert_eq!(position, Point::new(14.0, 37.0));
+
+        let flow = nested_lists();
+        let theme = get_theme();
+        let scroll = Vec2::new(0.0, 35.0 + 2.0 * theme.paragraph_spacing as f64);
+        let position =
+            widget_to_document(Point::new(55.0, 15.0), Vec2::new(10.0, 0.0), scroll);
+        let hit = hit_test_flow(&flow, position, &theme, &mut Vec::new()).unwrap();
+        assert_eq!(hit.path, [1, 1, 1, 0, 0]);
+        assert_eq!(hit.local_position, Point::new(5.0, 15.0));
+        // Left of the gutter is outside of the document.
+        let position = widget_to_document(
+            Point::new(5.0, 15.0),
+            Vec2::new(10.0, 0.0),
+            Vec2::ZERO,
+        );
+        assert!(hit_test_flow(&flow, position, &theme, &mut Vec::new()).is_none());
+    }
+
+    #[test]
+    fn pages_start_below_the_top_padding() {
+        let mut widget = laid_out_widget(&"Some filler text.\n\n".repeat(20))
+            .with_paginated(true);
+        widget.padding = Padding {
+            top: 10.0,
+            right: 5.0,
+            bottom: 20.0,
+            left: 5.0,
+        };
+        widget.update_pages();
+        assert!(widget.page_count() > 2);
+        assert!(widget.set_current_page(1));
+
+        let size = Size::new(300.0, widget.viewport_height);
+        let clip = widget.document_clip(size);
+        assert_eq!(clip.y0, 10.0);
+        // The page ends where the next one starts, above the bottom padding.
+        let page_end = (widget.page_starts[2] - widget.page_starts[1]) as f64;
+        assert_eq!(clip.y1, 10.0 + page_end);
+        assert!(clip.y1 <= widget.viewport_height - 20.0);
+
+        let first = widget
+            .markdown_layout
+            .iter()
+            .position(|element| element.offset == widget.page_starts[1])
+            .unwrap();
+        let rect = widget.item_rect(first).unwrap();
+        assert_eq!((rect.x0, rect.y0), (widget.left_gutter + 5.0, 10.0));
+        let hit = widget
+            .content_at(Point::new(rect.x0 + 1.0, rect.y0 + 1.0))
+            .unwrap();
+        assert_eq!(hit.path, [first]);
+
+        // Scrolled documents show their padding.
+        widget.paginated = false;
+        assert_eq!(widget.document_clip(size).y0, 0.0);
+    }
+
+    #[test]
+    fn background_is_filled_when_the_theme_has_one() {
+        let mut theme = get_theme().clone();
+        theme.background_color = None;
+        let mut scene = Scene::new();
+        draw_background(&mut scene, Size::new(100.0, 50.0), &theme);
+        assert!(scene.encoding().is_empty());
+        theme.background_color = Some(Color::from_rgb8(0x20, 0x20, 0x20));
+        draw_background(&mut scene, Size::new(100.0, 50.0), &theme);
+        assert_eq!(scene.encoding().n_paths, 1);
+    }
+
+    #[test]
+    fn theme_mode_applies_to_the_widget_only() {
+        let global_text_color = get_theme().text_color;
+        let widget = MarkdowWidget::from_source("Some text", PathBuf::new())
+            .with_theme_mode(ThemeMode::Light);
+        assert_eq!(get_theme().text_color, global_text_color);
+        let global_theme = get_theme();
+        let theme = widget.widget_theme(&global_theme).unwrap();
+        assert_eq!(theme.text_color, Theme::light().text_color);
+
+        // The other widget settings apply on top of the mode.
+        let widget = widget.with_scale_factor(2.0);
+        let theme = widget.widget_theme(&global_theme).unwrap();
+        assert_eq!(theme.text_color, Theme::light().text_color);
+        assert_eq!(theme.scale, Theme::light().scale * 2.0);
+    }
+
+    #[test]
+    fn margins_and_widths_come_from_the_theme_of_the_layout() {
+        let global_theme = get_theme().clone();
+        let mut theme = global_theme.clone();
+        theme.paragraph_spacing = global_theme.paragraph_spacing + 17.0;
+        theme.code_block_padding = global_theme.code_block_padding + 5.0;
+        theme.markdown_indentation_decoration_width =
+            global_theme.markdown_indentation_decoration_width + 9.0;
+        let mut flow = parse_markdown("One\n\n```\ncode\n```\n\n> Quote");
+        lay_out(&mut flow, 300.0, &theme);
+
+        let first = flow.get(0).unwrap();
+        let code = flow.get(1).unwrap();
+        assert_eq!(
+            code.offset,
+            first.offset + first.height + theme.paragraph_spacing
+        );
+        let MarkdownContent::CodeBlock { text_layout, .. } = &code.data else {
+            panic!("Expected a code block");
+        };
+        assert_eq!(
+            code.data.width(),
+            text_layout.width() + 2.0 * theme.code_block_padding
+        );
+        let MarkdownContent::Indented { flow: quote, .. } =
+            &flow.get(2).unwrap().data
+        else {
+            panic!("Expected a block quote");
+        };
+        assert_eq!(
+            flow.get(2).unwrap().data.width(),
+            theme.markdown_indentation_decoration_width + quote.width()
+        );
+    }
+
+    #[test]
+    fn hit_test_applies_scroll() {
+        let flow = nested_lists();
+        let scroll =
+            Vec2::new(0.0, 35.0 + 2.0 * get_theme().paragraph_spacing as f64);
+        let scrolled =
+            hit_test_document(&flow, Point::new(45.0, 15.0), scroll, &get_theme())
+                .unwrap();
+        assert_eq!(scrolled.path, [1, 1, 1, 0, 0]);
+        assert_eq!(scrolled.local_position, Point::new(5.0, 15.0));
+        assert!(hit_test_document(
+            &flow,
+            Point::new(45.0, 50.0),
+            scroll,
+            &get_theme()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn hit_test_list_marker_area() {
+        let flow = nested_lists();
+        let gap = get_theme().paragraph_spacing as f64;
+        let hit = hit_test_document(
+            &flow,
+            Point::new(5.0, 15.0 + gap),
+            Vec2::ZERO,
+            &get_theme(),
+        )
+        .unwrap();
+        assert_eq!(hit.path, [1]);
+        assert_eq!(hit.kind, BlockKind::List);
+    }
+
+    #[test]
+    fn list_continuation_blocks_align_with_item_text() {
+        let flow =
+            flow_of(vec![list_of(vec![flow_of(vec![line(10.0), line(10.0)])])]);
+        let mut theme = get_theme().clone();
+        // The second block starts after the paragraph spacing.
+        let y = 15.0 + theme.paragraph_spacing as f64;
+        let hit = hit_test_document(&flow, Point::new(22.0, y), Vec2::ZERO, &theme)
+            .unwrap();
+        assert_eq!(hit.path, [0, 0, 1]);
+        assert_eq!(hit.local_position, Point::new(2.0, 5.0));
+
+        theme.list_continuation_indent = 10.0;
+        let hit = hit_test_document(&flow, Point::new(32.0, y), Vec2::ZERO, &theme)
+            .unwrap();
+        assert_eq!(hit.local_position, Point::new(2.0, 5.0));
+        // The first block is not affected.
+        let hit =
+            hit_test_document(&flow, Point::new(22.0, 5.0), Vec2::ZERO, &theme)
+                .unwrap();
+        assert_eq!(hit.path, [0, 0, 0]);
+        assert_eq!(hit.local_position, Point::new(2.0, 5.0));
+        // Left of the shifted block is the list itself.
+        let hit = hit_test_document(&flow, Point::new(25.0, y), Vec2::ZERO, &theme)
+            .unwrap();
+        assert_eq!(hit.path, [0]);
+    }
+
+    #[test]
+    fn hit_test_block_quote_indentation() {
+        let flow = flow_of(vec![MarkdownContent::Indented {
+            decoration: super::IndentationDecoration::new(0),
+            flow: flow_of(vec![line(10.0)]),
+        }]);
+        let theme = get_theme();
+        let x = theme.markdown_indentation_decoration_width as f64 + 2.0;
+        let hit = hit_test_document(&flow, Point::new(x, 5.0), Vec2::ZERO, &theme)
+            .unwrap();
+        assert_eq!(hit.path, [0, 0]);
+        assert_eq!(hit.local_position, Point::new(2.0, 5.0));
+    }
+
+    #[test]
+    fn wrap_comment_applies_to_next_code_block() {
+        let flow = parse_markdown(
+            "<!-- wrap -->\n```\nwrapped\n```\n\n```\nnot wrapped\n```\n\n\
+             <!-- wrap -->\n\ntext\n\n```\nalso not\n```\n",
+        );
+        let wraps: Vec<_> = flow
+            .iter()
+            .filter_map(|e| match &e.data {
+                MarkdownContent::CodeBlock { wrap, .. } => Some(*wrap),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(wraps, [true, false, false]);
+        assert_eq!(flow.len(), 4);
+    }
+
+    #[test]
+    fn slugify_like_github() {
+        assert_eq!(slugify("Hello World!"), "hello-world");
+        assert_eq!(slugify("  API: `stats()` & more "), "api-stats--more");
+        assert_eq!(slugify("Über_uns - 2"), "über_uns---2");
+        assert_eq!(slugify("???"), "");
+    }
+
+    #[test]
+    fn headings_get_unique_anchor_ids() {
+        let flow = parse_markdown("# Intro\n\n## Intro\n\n> # Intro\n\n# !!!\n");
+        let mut anchors = Vec::new();
+        for element in flow.iter() {
+            match &element.data {
+                MarkdownContent::Header { anchor_id, .. } => {
+                    anchors.push(anchor_id.clone())
+                }
+                MarkdownContent::Indented { flow, .. } => {
+                    for element in flow.iter() {
+                        if let MarkdownContent::Header { anchor_id, .. } =
+                            &element.data
+                        {
+                            anchors.push(anchor_id.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            anchors,
+            [
+                Some("intro".to_string()),
+                Some("intro-1".to_string()),
+                Some("intro-2".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn headings_take_their_id_from_attributes() {
+        let flow = parse_markdown("# Title {#custom}\n\n# Title\n");
+        let headers: Vec<_> = flow
+            .iter()
+            .filter_map(|element| match &element.data {
+                MarkdownContent::Header {
+                    text,
+                    id,
+                    anchor_id,
+                    ..
+                } => Some((text.as_str(), id.as_deref(), anchor_id.as_deref())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                ("Title", Some("custom"), Some("custom")),
+                ("Title", None, Some("title")),
+            ]
+        );
+    }
+
+    #[test]
+    fn wikilinks_become_links() {
+        let flow = parse_markdown_with(
+            "See [[Page Name]], [[target|the label]], \\[[escaped]] and `[[code]]`.",
+            ParseOptions {
+                wikilinks: true,
+                ..ParseOptions::default()
+            },
+        );
+        let Some(MarkdownContent::Paragraph { text, markers, .. }) =
+            flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a paragraph");
+        };
+        assert_eq!(text, "See Page Name, the label, [[escaped]] and [[code]].");
+        let wiki = |url: &str| {
+            MarkerKind::Link(LinkTarget {
+                url: url.to_string(),
+                kind: LinkKind::Wiki,
+                scheme: LinkScheme::Relative,
+            })
+        };
+        let markers: Vec<_> = markers
+            .iter()
+            .map(|m| (m.range.start, m.range.end, m.kind.clone()))
+            .collect();
+        assert_eq!(
+            markers,
+            [
+                (4, 13, wiki("Page Name")),
+                (15, 24, wiki("target")),
+                (42, 50, MarkerKind::InlineCode),
+            ]
+        );
+    }
+
+    #[test]
+    fn links_are_clicked_only_without_dragging() {
+        let dir = std::env::temp_dir().join("wrenched-link-clicks");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("doc.md");
+        std::fs::write(&file, "[[Page]]").unwrap();
+        let widget = MarkdowWidget::new(file).with_wikilinks(true);
+        let mut harness = TestHarness::create(widget);
+        let _ = harness.render();
+        let clicked = |harness: &mut TestHarness| {
+            std::iter::from_fn(|| harness.pop_action()).any(|(action, _)| {
+                matches!(
+                    action,
+                    Action::Other(action) if matches!(
+                        action.downcast_ref::<MarkdownAction>(),
+                        Some(MarkdownAction::LinkClicked { .. })
+                    )
+                )
+            })
+        };
+
+        harness.mouse_move((5.0, 8.0));
+        harness.mouse_button_press(PointerButton::Primary);
+        harness.mouse_move((60.0, 80.0));
+        harness.mouse_button_release(PointerButton::Primary);
+        assert!(!clicked(&mut harness));
+
+        harness.mouse_move((5.0, 8.0));
+        harness.mouse_button_press(PointerButton::Primary);
+        assert!(!clicked(&mut harness));
+        harness.mouse_button_release(PointerButton::Primary);
+        assert!(clicked(&mut harness));
+    }
+
+    #[test]
+    fn link_click_handler_gets_anchors_first() {
+        let source = format!(
+            "[jump](#end)\n\n{}# End\n",
+            "Some filler text.\n\n".repeat(40)
+        );
+        let clicked = Arc::new(Mutex::new(Vec::new()));
+        let decision = Arc::new(Mutex::new(LinkDecision::Ignore));
+        let handler: LinkClickHandler = {
+            let clicked = clicked.clone();
+            let decision = decision.clone();
+            Arc::new(move |url: &str, kind: LinkKind| {
+                clicked.lock().unwrap().push((url.to_string(), kind));
+                *decision.lock().unwrap()
+            })
+        };
+        let widget = MarkdowWidget::from_source(source, PathBuf::new())
+            .with_link_click_handler(handler);
+        let mut harness = TestHarness::create(widget);
+        let _ = harness.render();
+        let scroll_y = |harness: &TestHarness| {
+            harness
+                .root_widget()
+                .downcast::<MarkdowWidget>()
+                .unwrap()
+                .scroll
+                .y
+        };
+
+        harness.mouse_move((5.0, 8.0));
+        harness.mouse_button_press(PointerButton::Primary);
+        harness.mouse_button_release(PointerButton::Primary);
+        assert_eq!(
+            *clicked.lock().unwrap(),
+            [("#end".to_string(), LinkKind::Url)]
+        );
+        assert_eq!(scroll_y(&harness), 0.0);
+
+        *decision.lock().unwrap() = LinkDecision::Default;
+        harness.mouse_button_press(PointerButton::Primary);
+        harness.mouse_button_release(PointerButton::Primary);
+        assert_eq!(clicked.lock().unwrap().len(), 2);
+        assert!(scroll_y(&harness) > 0.0);
+    }
+
+    #[test]
+    fn external_links_are_resolved_against_the_document() {
+        let widget = MarkdowWidget::from_source("", "/docs");
+        assert_eq!(
+            widget.external_url("files/a%20b.pdf?x=1"),
+            "/docs/files/a b.pdf"
+        );
+        assert_eq!(
+            widget.external_url("https://example.com/a"),
+            "https://example.com/a"
+        );
+        assert_eq!(widget.external_url("#top"), "#top");
+    }
+
+    #[test]
+    fn scale_factor_enlarges_text_and_images() {
+        let dir = std::env::temp_dir().join("wrenched-scale-factor");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("doc.md");
+        std::fs::write(&file, "Some text\n\n![image](small-image-test.png)\n")
+            .unwrap();
+        let [normal, scaled] = [1.0, 2.0].map(|scale_factor| {
+            let mut widget =
+                MarkdowWidget::new(&file).with_scale_factor(scale_factor);
+            let global_theme = get_theme();
+            let widget_theme = widget.widget_theme(&global_theme);
+            let theme = widget_theme.as_ref().unwrap_or(&global_theme);
+            lay_out(&mut widget.markdown_layout, 300.0, theme);
+            let text_height = widget.markdown_layout.get(0).unwrap().height;
+            let image_size = item(&widget.markdown_layout, 1)
+                .image_display_size()
+                .unwrap();
+            (text_height, image_size)
+        });
+        assert!(scaled.0 > normal.0 * 1.5);
+        assert_eq!(normal.1, Size::new(50.0, 50.0));
+        assert_eq!(scaled.1, Size::new(100.0, 100.0));
+    }
+
+    #[test]
+    fn links_are_styled_by_scheme() {
+        assert_eq!(LinkScheme::of("https://a.b"), LinkScheme::External);
+        assert_eq!(LinkScheme::of("HTTP://a.b"), LinkScheme::External);
+        assert_eq!(LinkScheme::of("mailto:me@a.b"), LinkScheme::Mail);
+        assert_eq!(LinkScheme::of("#usage"), LinkScheme::Anchor);
+        assert_eq!(LinkScheme::of("docs/usage.md"), LinkScheme::Relative);
+        assert_eq!(LinkScheme::of("C:\\docs\\usage.md"), LinkScheme::Relative);
+        assert_eq!(LinkScheme::of("ftp://a.b"), LinkScheme::Other);
+
+        let text = "web mail";
+        let (_, markers) =
+            first_paragraph("[web](https://a.b) [mail](mailto:me@a.b)");
+        let markers: Vec<_> = markers
+            .into_iter()
+            .map(|(start, end, kind)| TextMarker::new(start..end, kind))
+            .collect();
+        let mut font_ctx = FontContext::new();
+        let mut layout_ctx = LayoutContext::new();
+        let mut builder = text_to_builder(
+            text,
+            &markers,
+            &get_theme(),
+            &mut font_ctx,
+            &mut layout_ctx,
+        );
+        let mut layout = builder.build(text);
+        layout.break_all_lines(None);
+        for line in layout.lines() {
+            for item in line.items() {
+                let PositionedLayoutItem::GlyphRun(glyph_run) = item else {
+                    continue;
+                };
+                let range = glyph_run.run().text_range();
+                let style = glyph_run.style();
+                // Only the last character of the web link carries the arrow.
+                assert_eq!(
+                    style.brush.2 == GlyphMark::ExternalLink,
+                    range == (2..3),
+                    "{range:?}"
+                );
+                if range.start >= 4 {
+                    assert!(style.underline.is_none(), "{range:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dangerous_link_schemes_are_not_activated() {
+        let policy = LinkPolicy::default();
+        for url in [
+            "javascript:alert(1)",
+            "  JaVaScRiPt:alert(1)",
+            "java\tscript:alert(1)",
+            "java\nscript:alert(1)",
+            "\u{1}\u{1f}javascript:alert(1)",
+            "javascript\u{0}:alert(1)",
+            "java\u{7f}script:alert(1)",
+            "vbscript:msgbox(1)",
+            "data:text/html;base64,PHNjcmlwdD4=",
+            "ftp://example.com/file",
+            "https://example.com/\u{85}",
+        ] {
+            assert_eq!(policy.sanitize(url), None, "{url:?}");
+        }
+        // Entities and escapes are decoded by the parser first.
+        let (_, markers) = first_paragraph("[x](jav&#x09;ascript:alert(1))");
+        let [(_, _, MarkerKind::Link(target))] = &markers[..] else {
+            panic!("Expected a link, got {markers:?}");
+        };
+        assert_eq!(policy.sanitize(&target.url), None);
+
+        // Blocked schemes stay blocked when they are allowed.
+        let mut policy = LinkPolicy::default();
+        policy.allowed_schemes.push("javascript".to_string());
+        policy.allowed_schemes.push("FTP".to_string());
+        assert_eq!(policy.sanitize("javascript:alert(1)"), None);
+        assert_eq!(
+            policy.sanitize("ftp://example.com/file").as_deref(),
+            Some("ftp://example.com/file")
+        );
+
+        // Wikilink pages are names, not URLs, but still no scripts.
+        assert_eq!(policy.sanitize_page("javascript:alert(1)"), None);
+        assert_eq!(policy.sanitize_page(" Java\tScript:alert(1)"), None);
+        assert_eq!(policy.sanitize_page("Page\u{0}"), None);
+        assert_eq!(
+            policy.sanitize_page("Notes: Intro").as_deref(),
+            Some("Notes: Intro")
+        );
+    }
+
+    #[test]
+    fn links_with_blocked_schemes_reach_no_handler() {
+        let source = "[x](wikilink:javascript:alert(1))";
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let handler: LinkClickHandler = {
+            let handled = handled.clone();
+            Arc::new(move |url: &str, _| {
+                handled.lock().unwrap().push(url.to_string());
+                LinkDecision::OpenExternally
+            })
+        };
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<usize>>);
+        impl MarkdownObserver for Recorder {
+            fn on_link_clicked(&mut self, _url: &str) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+        let recorder = Recorder::default();
+        let mut widget = MarkdowWidget::from_source(source, PathBuf::new())
+            .with_link_click_handler(handler);
+        widget.add_observer(Box::new(recorder.clone()));
+        let mut harness = TestHarness::create(widget);
+        let _ = harness.render();
+
+        harness.mouse_move((5.0, 8.0));
+        harness.mouse_button_press(PointerButton::Primary);
+        harness.mouse_button_release(PointerButton::Primary);
+        let clicked =
+            std::iter::from_fn(|| harness.pop_action()).any(|(action, _)| {
+                matches!(
+                    action,
+                    Action::Other(action) if matches!(
+                        action.downcast_ref::<MarkdownAction>(),
+                        Some(MarkdownAction::LinkClicked { .. })
+                    )
+                )
+            });
+        assert!(!clicked);
+        assert!(handled.lock().unwrap().is_empty());
+        assert_eq!(*recorder.0.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn allowed_links_are_kept_escaped_and_resolved() {
+        let policy = LinkPolicy::default();
+        assert_eq!(
+            policy.sanitize(" https://a.b/a%20b?q=%2F ").as_deref(),
+            Some("https://a.b/a%20b?q=%2F")
+        );
+        assert_eq!(
+            policy.sanitize("mailto:me@a.b").as_deref(),
+            Some("mailto:me@a.b")
+        );
+        assert_eq!(policy.sanitize("docs/a.md").as_deref(), Some("docs/a.md"));
+        let target = LinkTarget::new("https://a.b/a%20b%C3%A4", LinkKind::Url);
+        assert_eq!(target.display_url(), "https://a.b/a b\u{e4}");
+        assert_eq!(target.url, "https://a.b/a%20b%C3%A4");
+
+        let policy = LinkPolicy {
+            base_url: Some(
+                "https://example.com/docs/guide/index.md?x#y".to_string(),
+            ),
+            ..LinkPolicy::default()
+        };
+        let resolved = |url: &str| policy.sanitize(url).unwrap();
+        assert_eq!(
+            resolved("intro.md"),
+            "https://example.com/docs/guide/intro.md"
+        );
+        assert_eq!(
+            resolved("../api.md#top"),
+            "https://example.com/docs/api.md#top"
+        );
+        assert_eq!(resolved("./a/./b/.."), "https://example.com/docs/guide/a/");
+        assert_eq!(resolved("../../../../x"), "https://example.com/x");
+        assert_eq!(resolved("/root.md"), "https://example.com/root.md");
+        assert_eq!(resolved("//cdn.example.com/a"), "https://cdn.example.com/a");
+        assert_eq!(
+            resolved("?page=2"),
+            "https://example.com/docs/guide/index.md?page=2"
+        );
+        assert_eq!(resolved("#usage"), "#usage");
+        assert_eq!(resolve_url("https://a.b", "x"), "https://a.b/x");
+        assert_eq!(resolve_url("notes/readme.md", "../x.md"), "x.md");
+    }
+
+    #[test]
+    fn anchors_find_their_top_level_item() {
+        let flow = parse_markdown(
+            "# Intro\n\ntext\n\n> ## Quoted\n\n- item\n\n  ### In List\n\n# Intro\n",
+        );
+        assert_eq!(anchor_item_index(&flow, "intro"), Some(0));
+        assert_eq!(anchor_item_index(&flow, "quoted"), Some(2));
+        assert_eq!(anchor_item_index(&flow, "in-list"), Some(3));
+        assert_eq!(anchor_item_index(&flow, "intro-1"), Some(4));
+        assert_eq!(anchor_item_index(&flow, "missing"), None);
+    }
+
+    #[test]
+    fn soft_breaks_as_hard_keep_chat_lines() {
+        let message =
+            "hey, *are you\ncoming* tonight?\n\u{4f60}\u{597d}\n\u{4e16}\u{754c}";
+        let options = ParseOptions {
+            soft_breaks_as_hard: true,
+            ..ParseOptions::default()
+        };
+        let flow = parse_markdown_with(message, options);
+        assert_paragraph_text(
+            &flow,
+            0,
+            "hey, are you\ncoming tonight?\n\u{4f60}\u{597d}\n\u{4e16}\u{754c}",
+        );
+        let MarkdownContent::Paragraph { markers, .. } = &flow.get(0).unwrap().data
+        else {
+            panic!("Expected a paragraph");
+        };
+        let italic: Vec<_> = markers
+            .iter()
+            .filter(|marker| marker.kind == MarkerKind::Italic)
+            .map(|marker| (marker.range.start, marker.range.end))
+            .collect();
+        assert_eq!(italic, [(5, 19)]);
 
-            let run_metrics = run.metrics();
-            if let Some(underline) = &style.underline {
-                draw_underline(
-                    scene,
-                    underline,
-                    &glyph_run,
-                    run_metrics,
-                    &transform,
-                );
-            }
+        // Without the option the lines are joined, East Asian ones without
+        // a space.
+        assert_paragraph_text(
+            &parse_markdown(message),
+            0,
+            "hey, are you coming tonight? \u{4f60}\u{597d}\u{4e16}\u{754c}",
+        );
+    }
 
-            if let Some(strikethrough) = &style.strikethrough {
-                draw_strikethrough(
-                    scene,
-                    strikethrough,
-                    &glyph_run,
-                    run_metrics,
-                    &transform,
-                );
+    #[test]
+    fn wikilinks_skip_code_blocks() {
+        let options = ParseOptions {
+            wikilinks: true,
+            ..ParseOptions::default()
+        };
+        let flow = parse_markdown_with(
+            "```\n[[a]]\n```\n\n> ~~~~\n> [[b]]\n> ~~~~\n\n    [[c]]\n\n[[d]] and [[e]](https://a.b)\n",
+            options,
+        );
+        let code = |data: &MarkdownContent| match data {
+            MarkdownContent::CodeBlock { text, .. } => text.clone(),
+            _ => panic!("expected a code block"),
+        };
+        assert_eq!(code(item(&flow, 0)), "[[a]]\n");
+        let MarkdownContent::Indented { flow: quote, .. } = item(&flow, 1) else {
+            panic!("expected a quote");
+        };
+        assert_eq!(code(item(quote, 0)), "[[b]]\n");
+        assert_eq!(code(item(&flow, 2)), "[[c]]\n");
+        let MarkdownContent::Paragraph { text, markers, .. } = item(&flow, 3) else {
+            panic!("expected a paragraph");
+        };
+        assert_eq!(text, "d and [e]");
+        let kinds: Vec<_> = markers
+            .iter()
+            .map(|m| (m.range.clone(), m.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (0..1, MarkerKind::Link(LinkTarget::new("d", LinkKind::Wiki))),
+                (
+                    6..9,
+                    MarkerKind::Link(LinkTarget::new("https://a.b", LinkKind::Url))
+                ),
+            ]
+        );
+        let (_, markers) = first_paragraph("[[Page]] and [link](https://a.b)");
+        assert_eq!(
+            markers,
+            [(
+                13,
+                17,
+                MarkerKind::Link(LinkTarget::new("https://a.b", LinkKind::Url))
+            )]
+        );
+
+        // Authors can't make wikilinks from regular links.
+        let flow = parse_markdown_with("[x](wikilink:Page)", options);
+        let MarkdownContent::Paragraph { markers, .. } = item(&flow, 0) else {
+            panic!("expected a paragraph");
+        };
+        assert_eq!(
+            markers[0].kind,
+            MarkerKind::Link(LinkTarget::new("wikilink:Page", LinkKind::Url))
+        );
+    }
+
+    #[test]
+    fn bounding_rects() {
+        let rect = image(30, 40).bounding_rect(Vec2::new(5.0, 10.0));
+        assert_eq!(rect, Rect::new(5.0, 10.0, 35.0, 50.0));
+        let list = list_of(vec![flow_of(vec![image(30, 40)])]);
+        assert_eq!(
+            list.bounding_rect(Vec2::ZERO),
+            Rect::new(0.0, 0.0, 50.0, 40.0)
+        );
+        assert_eq!(line(10.0).bounding_rect(Vec2::ZERO).area(), 0.0);
+    }
+
+    #[test]
+    fn tall_image_is_limited_and_centered_in_a_list() {
+        let mut theme = get_theme().clone();
+        theme.image_max_height = Some(300.0);
+        theme.image_alignment = ImageAlignment::Center;
+        let mut flow = flow_of(vec![list_of(vec![flow_of(vec![image(800, 1200)])])]);
+        let width = 400.0;
+        lay_out(&mut flow, width, &theme);
+        let Some(MarkdownContent::List { list }) = flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a list");
+        };
+        let element = list.list[0].get(0).unwrap();
+        // Fitting the width leaves it taller than the maximum.
+        assert_eq!(
+            element.data.image_display_size(),
+            Some(Size::new(200.0, 300.0))
+        );
+        assert_eq!(element.height, 300.0);
+        let available = (width - list.indentation) as f64;
+        let offset = (available - 200.0) / 2.0;
+        assert_eq!(element.data.image_offset(), offset);
+        assert_eq!(
+            element.data.width() as f64,
+            offset + 200.0,
+            "the width ends at the right edge of the image"
+        );
+
+        let x = list.indentation as f64 + offset;
+        let hit =
+            hit_test_document(&flow, Point::new(x + 1.0, 1.0), Vec2::ZERO, &theme)
+                .unwrap();
+        assert_eq!(hit.image_uri.as_deref(), Some("img.png"));
+        let hit =
+            hit_test_document(&flow, Point::new(x - 1.0, 1.0), Vec2::ZERO, &theme)
+                .unwrap();
+        assert_eq!(hit.image_uri, None);
+    }
+
+    #[test]
+    fn only_blocks_which_can_overflow_are_clipped() {
+        assert!(image(30, 40).clips_to_bounds());
+        assert!(MarkdownContent::code_block("x".to_string()).clips_to_bounds());
+        assert!(!MarkdownContent::paragraph("x".to_string()).clips_to_bounds());
+        assert!(!list_of(vec![flow_of(vec![image(30, 40)])]).clips_to_bounds());
+        assert!(!line(10.0).clips_to_bounds());
+    }
+
+    #[test]
+    fn quote_attribution() {
+        let flow = parse_markdown("> Quote text\n> \u{2014} Author, *Book*");
+        let Some(MarkdownContent::Indented { flow, .. }) =
+            flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a quote");
+        };
+        let paragraphs: Vec<_> = flow
+            .iter()
+            .filter_map(|e| match &e.data {
+                MarkdownContent::Paragraph {
+                    text,
+                    markers,
+                    attribution,
+                    ..
+                } => Some((text.as_str(), markers.len(), *attribution)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            paragraphs,
+            [("Quote text", 0, false), ("\u{2014} Author, Book", 1, true)]
+        );
+
+        // Only the last paragraph of a quote and never outside of one.
+        for markdown in ["> -- Not this\n>\n> Text", "-- Not a quote"] {
+            let flow = parse_markdown(markdown);
+            let mut attributions = 0;
+            for element in flow.iter() {
+                let MarkdownContent::Indented { flow, .. } = &element.data else {
+                    continue;
+                };
+                for element in flow.iter() {
+                    if let MarkdownContent::Paragraph {
+                        attribution: true, ..
+                    } = &element.data
+                    {
+                        attributions += 1;
+                    }
+                }
             }
+            assert_eq!(attributions, 0, "{markdown:?}");
         }
-        top_line_index += 1;
     }
-}
 
-fn draw_image(scene: &mut Scene, image: &Image, translation: Vec2) {
-    let transform: Affine = Affine::translate(translation);
-    scene.draw_image(image, transform);
-}
+    #[test]
+    fn block_backgrounds_span_the_layout_width() {
+        let mut flow = parse_markdown("```\nx\n```\n\n> Quote\n\nText");
+        lay_out(&mut flow, 300.0, &get_theme());
+        for index in 0..2 {
+            let data = &flow.get(index).unwrap().data;
+            assert!(data.width() < 300.0, "block {index}");
+            assert_eq!(data.background_width(), 300.0, "block {index}");
+            assert_eq!(data.bounding_rect(Vec2::ZERO).width(), 300.0);
+        }
+        let paragraph = &flow.get(2).unwrap().data;
+        assert_eq!(paragraph.background_width(), paragraph.width());
+    }
 
-fn draw_flow(
-    scene: &mut Scene,
-    flow: &LayoutFlow<MarkdownContent>,
-    source_translation: Vec2,
-    source_rect: &Rect,
-    theme: &Theme,
-    apply_scroll: bool,
-) {
-    let visible_parts = flow.get_visible_parts(
-        source_rect.y0 as f32,
-        (source_rect.y1 - source_rect.y0) as f32,
-    );
+    #[test]
+    fn clearing_forgets_the_document() {
+        let dir = std::env::temp_dir().join("wrenched-clear");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("doc.md");
+        std::fs::write(&file, "# Title\n\nText\n").unwrap();
+        let mut widget = MarkdowWidget::new(file);
+        assert!(!widget.is_empty());
+        widget.all_selected = true;
+        widget.hovered_heading = Some(0);
+        widget.collapsed_sections.insert(0);
+        widget.pressed_at = Some(Point::new(1.0, 2.0));
+        widget.dirty_items.insert(1);
+        widget.scroll.y = 20.0;
+        widget.back_history.push(widget.history_entry());
+        widget.forward_history.push(widget.history_entry());
+        widget.clear_all();
+        assert!(widget.is_empty());
+        assert!(widget.source.is_empty());
+        assert_eq!(widget.file_path, None);
+        assert!(!widget.can_go_back());
+        assert!(!widget.can_go_forward());
+        assert!(!widget.all_selected);
+        assert_eq!(widget.hovered_heading, None);
+        assert!(widget.collapsed_sections.is_empty());
+        assert_eq!(widget.pressed_at, None);
+        assert!(widget.dirty_items.is_empty());
+        assert_eq!(widget.scroll, Vec2::ZERO);
+    }
 
-    let offset = if apply_scroll { source_rect.y0 } else { 0.0 };
-    for visible_part in visible_parts {
-        let translation =
-            source_translation + Vec2::new(0.0, visible_part.offset as f64 - offset);
-        visible_part.get_source_rect(source_rect);
-        let sub_source_rect = visible_part.get_source_rect(source_rect);
-        visible_part
-            .data
-            .paint(scene, translation, &sub_source_rect, theme);
+    #[test]
+    fn decoration_positions() {
+        let baseline = 20.0;
+        // The underline starts 2px below the baseline and goes down.
+        let underline = decoration_center(baseline, -2.0, 1.0);
+        assert_eq!(underline, 22.5);
+        // The strikethrough starts 5px above the baseline, around the middle
+        // of the x-height, and goes down.
+        let strikethrough = decoration_center(baseline, 5.0, 1.0);
+        assert_eq!(strikethrough, 15.5);
+        assert!(strikethrough - 0.5 < baseline && strikethrough + 0.5 < baseline);
+        assert!(underline - 0.5 > baseline);
+        assert_eq!(decoration_thickness(0.2), 1.0);
+        assert_eq!(decoration_thickness(2.5), 2.5);
     }
-}
 
-impl Widget for MarkdowWidget {
-    fn on_pointer_event(&mut self, ctx: &mut EventCtx, event: &PointerEvent) {
-        println!("event: {event:?} >>> ctx: {}", ctx.size());
-        if let PointerEvent::MouseWheel(delta, _) = event {
-            const SCROLLING_SPEED: f64 = 3.0;
-            let delta =
-                Vec2::new(delta.x * -SCROLLING_SPEED, delta.y * -SCROLLING_SPEED);
-            self.scroll += delta;
-            let size = ctx.size();
-            let baseline = ctx.baseline_offset();
-            self.scroll.x = self.scroll.x.max(0.0);
-            self.scroll.y = self.scroll.y.max(0.0);
-            // TODO: Get corrent view port width so the horizontal scroll is
-            // possible.
-            self.scroll.x = self.scroll.x.min(0.0);
-            self.scroll.y = self
-                .scroll
-                .y
-                .min(self.markdown_layout.height() as f64 - size.height + baseline);
-            info!("scrolling new scroll: {} , self.markdown_layout.height() {}, ctx.size() {}", self.scroll, self.markdown_layout.height(), ctx.size());
-            if let Some(bla) = self.markdown_layout.flow.last() {
-                info!("bla.offset: {}", bla.offset);
+    /// Left and right edges of the glyphs of the ordered list labels where
+    /// the PDF export of `text` puts them, with the indentation of the list.
+    fn number_label_spans(text: &str) -> (f32, Vec<(f32, f32)>) {
+        let mut document = MarkdownDocument::new(text, "");
+        let pdf = document.to_pdf(PageOptions::new(Size::new(800.0, 800.0), 0.0));
+        let pdf = String::from_utf8_lossy(&pdf);
+        let Some(MarkdownContent::List { list }) =
+            document.flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a list");
+        };
+        let ListMarker::Numbers { layouted, .. } = &list.marker else {
+            panic!("Expected a numbered list");
+        };
+        let spans = layouted
+            .iter()
+            .enumerate()
+            .map(|(index, layout)| {
+                let label = list.marker.ordered_label(index).unwrap();
+                let op = pdf.find(&format!(" Td ({label}) Tj")).unwrap();
+                // The operands are `x y Td`.
+                let x: f32 = pdf[..op]
+                    .split_whitespace()
+                    .rev()
+                    .nth(1)
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let advance: f32 = layout
+                    .lines()
+                    .flat_map(|line| line.items())
+                    .map(|item| match item {
+                        PositionedLayoutItem::GlyphRun(run) => {
+                            run.positioned_glyphs().map(|glyph| glyph.advance).sum()
+                        }
+                        _ => 0.0,
+                    })
+                    .sum();
+                (x, x + advance)
+            })
+            .collect();
+        (list.indentation, spans)
+    }
+
+    #[test]
+    fn number_labels_share_their_right_edge() {
+        let theme = get_theme();
+        for text in [
+            "8. a\n9. b\n10. c\n11. d\n12. e\n",
+            "998. a\n999. b\n1000. c\n",
+        ] {
+            let (indentation, spans) = number_label_spans(text);
+            let text_gap = theme.markdown_list_after_indentation;
+            // The positions are written with two decimals.
+            for &(start, end) in &spans {
+                assert!(start >= theme.markdown_numbered_list_indentation - 0.01);
+                assert!((end - (indentation - text_gap)).abs() < 0.01);
             }
-            ctx.request_paint_only();
-            ctx.set_handled();
+            // The widest label fills the column.
+            let leftmost = spans.iter().map(|span| span.0).fold(f32::MAX, f32::min);
+            assert!(
+                (leftmost - theme.markdown_numbered_list_indentation).abs() < 0.01
+            );
         }
     }
 
-    fn register_children(&mut self, _ctx: &mut masonry::RegisterCtx) {}
+    #[test]
+    fn nested_quote_height_includes_padding() {
+        let mut theme = get_theme().clone();
+        theme.blockquote_padding_top = 3.0;
+        theme.blockquote_padding_bottom = 5.0;
+        let flow = laid_out("> outer\n>\n> > inner\n", 800.0, &theme);
+        let outer = &flow.get(0).unwrap().data;
+        let MarkdownContent::Indented {
+            flow: outer_flow, ..
+        } = outer
+        else {
+            panic!("Expected a block quote");
+        };
+        let Some(
+            inner @ MarkdownContent::Indented {
+                flow: inner_flow, ..
+            },
+        ) = outer_flow.get(1).map(|e| &e.data)
+        else {
+            panic!("Expected a nested block quote");
+        };
+        let inner_text = inner_flow.height();
+        assert_eq!(inner.height(), 3.0 + inner_text + 5.0);
+        assert_eq!(outer.height(), 3.0 + outer_flow.height() + 5.0);
+        // The outer paragraph and its margin come before the nested quote.
+        let inner_offset = outer_flow.get(1).unwrap().offset;
+        let expected = 2.0 * (3.0 + 5.0) + inner_offset + inner_text;
+        assert!((outer.height() - expected).abs() < 0.001);
+    }
 
-    fn compose(&mut self, ctx: &mut masonry::ComposeCtx) {
-        info!("compose called: size: {}, baseline_offset: {}, window_origin: {}, layout_rect: {}", ctx.size(), ctx.baseline_offset(), ctx.window_origin(), ctx.layout_rect());
+    #[test]
+    fn blocks_inside_list_items_use_the_item_width() {
+        let theme = get_theme();
+        let flow = laid_out(
+            "- item\n\n  > quoted\n\n  ![diagram](missing.png)\n\n  ```\n  code\n  ```\n",
+            400.0,
+            &theme,
+        );
+        assert_eq!(flow.len(), 1);
+        let list_height = flow.height();
+        let Some(MarkdownContent::List { list }) = flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a list");
+        };
+        assert_list_len(&flow, 0, 1);
+        let item = &list.list[0];
+        let kinds: Vec<_> = item.iter().map(|e| e.data.block_kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                BlockKind::Paragraph,
+                BlockKind::Indented,
+                BlockKind::Image,
+                BlockKind::CodeBlock
+            ]
+        );
+        // Margins are counted once, by the item flow.
+        assert_eq!(list_height, item.height());
+
+        let MarkdownContent::Image { layout_width, .. } = &item.get(2).unwrap().data
+        else {
+            panic!("Expected an image");
+        };
+        assert_eq!(*layout_width, 400.0 - list.indentation);
+
+        // The quote content starts after the list and the quote indentation.
+        let quote = item.get(1).unwrap();
+        let x =
+            (list.indentation + theme.markdown_indentation_decoration_width) as f64;
+        let y = (quote.offset + quote.height / 2.0) as f64;
+        let hit =
+            hit_test_document(&flow, Point::new(x + 1.0, y), Vec2::ZERO, &theme)
+                .unwrap();
+        assert_eq!(hit.path, [0, 0, 1, 0]);
+        assert!((hit.local_position.x - 1.0).abs() < 0.001);
+        let hit =
+            hit_test_document(&flow, Point::new(x - 1.0, y), Vec2::ZERO, &theme)
+                .unwrap();
+        assert_eq!(hit.path, [0, 0, 1]);
     }
 
-    fn layout(
-        &mut self,
-        ctx: &mut masonry::LayoutCtx,
-        bc: &masonry::BoxConstraints,
-    ) -> kurbo::Size {
-        debug!("cool layout");
-        let size = bc.max();
-        let theme = &get_theme();
-        // TODO: Think about putting the context into the theme??? Or somewhere else???
-        let (font_ctx, _layout_ctx) = ctx.text_contexts();
-        if self.dirty || self.max_advance != size.width {
-            self.markdown_layout.apply_to_all(|data| {
-                data.layout(
-                    font_ctx,
-                    &mut self.layout_ctx,
-                    size.width as f32,
-                    theme,
-                );
-            });
+    fn assert_text_within_layout(
+        text_layout: &Layout<MarkdownBrush>,
+        x: f32,
+        right: f32,
+    ) {
+        for line in text_layout.lines() {
+            let metrics = line.metrics();
+            assert!(
+                x + metrics.offset + metrics.advance <= right + 0.001,
+                "line ends at {} past {right}",
+                x + metrics.offset + metrics.advance
+            );
         }
+    }
 
-        self.max_advance = size.width;
-        self.dirty = false;
-        info!("size: {}", size);
-        size
+    /// Walks `flow` placed at `x` and checks that no text goes past `right`.
+    fn assert_text_within(flow: &LayoutFlow<MarkdownContent>, x: f32, right: f32) {
+        let theme = get_theme();
+        for element in flow.iter() {
+            match &element.data {
+                MarkdownContent::CodeBlock { text_layout, .. } => {
+                    assert_text_within_layout(
+                        text_layout,
+                        x + theme.code_block_padding,
+                        right,
+                    );
+                }
+                MarkdownContent::Paragraph { text_layout, .. }
+                | MarkdownContent::Header { text_layout, .. } => {
+                    assert_text_within_layout(text_layout, x, right)
+                }
+                MarkdownContent::Indented { flow, .. } => {
+                    assert_text_within(flow, x + quote_indentation(&theme), right)
+                }
+                MarkdownContent::Details {
+                    summary_layout,
+                    flow,
+                    ..
+                } => {
+                    let x = x + details_indentation(&theme);
+                    assert_text_within_layout(summary_layout, x, right);
+                    assert_text_within(flow, x, right);
+                }
+                MarkdownContent::List { list } => {
+                    for item in list.list.iter() {
+                        assert_text_within(item, x + list.indentation, right);
+                    }
+                }
+                _ => {}
+            }
+        }
     }
 
-    fn paint(&mut self, ctx: &mut masonry::PaintCtx, scene: &mut vello::Scene) {
-        scene.push_layer(
-            BlendMode::default(),
-            1.,
-            Affine::IDENTITY,
-            &ctx.size().to_rect(),
-        );
-        // TODO: Make scroll work
-        let source_rect =
-            Rect::new(0.0, self.scroll.y, 0.0, self.scroll.y + ctx.size().height);
-        let theme = &get_theme();
-        draw_flow(
-            scene,
-            &self.markdown_layout,
-            Vec2::new(0.0, 0.0),
-            &source_rect,
-            theme,
-            true,
+    #[test]
+    fn quoted_list_wraps_at_viewport_edge() {
+        let words = "wrapping words ".repeat(20);
+        let theme = get_theme();
+        let width = 300.0;
+        let flow = laid_out(
+            &format!("> - {words}\n>   - {words}\n>\n>   ```\n>   code\n>   ```\n"),
+            width,
+            &theme,
         );
-        scene.pop_layer();
+        assert_text_within(&flow, 0.0, width);
+
+        // The hit test finds the item text right after both indentations.
+        let Some(MarkdownContent::Indented { flow: quote, .. }) =
+            flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a block quote");
+        };
+        let Some(MarkdownContent::List { list }) = quote.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a list");
+        };
+        let x = (quote_indentation(&theme) + list.indentation) as f64;
+        let hit =
+            hit_test_document(&flow, Point::new(x + 1.0, 1.0), Vec2::ZERO, &theme)
+                .unwrap();
+        assert_eq!(hit.path, [0, 0, 0, 0]);
+        assert_eq!(hit.kind, BlockKind::Paragraph);
     }
 
-    fn accessibility_role(&self) -> accesskit::Role {
-        Role::Document
+    #[test]
+    fn spec_examples_wrap_at_narrow_width() {
+        // Examples in the style of the CommonMark spec, with words short
+        // enough to fit the width.
+        let examples = [
+            "aaa bbb ccc ddd eee fff ggg hhh iii jjj kkk lll mmm nnn ooo ppp",
+            "# Heading with several words in it which wraps\n\nText after it.",
+            "Setext heading with quite a few words in it\n===",
+            "*emphasis* and **strong emphasis** and `inline code` and \
+             [a link](/url \"title\") mixed with plain words in one paragraph",
+            "hard line break at the end of a long line of words  \nnext line",
+            "> quoted text which goes on for a while and needs several lines\n\
+             > > nested quote with some more words to wrap around",
+            "- list item with enough words to wrap onto the next line\n\
+             - another item\n\n  continuation paragraph of the item with words\n\
+             \x20 1. nested ordered item which also wraps around the edge",
+            "1. one two three four five six seven eight nine ten eleven\n\
+             2. twelve thirteen fourteen fifteen sixteen seventeen",
+            "```\nfn code() { let wrapped = \"with word wrap on\"; }\n```",
+            "    indented code block with many words that has to wrap\n",
+            "<details>\n<summary>Summary</summary>\n\n\
+             Hidden text that wraps like any other paragraph does\n\n</details>",
+        ];
+        let mut theme = get_theme().clone();
+        theme.code_block_word_wrap = true;
+        let width = 200.0;
+        for example in examples {
+            let flow = laid_out(example, width, &theme);
+            assert!(!flow.is_empty(), "nothing parsed from {example:?}");
+            assert_text_within(&flow, 0.0, width);
+        }
     }
 
-    fn accessibility(
-        &mut self,
-        _ctx: &mut masonry::AccessCtx,
-        _node: &mut accesskit::Node,
-    ) {
+    fn lay_out_text(text: &str, width: f32) -> Layout<MarkdownBrush> {
+        let mut font_ctx = FontContext::new();
+        let mut layout_ctx = LayoutContext::new();
+        let mut builder =
+            text_to_builder(text, &[], &get_theme(), &mut font_ctx, &mut layout_ctx);
+        let mut layout = builder.build(text);
+        layout.break_all_lines(Some(width));
+        layout
     }
 
-    fn children_ids(&self) -> SmallVec<[masonry::WidgetId; 16]> {
-        SmallVec::new()
+    #[test]
+    fn entities_decode_to_nbsp_and_soft_hyphen() {
+        let (text, _) = first_paragraph("glued&nbsp;words extra&shy;ordinary");
+        assert_eq!(text, "glued\u{a0}words extra\u{ad}ordinary");
+        let flow = parse_markdown("glued&nbsp;words extra&shy;ordinary");
+        assert_eq!(flow_to_plain_text(&flow), "glued\u{a0}words extraordinary");
     }
-}
 
-///// Highlight the text in a richtext builder like it was a markdown codeblock
-//pub fn highlight_as_code(
-//    attr_list: &mut AttrsList,
-//    default_attrs: Attrs,
-//    language: Option<LapceLanguage>,
-//    text: &str,
-//    start_offset: usize,
-//    config: &LapceConfig,
-//) {
-//    let syntax = language.map(Syntax::from_language);
-//
-//    let styles = syntax
-//        .map(|mut syntax| {
-//            syntax.parse(0, Rope::from(text), None);
-//            syntax.styles
-//        })
-//        .unwrap_or(None);
-//
-//    if let Some(styles) = styles {
-//        for (range, style) in styles.iter() {
-//            if let Some(color) = style
-//                .fg_color
-//                .as_ref()
-//                .and_then(|fg| config.style_color(fg))
-//            {
-//                attr_list.add_span(
-//                    start_offset + range.start..start_offset + range.end,
-//                    default_attrs.color(color),
-//                );
-//            }
-//        }
-//    }
-//}
+    #[test]
+    fn nbsp_keeps_words_on_one_line() {
+        let text = "some words then glued\u{a0}together at the end";
+        let glue = text.find('\u{a0}').unwrap();
+        for width in [60.0, 90.0, 120.0, 150.0, 180.0, 210.0] {
+            let layout = lay_out_text(text, width);
+            for line in layout.lines() {
+                let range = line.text_range();
+                assert_ne!(range.end, glue + '\u{a0}'.len_utf8(), "width {width}");
+                assert_ne!(range.end, glue, "width {width}");
+            }
+        }
+    }
 
-pub struct MarkdownView {
-    path: PathBuf,
-}
+    #[test]
+    fn soft_hyphens_show_only_at_breaks() {
+        let text = "in\u{ad}com\u{ad}pre\u{ad}hen\u{ad}si\u{ad}bil\u{ad}i\u{ad}ties";
+        let markers = [TextMarker::new(0..text.len(), MarkerKind::Bold)];
+        for width in [20.0, 40.0, 60.0, 80.0, 1000.0] {
+            let layout = lay_out_text(text, width);
+            let breaks = soft_hyphen_breaks(&layout, text);
+            let Some((hyphenated, shifted)) =
+                hyphenate_soft_breaks(&layout, text, &markers)
+            else {
+                assert!(breaks.is_empty());
+                continue;
+            };
+            assert_eq!(
+                hyphenated.matches('-').count(),
+                breaks.len(),
+                "width {width}"
+            );
+            let shy_count = text.matches('\u{ad}').count();
+            assert_eq!(
+                hyphenated.matches('\u{ad}').count(),
+                shy_count - breaks.len()
+            );
+            assert_eq!(shifted[0].range.end, hyphenated.len());
+            assert_eq!(
+                hyphenated.replace(['-', '\u{ad}'], ""),
+                "incomprehensibilities"
+            );
+        }
+        // A single line has no breaks.
+        let layout = lay_out_text(text, 1000.0);
+        assert!(soft_hyphen_breaks(&layout, text).is_empty());
+    }
 
-pub fn markdown_view(path: PathBuf) -> MarkdownView {
-    MarkdownView { path }
-}
+    #[test]
+    fn hyphenated_text_is_hit_at_its_source_offsets() {
+        let theme = get_theme();
+        let source = "in&shy;com&shy;pre&shy;hen&shy;si&shy;bil&shy;i&shy;ties \
+                      [link](https://example.com)";
+        let flow = laid_out(source, 60.0, &theme);
+        let Some(
+            paragraph @ MarkdownContent::Paragraph {
+                text,
+                shown_text: Some(shown_text),
+                text_layout,
+                ..
+            },
+        ) = flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a hyphenated paragraph");
+        };
+        assert!(shown_text.contains('-'));
+        assert_eq!(
+            map_char_offset(shown_text, text, shown_text.len()),
+            text.len()
+        );
+        let shown_link = shown_text.find("link").unwrap();
+        let run = text_layout
+            .lines()
+            .flat_map(|line| line.items())
+            .find_map(|item| match item {
+                PositionedLayoutItem::GlyphRun(run)
+                    if run.run().text_range().start == shown_link =>
+                {
+                    Some(run)
+                }
+                _ => None,
+            })
+            .unwrap();
+        let position =
+            Point::new(run.offset() as f64 + 1.0, run.baseline() as f64 - 1.0);
+        let hit = paragraph.hit_test(position, &theme, &mut Vec::new());
+        assert_eq!(hit.text_offset, text.find("link"));
+        assert!(hit.link.is_some());
+    }
 
-impl ViewMarker for MarkdownView {}
-impl<State, Action> View<State, Action, ViewCtx> for MarkdownView
-where
-    State: 'static,
-    Action: 'static,
-{
-    type Element = Pod<MarkdowWidget>;
+    #[test]
+    fn inline_code_in_heading_has_heading_size() {
+        let theme = get_theme();
+        let flow = laid_out("# Using the `Widget` type", 800.0, &theme);
+        let Some(MarkdownContent::Header { text_layout, .. }) =
+            flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a heading");
+        };
+        let heading_size = theme.heading_text_size(1);
+        for line in text_layout.lines() {
+            for item in line.items() {
+                if let PositionedLayoutItem::GlyphRun(glyph_run) = item {
+                    assert_eq!(glyph_run.run().font_size(), heading_size);
+                }
+            }
+        }
+    }
 
-    type ViewState = ();
+    #[test]
+    fn heading_line_height_comes_from_the_theme() {
+        let heading_lines = |theme: &Theme| {
+            let flow = laid_out(
+                "## A heading long enough to wrap onto a second line",
+                200.0,
+                theme,
+            );
+            let Some(MarkdownContent::Header { text_layout, .. }) =
+                flow.get(0).map(|e| &e.data)
+            else {
+                panic!("Expected a heading");
+            };
+            let line_heights: Vec<f32> = text_layout
+                .lines()
+                .map(|line| line.metrics().line_height)
+                .collect();
+            (line_heights, text_layout.height())
+        };
 
-    fn build(&self, ctx: &mut ViewCtx) -> (Self::Element, Self::ViewState) {
-        debug!("CodeView::build");
-        ctx.with_leaf_action_widget(|ctx| {
-            ctx.new_pod(MarkdowWidget::new(&self.path))
-        })
+        let mut theme = get_theme().clone();
+        let font_size = theme.heading_text_size(2);
+        for line_height in [1.2, 2.0] {
+            theme.heading_styles[1].line_height = line_height;
+            let (lines, height) = heading_lines(&theme);
+            for line in &lines {
+                assert!((line - font_size * line_height).abs() < 0.01);
+            }
+            let expected = lines.len() as f32 * font_size * line_height;
+            assert!((height - expected).abs() < 0.01);
+        }
     }
 
-    fn rebuild(
-        &self,
-        _prev: &Self,
-        _view_state: &mut Self::ViewState,
-        _ctx: &mut ViewCtx,
-        _element: xilem::core::Mut<Self::Element>,
-    ) {
-        debug!("CodeView::rebuild");
+    #[test]
+    fn quotes_pass_their_style_down() {
+        let mut theme = get_theme().clone();
+        let outer = InheritedStyle::default().quoted(&theme);
+        assert!(outer.properties().is_empty());
+
+        theme.blockquote_italic = true;
+        let outer = InheritedStyle::default().quoted(&theme);
+        assert_eq!(outer.properties().len(), 1);
+        // Nested quotes keep the style of the outer ones.
+        theme.blockquote_italic = false;
+        let inner = outer.quoted(&theme);
+        assert!(inner.italic);
+        assert_eq!(inner.color, None);
     }
 
-    fn teardown(
-        &self,
-        _view_state: &mut Self::ViewState,
-        ctx: &mut ViewCtx,
-        element: xilem::core::Mut<Self::Element>,
-    ) {
-        debug!("CodeView::teardown");
-        ctx.teardown_leaf(element);
+    #[test]
+    fn replaced_paragraph_text_updates_the_source() {
+        let edit = |widget: MarkdowWidget, index: usize, text: &str| {
+            let mut harness = TestHarness::create(widget);
+            let mut replaced = false;
+            harness.edit_root_widget(|mut root| {
+                let mut widget = root.downcast::<MarkdowWidget>();
+                replaced =
+                    MarkdowWidget::replace_paragraph_text(&mut widget, index, text);
+            });
+            let widget = harness.root_widget();
+            let source = widget
+                .downcast::<MarkdowWidget>()
+                .unwrap()
+                .source
+                .to_string();
+            (replaced, source)
+        };
+        let dir = std::env::temp_dir().join("wrenched-replace-paragraph");
+        std::fs::create_dir_all(&dir).unwrap();
+        let open = |name: &str, source: &str| {
+            let file = dir.join(name);
+            std::fs::write(&file, source).unwrap();
+            MarkdowWidget::new(file)
+        };
+        let source = "Intro\n\n## Some *title*\n\nText\n";
+        let widget = || open("doc.md", source);
+        let (replaced, new_source) = edit(widget(), 1, "New [title]");
+        assert!(replaced);
+        assert_eq!(new_source, "Intro\n\n## New \\[title\\]\n\nText\n");
+        let (replaced, new_source) = edit(widget(), 2, "1. *not* a list\nnext");
+        assert!(replaced);
+        let flow = parse_markdown(&new_source);
+        let Some(MarkdownContent::Paragraph { text, .. }) =
+            flow.get(2).map(|e| &e.data)
+        else {
+            panic!("Expected a paragraph");
+        };
+        assert_eq!(text, "1. *not* a list\nnext");
+        let Some(MarkdownContent::Header { text, .. }) =
+            flow.get(1).map(|e| &e.data)
+        else {
+            panic!("Expected a heading");
+        };
+        assert_eq!(text, "Some title");
+
+        // Merged documents keep their sources in order.
+        let mut merged = widget();
+        merged.append(open("more.md", "More"));
+        let (replaced, new_source) = edit(merged, 3, "Other");
+        assert!(replaced);
+        assert!(new_source.ends_with("Text\n\n\nOther"));
+
+        // Indentation is kept and makes no code block.
+        let (replaced, new_source) = edit(widget(), 0, "    indented\n\tnext");
+        assert!(replaced);
+        let flow = parse_markdown(&new_source);
+        let Some(MarkdownContent::Paragraph { text, .. }) =
+            flow.get(0).map(|e| &e.data)
+        else {
+            panic!("Expected a paragraph");
+        };
+        assert_eq!(text, "    indented\n\tnext");
+
+        let code = open("code.md", "```\ncode\n```\n");
+        assert!(!edit(code, 0, "Other").0);
+        assert_eq!(edit(widget(), 5, "Other"), (false, source.to_string()));
     }
 
-    fn message(
-        &self,
-        _view_state: &mut Self::ViewState,
-        _id_path: &[xilem::core::ViewId],
-        message: Box<dyn Message>,
-        _app_state: &mut State,
-    ) -> xilem::core::MessageResult<Action, Box<dyn Message>> {
-        debug!("CodeView::message");
-        match message.downcast::<masonry::Action>() {
-            Ok(action) => {
-                tracing::error!(
-                    "Wrong action type in CodeView::message: {action:?}"
-                );
-                MessageResult::Stale(action)
+    #[test]
+    fn replacing_paragraph_text_moves_the_block_ranges() {
+        let source = "Intro\n\n## Title\n\nText\n";
+        let widget = MarkdowWidget::from_source(source, PathBuf::new());
+        let mut harness = TestHarness::create(widget);
+        harness.edit_root_widget(|mut root| {
+            let mut widget = root.downcast::<MarkdowWidget>();
+            for (index, text) in [(1, "A longer title"), (0, "In"), (2, "Other")] {
+                assert!(MarkdowWidget::replace_paragraph_text(
+                    &mut widget,
+                    index,
+                    text
+                ));
             }
-            Err(message) => {
-                tracing::error!(
-                    "Wrong message type in Button::message: {message:?}"
+        });
+        let widget = harness.root_widget();
+        let widget = widget.downcast::<MarkdowWidget>().unwrap();
+        assert_eq!(&*widget.source, "In\n\n## A longer title\n\nOther\n");
+        // The source was split once, the ranges were moved since.
+        let (hash, ranges) = widget.source_blocks.clone().unwrap();
+        assert_eq!(hash, widget.source_hasher.finish());
+        assert_eq!(ranges, top_level_block_ranges(&widget.source));
+    }
+
+    #[test]
+    fn wavy_line_stays_around_the_line() {
+        let path = wavy_line(10.0, 50.0, 20.0, 16.0);
+        let bounds = path.bounding_box();
+        let amplitude = wavy_amplitude(16.0);
+        assert_eq!((bounds.x0, bounds.x1), (10.0, 50.0));
+        assert!(bounds.y0 >= 20.0 - amplitude - 1e-9);
+        assert!(bounds.y1 <= 20.0 + amplitude + 1e-9);
+        // Bigger text gets a bigger wave.
+        let big = wavy_line(10.0, 50.0, 20.0, 32.0).bounding_box();
+        assert!(big.height() > bounds.height());
+    }
+
+    #[test]
+    fn wavy_underlines_mark_ranges() {
+        let dir = std::env::temp_dir().join("wrenched-wavy-underlines");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("doc.md");
+        std::fs::write(&file, "# Title\n\nSome misspeled text\n\n---\n").unwrap();
+        let mut widget = MarkdowWidget::new(file);
+        let red = Color::from_rgb8(0xff, 0x00, 0x00);
+        assert!(widget.add_marker(1, 5..14, MarkerKind::WavyUnderline(red)));
+        assert!(!widget.add_marker(2, 0..1, MarkerKind::WavyUnderline(red)));
+        assert!(!widget.add_marker(3, 0..1, MarkerKind::WavyUnderline(red)));
+        // Clamped to the text.
+        assert!(widget.add_marker(0, 2..100, MarkerKind::WavyUnderline(red)));
+        assert_eq!(widget.dirty_items, BTreeSet::from([0, 1]));
+        lay_out(&mut widget.markdown_layout, 300.0, &get_theme());
+
+        let MarkdownContent::Paragraph { text_layout, .. } =
+            item(&widget.markdown_layout, 1)
+        else {
+            panic!("expected a paragraph");
+        };
+        for line in text_layout.lines() {
+            for item in line.items() {
+                let PositionedLayoutItem::GlyphRun(run) = item else {
+                    continue;
+                };
+                let range = run.run().text_range();
+                let underline = run.style().underline.as_ref();
+                assert_eq!(
+                    underline.is_some_and(|underline| underline.brush.1
+                        == LineStyle::Wavy
+                        && underline.brush.0 == red),
+                    range.start >= 5 && range.end <= 14,
+                    "{range:?}"
                 );
-                MessageResult::Stale(message)
             }
         }
+        let MarkdownContent::Header { markers, .. } =
+            item(&widget.markdown_layout, 0)
+        else {
+            panic!("expected a heading");
+        };
+        assert_eq!(markers[0].range, 2..5);
+    }
+
+    #[test]
+    fn horizontal_rules_use_the_theme_line_style() {
+        assert_eq!(dash_segments(&[], 10.0), [(0.0, 10.0)]);
+        assert_eq!(
+            dash_segments(LineStyle::Dashed.dash_pattern(), 30.0),
+            [(0.0, 8.0), (12.0, 20.0), (24.0, 30.0)]
+        );
+        assert_eq!(
+            dash_segments(LineStyle::Dotted.dash_pattern(), 13.0),
+            [(0.0, 2.0), (6.0, 8.0), (12.0, 13.0)]
+        );
+
+        let theme = Theme {
+            horizontal_rule_style: LineStyle::Dotted,
+            ..get_theme().clone()
+        };
+        let flow = laid_out("above\n\n---\n\nbelow\n", 300.0, &theme);
+        let Some(MarkdownContent::HorizontalLine {
+            height,
+            width,
+            style,
+            ..
+        }) = flow.get(1).map(|e| &e.data)
+        else {
+            panic!("Expected a horizontal line");
+        };
+        assert_eq!((*width, *style), (300.0, LineStyle::Dotted));
+        // The wave of a wavy rule fits in the height.
+        let wave =
+            wavy_line(0.0, 300.0, *height as f64 / 2.0, theme.text_size as f64);
+        let bounds = wave.bounding_box();
+        assert!(bounds.y0 >= 0.0 && bounds.y1 <= *height as f64);
+        assert_eq!(flow.get(1).unwrap().height, *height);
     }
 }
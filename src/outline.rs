@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+
+use tracing::error;
+use xilem::{
+    view::{button, flex, Axis},
+    WidgetView,
+};
+
+use crate::markdown::{markdown_view, table_of_contents, MarkdownAction, TocEntry};
+
+/// State of the `markdown_with_outline` component.
+#[derive(Default)]
+pub struct OutlineState {
+    /// Item index of the heading whose section is at the top of the view.
+    pub current_heading: Option<usize>,
+    /// Last clicked heading and a counter so clicking it again scrolls again.
+    scroll_request: Option<(usize, u64)>,
+    /// Outline of the shown file, extracted once per path.
+    toc: Option<(PathBuf, Vec<TocEntry>)>,
+}
+
+impl OutlineState {
+    fn toc(&mut self, path: &Path) -> &[TocEntry] {
+        if self
+            .toc
+            .as_ref()
+            .map_or(true, |(toc_path, _)| toc_path != path)
+        {
+            let toc = match std::fs::read_to_string(path) {
+                Ok(markdown) => table_of_contents(&markdown),
+                Err(e) => {
+                    error!("Failed to read {path:?} for the outline: {e}");
+                    Vec::new()
+                }
+            };
+            self.toc = Some((path.to_path_buf(), toc));
+        }
+        &self.toc.as_ref().unwrap().1
+    }
+
+    fn scroll_to(&mut self, item_index: usize) {
+        let request_id = self.scroll_request.map_or(0, |(_, id)| id + 1);
+        self.scroll_request = Some((item_index, request_id));
+        self.current_heading = Some(item_index);
+    }
+}
+
+/// Shows the markdown file with a clickable outline of its headings on the
+/// left. The section at the top of the view is highlighted in the outline.
+pub fn markdown_with_outline(
+    state: &mut OutlineState,
+    path: PathBuf,
+) -> impl WidgetView<OutlineState> {
+    let current_heading = state.current_heading;
+    let entries = state
+        .toc(&path)
+        .iter()
+        .map(|entry| {
+            let indent = "  ".repeat(entry.level as usize - 1);
+            let marker = if current_heading == Some(entry.item_index) {
+                "▸ "
+            } else {
+                "  "
+            };
+            let item_index = entry.item_index;
+            button(
+                format!("{marker}{indent}{}", entry.text),
+                move |state: &mut OutlineState| state.scroll_to(item_index),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut markdown = markdown_view::<OutlineState, ()>(path).on_action(
+        |state: &mut OutlineState, action: MarkdownAction| {
+            if let MarkdownAction::VisibleHeadingChanged(heading) = action {
+                state.current_heading = heading;
+            }
+        },
+    );
+    if let Some((item_index, request_id)) = state.scroll_request {
+        markdown = markdown.scroll_to(item_index, request_id);
+    }
+
+    flex((flex(entries).direction(Axis::Vertical), markdown))
+        .direction(Axis::Horizontal)
+}
@@ -0,0 +1,463 @@
+//! Minimal PDF writer for exporting rendered documents.
+//!
+//! Text is written with the standard Helvetica and Courier fonts, which only
+//! have the characters of the Windows-1252 character set, runs with other
+//! characters are drawn as the outlines of their glyphs instead. Each run of
+//! text is stretched to the width it was laid out at, which keeps lines
+//! aligned even though the fonts differ from the ones on screen. One layout
+//! pixel is one PDF point.
+
+use std::fmt::Write as _;
+
+use kurbo::{BezPath, PathEl, Point, Rect, Size};
+use vello::peniko::{Color, Image, ImageFormat};
+
+/// Page size and margins of an export, in points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageOptions {
+    pub size: Size,
+    pub margin: f64,
+}
+
+impl PageOptions {
+    pub const A4: Size = Size::new(595.0, 842.0);
+    pub const LETTER: Size = Size::new(612.0, 792.0);
+
+    pub fn new(size: Size, margin: f64) -> Self {
+        Self { size, margin }
+    }
+
+    /// Size of the page without the margins, never negative.
+    pub fn content_size(&self) -> Size {
+        Size::new(
+            (self.size.width - 2.0 * self.margin).max(0.0),
+            (self.size.height - 2.0 * self.margin).max(0.0),
+        )
+    }
+}
+
+impl Default for PageOptions {
+    fn default() -> Self {
+        Self::new(Self::A4, 56.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PdfFont {
+    Sans,
+    Mono,
+}
+
+impl PdfFont {
+    fn resource_name(self) -> &'static str {
+        match self {
+            PdfFont::Sans => "F1",
+            PdfFont::Mono => "F2",
+        }
+    }
+
+    /// Width of `byte` in thousandths of the font size.
+    fn glyph_width(self, byte: u8) -> u32 {
+        match self {
+            PdfFont::Mono => 600,
+            PdfFont::Sans => match byte {
+                b' '..=b'~' => HELVETICA_WIDTHS[(byte - b' ') as usize],
+                _ => 556,
+            },
+        }
+    }
+}
+
+/// Widths of the printable ASCII characters in Helvetica.
+const HELVETICA_WIDTHS: [u32; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278,
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556,
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778,
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556,
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556,
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+/// Maps `c` to Windows-1252, which the standard fonts are used with.
+fn win_ansi_byte(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' => Some(c as u8),
+        '\u{a0}'..='\u{ff}' => Some(c as u32 as u8),
+        '\u{20ac}' => Some(0x80),
+        '\u{2026}' => Some(0x85),
+        '\u{2018}' => Some(0x91),
+        '\u{2019}' => Some(0x92),
+        '\u{201c}' => Some(0x93),
+        '\u{201d}' => Some(0x94),
+        '\u{2022}' => Some(0x95),
+        '\u{2013}' => Some(0x96),
+        '\u{2014}' => Some(0x97),
+        '\t' => Some(b' '),
+        _ => None,
+    }
+}
+
+/// Returns `true` when the standard fonts have all characters of `text`.
+pub(crate) fn is_win_ansi(text: &str) -> bool {
+    text.chars().all(|c| win_ansi_byte(c).is_some())
+}
+
+/// An image placed on a page, converted to 8-bit RGB on white.
+struct PdfImage {
+    width: u32,
+    height: u32,
+    rgb: Vec<u8>,
+}
+
+/// Content of one page. Positions are relative to the top-left corner of
+/// the page and grow downwards like in the widget.
+pub(crate) struct PdfPage {
+    height: f64,
+    content: String,
+    images: Vec<PdfImage>,
+}
+
+impl PdfPage {
+    pub(crate) fn new(height: f64) -> Self {
+        Self {
+            height,
+            content: String::new(),
+            images: Vec::new(),
+        }
+    }
+
+    pub(crate) fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let rect = rect.abs();
+        if rect.area() <= 0.0 {
+            return;
+        }
+        let _ = writeln!(
+            self.content,
+            "{} rg {:.2} {:.2} {:.2} {:.2} re f",
+            rgb_operands(color),
+            rect.x0,
+            self.height - rect.y1,
+            rect.width(),
+            rect.height(),
+        );
+    }
+
+    /// Fills `path` with the non-zero winding rule.
+    pub(crate) fn fill_path(&mut self, path: &BezPath, color: Color) {
+        let mut operators = String::new();
+        let mut last = Point::ZERO;
+        let flip = |point: Point| (point.x, self.height - point.y);
+        for element in path.elements() {
+            let _ = match *element {
+                PathEl::MoveTo(p) => {
+                    let (x, y) = flip(p);
+                    writeln!(operators, "{x:.2} {y:.2} m")
+                }
+                PathEl::LineTo(p) => {
+                    let (x, y) = flip(p);
+                    writeln!(operators, "{x:.2} {y:.2} l")
+                }
+                // PDF only has cubic curves, the quadratic one is raised.
+                PathEl::QuadTo(p1, p2) => {
+                    let c1 = last + (p1 - last) * (2.0 / 3.0);
+                    let c2 = p2 + (p1 - p2) * (2.0 / 3.0);
+                    let ((x1, y1), (x2, y2), (x, y)) =
+                        (flip(c1), flip(c2), flip(p2));
+                    writeln!(
+                        operators,
+                        "{x1:.2} {y1:.2} {x2:.2} {y2:.2} {x:.2} {y:.2} c"
+                    )
+                }
+                PathEl::CurveTo(p1, p2, p3) => {
+                    let ((x1, y1), (x2, y2), (x, y)) =
+                        (flip(p1), flip(p2), flip(p3));
+                    writeln!(
+                        operators,
+                        "{x1:.2} {y1:.2} {x2:.2} {y2:.2} {x:.2} {y:.2} c"
+                    )
+                }
+                PathEl::ClosePath => writeln!(operators, "h"),
+            };
+            last = match *element {
+                PathEl::MoveTo(p) | PathEl::LineTo(p) | PathEl::QuadTo(_, p) => p,
+                PathEl::CurveTo(_, _, p) => p,
+                PathEl::ClosePath => last,
+            };
+        }
+        if operators.is_empty() {
+            return;
+        }
+        let _ = writeln!(self.content, "{} rg", rgb_operands(color));
+        self.content.push_str(&operators);
+        self.content.push_str("f\n");
+    }
+
+    /// Restricts everything drawn afterwards to `rect`.
+    pub(crate) fn clip(&mut self, rect: Rect) {
+        let _ = writeln!(
+            self.content,
+            "{:.2} {:.2} {:.2} {:.2} re W n",
+            rect.x0,
+            self.height - rect.y1,
+            rect.width(),
+            rect.height(),
+        );
+    }
+
+    /// Writes `text` with its baseline starting at `origin`, stretched to
+    /// `advance`.
+    pub(crate) fn text(
+        &mut self,
+        origin: Point,
+        font: PdfFont,
+        font_size: f32,
+        advance: f32,
+        color: Color,
+        text: &str,
+    ) {
+        let bytes: Vec<u8> = text
+            .chars()
+            .map(|c| win_ansi_byte(c).unwrap_or(b'?'))
+            .collect();
+        let natural: u32 = bytes.iter().map(|&b| font.glyph_width(b)).sum();
+        if bytes.iter().all(|&b| b == b' ') || natural == 0 || font_size <= 0.0 {
+            return;
+        }
+        let natural = natural as f32 * font_size / 1000.0;
+        let scale = (advance / natural * 100.0).clamp(50.0, 200.0);
+        let _ = write!(
+            self.content,
+            "BT {} rg /{} {:.2} Tf {:.1} Tz {:.2} {:.2} Td (",
+            rgb_operands(color),
+            font.resource_name(),
+            font_size,
+            scale,
+            origin.x,
+            self.height - origin.y,
+        );
+        for byte in bytes {
+            match byte {
+                b'(' | b')' | b'\\' => {
+                    self.content.push('\\');
+                    self.content.push(byte as char);
+                }
+                b' '..=b'~' => self.content.push(byte as char),
+                _ => {
+                    let _ = write!(self.content, "\\{byte:03o}");
+                }
+            }
+        }
+        self.content.push_str(") Tj ET\n");
+    }
+
+    /// Draws `image` scaled into `rect`, transparent parts are blended
+    /// with white.
+    pub(crate) fn image(&mut self, rect: Rect, image: &Image) {
+        if !matches!(image.format, ImageFormat::Rgba8) || rect.area() <= 0.0 {
+            return;
+        }
+        let rgb = image
+            .data
+            .data()
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let alpha = pixel[3] as u32;
+                let blend = move |c: u8| {
+                    ((c as u32 * alpha + 255 * (255 - alpha)) / 255) as u8
+                };
+                [blend(pixel[0]), blend(pixel[1]), blend(pixel[2])]
+            })
+            .collect();
+        let _ = writeln!(
+            self.content,
+            "q {:.2} 0 0 {:.2} {:.2} {:.2} cm /Im{} Do Q",
+            rect.width(),
+            rect.height(),
+            rect.x0,
+            self.height - rect.y1,
+            self.images.len(),
+        );
+        self.images.push(PdfImage {
+            width: image.width,
+            height: image.height,
+            rgb,
+        });
+    }
+}
+
+fn rgb_operands(color: Color) -> String {
+    let rgba = color.to_rgba8();
+    let alpha = rgba.a as f32 / 255.0;
+    // There is no transparency, colors are blended with the white paper.
+    let channel = |c: u8| (c as f32 * alpha + 255.0 * (1.0 - alpha)) / 255.0;
+    format!(
+        "{:.3} {:.3} {:.3}",
+        channel(rgba.r),
+        channel(rgba.g),
+        channel(rgba.b)
+    )
+}
+
+/// Writes `pages` of `size` into a PDF file.
+pub(crate) fn write_pdf(pages: &[PdfPage], size: Size) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut offsets = Vec::new();
+    out.extend_from_slice(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n");
+
+    // Objects 1 and 2 are the catalog and the page tree, 3 and 4 the fonts,
+    // the pages follow with their content and images.
+    let mut next_id = 5;
+    let mut page_ids = Vec::new();
+    for page in pages {
+        page_ids.push(next_id);
+        next_id += 2 + page.images.len();
+    }
+
+    let mut object = |out: &mut Vec<u8>, body: &[u8]| {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+        out.extend_from_slice(body);
+        out.extend_from_slice(b"\nendobj\n");
+    };
+    object(&mut out, b"<< /Type /Catalog /Pages 2 0 R >>");
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{id} 0 R")).collect();
+    object(
+        &mut out,
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            pages.len()
+        )
+        .as_bytes(),
+    );
+    for font in ["Helvetica", "Courier"] {
+        object(
+            &mut out,
+            format!(
+                "<< /Type /Font /Subtype /Type1 /BaseFont /{font} \
+                 /Encoding /WinAnsiEncoding >>"
+            )
+            .as_bytes(),
+        );
+    }
+    for (page, id) in pages.iter().zip(page_ids) {
+        let images: String = (0..page.images.len())
+            .map(|index| format!("/Im{index} {} 0 R ", id + 2 + index))
+            .collect();
+        object(
+            &mut out,
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] \
+                 /Resources << /Font << /F1 3 0 R /F2 4 0 R >> \
+                 /XObject << {images}>> >> /Contents {} 0 R >>",
+                size.width,
+                size.height,
+                id + 1,
+            )
+            .as_bytes(),
+        );
+        object(&mut out, &stream("", page.content.as_bytes()));
+        for image in &page.images {
+            let dictionary = format!(
+                "/Type /XObject /Subtype /Image /Width {} /Height {} \
+                 /ColorSpace /DeviceRGB /BitsPerComponent 8 ",
+                image.width, image.height
+            );
+            object(&mut out, &stream(&dictionary, &image.rgb));
+        }
+    }
+
+    let xref = out.len();
+    out.extend_from_slice(
+        format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes(),
+    );
+    for offset in &offsets {
+        out.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            offsets.len() + 1
+        )
+        .as_bytes(),
+    );
+    out
+}
+
+fn stream(dictionary: &str, data: &[u8]) -> Vec<u8> {
+    let mut body =
+        format!("<< {dictionary}/Length {} >>\nstream\n", data.len()).into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(b"\nendstream");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_escaped_and_encoded() {
+        let mut page = PdfPage::new(100.0);
+        page.text(
+            Point::new(10.0, 20.0),
+            PdfFont::Sans,
+            10.0,
+            50.0,
+            Color::BLACK,
+            "(a\\b) caf\u{e9} \u{2014} \u{4f60}",
+        );
+        assert!(page.content.contains("(\\(a\\\\b\\) caf\\351 \\227 ?) Tj"));
+        assert!(page.content.contains("10.00 80.00 Td"));
+    }
+
+    #[test]
+    fn paths_are_flipped_to_the_page() {
+        let mut page = PdfPage::new(100.0);
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.quad_to((30.0, 0.0), (30.0, 30.0));
+        path.line_to((0.0, 30.0));
+        path.close_path();
+        page.fill_path(&path, Color::BLACK);
+        assert_eq!(
+            page.content,
+            "0.000 0.000 0.000 rg\n0.00 100.00 m\n\
+             20.00 100.00 30.00 90.00 30.00 70.00 c\n0.00 70.00 l\nh\nf\n"
+        );
+        page.content.clear();
+        page.fill_path(&BezPath::new(), Color::BLACK);
+        assert!(page.content.is_empty());
+        assert!(is_win_ansi("caf\u{e9} \u{2014}"));
+        assert!(!is_win_ansi("\u{4f60}"));
+    }
+
+    #[test]
+    fn blank_text_is_skipped() {
+        let mut page = PdfPage::new(100.0);
+        page.text(Point::ZERO, PdfFont::Mono, 10.0, 20.0, Color::BLACK, "   ");
+        assert!(page.content.is_empty());
+    }
+
+    #[test]
+    fn xref_points_at_the_objects() {
+        let pages = [PdfPage::new(842.0), PdfPage::new(842.0)];
+        let pdf = write_pdf(&pages, PageOptions::A4);
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Kids [5 0 R 7 0 R] /Count 2"));
+        // The binary comment is replaced in `text`, which shifts its
+        // offsets, the entries are checked against the bytes.
+        let xref = text.rfind("xref\n").unwrap();
+        let entries: Vec<usize> = text[xref..]
+            .lines()
+            .skip(3)
+            .take_while(|line| line.ends_with(" n "))
+            .map(|line| line[..10].parse().unwrap())
+            .collect();
+        assert_eq!(entries.len(), 8);
+        for (index, offset) in entries.into_iter().enumerate() {
+            let object = format!("{} 0 obj", index + 1);
+            assert!(pdf[offset..].starts_with(object.as_bytes()));
+        }
+    }
+}
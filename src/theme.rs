@@ -1,28 +1,354 @@
 use std::sync::{LazyLock, RwLock, RwLockReadGuard};
 
-use parley::{FontFamily, FontStack, GenericFamily};
+use parley::{FontFamily, FontStack, FontWeight, GenericFamily};
 use vello::peniko::Color;
 
 static THEME: LazyLock<RwLock<Theme>> = LazyLock::new(|| RwLock::new(Theme::new()));
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Theme {
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
     pub text_color: Color,
+    /// Page color the document is painted on, `None` keeps whatever is
+    /// behind the widget.
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::option_color"))]
+    pub background_color: Option<Color>,
+    /// Space between the edges of the widget and the document.
+    pub content_padding: Padding,
     pub text_size: u32,
     pub scale: f32,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::font_stack"))]
     pub font_stack: FontStack<'static>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::font_stack"))]
     pub monospace_font_stack: FontStack<'static>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
     pub monospace_text_color: Color,
+    /// Font size of code line numbers relative to the code text.
+    pub code_line_number_size_ratio: f32,
+    /// Colors of code blocks, independent of the colors of the text around
+    /// them.
+    pub code_color_scheme: CodeColorScheme,
+    /// Space between the edges of a code block and its code.
+    pub code_block_padding: f32,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::option_color"))]
+    pub quote_background_color: Option<Color>,
+    /// Shows the text of block quotes in italics, explicit markers still
+    /// apply.
+    pub blockquote_italic: bool,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::option_color"))]
+    pub blockquote_text_color: Option<Color>,
+    /// Space between the border of a block quote and its content.
+    pub blockquote_padding_top: f32,
+    pub blockquote_padding_bottom: f32,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub selection_color: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub link_color: Color,
+    /// Shows an arrow after links to `http` and `https` URLs.
+    pub external_link_indicator: bool,
+    /// Underlines `mailto:` links like the other links.
+    pub underline_mail_links: bool,
+    /// Hinting can make text blurry on HiDPI displays.
+    pub text_hinting: bool,
+    // TODO: Not used until Vello supports subpixel antialiasing.
+    pub subpixel_rendering: bool,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub progress_bar_color: Color,
+    pub progress_bar_height: f32,
+    /// Colors of the right-click menu.
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub context_menu_background_color: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub context_menu_text_color: Color,
+    /// Keeps the last word of a paragraph from ending up alone on a line.
+    pub orphan_widow_control: bool,
+    /// Wraps long lines of code blocks instead of letting them overflow.
+    pub code_block_word_wrap: bool,
+    /// Last lines narrower than this fraction of the width count as short.
+    pub orphan_widow_threshold: f32,
+    /// Space between adjacent blocks like paragraphs, headings, code
+    /// blocks, lists and images. The first block of a flow gets none.
+    pub paragraph_spacing: f32,
+    /// Emphasis of the first characters of paragraphs, like small caps at
+    /// the start of a chapter.
+    pub paragraph_first_line_style: Option<FirstLineStyle>,
     pub markdown_bullet_list_indentation: f32,
     pub markdown_numbered_list_indentation: f32,
     pub markdown_list_after_indentation: f32,
+    /// Extra indentation of the paragraphs after the first one in a list
+    /// item. With zero they are aligned with the item text.
+    pub list_continuation_indent: f32,
     pub markdown_indentation_decoration_width: f32,
+    /// Border colors of nested block quotes, cycled by the nesting level.
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::colors"))]
+    pub indentation_colors: Vec<Color>,
+    /// Font size of quote attributions relative to `text_size`.
+    pub attribution_text_scale: f32,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub attribution_text_color: Color,
+    pub attribution_right_aligned: bool,
+    /// Font size of image captions relative to `text_size`.
+    pub image_caption_text_scale: f32,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub image_caption_text_color: Color,
+    /// Images taller than this are scaled down further, `None` only fits
+    /// them to the width.
+    pub image_max_height: Option<f32>,
+    /// Where images narrower than the content are placed.
+    pub image_alignment: ImageAlignment,
+    /// Color of the line around images, `None` draws no border.
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::option_color"))]
+    pub image_border_color: Option<Color>,
+    pub image_border_width: f32,
+    /// Radius of the rounded corners of images and their border.
+    pub image_corner_radius: f32,
+    /// Style of the lines of `---` horizontal rules.
+    pub horizontal_rule_style: LineStyle,
+    /// Styles of the heading levels 1 to 6, see `heading_style`.
+    pub heading_styles: [HeadingStyle; 6],
+}
+
+/// Kind of a highlighted piece of code, picks its color from a
+/// `CodeColorScheme`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenClass {
+    Plain,
+    Keyword,
+    String,
+    Comment,
+    Number,
+    Function,
+    Type,
+    Constant,
+}
+
+/// Colors of code blocks and of their syntax highlighting.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CodeColorScheme {
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::option_color"))]
+    pub background: Option<Color>,
+    /// Color of code which is not highlighted.
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub foreground: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub keyword: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub string: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub comment: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub number: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub function: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub r#type: Color,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::color"))]
+    pub constant: Color,
+}
+
+impl CodeColorScheme {
+    pub fn dark() -> Self {
+        CodeColorScheme {
+            background: Some(Color::from_rgba8(0x28, 0x28, 0x26, 0xff)),
+            foreground: Color::from_rgba8(0xff, 0x8c, 0x00, 0xff),
+            keyword: Color::from_rgba8(0xf9, 0x26, 0x72, 0xff),
+            string: Color::from_rgba8(0xe6, 0xdb, 0x74, 0xff),
+            comment: Color::from_rgba8(0x88, 0x84, 0x6f, 0xff),
+            number: Color::from_rgba8(0xae, 0x81, 0xff, 0xff),
+            function: Color::from_rgba8(0xa6, 0xe2, 0x2e, 0xff),
+            r#type: Color::from_rgba8(0x66, 0xd9, 0xef, 0xff),
+            constant: Color::from_rgba8(0xae, 0x81, 0xff, 0xff),
+        }
+    }
+
+    pub fn light() -> Self {
+        CodeColorScheme {
+            background: Some(Color::from_rgba8(0xf6, 0xf8, 0xfa, 0xff)),
+            foreground: Color::from_rgba8(0x24, 0x29, 0x2e, 0xff),
+            keyword: Color::from_rgba8(0xd7, 0x3a, 0x49, 0xff),
+            string: Color::from_rgba8(0x03, 0x2f, 0x62, 0xff),
+            comment: Color::from_rgba8(0x6a, 0x73, 0x7d, 0xff),
+            number: Color::from_rgba8(0x00, 0x5c, 0xc5, 0xff),
+            function: Color::from_rgba8(0x6f, 0x42, 0xc1, 0xff),
+            r#type: Color::from_rgba8(0xe3, 0x62, 0x09, 0xff),
+            constant: Color::from_rgba8(0x00, 0x5c, 0xc5, 0xff),
+        }
+    }
+
+    pub fn high_contrast() -> Self {
+        CodeColorScheme {
+            background: Some(Color::from_rgba8(0x00, 0x00, 0x00, 0xff)),
+            foreground: Color::from_rgba8(0xff, 0xff, 0xff, 0xff),
+            keyword: Color::from_rgba8(0xff, 0xff, 0x00, 0xff),
+            string: Color::from_rgba8(0x00, 0xff, 0x00, 0xff),
+            comment: Color::from_rgba8(0x00, 0xff, 0xff, 0xff),
+            number: Color::from_rgba8(0xff, 0x80, 0xff, 0xff),
+            function: Color::from_rgba8(0xff, 0xa0, 0x40, 0xff),
+            r#type: Color::from_rgba8(0x80, 0xc0, 0xff, 0xff),
+            constant: Color::from_rgba8(0xff, 0x80, 0xff, 0xff),
+        }
+    }
+
+    /// Returns the built-in scheme `light`, `dark` or `high-contrast`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "high-contrast" => Some(Self::high_contrast()),
+            _ => None,
+        }
+    }
+
+    pub fn token_color(&self, class: TokenClass) -> Color {
+        match class {
+            TokenClass::Plain => self.foreground,
+            TokenClass::Keyword => self.keyword,
+            TokenClass::String => self.string,
+            TokenClass::Comment => self.comment,
+            TokenClass::Number => self.number,
+            TokenClass::Function => self.function,
+            TokenClass::Type => self.r#type,
+            TokenClass::Constant => self.constant,
+        }
+    }
+}
+
+impl Default for CodeColorScheme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+/// Space on each side of a box.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Padding {
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub left: f32,
+}
+
+impl Padding {
+    pub const fn uniform(padding: f32) -> Self {
+        Padding {
+            top: padding,
+            right: padding,
+            bottom: padding,
+            left: padding,
+        }
+    }
+
+    pub fn horizontal(&self) -> f32 {
+        self.left + self.right
+    }
+
+    pub fn vertical(&self) -> f32 {
+        self.top + self.bottom
+    }
+}
+
+/// How decoration lines like underlines and horizontal rules look.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum LineStyle {
+    #[default]
+    Solid,
+    /// Wavy line used for annotations like spelling mistakes.
+    Wavy,
+    Dashed,
+    Dotted,
+}
+
+impl LineStyle {
+    /// Lengths of the alternating dashes and gaps, empty for continuous
+    /// lines.
+    pub fn dash_pattern(self) -> &'static [f64] {
+        match self {
+            LineStyle::Solid | LineStyle::Wavy => &[],
+            LineStyle::Dashed => &[8.0, 4.0],
+            LineStyle::Dotted => &[2.0, 4.0],
+        }
+    }
+}
+
+/// Style of the first `char_count` characters of a paragraph.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirstLineStyle {
+    pub char_count: usize,
+    #[cfg_attr(feature = "serde", serde(with = "serde_impl::font_weight"))]
+    pub font_weight: FontWeight,
+    pub font_variant: FontVariant,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum FontVariant {
+    #[default]
+    Normal,
+    /// Lowercase letters as small capitals, needs a font with the `smcp`
+    /// feature.
+    SmallCaps,
+}
+
+impl FontVariant {
+    /// The variant as CSS `font-feature-settings`, `None` for the normal
+    /// glyphs.
+    pub fn feature_settings(self) -> Option<&'static str> {
+        match self {
+            FontVariant::Normal => None,
+            FontVariant::SmallCaps => Some("\"smcp\" on"),
+        }
+    }
+}
+
+/// Horizontal placement of a block within the content width.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ImageAlignment {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl ImageAlignment {
+    /// Offset of a block `width` wide in `available` width.
+    pub fn offset(self, width: f64, available: f64) -> f64 {
+        let space = (available - width).max(0.0);
+        match self {
+            ImageAlignment::Left => 0.0,
+            ImageAlignment::Center => space / 2.0,
+            ImageAlignment::Right => space,
+        }
+    }
+}
+
+/// Text style of one heading level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeadingStyle {
+    /// Font size relative to `Theme::text_size`.
+    pub text_scale: f32,
+    /// Line height of wrapped headings relative to their font size. The
+    /// space around headings is `Theme::paragraph_spacing`.
+    pub line_height: f32,
 }
 
 impl Theme {
     fn new() -> Theme {
         Theme {
             text_color: Color::from_rgba8(0xf0, 0xf0, 0xea, 0xff),
+            background_color: None,
+            content_padding: Padding::default(),
             text_size: 16,
             scale: 1.0,
             font_stack: FontStack::Single(FontFamily::Generic(
@@ -32,11 +358,363 @@ impl Theme {
                 GenericFamily::Monospace,
             )),
             monospace_text_color: Color::from_rgba8(0xFF, 0x8C, 0x00, 0xff),
+            code_line_number_size_ratio: 0.9,
+            code_color_scheme: CodeColorScheme::dark(),
+            code_block_padding: 8.0,
+            quote_background_color: None,
+            blockquote_italic: false,
+            blockquote_text_color: None,
+            blockquote_padding_top: 0.0,
+            blockquote_padding_bottom: 0.0,
+            selection_color: Color::from_rgba8(0x44, 0x77, 0xcc, 0x80),
+            link_color: Color::from_rgba8(0x6c, 0xa8, 0xf0, 0xff),
+            external_link_indicator: true,
+            underline_mail_links: false,
+            text_hinting: true,
+            subpixel_rendering: false,
+            progress_bar_color: Color::from_rgba8(0x44, 0x77, 0xcc, 0xff),
+            progress_bar_height: 3.0,
+            context_menu_background_color: Color::from_rgba8(0x30, 0x30, 0x30, 0xf0),
+            context_menu_text_color: Color::from_rgba8(0xf0, 0xf0, 0xea, 0xff),
+            orphan_widow_control: false,
+            code_block_word_wrap: false,
+            orphan_widow_threshold: 0.2,
             // TODO: These should scale with text size somehow
+            paragraph_spacing: 12.0,
+            paragraph_first_line_style: None,
             markdown_bullet_list_indentation: 10.0,
             markdown_numbered_list_indentation: 5.0,
             markdown_list_after_indentation: 5.0,
+            list_continuation_indent: 0.0,
             markdown_indentation_decoration_width: 10.0,
+            indentation_colors: vec![
+                Color::from_rgba8(0x60, 0x60, 0x5c, 0xff),
+                Color::from_rgba8(0x44, 0x77, 0xcc, 0xff),
+                Color::from_rgba8(0x6a, 0x99, 0x55, 0xff),
+                Color::from_rgba8(0xb0, 0x7a, 0xc0, 0xff),
+            ],
+            attribution_text_scale: 0.85,
+            attribution_text_color: Color::from_rgba8(0xb0, 0xb0, 0xaa, 0xff),
+            attribution_right_aligned: true,
+            image_caption_text_scale: 0.85,
+            image_caption_text_color: Color::from_rgba8(0xb0, 0xb0, 0xaa, 0xff),
+            image_max_height: None,
+            image_alignment: ImageAlignment::Left,
+            image_border_color: None,
+            image_border_width: 1.0,
+            image_corner_radius: 0.0,
+            horizontal_rule_style: LineStyle::Solid,
+            heading_styles: [
+                (2.125, 1.2),
+                (1.875, 1.2),
+                (1.5, 1.25),
+                (1.25, 1.25),
+                (1.125, 1.3),
+                (1.0, 1.3),
+            ]
+            .map(|(text_scale, line_height)| HeadingStyle {
+                text_scale,
+                line_height,
+            }),
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::new()
+    }
+}
+
+/// Light or dark appearance, like the one of the operating system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThemeMode {
+    Light,
+    Dark,
+}
+
+impl From<winit::window::Theme> for ThemeMode {
+    fn from(theme: winit::window::Theme) -> Self {
+        match theme {
+            winit::window::Theme::Light => ThemeMode::Light,
+            winit::window::Theme::Dark => ThemeMode::Dark,
+        }
+    }
+}
+
+impl Theme {
+    /// The default theme, light text on a dark background.
+    pub fn dark() -> Theme {
+        Theme::new()
+    }
+
+    /// Dark text for light backgrounds.
+    pub fn light() -> Theme {
+        let text_color = Color::from_rgba8(0x24, 0x29, 0x2e, 0xff);
+        let secondary_text_color = Color::from_rgba8(0x6a, 0x73, 0x7d, 0xff);
+        Theme {
+            text_color,
+            monospace_text_color: Color::from_rgba8(0xd7, 0x3a, 0x49, 0xff),
+            code_color_scheme: CodeColorScheme::light(),
+            selection_color: Color::from_rgba8(0x03, 0x66, 0xd6, 0x40),
+            link_color: Color::from_rgba8(0x03, 0x66, 0xd6, 0xff),
+            progress_bar_color: Color::from_rgba8(0x03, 0x66, 0xd6, 0xff),
+            context_menu_background_color: Color::from_rgba8(0xf6, 0xf8, 0xfa, 0xf0),
+            context_menu_text_color: text_color,
+            indentation_colors: vec![
+                Color::from_rgba8(0xd0, 0xd7, 0xde, 0xff),
+                Color::from_rgba8(0x03, 0x66, 0xd6, 0xff),
+                Color::from_rgba8(0x28, 0xa7, 0x45, 0xff),
+                Color::from_rgba8(0x6f, 0x42, 0xc1, 0xff),
+            ],
+            attribution_text_color: secondary_text_color,
+            image_caption_text_color: secondary_text_color,
+            ..Theme::new()
+        }
+    }
+
+    pub fn for_mode(mode: ThemeMode) -> Theme {
+        match mode {
+            ThemeMode::Light => Theme::light(),
+            ThemeMode::Dark => Theme::dark(),
+        }
+    }
+}
+
+impl Theme {
+    pub fn code_text_size(&self) -> f32 {
+        self.text_size as f32
+    }
+
+    /// Style of the heading `level`, 1 for the top level. Levels out of
+    /// range are clamped.
+    pub fn heading_style(&self, level: usize) -> &HeadingStyle {
+        &self.heading_styles[level.clamp(1, 6) - 1]
+    }
+
+    /// Font size of headings of `level`.
+    pub fn heading_text_size(&self, level: usize) -> f32 {
+        self.text_size as f32 * self.heading_style(level).text_scale
+    }
+
+    /// Font size of the line numbers in the gutter of code blocks. Lay them
+    /// out on the baselines of the code lines to keep them aligned.
+    pub fn code_gutter_font_size(&self) -> f32 {
+        self.code_text_size() * self.code_line_number_size_ratio
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Theme {
+    /// Reads a theme from JSON, missing fields keep their default values.
+    pub fn from_json(json: &str) -> Result<Theme, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl CodeColorScheme {
+    /// Reads a code color scheme from JSON, the format is the same as of
+    /// `code_color_scheme` in a theme.
+    pub fn from_json(json: &str) -> Result<CodeColorScheme, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// Colors are stored as `#rrggbbaa` strings and font stacks as lists of
+/// family names, generic families use their CSS names.
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use std::borrow::Cow;
+
+    use parley::{FontFamily, FontStack, FontWeight};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use vello::peniko::Color;
+
+    fn color_to_hex(color: &Color) -> String {
+        let rgba = color.to_rgba8();
+        format!("#{:02x}{:02x}{:02x}{:02x}", rgba.r, rgba.g, rgba.b, rgba.a)
+    }
+
+    fn color_from_hex(hex: &str) -> Option<Color> {
+        let digits = hex.strip_prefix('#')?;
+        if !digits.is_ascii() || !matches!(digits.len(), 6 | 8) {
+            return None;
+        }
+        let channel =
+            |index: usize| u8::from_str_radix(&digits[index..index + 2], 16).ok();
+        let alpha = if digits.len() == 8 { channel(6)? } else { 0xff };
+        Some(Color::from_rgba8(
+            channel(0)?,
+            channel(2)?,
+            channel(4)?,
+            alpha,
+        ))
+    }
+
+    fn parse_color<E: Error>(hex: &str) -> Result<Color, E> {
+        color_from_hex(hex)
+            .ok_or_else(|| E::custom(format!("invalid color {hex:?}")))
+    }
+
+    pub mod color {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            color: &Color,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&color_to_hex(color))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Color, D::Error> {
+            parse_color(&String::deserialize(deserializer)?)
+        }
+    }
+
+    pub mod option_color {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            color: &Option<Color>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match color {
+                Some(color) => serializer.serialize_some(&color_to_hex(color)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Color>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|hex| parse_color(&hex))
+                .transpose()
+        }
+    }
+
+    pub mod colors {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            colors: &[Color],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(colors.iter().map(color_to_hex))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Color>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|hex| parse_color(hex))
+                .collect()
+        }
+    }
+
+    pub mod font_weight {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            weight: &FontWeight,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.serialize_f32(weight.value())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<FontWeight, D::Error> {
+            f32::deserialize(deserializer).map(FontWeight::new)
+        }
+    }
+
+    pub mod font_stack {
+        use super::*;
+
+        fn family_name(family: &FontFamily<'_>) -> String {
+            match family {
+                FontFamily::Named(name) => name.to_string(),
+                FontFamily::Generic(generic) => generic.to_string(),
+            }
+        }
+
+        pub fn serialize<S: Serializer>(
+            stack: &FontStack<'static>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let names: Vec<String> = match stack {
+                FontStack::Source(source) => FontFamily::parse_list(source)
+                    .map(|f| family_name(&f))
+                    .collect(),
+                FontStack::Single(family) => vec![family_name(family)],
+                FontStack::List(families) => {
+                    families.iter().map(family_name).collect()
+                }
+            };
+            serializer.collect_seq(names)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<FontStack<'static>, D::Error> {
+            let families: Vec<FontFamily<'static>> =
+                Vec::<String>::deserialize(deserializer)?
+                    .into_iter()
+                    .map(|name| {
+                        let generic = match FontFamily::parse(&name) {
+                            Some(FontFamily::Generic(generic)) => Some(generic),
+                            _ => None,
+                        };
+                        match generic {
+                            Some(generic) => FontFamily::Generic(generic),
+                            None => FontFamily::Named(Cow::Owned(name)),
+                        }
+                    })
+                    .collect();
+            match <[FontFamily<'static>; 1]>::try_from(families) {
+                Ok([family]) => Ok(FontStack::Single(family)),
+                Err(families) => Ok(FontStack::List(Cow::Owned(families))),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::theme::{CodeColorScheme, Theme};
+
+        #[test]
+        fn theme_round_trip() {
+            let theme = Theme::default();
+            let json = serde_json::to_string(&theme).unwrap();
+            assert!(json.contains(r##""text_color":"#f0f0eaff""##));
+            assert!(json.contains(r#""font_stack":["sans-serif"]"#));
+            let read = Theme::from_json(&json).unwrap();
+            assert_eq!(serde_json::to_string(&read).unwrap(), json);
+
+            let theme =
+                Theme::from_json(r##"{"text_size": 20, "link_color": "#102030"}"##)
+                    .unwrap();
+            assert_eq!(theme.text_size, 20);
+            assert_eq!(theme.link_color.to_rgba8().b, 0x30);
+            assert!(Theme::from_json(r#"{"link_color": "blue"}"#).is_err());
+        }
+
+        #[test]
+        fn code_color_scheme_round_trip() {
+            let scheme = CodeColorScheme::light();
+            let json = serde_json::to_string(&scheme).unwrap();
+            assert!(json.contains(r##""type":"#e36209ff""##));
+            assert_eq!(CodeColorScheme::from_json(&json).unwrap(), scheme);
+
+            let theme =
+                Theme::from_json(&format!(r#"{{"code_color_scheme": {json}}}"#))
+                    .unwrap();
+            assert_eq!(theme.code_color_scheme, scheme);
         }
     }
 }
@@ -44,3 +722,24 @@ impl Theme {
 pub fn get_theme<'a>() -> RwLockReadGuard<'a, Theme> {
     (*THEME).read().unwrap()
 }
+
+/// Replaces the theme of all widgets. Widgets pick it up on their next
+/// layout, see `MarkdowWidget::set_theme_mode`.
+pub fn set_theme(theme: Theme) {
+    *(*THEME).write().unwrap() = theme;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Theme, ThemeMode};
+
+    #[test]
+    fn theme_modes_follow_the_system_appearance() {
+        let light = Theme::for_mode(winit::window::Theme::Light.into());
+        let dark = Theme::for_mode(ThemeMode::from(winit::window::Theme::Dark));
+        assert_eq!(light.text_color, Theme::light().text_color);
+        assert_eq!(dark.text_color, Theme::default().text_color);
+        assert_ne!(light.text_color, dark.text_color);
+        assert_ne!(light.code_color_scheme, dark.code_color_scheme);
+    }
+}